@@ -67,3 +67,12 @@ app.kubernetes.io/component: webhook
 {{- define "checkpoint.caIssuerName" -}}
 {{ include "checkpoint.fullname" . }}-ca
 {{- end -}}
+
+{{/*
+Annotations of CRDs with the conversion webhook, to inject the CA bundle with cert-manager
+*/}}
+{{- define "checkpoint.conversionAnnotations" -}}
+{{- if not .Values.certBootstrap }}
+cert-manager.io/inject-ca-from: {{ printf "%s/%s" .Release.Namespace (include "checkpoint.fullname" .) }}
+{{- end }}
+{{- end -}}
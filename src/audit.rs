@@ -0,0 +1,465 @@
+//! Audit trail of requests denied by rules, and notifications of the denials
+
+use std::{collections::HashMap, future::Future, sync::Mutex};
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use kube::{
+    api::{ObjectMeta, Patch, PatchParams, PostParams},
+    core::{
+        admission::{AdmissionRequest, AdmissionResponse},
+        DynamicObject,
+    },
+    discovery::ApiResource,
+    Api, Resource, ResourceExt,
+};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::Deserialize;
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+use tracing::Instrument;
+
+use crate::{
+    checker::{fingerprint, notify, CheckerOutput, CheckerState, NotificationRecord},
+    config::WebhookConfig,
+    types::{
+        denial::{
+            DenialRecord, DenialRecordObject, DenialRecordRule, DenialRecordSpec, DenialRecordUser,
+            RecentDenial,
+        },
+        policy::CronPolicyNotification,
+        rule::{MutatingRule, NamespacedMutatingRule, NamespacedValidatingRule, ValidatingRule},
+    },
+};
+
+/// Label key of DenialRecords to select the records of a rule
+pub const DENIAL_RECORD_RULE_LABEL_KEY: &str = "checkpoint.devsisters.com/rule";
+
+/// Make the record of the request denied by the rule
+///
+/// `would_deny` marks the request allowed by the rollout of the rule.
+pub fn make_denial_record<K>(
+    rule: &K,
+    req: &AdmissionRequest<DynamicObject>,
+    resp: &AdmissionResponse,
+    would_deny: bool,
+) -> DenialRecordSpec
+where
+    K: Resource<DynamicType = ()>,
+{
+    DenialRecordSpec {
+        rule: DenialRecordRule {
+            kind: K::kind(&()).into_owned(),
+            name: rule.name_any(),
+            namespace: rule.namespace(),
+        },
+        request_uid: req.uid.clone(),
+        operation: serde_json::to_value(&req.operation)
+            .ok()
+            .and_then(|operation| operation.as_str().map(str::to_string))
+            .unwrap_or_default(),
+        object: DenialRecordObject {
+            group: req.kind.group.clone(),
+            version: req.kind.version.clone(),
+            kind: req.kind.kind.clone(),
+            namespace: req.namespace.clone(),
+            name: req.name.clone(),
+        },
+        user: DenialRecordUser {
+            username: req.user_info.username.clone(),
+            groups: req.user_info.groups.clone(),
+        },
+        reason: resp.result.message.clone(),
+        would_deny,
+        time: Time(Utc::now()),
+    }
+}
+
+/// Notifications sent recently by rule, to throttle the notifications of the denials in this replica
+static NOTIFICATION_STATES: Lazy<Mutex<HashMap<String, CheckerState>>> =
+    Lazy::new(Default::default);
+
+/// Output of the denial, available in notification templates as `{output.<key>}`
+fn make_denial_output(record: &DenialRecordSpec) -> CheckerOutput {
+    let object = match &record.object.namespace {
+        Some(namespace) => format!("{}/{}", namespace, record.object.name),
+        None => record.object.name.clone(),
+    };
+    CheckerOutput::Flat(
+        [
+            ("operation", record.operation.clone()),
+            ("kind", record.object.kind.clone()),
+            ("object", object),
+            (
+                "user",
+                record
+                    .user
+                    .username
+                    .clone()
+                    .unwrap_or_else(|| "unknown".to_string()),
+            ),
+            ("reason", record.reason.clone()),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value))
+        .collect(),
+    )
+}
+
+/// Number of denials waiting to be notified, over which denials are not notified
+const NOTIFICATION_QUEUE_SIZE: usize = 256;
+
+/// Denial to notify, with the span of the request
+type DenialNotification = (CronPolicyNotification, DenialRecordSpec, tracing::Span);
+
+/// Queue of denials to notify, or None if the notifier failed to start
+static NOTIFICATION_QUEUE: Lazy<Option<Sender<DenialNotification>>> = Lazy::new(|| {
+    start_notifier()
+        .map_err(|error| tracing::error!(%error, "failed to start notifier of denials"))
+        .ok()
+});
+
+/// Start the thread notifying the denials from the queue one by one
+///
+/// Notifying is not `Send`, so it runs on its own single-threaded Tokio runtime.
+fn start_notifier() -> Result<Sender<DenialNotification>> {
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(NOTIFICATION_QUEUE_SIZE);
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("failed to create Tokio runtime")?;
+    std::thread::Builder::new()
+        .name("denial-notifier".to_string())
+        .spawn(move || {
+            rt.block_on(async move {
+                while let Some((notifications, record, span)) = receiver.recv().await {
+                    notify_denial(notifications, record).instrument(span).await;
+                }
+            })
+        })
+        .context("failed to spawn notifier thread")?;
+    Ok(sender)
+}
+
+/// Queue the denial to notify to the channels of the rule in background
+///
+/// The denial is not notified if the queue is full, not to pile up denials behind slow channels.
+pub fn queue_denial_notification(notifications: CronPolicyNotification, record: DenialRecordSpec) {
+    let Some(sender) = &*NOTIFICATION_QUEUE else {
+        return;
+    };
+    match sender.try_send((notifications, record, tracing::Span::current())) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => {
+            tracing::warn!("queue of denial notifications is full, so the denial is not notified")
+        }
+        Err(TrySendError::Closed(_)) => tracing::error!("notifier of denials is stopped"),
+    }
+}
+
+/// Notify the denial to the channels of the rule, unless throttled
+///
+/// The name of the rule is available in templates as `{policy.name}`.
+async fn notify_denial(notifications: CronPolicyNotification, record: DenialRecordSpec) {
+    let rule_name = match &record.rule.namespace {
+        Some(namespace) => format!("{}/{}", namespace, record.rule.name),
+        None => record.rule.name.clone(),
+    };
+    let output = make_denial_output(&record);
+
+    let now = Utc::now();
+    let throttle = notifications.throttle.clone().unwrap_or_default();
+    {
+        let mut states = NOTIFICATION_STATES.lock().unwrap();
+        let state = states
+            .entry(format!("{}/{}", record.rule.kind, rule_name))
+            .or_default();
+        state.prune_notifications(&throttle, now);
+        let fingerprint = fingerprint(&output);
+        if let Some(reason) = state.throttle_reason(&throttle, &fingerprint, now) {
+            tracing::info!(%reason, "notification of the denial is throttled");
+            return;
+        }
+        state.notifications.push(NotificationRecord {
+            fingerprint,
+            time: now,
+        });
+    }
+
+    // Denials are not threaded
+    notify(rule_name, output, None, notifications, &mut HashMap::new()).await;
+}
+
+/// Whether any audit sink is configured
+pub fn is_enabled(config: &WebhookConfig) -> bool {
+    config.audit_log
+        || config.audit_denial_record_namespace.is_some()
+        || config.audit_webhook_url.is_some()
+        || config.audit_recent_denials > 0
+}
+
+/// Number of denials waiting for each audit sink, over which denials are not recorded to the sink
+const AUDIT_QUEUE_SIZE: usize = 1024;
+
+/// Maximum number of denials written to a sink at once
+const AUDIT_BATCH_SIZE: usize = 64;
+
+/// Queues of the audit sinks writing to the API server and the audit webhook, started on the first denial
+static AUDIT_SINKS: OnceCell<AuditSinks> = OnceCell::new();
+
+/// Queues of the configured audit sinks, each written by a single task
+struct AuditSinks {
+    webhook: Option<Sender<DenialRecordSpec>>,
+    recent_denials: Option<Sender<DenialRecordSpec>>,
+    denial_records: Option<Sender<DenialRecordSpec>>,
+}
+
+impl AuditSinks {
+    fn start(kube_client: kube::Client, config: &WebhookConfig) -> Self {
+        let webhook = config.audit_webhook_url.clone().map(|url| {
+            spawn_audit_writer("audit webhook", move |records| {
+                post_denial_records(url.clone(), records)
+            })
+        });
+        let recent_denials = (config.audit_recent_denials > 0).then(|| {
+            let client = kube_client.clone();
+            let limit = config.audit_recent_denials;
+            spawn_audit_writer("recent denials", move |records| {
+                append_recent_denials(client.clone(), records, limit)
+            })
+        });
+        let denial_records = config
+            .audit_denial_record_namespace
+            .clone()
+            .map(|namespace| {
+                spawn_audit_writer("DenialRecords", move |records| {
+                    create_denial_records(kube_client.clone(), namespace.clone(), records)
+                })
+            });
+        Self {
+            webhook,
+            recent_denials,
+            denial_records,
+        }
+    }
+}
+
+/// Spawn the task writing the denials from a bounded queue to the sink, in batches of the queued ones
+fn spawn_audit_writer<F, Fut>(sink: &'static str, mut write: F) -> Sender<DenialRecordSpec>
+where
+    F: FnMut(Vec<DenialRecordSpec>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send,
+{
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(AUDIT_QUEUE_SIZE);
+    tokio::spawn(async move {
+        while let Some(record) = receiver.recv().await {
+            let mut records = vec![record];
+            while records.len() < AUDIT_BATCH_SIZE {
+                let Ok(record) = receiver.try_recv() else {
+                    break;
+                };
+                records.push(record);
+            }
+            if let Err(error) = write(records).await {
+                tracing::error!(%sink, error = format!("{:#}", error), "failed to record denials");
+            }
+        }
+    });
+    sender
+}
+
+/// Queue the denial to the sink, dropping it if the queue is full
+fn queue_denial(sink: &str, sender: &Option<Sender<DenialRecordSpec>>, record: &DenialRecordSpec) {
+    let Some(sender) = sender else {
+        return;
+    };
+    match sender.try_send(record.clone()) {
+        Ok(()) => {}
+        Err(TrySendError::Full(_)) => tracing::warn!(
+            %sink,
+            request_uid = %record.request_uid,
+            "audit queue is full, so the denial is not recorded"
+        ),
+        Err(TrySendError::Closed(_)) => tracing::error!(%sink, "audit writer is stopped"),
+    }
+}
+
+/// Record the denial to the configured sinks
+///
+/// The denial is logged right away, and queued for the other sinks to write in background. Failure of a sink is
+/// logged, and does not prevent recording to the others.
+pub fn record_denial(kube_client: &kube::Client, config: &WebhookConfig, record: DenialRecordSpec) {
+    if config.audit_log {
+        tracing::info!(
+            target: "checkpoint::audit",
+            rule_kind = %record.rule.kind,
+            rule_name = %record.rule.name,
+            rule_namespace = ?record.rule.namespace,
+            request_uid = %record.request_uid,
+            operation = %record.operation,
+            object_kind = %record.object.kind,
+            object_namespace = ?record.object.namespace,
+            object_name = %record.object.name,
+            user = ?record.user.username,
+            reason = %record.reason,
+            would_deny = record.would_deny,
+            "request denied",
+        );
+    }
+
+    let sinks = AUDIT_SINKS.get_or_init(|| AuditSinks::start(kube_client.clone(), config));
+    queue_denial("audit webhook", &sinks.webhook, &record);
+    queue_denial("recent denials", &sinks.recent_denials, &record);
+    queue_denial("DenialRecords", &sinks.denial_records, &record);
+}
+
+async fn post_denial_records(url: url::Url, records: Vec<DenialRecordSpec>) -> Result<()> {
+    let client = reqwest::Client::new();
+    for record in records {
+        client
+            .post(url.clone())
+            .json(&record)
+            .send()
+            .await
+            .context("failed to request to audit webhook")?
+            .error_for_status()
+            .context("audit webhook returned error")?;
+    }
+    Ok(())
+}
+
+/// Prepend the denials to the recent denials in the status of their rules, with a single patch per rule
+///
+/// Failure of a rule is logged, and does not prevent recording to the others.
+async fn append_recent_denials(
+    kube_client: kube::Client,
+    records: Vec<DenialRecordSpec>,
+    limit: usize,
+) -> Result<()> {
+    let mut denials_by_rule: Vec<(DenialRecordRule, Vec<RecentDenial>)> = Vec::new();
+    for record in records {
+        let rule = record.rule.clone();
+        let index = denials_by_rule.iter().position(|(other, _)| {
+            (&other.kind, &other.namespace, &other.name)
+                == (&rule.kind, &rule.namespace, &rule.name)
+        });
+        let denials = match index {
+            Some(index) => &mut denials_by_rule[index].1,
+            None => {
+                denials_by_rule.push((rule, Vec::new()));
+                &mut denials_by_rule.last_mut().unwrap().1
+            }
+        };
+        // Newest first
+        denials.insert(0, RecentDenial::from(record));
+    }
+
+    for (rule, denials) in denials_by_rule {
+        let res = append_recent_denial(kube_client.clone(), &rule, denials, limit).await;
+        if let Err(error) = res {
+            tracing::error!(
+                rule_kind = %rule.kind,
+                rule_name = %rule.name,
+                rule_namespace = ?rule.namespace,
+                error = format!("{:#}", error),
+                "failed to append recent denials to rule status"
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Prepend the denials to the recent denials in the status of the rule, dropping the oldest ones over the limit
+///
+/// Denials are written with the resource version of the rule, and retried on conflicts with other replicas.
+async fn append_recent_denial(
+    kube_client: kube::Client,
+    rule: &DenialRecordRule,
+    denials: Vec<RecentDenial>,
+    limit: usize,
+) -> Result<()> {
+    let ar = match rule.kind.as_str() {
+        "ValidatingRule" => ApiResource::erase::<ValidatingRule>(&()),
+        "MutatingRule" => ApiResource::erase::<MutatingRule>(&()),
+        "NamespacedValidatingRule" => ApiResource::erase::<NamespacedValidatingRule>(&()),
+        "NamespacedMutatingRule" => ApiResource::erase::<NamespacedMutatingRule>(&()),
+        kind => anyhow::bail!("unsupported rule kind `{}`", kind),
+    };
+    let api: Api<DynamicObject> = match &rule.namespace {
+        Some(namespace) => Api::namespaced_with(kube_client, namespace, &ar),
+        None => Api::all_with(kube_client, &ar),
+    };
+
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let object = api
+            .get_status(&rule.name)
+            .await
+            .context("failed to get rule")?;
+        let previous_denials = match object.data.pointer("/status/recentDenials") {
+            Some(recent_denials) => Vec::<RecentDenial>::deserialize(recent_denials)
+                .context("failed to deserialize recent denials")?,
+            None => Vec::new(),
+        };
+        let mut recent_denials = denials.clone();
+        recent_denials.extend(previous_denials);
+        recent_denials.truncate(limit);
+
+        let patch = serde_json::json!({
+            "metadata": {
+                "resourceVersion": object.resource_version(),
+            },
+            "status": {
+                "recentDenials": recent_denials,
+            },
+        });
+        match api
+            .patch_status(&rule.name, &PatchParams::default(), &Patch::Merge(patch))
+            .await
+        {
+            Err(kube::Error::Api(response)) if response.code == 409 && attempts < 3 => continue,
+            res => {
+                res.context("failed to patch rule status")?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn create_denial_records(
+    kube_client: kube::Client,
+    namespace: String,
+    records: Vec<DenialRecordSpec>,
+) -> Result<()> {
+    for record in records {
+        create_denial_record(kube_client.clone(), &namespace, record).await?;
+    }
+    Ok(())
+}
+
+async fn create_denial_record(
+    kube_client: kube::Client,
+    namespace: &str,
+    record: DenialRecordSpec,
+) -> Result<()> {
+    let denial_record = DenialRecord {
+        metadata: ObjectMeta {
+            generate_name: Some(format!("{}-", record.rule.name)),
+            labels: Some(
+                [(
+                    DENIAL_RECORD_RULE_LABEL_KEY.to_string(),
+                    record.rule.name.clone(),
+                )]
+                .into(),
+            ),
+            ..Default::default()
+        },
+        spec: record,
+    };
+    Api::<DenialRecord>::namespaced(kube_client, namespace)
+        .create(&PostParams::default(), &denial_record)
+        .await
+        .context("failed to create DenialRecord")?;
+    Ok(())
+}
@@ -1,18 +1,51 @@
-use std::collections::HashMap;
+use std::time::Instant;
 
 use anyhow::{Context, Result};
+use chrono::Utc;
 
 use checkpoint::{
-    checker::{fetch_resources, notify, prepare_js_runtime},
+    checker::{
+        fetch_resources, fingerprint, load_state, notify, prepare_js_runtime, push_metrics,
+        save_state, update_finding_annotations, CheckerOutput, CheckerState, Finding,
+        NotificationRecord,
+    },
     config::CheckerConfig,
-    js::eval,
+    js::{eval, set_context},
+    types::policy::Severity,
 };
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    checkpoint::config::init_tracing()?;
 
-    let config = CheckerConfig::try_from_env().context("failed to parse config from env")?;
+    let config = CheckerConfig::load().context("failed to load config")?;
+    let policy_name = config.policy_name.clone();
+    let pushgateway_url = config.pushgateway_url.clone();
+
+    let started = Instant::now();
+    let result = run(config).await;
+
+    // Export metrics of the run
+    if let Some(pushgateway_url) = pushgateway_url {
+        let output = result.as_ref().ok().and_then(Option::as_ref);
+        let res = push_metrics(
+            &pushgateway_url,
+            &policy_name,
+            started.elapsed(),
+            result.is_ok(),
+            output,
+        )
+        .await;
+        if let Err(error) = res {
+            tracing::error!(%error, "failed to push metrics");
+        }
+    }
+
+    result.map(|_| ())
+}
+
+/// Run the check, and return the output
+async fn run(config: CheckerConfig) -> Result<Option<CheckerOutput>> {
     let kube_config = kube::Config::infer()
         .await
         .context("failed to infer Kubernetes config")?;
@@ -21,23 +54,89 @@ async fn main() -> Result<()> {
         .context("failed to make Kubernetes client")?;
 
     // Fetch resources
-    let resources = fetch_resources(kube_client, &config.resources).await?;
+    let resources = fetch_resources(kube_client.clone(), &config.resources).await?;
 
     // Set up runtime
-    let mut js_runtime =
-        prepare_js_runtime(resources).context("failed to prepare JavaScript runtime")?;
+    let mut js_runtime = prepare_js_runtime(&resources, config.allow_remediation, None)
+        .context("failed to prepare JavaScript runtime")?;
+
+    // Load the state of the previous run
+    let state_location = config
+        .state_configmap_name
+        .as_deref()
+        .zip(config.namespace.as_deref());
+    let mut state = match state_location {
+        Some((name, namespace)) => load_state(kube_client.clone(), namespace, name).await?,
+        None => CheckerState::default(),
+    };
+    set_context(&mut js_runtime, "previousOutput", &state.output)
+        .context("failed to set previous output")?;
 
     js_runtime
         .execute_script("<checkpoint>", config.code.into())
         .context("failed to execute JavaScript code")?;
+    js_runtime
+        .run_event_loop(false)
+        .await
+        .context("failed to execute JavaScript code")?;
 
-    let output: Option<HashMap<String, String>> =
+    let output: Option<CheckerOutput> =
         eval(&mut js_runtime, "__checkpoint_get_context(\"output\")")
             .context("failed to evaluate JavaScript code")?;
+    let severity: Option<Severity> =
+        eval(&mut js_runtime, "__checkpoint_get_context(\"severity\")")
+            .context("failed to evaluate severity")?;
+
+    // Annotate objects with findings
+    if config.finding_annotation {
+        let findings: Vec<Finding> =
+            eval(&mut js_runtime, "__checkpoint_get_context(\"findings\")")
+                .context("failed to evaluate JavaScript code")?;
+        update_finding_annotations(
+            kube_client.clone(),
+            &config.policy_name,
+            &config.resources,
+            &resources,
+            &findings,
+        )
+        .await
+        .context("failed to update finding annotations")?;
+    }
+
+    let now = Utc::now();
+    let throttle = config.notifications.throttle.clone().unwrap_or_default();
+    state.prune_notifications(&throttle, now);
+
+    if let Some(output) = &output {
+        let fingerprint = fingerprint(output);
+        if config.notify_only_on_change && Some(output) == state.output.as_ref() {
+            tracing::info!("output is not changed since the previous run, skipping notification");
+        } else if let Some(reason) = state.throttle_reason(&throttle, &fingerprint, now) {
+            tracing::info!(%reason, "notification is throttled");
+        } else {
+            notify(
+                config.policy_name,
+                output.clone(),
+                severity,
+                config.notifications,
+                &mut state.slack_threads,
+            )
+            .await;
+            state.notifications.push(NotificationRecord {
+                fingerprint,
+                time: now,
+            });
+        }
+    } else {
+        // Start new Slack threads for the next failure
+        state.slack_threads.clear();
+    }
 
-    if let Some(output) = output {
-        notify(config.policy_name, output, config.notifications).await;
+    // Store the state for the next run
+    if let Some((name, namespace)) = state_location {
+        state.output = output.clone();
+        save_state(kube_client, namespace, name, &state).await?;
     }
 
-    Ok(())
+    Ok(output)
 }
@@ -1,31 +1,70 @@
 use std::{
     collections::HashMap,
     fs,
+    future::Future,
+    io::IsTerminal,
+    net::SocketAddr,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Context, Result};
+use axum_server::tls_rustls::RustlsConfig;
 use clap::{Args, Parser, Subcommand};
+use futures_util::{future, stream, StreamExt};
 use itertools::Itertools;
 use json_patch::PatchOperation;
+use k8s_openapi::{
+    api::{
+        apps::v1::{ControllerRevision, ReplicaSet},
+        core::v1::{ConfigMap, Secret},
+    },
+    apimachinery::pkg::apis::meta::v1::OwnerReference,
+};
 use kube::{
-    core::{admission::AdmissionRequest, DynamicObject, ObjectList},
-    ResourceExt,
+    api::ListParams,
+    core::{
+        admission::{AdmissionRequest, Operation},
+        DynamicObject, GroupVersionKind, GroupVersionResource, TypeMeta,
+    },
+    discovery::{ApiResource, Discovery, Scope},
+    Api, ResourceExt,
 };
+use serde::{Deserialize, Serialize};
+use stopper::Stopper;
 use tracing::Instrument;
 
 use checkpoint::{
-    checker::fetch_resources,
+    checker::{fetch_resources, SingleOrList},
+    config::{dynamic::DynamicConfig, WebhookConfig},
+    filewatcher::FileWatcher,
     handler::{
-        js::helper::{KubeGetArgument, KubeListArgument, KubeListArgumentListParamsVersionMatch},
-        mutate, validate,
+        js::ScriptKey,
+        mutate,
+        rule_source::{FileRules, RuleSource},
+        validate,
     },
-    js::eval,
+    js::{
+        eval, set_context,
+        stub::{StubCalls, Stubs},
+    },
+    lint::{lint_document, Severity},
+    reconcile::rule::webhook_entries,
+    recording::{
+        parse_recording, RecordedResponse, RECORDING_RULE_KIND_LABEL_KEY, RECORDING_RULE_LABEL_KEY,
+        RECORDING_RULE_NAMESPACE_LABEL_KEY,
+    },
+    tls::CertSource,
     types::{
-        policy::CronPolicy,
-        rule::{MutatingRule, ValidatingRule},
-        testcase::{Case, TestCase},
+        policy::{CronPolicy, CronPolicyValueFrom},
+        rule::{MutatingRule, RuleSpec, ValidatingRule},
+        testcase::{Case, CheckCase, Step, Stub, TestCase},
     },
+    util::glob_match,
 };
 
 #[derive(Parser, Debug)]
@@ -38,12 +77,218 @@ struct Cli {
 enum Commands {
     Test(TestArgs),
     Check(CheckArgs),
+    Lint(LintArgs),
+    Eval(EvalArgs),
+    /// Show the mutation a MutatingRule would apply to the object
+    Diff(EvalArgs),
+    /// Generate test case inputs
+    #[clap(subcommand)]
+    Fixture(FixtureCommands),
+    /// Convert rules to other kinds of resources
+    #[clap(subcommand)]
+    Export(ExportCommands),
+    /// Convert policies of other policy engines to ValidatingRule skeletons
+    Import(ImportArgs),
+    /// Serve the webhook with rules loaded from local files, without CRDs installed
+    Serve(ServeArgs),
+    /// Evaluate a rule against the requests recorded in the cluster, and report the changed decisions
+    Replay(ReplayArgs),
+}
+
+#[derive(Args, Debug)]
+struct ReplayArgs {
+    /// YAML file of a ValidatingRule, MutatingRule, NamespacedValidatingRule, or NamespacedMutatingRule
+    #[clap(long, value_parser)]
+    rule: PathBuf,
+    /// Name of the rule the requests were recorded for.  Defaults to the name of the rule in the file.
+    #[clap(long, value_parser)]
+    recorded_rule: Option<String>,
+    /// Namespace of the recordings.  Defaults to the namespace of the current context.
+    #[clap(short, long, value_parser)]
+    namespace: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct ServeArgs {
+    /// Directory of YAML files of Rules
+    #[clap(long, value_parser)]
+    rules: PathBuf,
+    /// Certificate path for HTTPS
+    #[clap(long, value_parser)]
+    cert: PathBuf,
+    /// Certificate key path for HTTPS
+    #[clap(long, value_parser)]
+    key: PathBuf,
+    #[clap(long, value_parser, default_value = "[::]:3000")]
+    listen_addr: SocketAddr,
+}
+
+#[derive(Args, Debug)]
+struct ImportArgs {
+    /// Policy engine the files are written for
+    #[clap(long, value_enum)]
+    from: ImportSource,
+    /// YAML files of the policies, possibly with multiple documents
+    #[clap(value_parser, required = true)]
+    paths: Vec<PathBuf>,
+    /// File to write the rules to.  Defaults to stdout.
+    #[clap(short, long, value_parser)]
+    output: Option<PathBuf>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ImportSource {
+    /// ClusterPolicies and Policies of Kyverno
+    Kyverno,
+    /// Constraints of Gatekeeper, with their ConstraintTemplates
+    Gatekeeper,
+}
+
+#[derive(Subcommand, Debug)]
+enum ExportCommands {
+    /// Render a ValidatingRule as ValidatingAdmissionPolicy and ValidatingAdmissionPolicyBinding manifests
+    Vap(ExportVapArgs),
+}
+
+#[derive(Args, Debug)]
+struct ExportVapArgs {
+    /// YAML file of a ValidatingRule
+    #[clap(long, value_parser)]
+    rule: PathBuf,
+    /// CEL expression equivalent to the JS code, which evaluates to true to allow the request.  Can be repeated.
+    ///
+    /// JS code cannot be converted to CEL, so the expressions must be given.
+    #[clap(long = "validation", value_parser, required = true)]
+    validations: Vec<String>,
+    /// Message of denied requests
+    #[clap(long, value_parser)]
+    message: Option<String>,
+    /// File to write the manifests to.  Defaults to stdout.
+    #[clap(short, long, value_parser)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum FixtureCommands {
+    /// Make an AdmissionRequest from an object in the cluster
+    Get(FixtureGetArgs),
+    /// Make a CONNECT AdmissionRequest of `kubectl exec` or `kubectl attach` into a Pod
+    Exec(FixtureExecArgs),
+}
+
+#[derive(Args, Debug)]
+struct FixtureGetArgs {
+    /// Object to fetch in `<kind>/<name>` format.  Kind can also be a resource name, e.g. `deployments.apps`.
+    #[clap(value_parser)]
+    object: String,
+    /// Namespace of the object.  Defaults to the namespace of the current context.
+    #[clap(short, long, value_parser)]
+    namespace: Option<String>,
+    #[clap(long, value_enum, ignore_case = true, default_value_t = OperationArg::Create)]
+    operation: OperationArg,
+    /// Build oldObject of UPDATE requests from the previous revision of a Deployment, StatefulSet, or DaemonSet,
+    /// instead of the object itself
+    #[clap(long)]
+    previous_revision: bool,
+    /// File to write the AdmissionRequest to.  Defaults to stdout.
+    #[clap(short, long, value_parser)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct FixtureExecArgs {
+    /// Name of the Pod
+    #[clap(value_parser)]
+    pod: String,
+    /// Namespace of the Pod
+    #[clap(short, long, value_parser, default_value = "default")]
+    namespace: String,
+    /// Container to run the command in
+    #[clap(short, long, value_parser)]
+    container: Option<String>,
+    /// Attach to the running container instead of running the command
+    #[clap(long, conflicts_with = "command")]
+    attach: bool,
+    /// Pass stdin to the container
+    #[clap(short = 'i', long)]
+    stdin: bool,
+    /// Allocate a TTY
+    #[clap(short, long)]
+    tty: bool,
+    /// File to write the AdmissionRequest to.  Defaults to stdout.
+    #[clap(short, long, value_parser)]
+    output: Option<PathBuf>,
+    /// Command to run in the container
+    #[clap(value_parser, last = true)]
+    command: Vec<String>,
 }
 
 #[derive(Args, Debug)]
 struct TestArgs {
     #[clap(value_parser)]
     test_case_paths: Vec<PathBuf>,
+    /// Run only the cases with names matching the glob pattern, e.g. `deny-*`
+    #[clap(long, value_parser)]
+    filter: Option<String>,
+    /// Number of test case files to run concurrently
+    #[clap(short, long, value_parser, default_value_t = 1)]
+    jobs: usize,
+    /// Stop at the first failed case
+    #[clap(long)]
+    fail_fast: bool,
+    /// Fail cases with kubeGet or kubeList stubs that are never called
+    #[clap(long)]
+    strict_stubs: bool,
+    /// File to write the report of the results to
+    #[clap(long, value_parser)]
+    report: Option<PathBuf>,
+    /// Format of the report
+    #[clap(long, value_enum, default_value_t = ReportFormat::Junit)]
+    format: ReportFormat,
+    /// Run the test case files again whenever they or the files they refer to change
+    #[clap(long)]
+    watch: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ReportFormat {
+    /// JUnit XML
+    Junit,
+    Json,
+}
+
+struct TestOptions {
+    filter: Option<String>,
+    fail_fast: bool,
+    strict_stubs: bool,
+    /// Set when a case failed with `fail_fast`
+    stopped: AtomicBool,
+}
+
+impl TestOptions {
+    /// Whether the case is selected by the filter
+    fn selects(&self, case_name: &str) -> bool {
+        self.filter
+            .as_ref()
+            .map_or(true, |filter| glob_match(filter, case_name))
+    }
+}
+
+/// Result of a test case file
+#[derive(Debug)]
+struct FileOutcome {
+    path: PathBuf,
+    duration: Duration,
+    /// Error if the file could not be loaded, or the results of the cases
+    result: Result<Vec<CaseOutcome>>,
+}
+
+/// Result of a case in a test case file
+#[derive(Debug)]
+struct CaseOutcome {
+    name: String,
+    duration: Duration,
+    error: Option<anyhow::Error>,
 }
 
 #[derive(Args, Debug)]
@@ -52,6 +297,60 @@ struct CheckArgs {
     cron_policy_paths: Vec<PathBuf>,
 }
 
+#[derive(Args, Debug)]
+struct LintArgs {
+    /// YAML files of Rules and Policies, possibly with multiple documents
+    #[clap(value_parser)]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct EvalArgs {
+    /// YAML file of a ValidatingRule, MutatingRule, NamespacedValidatingRule, or NamespacedMutatingRule
+    #[clap(long, value_parser)]
+    rule: PathBuf,
+    /// YAML manifest of the object in the request
+    #[clap(long, value_parser)]
+    object: PathBuf,
+    /// YAML manifest of the existing object, for UPDATE requests.  Defaults to the object.
+    #[clap(long, value_parser)]
+    old_object: Option<PathBuf>,
+    #[clap(long, value_enum, ignore_case = true, default_value_t = OperationArg::Create)]
+    operation: OperationArg,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum OperationArg {
+    Create,
+    Update,
+    Delete,
+    Connect,
+}
+
+impl From<OperationArg> for Operation {
+    fn from(operation: OperationArg) -> Self {
+        match operation {
+            OperationArg::Create => Operation::Create,
+            OperationArg::Update => Operation::Update,
+            OperationArg::Delete => Operation::Delete,
+            OperationArg::Connect => Operation::Connect,
+        }
+    }
+}
+
+/// Result of `eval` subcommand
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EvalOutput {
+    allowed: bool,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    patch: Option<Vec<PatchOperation>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warnings: Option<Vec<String>>,
+}
+
 #[derive(Debug)]
 struct CaseResult {
     allowed: bool,
@@ -76,27 +375,341 @@ async fn main() -> Result<()> {
     match cli.subcommand {
         Commands::Test(args) => cli_test(args).await,
         Commands::Check(args) => cli_check(args).await,
+        Commands::Lint(args) => cli_lint(args),
+        Commands::Eval(args) => cli_eval(args).await,
+        Commands::Diff(args) => cli_diff(args).await,
+        Commands::Fixture(FixtureCommands::Get(args)) => cli_fixture_get(args).await,
+        Commands::Fixture(FixtureCommands::Exec(args)) => cli_fixture_exec(args),
+        Commands::Export(ExportCommands::Vap(args)) => cli_export_vap(args),
+        Commands::Import(args) => cli_import(args),
+        Commands::Serve(args) => cli_serve(args).await,
+        Commands::Replay(args) => cli_replay(args).await,
     }
 }
 
 async fn cli_test(args: TestArgs) -> Result<()> {
-    for test_case_path in args.test_case_paths {
-        let test_case_span =
-            tracing::info_span!("test-case-file", path = %test_case_path.display());
-        run_test_case(&test_case_path)
-            .instrument(test_case_span)
-            .await
-            .with_context(|| {
-                format!(
-                    "failed to test for test case file `{}`",
-                    test_case_path.display()
-                )
-            })?;
+    let result = run_tests(&args, args.test_case_paths.clone()).await;
+    if !args.watch {
+        return result;
+    }
+    if let Err(error) = result {
+        tracing::error!("{:#}", error);
+    }
+    watch_tests(&args).await
+}
+
+/// Map the files test case files refer to, including themselves, to the test case files
+fn test_case_dependencies(test_case_paths: &[PathBuf]) -> HashMap<PathBuf, Vec<PathBuf>> {
+    let mut dependencies: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    for test_case_path in test_case_paths {
+        dependencies
+            .entry(test_case_path.clone())
+            .or_default()
+            .push(test_case_path.clone());
+
+        // Files of a broken test case are found again when it is fixed
+        let Ok(test_case_file) = fs::File::open(test_case_path) else {
+            continue;
+        };
+        let Ok(test_case) = serde_yaml::from_reader::<_, TestCase>(test_case_file) else {
+            continue;
+        };
+        let test_case_base_path = test_case_path.parent().unwrap();
+        for path in test_case.referenced_paths(test_case_base_path) {
+            dependencies
+                .entry(path)
+                .or_default()
+                .push(test_case_path.clone());
+        }
+    }
+    dependencies
+}
+
+/// Re-run test case files whenever the files they refer to change, until interrupted
+async fn watch_tests(args: &TestArgs) -> Result<()> {
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+
+    let mut dependencies = HashMap::new();
+    let mut watcher_stopper: Option<Stopper> = None;
+    loop {
+        // Watch again if test case files refer to other files
+        let new_dependencies = test_case_dependencies(&args.test_case_paths);
+        if watcher_stopper.is_none() || new_dependencies != dependencies {
+            dependencies = new_dependencies;
+            if let Some(stopper) = watcher_stopper.take() {
+                stopper.stop();
+            }
+
+            let stopper = Stopper::new();
+            let mut watcher = FileWatcher::new(
+                {
+                    let sender = sender.clone();
+                    move |changed_paths| {
+                        let sender = sender.clone();
+                        async move {
+                            let _ = sender.send(changed_paths).await;
+                        }
+                    }
+                },
+                10,
+                stopper.clone(),
+            );
+            for path in dependencies.keys() {
+                watcher.watch(path.clone());
+            }
+            watcher.spawn().context("failed to watch files")?;
+            watcher_stopper = Some(stopper);
+        }
+        tracing::info!("watching for changes");
+
+        let Some(changed_paths) = receiver.recv().await else {
+            return Ok(());
+        };
+        let affected_paths = args
+            .test_case_paths
+            .iter()
+            .filter(|test_case_path| {
+                changed_paths.iter().any(|changed_path| {
+                    dependencies
+                        .get(changed_path)
+                        .map_or(false, |dependents| dependents.contains(test_case_path))
+                })
+            })
+            .cloned()
+            .collect();
+        if let Err(error) = run_tests(args, affected_paths).await {
+            tracing::error!("{:#}", error);
+        }
+    }
+}
+
+/// Run the test case files, and write the report of them
+async fn run_tests(args: &TestArgs, test_case_paths: Vec<PathBuf>) -> Result<()> {
+    let options = TestOptions {
+        filter: args.filter.clone(),
+        fail_fast: args.fail_fast,
+        strict_stubs: args.strict_stubs,
+        stopped: AtomicBool::new(false),
+    };
+    let options = &options;
+
+    let outcomes: Vec<FileOutcome> = stream::iter(test_case_paths)
+        .map(|test_case_path| async move {
+            // Do not start other files after a failure
+            if options.stopped.load(Ordering::Relaxed) {
+                return None;
+            }
+            let test_case_span =
+                tracing::info_span!("test-case-file", path = %test_case_path.display());
+            let start = Instant::now();
+            let result = run_test_case(&test_case_path, options)
+                .instrument(test_case_span)
+                .await;
+            let duration = start.elapsed();
+            let failed = match &result {
+                Ok(outcomes) => outcomes.iter().any(|outcome| outcome.error.is_some()),
+                Err(_) => true,
+            };
+            if failed && options.fail_fast {
+                options.stopped.store(true, Ordering::Relaxed);
+            }
+            Some(FileOutcome {
+                path: test_case_path,
+                duration,
+                result,
+            })
+        })
+        // Keep the order of the files in the report
+        .buffered(args.jobs.max(1))
+        .filter_map(future::ready)
+        .collect()
+        .await;
+
+    let (mut passed, mut failed) = (0, 0);
+    for outcome in &outcomes {
+        match &outcome.result {
+            Ok(case_outcomes) => {
+                for case_outcome in case_outcomes {
+                    if case_outcome.error.is_some() {
+                        tracing::error!(
+                            path = %outcome.path.display(),
+                            case = case_outcome.name,
+                            "failed"
+                        );
+                        failed += 1;
+                    } else {
+                        passed += 1;
+                    }
+                }
+            }
+            Err(error) => {
+                tracing::error!(
+                    path = %outcome.path.display(),
+                    "failed to test for test case file: {:#}",
+                    error
+                );
+                failed += 1;
+            }
+        }
+    }
+
+    tracing::info!(passed, failed, "finished");
+    if let Some(report_path) = &args.report {
+        let report = match args.format {
+            ReportFormat::Junit => junit_report(&outcomes),
+            ReportFormat::Json => json_report(&outcomes)?,
+        };
+        fs::write(report_path, report)
+            .with_context(|| format!("failed to write report to `{}`", report_path.display()))?;
+    }
+    if failed > 0 {
+        return Err(anyhow!("{} test(s) failed", failed));
     }
     Ok(())
 }
 
-async fn run_test_case(test_case_path: &Path) -> Result<()> {
+/// Escape the text to put in XML attributes or elements
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' => escaped.push_str("&#10;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Make a JUnit XML report, with a test suite for each test case file
+fn junit_report(outcomes: &[FileOutcome]) -> String {
+    let mut suites = String::new();
+    let (mut total_tests, mut total_failures, mut total_errors) = (0, 0, 0);
+    for outcome in outcomes {
+        let path = xml_escape(&outcome.path.display().to_string());
+        let mut test_cases = String::new();
+        let (mut tests, mut failures, mut errors) = (0, 0, 0);
+        match &outcome.result {
+            Ok(case_outcomes) => {
+                for case_outcome in case_outcomes {
+                    tests += 1;
+                    let name = xml_escape(&case_outcome.name);
+                    let time = case_outcome.duration.as_secs_f64();
+                    match &case_outcome.error {
+                        Some(error) => {
+                            failures += 1;
+                            test_cases += &format!(
+                                r#"    <testcase name="{name}" classname="{path}" time="{time:.3}">
+      <failure message="{}">{}</failure>
+    </testcase>
+"#,
+                                xml_escape(&error.to_string()),
+                                xml_escape(&format!("{:#}", error)),
+                            );
+                        }
+                        None => {
+                            test_cases += &format!(
+                                r#"    <testcase name="{name}" classname="{path}" time="{time:.3}"/>
+"#
+                            )
+                        }
+                    }
+                }
+            }
+            // Report a file failed to load as a test case with the name of the file
+            Err(error) => {
+                tests += 1;
+                errors += 1;
+                test_cases += &format!(
+                    r#"    <testcase name="{path}" classname="{path}" time="{:.3}">
+      <error message="{}"/>
+    </testcase>
+"#,
+                    outcome.duration.as_secs_f64(),
+                    xml_escape(&format!("{:#}", error)),
+                );
+            }
+        }
+        suites += &format!(
+            r#"  <testsuite name="{path}" tests="{tests}" failures="{failures}" errors="{errors}" time="{:.3}">
+{test_cases}  </testsuite>
+"#,
+            outcome.duration.as_secs_f64(),
+        );
+        total_tests += tests;
+        total_failures += failures;
+        total_errors += errors;
+    }
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites tests="{total_tests}" failures="{total_failures}" errors="{total_errors}">
+{suites}</testsuites>
+"#
+    )
+}
+
+/// Report of a test case file in JSON report
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonFileReport {
+    path: PathBuf,
+    duration_seconds: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    cases: Vec<JsonCaseReport>,
+}
+
+/// Report of a case in JSON report
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonCaseReport {
+    name: String,
+    passed: bool,
+    duration_seconds: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Make a JSON report, with the results of the cases in each test case file
+fn json_report(outcomes: &[FileOutcome]) -> Result<String> {
+    let files: Vec<_> = outcomes
+        .iter()
+        .map(|outcome| {
+            let (error, cases) = match &outcome.result {
+                Ok(case_outcomes) => (
+                    None,
+                    case_outcomes
+                        .iter()
+                        .map(|case_outcome| JsonCaseReport {
+                            name: case_outcome.name.clone(),
+                            passed: case_outcome.error.is_none(),
+                            duration_seconds: case_outcome.duration.as_secs_f64(),
+                            error: case_outcome
+                                .error
+                                .as_ref()
+                                .map(|error| format!("{:#}", error)),
+                        })
+                        .collect(),
+                ),
+                Err(error) => (Some(format!("{:#}", error)), Vec::new()),
+            };
+            JsonFileReport {
+                path: outcome.path.clone(),
+                duration_seconds: outcome.duration.as_secs_f64(),
+                error,
+                cases,
+            }
+        })
+        .collect();
+    serde_json::to_string_pretty(&files).context("failed to serialize report")
+}
+
+async fn run_test_case(test_case_path: &Path, options: &TestOptions) -> Result<Vec<CaseOutcome>> {
     // Open and deserialize test case file
     let test_case_file = fs::File::open(test_case_path).context("failed to open test case file")?;
     let test_case: TestCase =
@@ -120,38 +733,88 @@ async fn run_test_case(test_case_path: &Path) -> Result<()> {
         .try_collect()
         .context("failed to load validating rules")?;
 
+    let cron_policy: Option<CronPolicy> = test_case
+        .cron_policy
+        .map(|fnoo| fnoo.into_object(test_case_base_path))
+        .transpose()
+        .context("failed to load cron policy")?;
+
     // Evaulate cases
+    let mut outcomes = Vec::new();
     for (i, case) in test_case.cases.into_iter().enumerate() {
         let case_name = case.name.clone().unwrap_or_else(|| format!("{}", i));
+        if !options.selects(&case_name) {
+            continue;
+        }
+        if options.stopped.load(Ordering::Relaxed) {
+            break;
+        }
+
         let case_span = tracing::info_span!("case", case = case_name);
-        run_case(
+        let case = run_case(
             case,
             test_case_base_path,
             &mutating_rules,
             &validating_rules,
-        )
-        .instrument(case_span)
-        .await
-        .with_context(|| format!("failed to test for case \"{}\"", case_name))?;
+            options.strict_stubs,
+        );
+        outcomes.push(record_case(case_name, case_span, options, case).await);
     }
 
-    Ok(())
+    // Evaluate check cases of the CronPolicy
+    for (i, check_case) in test_case.check_cases.into_iter().enumerate() {
+        let case_name = check_case
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("check-{}", i));
+        if !options.selects(&case_name) {
+            continue;
+        }
+        if options.stopped.load(Ordering::Relaxed) {
+            break;
+        }
+
+        let cron_policy = cron_policy
+            .as_ref()
+            .ok_or_else(|| anyhow!("cronPolicy is required for checkCases"))?;
+        let case_span = tracing::info_span!("check-case", case = case_name);
+        let case = run_check_case(
+            check_case,
+            test_case_base_path,
+            cron_policy,
+            options.strict_stubs,
+        );
+        outcomes.push(record_case(case_name, case_span, options, case).await);
+    }
+
+    Ok(outcomes)
 }
 
-async fn run_case(
-    case: Case,
-    test_case_base_path: &Path,
-    mutating_rules: &[MutatingRule],
-    validating_rules: &[ValidatingRule],
-) -> Result<()> {
-    let mut request = case
-        .request
-        .into_object(test_case_base_path)
-        .context("failed to load request")?;
+/// Run the case in the span, logging the failure
+async fn record_case(
+    case_name: String,
+    case_span: tracing::Span,
+    options: &TestOptions,
+    case: impl Future<Output = Result<()>>,
+) -> CaseOutcome {
+    let start = Instant::now();
+    let result = case.instrument(case_span.clone()).await;
+    if let Err(error) = &result {
+        case_span.in_scope(|| tracing::error!("{:#}", error));
+        if options.fail_fast {
+            options.stopped.store(true, Ordering::Relaxed);
+        }
+    }
+    CaseOutcome {
+        name: case_name,
+        duration: start.elapsed(),
+        error: result.err(),
+    }
+}
 
-    // Make stub map
-    let kube_get_stub_map = case
-        .stubs
+/// Load kubeGet, kubeList and time stubs of a case
+fn load_stubs(stubs: Stub, test_case_base_path: &Path) -> Result<Stubs> {
+    let kube_get_stub_map = stubs
         .kube_get
         .into_iter()
         .map(|stub| {
@@ -161,8 +824,7 @@ async fn run_case(
         })
         .try_collect()
         .context("failed to load kubeGet stub map")?;
-    let kube_list_stub_map = case
-        .stubs
+    let kube_list_stub_map = stubs
         .kube_list
         .into_iter()
         .map(|stub| {
@@ -172,11 +834,232 @@ async fn run_case(
         })
         .try_collect()
         .context("failed to load kubeList stub map")?;
+    let mut loaded = Stubs::new(kube_get_stub_map, kube_list_stub_map);
+    loaded.kube_discover = stubs
+        .kube_discover
+        .map(|kube_discover| kube_discover.into_object(test_case_base_path))
+        .transpose()
+        .context("failed to load kubeDiscover stub")?;
+    loaded.now = stubs.now;
+    Ok(loaded)
+}
+
+/// Fail if any stub is never called
+fn check_unused_stubs(stubs: &Stubs) -> Result<()> {
+    let calls = stubs.calls();
+    let unused_kube_get = stubs
+        .kube_get
+        .keys()
+        .filter(|args| !calls.kube_get.contains_key(args))
+        .map(|args| serde_json::json!(args).to_string());
+    let unused_kube_list = stubs
+        .kube_list
+        .keys()
+        .filter(|args| !calls.kube_list.contains_key(args))
+        .map(|args| serde_json::json!(args).to_string());
+    let unused = unused_kube_get.chain(unused_kube_list).join(", ");
+    if !unused.is_empty() {
+        return Err(anyhow!("test failed. stubs are not called: {}", unused));
+    }
+    Ok(())
+}
+
+/// Compare the number of stub calls since `calls_before` with the expected ones
+fn check_stub_calls(
+    stubs: &Stubs,
+    calls_before: &StubCalls,
+    expected_kube_get_calls: Option<usize>,
+    expected_kube_list_calls: Option<usize>,
+) -> Result<()> {
+    let calls = stubs.calls();
+    for (function, expected_calls, actual_calls) in [
+        (
+            "kubeGetCalls",
+            expected_kube_get_calls,
+            calls.kube_get_total() - calls_before.kube_get_total(),
+        ),
+        (
+            "kubeListCalls",
+            expected_kube_list_calls,
+            calls.kube_list_total() - calls_before.kube_list_total(),
+        ),
+    ] {
+        if let Some(expected_calls) = expected_calls {
+            if expected_calls != actual_calls {
+                return Err(anyhow!(
+                    "test failed. `{}` expected: {}, actual: {}",
+                    function,
+                    expected_calls,
+                    actual_calls
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run the code of the CronPolicy with the resource fixtures instead of fetching them
+async fn run_check_case(
+    check_case: CheckCase,
+    test_case_base_path: &Path,
+    cron_policy: &CronPolicy,
+    strict_stubs: bool,
+) -> Result<()> {
+    if cron_policy.spec.code_from.is_some() {
+        return Err(anyhow!("codeFrom cannot be tested, use code instead"));
+    }
+
+    let resources: Vec<SingleOrList> = check_case
+        .resources
+        .into_iter()
+        .map(|fnoo| {
+            fnoo.into_object(test_case_base_path)
+                .map(SingleOrList::Value)
+        })
+        .try_collect()
+        .context("failed to load resources")?;
+    if resources.len() != cron_policy.spec.resources.len() {
+        return Err(anyhow!(
+            "{} resources are given, but the CronPolicy has {} resources",
+            resources.len(),
+            cron_policy.spec.resources.len()
+        ));
+    }
+    let stubs = Arc::new(load_stubs(check_case.stubs, test_case_base_path)?);
+
+    let mut js_runtime = checkpoint::checker::prepare_js_runtime(
+        &resources,
+        cron_policy.spec.allow_remediation,
+        Some(stubs.clone()),
+    )
+    .context("failed to prepare JavaScript runtime")?;
+    set_context(
+        &mut js_runtime,
+        "previousOutput",
+        &check_case.previous_output,
+    )
+    .context("failed to set previous output")?;
+
+    js_runtime
+        .execute_script("<checkpoint>", cron_policy.spec.code.clone().into())
+        .context("failed to execute JavaScript code")?;
+    js_runtime
+        .run_event_loop(false)
+        .await
+        .context("failed to execute JavaScript code")?;
+    let output: Option<checkpoint::checker::CheckerOutput> =
+        eval(&mut js_runtime, "__checkpoint_get_context(\"output\")")
+            .context("failed to evaluate JavaScript code")?;
+
+    if check_case.expected.output != output {
+        return Err(anyhow!(
+            "test failed. `output` expected: {:?}, actual: {:?}",
+            check_case.expected.output,
+            output
+        ));
+    }
+    check_stub_calls(
+        &stubs,
+        &StubCalls::default(),
+        check_case.expected.kube_get_calls,
+        check_case.expected.kube_list_calls,
+    )?;
+    if strict_stubs {
+        check_unused_stubs(&stubs)?;
+    }
+    tracing::info!("passed");
+
+    Ok(())
+}
+
+async fn run_case(
+    case: Case,
+    test_case_base_path: &Path,
+    mutating_rules: &[MutatingRule],
+    validating_rules: &[ValidatingRule],
+    strict_stubs: bool,
+) -> Result<()> {
+    let stubs = Arc::new(load_stubs(case.stubs, test_case_base_path)?);
+
+    let scenario = !case.steps.is_empty();
+    let steps = match (case.request, case.expected) {
+        (Some(request), Some(expected)) if !scenario => vec![Step { request, expected }],
+        (None, None) if scenario => case.steps,
+        _ => {
+            return Err(anyhow!(
+                "either request and expected, or steps must be specified"
+            ))
+        }
+    };
+
+    // The object stored in the cluster after each step
+    let mut stored_object = None;
+    for (i, step) in steps.into_iter().enumerate() {
+        let step_span = if scenario {
+            tracing::info_span!("step", step = i)
+        } else {
+            tracing::Span::none()
+        };
+        stored_object = run_step(
+            step,
+            stored_object,
+            test_case_base_path,
+            mutating_rules,
+            validating_rules,
+            &stubs,
+        )
+        .instrument(step_span)
+        .await
+        .with_context(|| format!("failed at step {}", i))?;
+    }
+
+    if strict_stubs {
+        check_unused_stubs(&stubs)?;
+    }
+    tracing::info!("passed");
+
+    Ok(())
+}
+
+/// Evaluate a request of a case, returning the object stored in the cluster after the request
+///
+/// Objects of UPDATE and DELETE requests default to the object stored by the previous steps.
+async fn run_step(
+    step: Step,
+    stored_object: Option<DynamicObject>,
+    test_case_base_path: &Path,
+    mutating_rules: &[MutatingRule],
+    validating_rules: &[ValidatingRule],
+    stubs: &Arc<Stubs>,
+) -> Result<Option<DynamicObject>> {
+    let calls_before = stubs.calls();
+    let mut request = step
+        .request
+        .into_object(test_case_base_path)
+        .context("failed to load request")?;
+    match request.operation {
+        Operation::Update => {
+            if request.old_object.is_none() {
+                request.old_object = stored_object.clone();
+            }
+            if request.object.is_none() {
+                request.object = stored_object.clone();
+            }
+        }
+        Operation::Delete => {
+            // The API server sends the deleted object as `oldObject`, with `object` null
+            let object = request.object.take();
+            if request.old_object.is_none() {
+                request.old_object = object.or_else(|| stored_object.clone());
+            }
+        }
+        _ => {}
+    }
 
     let expected = CaseResult {
-        allowed: case.expected.allowed,
-        message: case.expected.message,
-        final_object: case
+        allowed: step.expected.allowed,
+        message: step.expected.message,
+        final_object: step
             .expected
             .final_object
             .map(|fnoo| fnoo.into_object(test_case_base_path))
@@ -198,7 +1081,7 @@ async fn run_case(
             .ok_or_else(|| anyhow!("rule does not have name"))?;
         let rule_span = tracing::info_span!("mutating-rule", rule = rule_name);
 
-        actual = run_mutating_rule(rule, &mut request, &kube_get_stub_map, &kube_list_stub_map)
+        actual = run_mutating_rule(rule, &mut request, stubs)
             .instrument(rule_span.clone())
             .await
             .with_context(|| format!("failed to test for rule \"{}\"", rule_name))?;
@@ -220,7 +1103,7 @@ async fn run_case(
             .ok_or_else(|| anyhow!("rule does not have name"))?;
         let rule_span = tracing::info_span!("validating-rule", rule = rule_name);
 
-        actual = run_validating_rule(rule, &request, &kube_get_stub_map, &kube_list_stub_map)
+        actual = run_validating_rule(rule, &request, stubs)
             .instrument(rule_span.clone())
             .await
             .with_context(|| format!("failed to test for rule \"{}\"", rule_name))?;
@@ -257,23 +1140,36 @@ async fn run_case(
                 .context("failed to serialize actual final object of failed test")?,
         ));
     }
-    tracing::info!("passed");
+    check_stub_calls(
+        stubs,
+        &calls_before,
+        step.expected.kube_get_calls,
+        step.expected.kube_list_calls,
+    )?;
 
-    Ok(())
+    // Denied requests do not change the stored object
+    Ok(if !actual.allowed {
+        stored_object
+    } else if request.operation == Operation::Delete {
+        None
+    } else {
+        actual.final_object
+    })
 }
 
 async fn run_mutating_rule(
     rule: &MutatingRule,
     request: &mut AdmissionRequest<DynamicObject>,
-    kube_get: &HashMap<KubeGetArgument, Option<DynamicObject>>,
-    kube_list: &HashMap<KubeListArgument, ObjectList<DynamicObject>>,
+    stubs: &Arc<Stubs>,
 ) -> Result<CaseResult> {
-    let js_context = prepare_js_context_for_test_case(kube_get, kube_list)
-        .context("failed to prepare JavaScript stub code")?;
-
-    let response = mutate(&rule.spec.0, request, js_context)
-        .await
-        .context("failed to mutate")?;
+    let response = mutate(
+        &rule.spec.0,
+        request,
+        Some(ScriptKey::of(rule, &rule.spec.0.code)),
+        Some(stubs.clone()),
+    )
+    .await
+    .context("failed to mutate")?;
     let patch = response
         .patch
         .map(|patch| serde_json::from_slice::<Vec<PatchOperation>>(&patch))
@@ -309,15 +1205,16 @@ async fn run_mutating_rule(
 async fn run_validating_rule(
     rule: &ValidatingRule,
     request: &AdmissionRequest<DynamicObject>,
-    kube_get: &HashMap<KubeGetArgument, Option<DynamicObject>>,
-    kube_list: &HashMap<KubeListArgument, ObjectList<DynamicObject>>,
+    stubs: &Arc<Stubs>,
 ) -> Result<CaseResult> {
-    let js_context = prepare_js_context_for_test_case(kube_get, kube_list)
-        .context("failed to prepare JavaScript stub code")?;
-
-    let response = validate(&rule.spec.0, request, js_context)
-        .await
-        .context("failed to validate")?;
+    let response = validate(
+        &rule.spec.0,
+        request,
+        Some(ScriptKey::of(rule, &rule.spec.0.code)),
+        Some(stubs.clone()),
+    )
+    .await
+    .context("failed to validate")?;
 
     Ok(CaseResult {
         allowed: response.allowed,
@@ -326,131 +1223,6 @@ async fn run_validating_rule(
     })
 }
 
-/// Prepare test JS context with stubs
-fn prepare_js_context_for_test_case(
-    kube_get: &HashMap<KubeGetArgument, Option<DynamicObject>>,
-    kube_list: &HashMap<KubeListArgument, ObjectList<DynamicObject>>,
-) -> Result<String> {
-    let mut code = r#"function kubeGet(args) {
-    if (false) {
-        // Nothing
-    }"#
-    .to_string();
-
-    // Populate kubeGet
-    for (args, object) in kube_get {
-        code += &format!(
-            r#" else if (args.kind === "{}" && args.version === "{}" && {} && {} && args.name === "{}") {{
-        return {};
-    }}"#,
-            args.kind,
-            args.version,
-            if let Some(plural) = &args.plural {
-                format!("args.plural === \"{}\"", plural)
-            } else {
-                "args.plural === undefined".to_string()
-            },
-            if let Some(namespace) = &args.namespace {
-                format!("args.namespace === \"{}\"", namespace)
-            } else {
-                "args.namespace === undefined".to_string()
-            },
-            args.name,
-            serde_json::to_string(&object).context("failed to serialize Kubernetes object")?,
-        );
-    }
-
-    code += r#" else {
-        throw new Error("kubeGet stub not found");
-    }
-}
-function kubeList(args) {
-    if (false) {
-        // Nothing
-    }"#;
-
-    // Populate kubeList
-    for (args, object_list) in kube_list {
-        code += &format!(
-            r#" else if (args.kind === "{}" && args.version === "{}" && {} && {} && {}) {{
-        return {};
-    }}"#,
-            args.kind,
-            args.version,
-            if let Some(plural) = &args.plural {
-                format!("args.plural === \"{}\"", plural)
-            } else {
-                "args.plural === undefined".to_string()
-            },
-            if let Some(namespace) = &args.namespace {
-                format!("args.namespace === \"{}\"", namespace)
-            } else {
-                "args.namespace === undefined".to_string()
-            },
-            if let Some(list_params) = &args.list_params {
-                format!(
-                    "{} && {} && {} && {} && {} && {} && {}",
-                    if let Some(label_selector) = &list_params.label_selector {
-                        format!("args.listParams.labelSelector === \"{}\"", label_selector)
-                    } else {
-                        "args.listParams.labelSelector === undefined".to_string()
-                    },
-                    if let Some(field_selector) = &list_params.field_selector {
-                        format!("args.listParams.fieldSelector === \"{}\"", field_selector)
-                    } else {
-                        "args.listParams.fieldSelector === undefined".to_string()
-                    },
-                    if let Some(timeout) = list_params.timeout {
-                        format!("args.listParams.timeout === {}", timeout)
-                    } else {
-                        "args.listParams.timeout === undefined".to_string()
-                    },
-                    if let Some(limit) = list_params.limit {
-                        format!("args.listParams.limit === {}", limit)
-                    } else {
-                        "args.listParams.limit === undefined".to_string()
-                    },
-                    if let Some(continue_token) = &list_params.continue_token {
-                        format!("args.listParams.continueToken === {}", continue_token)
-                    } else {
-                        "args.listParams.continueToken === undefined".to_string()
-                    },
-                    if let Some(version_match) = &list_params.version_match {
-                        format!(
-                            "args.listParams.versionMatch === {}",
-                            match version_match {
-                                KubeListArgumentListParamsVersionMatch::NotOlderThan =>
-                                    "NotOlderThan",
-                                KubeListArgumentListParamsVersionMatch::Exact => "Exact",
-                            }
-                        )
-                    } else {
-                        "args.listParams.versionMatch === undefined".to_string()
-                    },
-                    if let Some(resource_version) = &list_params.resource_version {
-                        format!("args.listParams.resourceVersion === {}", resource_version)
-                    } else {
-                        "args.listParams.resourceVersion === undefined".to_string()
-                    },
-                )
-            } else {
-                "(args.list_params === undefined || Object.keys(args.list_params).length === 0)"
-                    .to_string()
-            },
-            serde_json::to_string(&object_list)
-                .context("failed to serialize Kubernetes object list")?,
-        );
-    }
-
-    code += r#" else {
-        throw new Error("kubeList stub not found");
-    }
-}
-"#;
-
-    Ok(code)
-}
-
 async fn cli_check(args: CheckArgs) -> Result<()> {
     for cronpolicy_path in args.cron_policy_paths {
         let cronpolicy_path_span =
@@ -486,6 +1258,39 @@ async fn check_cronpolicy_path(cronpolicy_path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Read the value of the key in the referenced ConfigMap or Secret
+async fn read_value_from(
+    kube_client: kube::Client,
+    namespace: &str,
+    value_from: &CronPolicyValueFrom,
+) -> Result<String> {
+    if let Some(selector) = &value_from.config_map_key_ref {
+        let name = selector.name.as_deref().unwrap_or_default();
+        let cm = Api::<ConfigMap>::namespaced(kube_client, namespace)
+            .get(name)
+            .await
+            .with_context(|| format!("failed to get ConfigMap `{}`", name))?;
+        cm.data
+            .and_then(|mut data| data.remove(&selector.key))
+            .ok_or_else(|| anyhow!("key `{}` not found in ConfigMap `{}`", selector.key, name))
+    } else if let Some(selector) = &value_from.secret_key_ref {
+        let name = selector.name.as_deref().unwrap_or_default();
+        let secret = Api::<Secret>::namespaced(kube_client, namespace)
+            .get(name)
+            .await
+            .with_context(|| format!("failed to get Secret `{}`", name))?;
+        let value = secret
+            .data
+            .and_then(|mut data| data.remove(&selector.key))
+            .ok_or_else(|| anyhow!("key `{}` not found in Secret `{}`", selector.key, name))?;
+        String::from_utf8(value.0).context("Secret value is not valid UTF-8")
+    } else {
+        Err(anyhow!(
+            "neither configMapKeyRef nor secretKeyRef is specified"
+        ))
+    }
+}
+
 async fn check_cronpolicy(cronpolicy: CronPolicy) -> Result<()> {
     let kube_config = kube::Config::infer()
         .await
@@ -494,16 +1299,32 @@ async fn check_cronpolicy(cronpolicy: CronPolicy) -> Result<()> {
         .try_into()
         .context("failed to make Kubernetes client")?;
 
+    // Read the code from the referenced ConfigMap or Secret as the checker would
+    let code = match &cronpolicy.spec.code_from {
+        Some(code_from) => {
+            let namespace = cronpolicy.spec.namespace.as_deref().unwrap_or("default");
+            read_value_from(kube_client.clone(), namespace, code_from)
+                .await
+                .context("failed to read codeFrom")?
+        }
+        None => cronpolicy.spec.code,
+    };
+
     let resources = fetch_resources(kube_client, &cronpolicy.spec.resources).await?;
 
-    let mut js_runtime = checkpoint::checker::prepare_js_runtime(resources)
+    // Checking from the CLI does not modify objects in the cluster
+    let mut js_runtime = checkpoint::checker::prepare_js_runtime(&resources, false, None)
         .context("failed to prepare JavaScript runtime")?;
 
     js_runtime
-        .execute_script("<checkpoint>", cronpolicy.spec.code.into())
+        .execute_script("<checkpoint>", code.into())
+        .context("failed to execute JavaScript code")?;
+    js_runtime
+        .run_event_loop(false)
+        .await
         .context("failed to execute JavaScript code")?;
 
-    let output: Option<HashMap<String, String>> =
+    let output: Option<checkpoint::checker::CheckerOutput> =
         eval(&mut js_runtime, "__checkpoint_get_context(\"output\")")
             .context("failed to evaluate JavaScript code")?;
 
@@ -515,3 +1336,903 @@ async fn check_cronpolicy(cronpolicy: CronPolicy) -> Result<()> {
         Ok(())
     }
 }
+
+fn cli_lint(args: LintArgs) -> Result<()> {
+    let mut errors = 0;
+    for path in args.paths {
+        let path_span = tracing::info_span!("lint-file", path = %path.display());
+        let _enter = path_span.enter();
+        errors += lint_path(&path)
+            .with_context(|| format!("failed to lint file `{}`", path.display()))?;
+    }
+    if errors > 0 {
+        return Err(anyhow!("lint found {} error(s)", errors));
+    }
+    Ok(())
+}
+
+/// Read non-empty documents in the YAML file, with their indices
+fn read_documents(path: &Path) -> Result<Vec<(usize, serde_json::Value)>> {
+    let file = fs::File::open(path).context("failed to open file")?;
+
+    let mut documents = Vec::new();
+    for (i, document) in serde_yaml::Deserializer::from_reader(file).enumerate() {
+        let document = serde_yaml::Value::deserialize(document)
+            .with_context(|| format!("failed to parse document {}", i))?;
+        if document.is_null() {
+            continue;
+        }
+        let document = serde_yaml::from_value(document)
+            .with_context(|| format!("failed to convert document {} to JSON", i))?;
+        documents.push((i, document));
+    }
+    Ok(documents)
+}
+
+/// Lint all documents in the file, returning the number of errors
+fn lint_path(path: &Path) -> Result<usize> {
+    let mut errors = 0;
+    for (i, document) in read_documents(path)? {
+        let name = document
+            .pointer("/metadata/name")
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or_default();
+
+        let findings = lint_document(&document);
+        for finding in &findings {
+            match finding.severity {
+                Severity::Error => tracing::error!(document = i, name, "{}", finding),
+                Severity::Warning => tracing::warn!(document = i, name, "{}", finding),
+            }
+        }
+        if findings.is_empty() {
+            tracing::info!(document = i, name, "no problems found");
+        }
+        errors += findings
+            .iter()
+            .filter(|finding| finding.severity == Severity::Error)
+            .count();
+    }
+    Ok(errors)
+}
+
+/// Read the rule and make the AdmissionRequest to evaluate it with
+fn read_eval_args(args: EvalArgs) -> Result<(bool, RuleSpec, AdmissionRequest<DynamicObject>)> {
+    let (mutating, rule_spec) = read_rule(&args.rule)
+        .with_context(|| format!("failed to read rule file `{}`", args.rule.display()))?;
+    let object = read_object(&args.object)
+        .with_context(|| format!("failed to read object file `{}`", args.object.display()))?;
+    let old_object = args
+        .old_object
+        .as_deref()
+        .map(|path| {
+            read_object(path)
+                .with_context(|| format!("failed to read old object file `{}`", path.display()))
+        })
+        .transpose()?;
+
+    let request = admission_request_from_manifest(args.operation.into(), object, old_object, None)
+        .context("failed to make admission request")?;
+    Ok((mutating, rule_spec, request))
+}
+
+async fn cli_eval(args: EvalArgs) -> Result<()> {
+    let (mutating, rule_spec, request) = read_eval_args(args)?;
+
+    // kubeGet and kubeList throw without stubs, as no cluster is accessed
+    let stubs = Some(Arc::new(Stubs::default()));
+    let response = if mutating {
+        mutate(&rule_spec, &request, None, stubs)
+            .await
+            .context("failed to mutate")?
+    } else {
+        validate(&rule_spec, &request, None, stubs)
+            .await
+            .context("failed to validate")?
+    };
+
+    let output = EvalOutput {
+        allowed: response.allowed,
+        message: response.result.message,
+        patch: response
+            .patch
+            .map(|patch| serde_json::from_slice(&patch))
+            .transpose()
+            .context("failed to deserialize patch")?,
+        warnings: response.warnings,
+    };
+    print!(
+        "{}",
+        serde_yaml::to_string(&output).context("failed to serialize result")?
+    );
+    Ok(())
+}
+
+/// Read a Rule of any kind, returning whether it is a mutating one with its spec
+fn read_rule(path: &Path) -> Result<(bool, RuleSpec)> {
+    let file = fs::File::open(path).context("failed to open file")?;
+    let rule: serde_json::Value =
+        serde_yaml::from_reader(file).context("failed to deserialize file")?;
+    let mutating = match rule.get("kind").and_then(serde_json::Value::as_str) {
+        Some("ValidatingRule" | "NamespacedValidatingRule") => false,
+        Some("MutatingRule" | "NamespacedMutatingRule") => true,
+        Some(kind) => return Err(anyhow!("`{}` is not a kind of Rule", kind)),
+        None => return Err(anyhow!("kind is not specified")),
+    };
+    let spec = rule
+        .get("spec")
+        .cloned()
+        .ok_or_else(|| anyhow!("spec is not specified"))?;
+    let spec = serde_json::from_value(spec).context("failed to deserialize spec")?;
+    Ok((mutating, spec))
+}
+
+fn read_object(path: &Path) -> Result<DynamicObject> {
+    let file = fs::File::open(path).context("failed to open file")?;
+    serde_yaml::from_reader(file).context("failed to deserialize file")
+}
+
+/// Make an AdmissionRequest as the API server would send for the operation on the object
+///
+/// The resource name is guessed from the kind if `plural` is not given.
+fn admission_request_from_manifest(
+    operation: Operation,
+    object: DynamicObject,
+    old_object: Option<DynamicObject>,
+    plural: Option<&str>,
+) -> Result<AdmissionRequest<DynamicObject>> {
+    let types = object
+        .types
+        .as_ref()
+        .ok_or_else(|| anyhow!("apiVersion and kind of the object are not specified"))?;
+    let gvk = GroupVersionKind::try_from(types).context("invalid apiVersion of the object")?;
+    let api_resource = match plural {
+        Some(plural) => ApiResource::from_gvk_with_plural(&gvk, plural),
+        None => ApiResource::from_gvk(&gvk),
+    };
+    let gvr = GroupVersionResource::gvr(&gvk.group, &gvk.version, &api_resource.plural);
+
+    let (object, old_object) = match operation {
+        Operation::Create | Operation::Connect => (Some(object), None),
+        Operation::Update => {
+            let old_object = old_object.unwrap_or_else(|| object.clone());
+            (Some(object), Some(old_object))
+        }
+        Operation::Delete => (None, Some(object)),
+    };
+    let target = object.as_ref().or(old_object.as_ref()).unwrap();
+
+    Ok(AdmissionRequest {
+        types: TypeMeta {
+            api_version: "admission.k8s.io/v1".to_string(),
+            kind: "AdmissionReview".to_string(),
+        },
+        uid: "00000000-0000-0000-0000-000000000000".to_string(),
+        kind: gvk.clone(),
+        resource: gvr.clone(),
+        sub_resource: None,
+        request_kind: Some(gvk),
+        request_resource: Some(gvr),
+        request_sub_resource: None,
+        name: target.metadata.name.clone().unwrap_or_default(),
+        namespace: target.metadata.namespace.clone(),
+        operation,
+        user_info: Default::default(),
+        object,
+        old_object,
+        dry_run: false,
+        options: None,
+    })
+}
+
+async fn cli_diff(args: EvalArgs) -> Result<()> {
+    let (mutating, rule_spec, request) = read_eval_args(args)?;
+    if !mutating {
+        return Err(anyhow!(
+            "only MutatingRule and NamespacedMutatingRule mutate objects"
+        ));
+    }
+    let before = request
+        .object
+        .as_ref()
+        .ok_or_else(|| anyhow!("DELETE requests have no object to mutate"))?;
+    let before = serde_json::to_value(before).context("failed to serialize object")?;
+
+    let stubs = Some(Arc::new(Stubs::default()));
+    let response = mutate(&rule_spec, &request, None, stubs)
+        .await
+        .context("failed to mutate")?;
+    if !response.allowed {
+        return Err(anyhow!("denied: {}", response.result.message));
+    }
+    let Some(patch) = response.patch else {
+        println!("No mutation");
+        return Ok(());
+    };
+    let patch: Vec<PatchOperation> =
+        serde_json::from_slice(&patch).context("failed to deserialize patch")?;
+
+    let mut after = before.clone();
+    json_patch::patch(&mut after, &patch).context("failed to apply patch")?;
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&patch).context("failed to serialize patch")?
+    );
+    print!(
+        "{}",
+        unified_diff(
+            &serde_yaml::to_string(&before).context("failed to serialize object")?,
+            &serde_yaml::to_string(&after).context("failed to serialize mutated object")?,
+            std::io::stdout().is_terminal(),
+        )
+    );
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum DiffTag {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Diff lines with the longest common subsequence
+fn diff_lines<'a>(before: &[&'a str], after: &[&'a str]) -> Vec<(DiffTag, &'a str)> {
+    let (n, m) = (before.len(), after.len());
+    // lcs[i][j] is the length of the longest common subsequence of before[i..] and after[j..]
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if before[i] == after[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut ops = Vec::with_capacity(n.max(m));
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && before[i] == after[j] {
+            ops.push((DiffTag::Equal, before[i]));
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            ops.push((DiffTag::Delete, before[i]));
+            i += 1;
+        } else {
+            ops.push((DiffTag::Insert, after[j]));
+            j += 1;
+        }
+    }
+    ops
+}
+
+/// Format the difference of texts in unified diff format, colored with ANSI escape codes if `color`
+fn unified_diff(before: &str, after: &str, color: bool) -> String {
+    const CONTEXT: usize = 3;
+    let paint = |code: &str, line: String| {
+        if color {
+            format!("\x1b[{}m{}\x1b[0m\n", code, line)
+        } else {
+            format!("{}\n", line)
+        }
+    };
+
+    let before: Vec<_> = before.lines().collect();
+    let after: Vec<_> = after.lines().collect();
+    let ops = diff_lines(&before, &after);
+
+    // Merge changes closer than the context into hunks
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (k, _) in ops
+        .iter()
+        .enumerate()
+        .filter(|(_, (tag, _))| *tag != DiffTag::Equal)
+    {
+        let start = k.saturating_sub(CONTEXT);
+        let end = (k + CONTEXT + 1).min(ops.len());
+        match hunks.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    if hunks.is_empty() {
+        return String::new();
+    }
+
+    // Line numbers of before and after at each operation
+    let mut positions = Vec::with_capacity(ops.len());
+    let (mut old, mut new) = (0, 0);
+    for (tag, _) in &ops {
+        positions.push((old, new));
+        match tag {
+            DiffTag::Equal => {
+                old += 1;
+                new += 1;
+            }
+            DiffTag::Delete => old += 1,
+            DiffTag::Insert => new += 1,
+        }
+    }
+
+    let mut out = paint("1", "--- before".to_string()) + &paint("1", "+++ after".to_string());
+    for (start, end) in hunks {
+        let hunk = &ops[start..end];
+        let (old_start, new_start) = positions[start];
+        let old_len = hunk
+            .iter()
+            .filter(|(tag, _)| *tag != DiffTag::Insert)
+            .count();
+        let new_len = hunk
+            .iter()
+            .filter(|(tag, _)| *tag != DiffTag::Delete)
+            .count();
+        // Unified diff numbers lines from 1, but an empty range starts from the line before
+        out += &paint(
+            "36",
+            format!(
+                "@@ -{},{} +{},{} @@",
+                old_start + (old_len > 0) as usize,
+                old_len,
+                new_start + (new_len > 0) as usize,
+                new_len
+            ),
+        );
+        for (tag, line) in hunk {
+            out += &match tag {
+                DiffTag::Equal => format!(" {}\n", line),
+                DiffTag::Delete => paint("31", format!("-{}", line)),
+                DiffTag::Insert => paint("32", format!("+{}", line)),
+            };
+        }
+    }
+    out
+}
+
+async fn cli_replay(args: ReplayArgs) -> Result<()> {
+    let (mutating, rule_spec) = read_rule(&args.rule)
+        .with_context(|| format!("failed to read rule file `{}`", args.rule.display()))?;
+    let rule = read_object(&args.rule)
+        .with_context(|| format!("failed to read rule file `{}`", args.rule.display()))?;
+    let kind = rule.types.map(|types| types.kind).unwrap_or_default();
+    let name = args
+        .recorded_rule
+        .or(rule.metadata.name)
+        .ok_or_else(|| anyhow!("rule does not have name"))?;
+
+    let mut selector = format!(
+        "{}={},{}={}",
+        RECORDING_RULE_KIND_LABEL_KEY, kind, RECORDING_RULE_LABEL_KEY, name
+    );
+    if let Some(namespace) = &rule.metadata.namespace {
+        selector += &format!(",{}={}", RECORDING_RULE_NAMESPACE_LABEL_KEY, namespace);
+    }
+
+    let kube_config = kube::Config::infer()
+        .await
+        .context("failed to infer Kubernetes config")?;
+    let kube_client: kube::Client = kube_config
+        .try_into()
+        .context("failed to make Kubernetes client")?;
+    let namespace = args
+        .namespace
+        .unwrap_or_else(|| kube_client.default_namespace().to_string());
+    let recordings = Api::<ConfigMap>::namespaced(kube_client, &namespace)
+        .list(&ListParams::default().labels(&selector))
+        .await
+        .context("failed to list recordings")?;
+    if recordings.items.is_empty() {
+        return Err(anyhow!("no requests are recorded for {} `{}`", kind, name));
+    }
+
+    // kubeGet and kubeList throw without stubs, as the cluster may have changed since the recording
+    let stubs = Some(Arc::new(Stubs::default()));
+    let mut changed = 0;
+    for recording in &recordings.items {
+        let recording_name = recording.name_any();
+        let (request, recorded) = parse_recording(recording)
+            .with_context(|| format!("failed to parse recording `{}`", recording_name))?;
+
+        let response = if mutating {
+            mutate(&rule_spec, &request, None, stubs.clone()).await
+        } else {
+            validate(&rule_spec, &request, None, stubs.clone()).await
+        };
+        let replayed = response
+            .map_err(anyhow::Error::from)
+            .and_then(|response| RecordedResponse::from_response(&response));
+        let object = match &request.namespace {
+            Some(namespace) => format!("{}/{}", namespace, request.name),
+            None => request.name.clone(),
+        };
+        match replayed {
+            Ok(replayed) if replayed == recorded => {
+                tracing::info!(recording = %recording_name, "decision unchanged");
+            }
+            Ok(replayed) => {
+                changed += 1;
+                tracing::warn!(
+                    recording = %recording_name,
+                    operation = ?request.operation,
+                    kind = %request.kind.kind,
+                    object,
+                    recorded = %serde_json::to_string(&recorded)?,
+                    replayed = %serde_json::to_string(&replayed)?,
+                    "decision changed",
+                );
+            }
+            Err(error) => {
+                changed += 1;
+                tracing::error!(
+                    recording = %recording_name,
+                    object,
+                    "failed to evaluate: {:#}",
+                    error,
+                );
+            }
+        }
+    }
+
+    tracing::info!(
+        "{} of {} recorded requests changed",
+        changed,
+        recordings.items.len()
+    );
+    if changed > 0 {
+        return Err(anyhow!("{} decisions changed", changed));
+    }
+    Ok(())
+}
+
+async fn cli_fixture_get(args: FixtureGetArgs) -> Result<()> {
+    let (kind, name) = args
+        .object
+        .split_once('/')
+        .ok_or_else(|| anyhow!("object must be in `<kind>/<name>` format"))?;
+
+    let kube_config = kube::Config::infer()
+        .await
+        .context("failed to infer Kubernetes config")?;
+    let kube_client: kube::Client = kube_config
+        .try_into()
+        .context("failed to make Kubernetes client")?;
+
+    let discovery = Discovery::new(kube_client.clone())
+        .run()
+        .await
+        .context("failed to discover API resources")?;
+    let (api_resource, capabilities) = discovery
+        .groups()
+        .flat_map(|group| group.recommended_resources())
+        .find(|(api_resource, _)| {
+            kind.eq_ignore_ascii_case(&api_resource.kind)
+                || kind.eq_ignore_ascii_case(&api_resource.plural)
+                || kind.eq_ignore_ascii_case(&format!(
+                    "{}.{}",
+                    api_resource.plural, api_resource.group
+                ))
+        })
+        .ok_or_else(|| anyhow!("resource `{}` not found", kind))?;
+
+    let namespace = match capabilities.scope {
+        Scope::Namespaced => Some(
+            args.namespace
+                .unwrap_or_else(|| kube_client.default_namespace().to_string()),
+        ),
+        Scope::Cluster => None,
+    };
+    let api = match &namespace {
+        Some(namespace) => {
+            Api::<DynamicObject>::namespaced_with(kube_client.clone(), namespace, &api_resource)
+        }
+        None => Api::<DynamicObject>::all_with(kube_client.clone(), &api_resource),
+    };
+    let object = api
+        .get(name)
+        .await
+        .with_context(|| format!("failed to get {} `{}`", api_resource.kind, name))?;
+
+    let old_object = if args.previous_revision {
+        let namespace = namespace
+            .as_deref()
+            .ok_or_else(|| anyhow!("{} does not have revisions", api_resource.kind))?;
+        Some(
+            previous_revision(kube_client, namespace, &api_resource.kind, &object)
+                .await
+                .context("failed to build object of the previous revision")?,
+        )
+    } else {
+        None
+    };
+
+    let request = admission_request_from_manifest(
+        args.operation.into(),
+        object,
+        old_object,
+        Some(&api_resource.plural),
+    )
+    .context("failed to make admission request")?;
+    let request =
+        serde_yaml::to_string(&request).context("failed to serialize admission request")?;
+
+    match args.output {
+        Some(output) => fs::write(&output, request)
+            .with_context(|| format!("failed to write to `{}`", output.display()))?,
+        None => print!("{}", request),
+    }
+    Ok(())
+}
+
+/// Make the AdmissionRequest the API server sends for `pods/exec` or `pods/attach`
+///
+/// The object is PodExecOptions or PodAttachOptions, which has no metadata, so it is written
+/// without `metadata` as the API server sends it.
+fn cli_fixture_exec(args: FixtureExecArgs) -> Result<()> {
+    let (kind, subresource) = if args.attach {
+        ("PodAttachOptions", "attach")
+    } else {
+        ("PodExecOptions", "exec")
+    };
+    let gvk = GroupVersionKind::gvk("", "v1", kind);
+    let gvr = GroupVersionResource::gvr("", "v1", "pods");
+
+    let mut options = serde_json::json!({
+        "apiVersion": "v1",
+        "kind": kind,
+        "stdin": args.stdin,
+        "stdout": true,
+        "stderr": !args.tty,
+        "tty": args.tty,
+    });
+    if let Some(container) = &args.container {
+        options["container"] = container.clone().into();
+    }
+    if !args.attach {
+        options["command"] = args.command.clone().into();
+    }
+    let object: DynamicObject =
+        serde_json::from_value(options).context("failed to make options object")?;
+
+    let request = AdmissionRequest {
+        types: TypeMeta {
+            api_version: "admission.k8s.io/v1".to_string(),
+            kind: "AdmissionReview".to_string(),
+        },
+        uid: "00000000-0000-0000-0000-000000000000".to_string(),
+        kind: gvk.clone(),
+        resource: gvr.clone(),
+        sub_resource: Some(subresource.to_string()),
+        request_kind: Some(gvk),
+        request_resource: Some(gvr),
+        request_sub_resource: Some(subresource.to_string()),
+        name: args.pod,
+        namespace: Some(args.namespace),
+        operation: Operation::Connect,
+        user_info: Default::default(),
+        object: Some(object),
+        old_object: None,
+        dry_run: false,
+        options: None,
+    };
+    let mut request =
+        serde_json::to_value(&request).context("failed to serialize admission request")?;
+    // DynamicObject always serializes metadata, even if it is empty
+    if let Some(object) = request["object"].as_object_mut() {
+        object.remove("metadata");
+    }
+    let request =
+        serde_yaml::to_string(&request).context("failed to serialize admission request")?;
+
+    match args.output {
+        Some(output) => fs::write(&output, request)
+            .with_context(|| format!("failed to write to `{}`", output.display()))?,
+        None => print!("{}", request),
+    }
+    Ok(())
+}
+
+fn cli_export_vap(args: ExportVapArgs) -> Result<()> {
+    let file = fs::File::open(&args.rule).context("failed to open rule file")?;
+    let rule: ValidatingRule =
+        serde_yaml::from_reader(file).context("failed to deserialize ValidatingRule")?;
+    let rule_name = rule
+        .metadata
+        .name
+        .as_deref()
+        .ok_or_else(|| anyhow!("rule does not have name"))?;
+    let spec = &rule.spec.0;
+    if spec.service_account.is_some() {
+        tracing::warn!("serviceAccount is dropped, as CEL cannot call kubeGet or kubeList");
+    }
+
+    let validations = args
+        .validations
+        .iter()
+        .map(|expression| {
+            let mut validation = serde_json::json!({ "expression": expression });
+            if let Some(message) = &args.message {
+                validation["message"] = message.clone().into();
+            }
+            validation
+        })
+        .collect::<Vec<_>>();
+
+    // A pair of ValidatingAdmissionPolicy and its binding for each webhook of the rule
+    let mut manifests = Vec::new();
+    for entry in webhook_entries(spec) {
+        let name = match &entry.name {
+            Some(entry_name) => format!("{}.{}", rule_name, entry_name),
+            None => rule_name.to_string(),
+        };
+
+        let mut match_constraints = serde_json::json!({
+            "resourceRules": entry.object_rules.unwrap_or_default(),
+        });
+        if let Some(namespace_selector) = entry.namespace_selector {
+            match_constraints["namespaceSelector"] = serde_json::to_value(namespace_selector)?;
+        }
+        if let Some(object_selector) = entry.object_selector {
+            match_constraints["objectSelector"] = serde_json::to_value(object_selector)?;
+        }
+        if let Some(match_policy) = &spec.match_policy {
+            match_constraints["matchPolicy"] = match_policy.to_string().into();
+        }
+
+        let mut policy_spec = serde_json::json!({
+            "matchConstraints": match_constraints,
+            "validations": validations,
+        });
+        if let Some(failure_policy) = &spec.failure_policy {
+            policy_spec["failurePolicy"] = failure_policy.to_string().into();
+        }
+        if let Some(match_conditions) = entry.match_conditions {
+            policy_spec["matchConditions"] = serde_json::to_value(match_conditions)?;
+        }
+
+        manifests.push(serde_json::json!({
+            "apiVersion": "admissionregistration.k8s.io/v1",
+            "kind": "ValidatingAdmissionPolicy",
+            "metadata": { "name": name },
+            "spec": policy_spec,
+        }));
+        manifests.push(serde_json::json!({
+            "apiVersion": "admissionregistration.k8s.io/v1",
+            "kind": "ValidatingAdmissionPolicyBinding",
+            "metadata": { "name": name },
+            "spec": {
+                "policyName": name,
+                "validationActions": ["Deny"],
+            },
+        }));
+    }
+
+    let manifests = manifests
+        .iter()
+        .map(serde_yaml::to_string)
+        .collect::<Result<Vec<_>, _>>()
+        .context("failed to serialize manifests")?
+        .join("---\n");
+    match args.output {
+        Some(output) => fs::write(&output, manifests)
+            .with_context(|| format!("failed to write to `{}`", output.display()))?,
+        None => print!("{}", manifests),
+    }
+    Ok(())
+}
+
+fn cli_import(args: ImportArgs) -> Result<()> {
+    let mut documents = Vec::new();
+    for path in &args.paths {
+        let path_documents = read_documents(path)
+            .with_context(|| format!("failed to read file `{}`", path.display()))?;
+        documents.extend(path_documents.into_iter().map(|(_, document)| document));
+    }
+    let api_group_of = |document: &serde_json::Value| {
+        let api_version = document["apiVersion"].as_str().unwrap_or_default();
+        api_version
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    };
+    let kind_of = |document: &serde_json::Value| document["kind"].as_str().unwrap_or_default();
+
+    let mut rules = Vec::new();
+    match args.from {
+        ImportSource::Kyverno => {
+            for document in &documents {
+                match (api_group_of(document).as_str(), kind_of(document)) {
+                    ("kyverno.io", "ClusterPolicy" | "Policy") => {
+                        rules.extend(checkpoint::import::from_kyverno(document)?)
+                    }
+                    (_, kind) => {
+                        tracing::warn!(kind, "skipped document that is not a Kyverno policy")
+                    }
+                }
+            }
+        }
+        ImportSource::Gatekeeper => {
+            // Constraints are instances of the CRDs the templates define
+            let templates = documents
+                .iter()
+                .filter(|document| api_group_of(document) == "templates.gatekeeper.sh")
+                .filter_map(|template| {
+                    let kind = template.pointer("/spec/crd/spec/names/kind")?.as_str()?;
+                    Some((kind.to_string(), template.clone()))
+                })
+                .collect();
+            for document in &documents {
+                match api_group_of(document).as_str() {
+                    "constraints.gatekeeper.sh" => {
+                        rules.push(checkpoint::import::from_gatekeeper(document, &templates)?)
+                    }
+                    "templates.gatekeeper.sh" => {}
+                    _ => tracing::warn!(
+                        kind = kind_of(document),
+                        "skipped document that is not a Gatekeeper constraint"
+                    ),
+                }
+            }
+        }
+    }
+
+    let mut manifests = Vec::new();
+    for rule in rules {
+        let mut rule = serde_json::to_value(rule).context("failed to serialize rule")?;
+        remove_nulls(&mut rule);
+        manifests.push(serde_yaml::to_string(&rule).context("failed to serialize rule")?);
+    }
+    let manifests = manifests.join("---\n");
+    match args.output {
+        Some(output) => fs::write(&output, manifests)
+            .with_context(|| format!("failed to write to `{}`", output.display()))?,
+        None => print!("{}", manifests),
+    }
+    Ok(())
+}
+
+/// Remove null fields of objects, which are defaults of optional fields
+fn remove_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(object) => {
+            object.retain(|_, field| !field.is_null());
+            object.values_mut().for_each(remove_nulls);
+        }
+        serde_json::Value::Array(array) => array.iter_mut().for_each(remove_nulls),
+        _ => {}
+    }
+}
+
+/// Make the object with the pod template of the revision before the current one
+async fn previous_revision(
+    kube_client: kube::Client,
+    namespace: &str,
+    kind: &str,
+    object: &DynamicObject,
+) -> Result<DynamicObject> {
+    let uid = object
+        .uid()
+        .ok_or_else(|| anyhow!("object does not have uid"))?;
+    let is_owned = |owner_references: &[OwnerReference]| {
+        owner_references
+            .iter()
+            .any(|owner_reference| owner_reference.uid == uid)
+    };
+
+    // Pod templates of the revisions of the object
+    let mut revisions: Vec<(i64, serde_json::Value)> = match kind {
+        "Deployment" => {
+            let replica_sets = Api::<ReplicaSet>::namespaced(kube_client, namespace)
+                .list(&Default::default())
+                .await
+                .context("failed to list ReplicaSets")?;
+            replica_sets
+                .into_iter()
+                .filter(|replica_set| is_owned(replica_set.owner_references()))
+                .filter_map(|replica_set| {
+                    let revision = replica_set
+                        .annotations()
+                        .get("deployment.kubernetes.io/revision")?
+                        .parse()
+                        .ok()?;
+                    let mut template = serde_json::to_value(replica_set.spec?.template).ok()?;
+                    // The label is added to pods of each ReplicaSet, not in the Deployment
+                    if let Some(labels) = template
+                        .pointer_mut("/metadata/labels")
+                        .and_then(serde_json::Value::as_object_mut)
+                    {
+                        labels.remove("pod-template-hash");
+                    }
+                    Some((revision, template))
+                })
+                .collect()
+        }
+        "StatefulSet" | "DaemonSet" => {
+            let controller_revisions =
+                Api::<ControllerRevision>::namespaced(kube_client, namespace)
+                    .list(&Default::default())
+                    .await
+                    .context("failed to list ControllerRevisions")?;
+            controller_revisions
+                .into_iter()
+                .filter(|controller_revision| is_owned(controller_revision.owner_references()))
+                .filter_map(|controller_revision| {
+                    // Data is a strategic merge patch replacing the pod template
+                    let mut template = controller_revision
+                        .data?
+                        .0
+                        .pointer("/spec/template")?
+                        .clone();
+                    template.as_object_mut()?.remove("$patch");
+                    Some((controller_revision.revision, template))
+                })
+                .collect()
+        }
+        _ => return Err(anyhow!("{} does not have revisions", kind)),
+    };
+    revisions.sort_by_key(|(revision, _)| *revision);
+
+    // The last one is the current revision
+    let (_, template) = revisions
+        .into_iter()
+        .rev()
+        .nth(1)
+        .ok_or_else(|| anyhow!("previous revision not found"))?;
+    let mut old_object = object.clone();
+    let spec = old_object
+        .data
+        .get_mut("spec")
+        .and_then(serde_json::Value::as_object_mut)
+        .ok_or_else(|| anyhow!("object does not have spec"))?;
+    spec.insert("template".to_string(), template);
+    Ok(old_object)
+}
+
+async fn cli_serve(args: ServeArgs) -> Result<()> {
+    let mut config = WebhookConfig::load().context("failed to load config")?;
+    config.cert_path = Some(args.cert.clone());
+    config.key_path = Some(args.key.clone());
+    config.tls_secret_name = None;
+
+    let kube_config = kube::Config::infer()
+        .await
+        .context("failed to infer Kubernetes config")?;
+    let kube_client: kube::Client = kube_config
+        .try_into()
+        .context("failed to make Kubernetes client")?;
+
+    let rules = FileRules::load(args.rules).context("failed to load rules")?;
+    tracing::info!(
+        "loaded {} rules from `{}`",
+        rules.len(),
+        rules.dir().display()
+    );
+
+    let cert_source = CertSource::Files {
+        cert_path: args.cert,
+        key_path: args.key,
+    };
+    let tls_config = RustlsConfig::from_config(
+        checkpoint::tls::read_server_config(&cert_source, config.client_ca_path.as_deref()).await?,
+    );
+
+    checkpoint::handler::js::configure_cluster_info(
+        checkpoint::cluster::fetch_cluster_info(kube_client.clone(), &config).await,
+    );
+
+    let http_app = checkpoint::handler::create_app(
+        kube_client,
+        config,
+        DynamicConfig::default(),
+        RuleSource::Files(Arc::new(rules)),
+    );
+
+    tracing::info!("listening at {}...", args.listen_addr);
+    axum_server::bind_rustls(args.listen_addr, tls_config)
+        .serve(http_app.into_make_service())
+        .await?;
+    Ok(())
+}
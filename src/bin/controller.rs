@@ -1,6 +1,6 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use futures_util::{
     future::try_join,
     stream::{FuturesUnordered, StreamExt, TryStreamExt},
@@ -8,34 +8,44 @@ use futures_util::{
 use k8s_openapi::{
     api::{
         admissionregistration::v1::{MutatingWebhookConfiguration, ValidatingWebhookConfiguration},
-        batch::v1::CronJob,
+        batch::v1::{CronJob, Job},
         core::v1::ServiceAccount,
         rbac::v1::{ClusterRole, ClusterRoleBinding, Role, RoleBinding},
     },
+    apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition,
     ByteString,
 };
 use kube::{
-    api::{Api, ListParams, Patch, PatchParams},
+    api::{Api, ListParams, Patch, PatchParams, PostParams},
     runtime::{
         controller::{self, Action},
+        events::Reporter,
         reflector::ObjectRef,
-        Controller,
+        watcher, Controller,
     },
-    Resource, ResourceExt,
+    CustomResourceExt, Resource, ResourceExt,
 };
 use stopper::Stopper;
 use tokio::sync::{broadcast::Sender, RwLock};
 
 use checkpoint::{
-    config::ControllerConfig,
+    bootstrap::Bootstrap,
+    config::{dynamic::DynamicConfig, ControllerConfig},
     leader_election::Lease,
+    metrics::CONTROLLER_METRICS,
     reconcile,
     types::{
-        policy::CronPolicy,
-        rule::{MutatingRule, ValidatingRule},
+        policy::{CronPolicy, Policy},
+        rule::{MutatingRule, NamespacedMutatingRule, NamespacedValidatingRule, ValidatingRule},
+        ruleset::RuleSet,
     },
 };
 
+/// Interval to check bootstrapped certificates for rotation
+const CERT_BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(3600);
+/// Interval to check expiry of the CA bundle
+const CERT_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
 /// Generate future that awaits shutdown signal
 async fn shutdown_signal(shutdown_signal_broadcast_tx: Sender<()>, stopper: Stopper) {
     let ctrl_c = async {
@@ -61,19 +71,91 @@ async fn shutdown_signal(shutdown_signal_broadcast_tx: Sender<()>, stopper: Stop
     }
 
     tracing::info!("terminate signal received");
+    CONTROLLER_METRICS.set_shutting_down();
 
     let _ = shutdown_signal_broadcast_tx.send(());
     stopper.stop();
 }
 
+async fn read_ca_bundle(path: &Path) -> Result<ByteString> {
+    let ca_bundle = tokio::fs::read_to_string(path).await?;
+    Ok(ByteString(ca_bundle.as_bytes().to_vec()))
+}
+
+/// Inject the CA bundle into the webhook configurations of internal webhooks, which are installed with the controller,
+/// and the conversion webhooks of CRDs
+async fn inject_internal_ca_bundle(
+    vwc_api: &Api<ValidatingWebhookConfiguration>,
+    mwc_api: &Api<MutatingWebhookConfiguration>,
+    name: &str,
+    ca_bundle: &ByteString,
+) -> Result<()> {
+    macro_rules! inject {
+        ($api:expr) => {
+            if let Some(mut wc) = $api.get_opt(name).await? {
+                let mut changed = false;
+                for webhook in wc.webhooks.iter_mut().flatten() {
+                    if webhook.client_config.ca_bundle.as_ref() != Some(ca_bundle) {
+                        webhook.client_config.ca_bundle = Some(ca_bundle.clone());
+                        changed = true;
+                    }
+                }
+                if changed {
+                    $api.replace(name, &PostParams::default(), &wc).await?;
+                    tracing::info!(%name, "CA bundle injected into internal webhook configuration");
+                }
+            } else {
+                tracing::warn!(%name, "internal webhook configuration does not exist");
+            }
+        };
+    }
+    inject!(vwc_api);
+    inject!(mwc_api);
+
+    // CRDs with multiple versions are converted by the internal conversion webhook
+    let crd_api = Api::<CustomResourceDefinition>::all(mwc_api.clone().into_client());
+    for crd_name in [ValidatingRule::crd_name(), MutatingRule::crd_name()] {
+        let Some(crd) = crd_api.get_opt(crd_name).await? else {
+            tracing::warn!(%crd_name, "CRD does not exist");
+            continue;
+        };
+        let Some(webhook) = crd.spec.conversion.and_then(|conversion| conversion.webhook) else {
+            continue;
+        };
+        let current_ca_bundle = webhook
+            .client_config
+            .and_then(|client_config| client_config.ca_bundle);
+        if current_ca_bundle.as_ref() != Some(ca_bundle) {
+            let patch = serde_json::json!({
+                "spec": {
+                    "conversion": {
+                        "webhook": {
+                            "clientConfig": {
+                                "caBundle": ca_bundle,
+                            },
+                        },
+                    },
+                },
+            });
+            crd_api
+                .patch(crd_name, &PatchParams::default(), &Patch::Merge(patch))
+                .await?;
+            tracing::info!(%crd_name, "CA bundle injected into conversion webhook of CRD");
+        }
+    }
+    Ok(())
+}
+
 async fn reload_ca_bundle(
     config: &ControllerConfig,
+    ca_bundle: ByteString,
     vwc_api: &Api<ValidatingWebhookConfiguration>,
     mwc_api: &Api<MutatingWebhookConfiguration>,
     ca_bundle_lock: &RwLock<ByteString>,
 ) -> Result<()> {
-    let ca_bundle = tokio::fs::read_to_string(&config.ca_bundle_path).await?;
-    let ca_bundle = k8s_openapi::ByteString(ca_bundle.as_bytes().to_vec());
+    if let Some(name) = &config.internal_webhook_configuration_name {
+        inject_internal_ca_bundle(vwc_api, mwc_api, name, &ca_bundle).await?;
+    }
 
     {
         let current_ca_bundle = ca_bundle_lock.read().await;
@@ -88,14 +170,38 @@ async fn reload_ca_bundle(
         *ca_bundle_lock_write = ca_bundle.clone();
     }
 
-    let vwcs = vwc_api
+    let mut vwcs = vwc_api
         .list(&ListParams::default().labels(reconcile::rule::VALIDATINGRULE_OWNED_LABEL_KEY))
         .await?
         .items;
-    let mwcs = mwc_api
+    vwcs.extend(
+        vwc_api
+            .list(
+                &ListParams::default()
+                    .labels(reconcile::rule::NAMESPACEDVALIDATINGRULE_OWNED_LABEL_KEY),
+            )
+            .await?
+            .items,
+    );
+    vwcs.extend(
+        vwc_api
+            .list(&ListParams::default().labels(reconcile::rule::RULESET_OWNED_LABEL_KEY))
+            .await?
+            .items,
+    );
+    let mut mwcs = mwc_api
         .list(&ListParams::default().labels(reconcile::rule::MUTATINGRULE_OWNED_LABEL_KEY))
         .await?
         .items;
+    mwcs.extend(
+        mwc_api
+            .list(
+                &ListParams::default()
+                    .labels(reconcile::rule::NAMESPACEDMUTATINGRULE_OWNED_LABEL_KEY),
+            )
+            .await?
+            .items,
+    );
 
     macro_rules! patch {
         ($wcs:expr, $api:expr, $manager:literal) => {
@@ -133,11 +239,28 @@ async fn reload_ca_bundle(
     Ok(())
 }
 
+/// Map a webhook configuration to the namespaced rule owning it
+fn namespaced_rule_ref<T, K>(
+    webhook_configuration: K,
+    owned_label_key: &str,
+) -> Option<ObjectRef<T>>
+where
+    T: Resource<DynamicType = ()>,
+    K: Resource,
+{
+    let labels = webhook_configuration.labels();
+    let name = labels.get(owned_label_key)?;
+    let namespace = labels.get(reconcile::rule::RULE_NAMESPACE_LABEL_KEY)?;
+    Some(ObjectRef::new(name).within(namespace))
+}
+
 async fn controller_for_each<T, E1, E2>(
+    controller: &'static str,
     res: Result<(ObjectRef<T>, Action), controller::Error<E1, E2>>,
 ) where
     T: Resource,
 {
+    CONTROLLER_METRICS.record_reconcile(controller, res.is_ok());
     match res {
         Ok((object, _)) => tracing::info!(name = object.name, "reconciled"),
         Err(error) => tracing::error!(%error, "reconcile failed"),
@@ -146,9 +269,9 @@ async fn controller_for_each<T, E1, E2>(
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    checkpoint::config::init_tracing()?;
 
-    let config = ControllerConfig::try_from_env()?;
+    let config = ControllerConfig::load()?;
     let kube_config = kube::Config::infer().await?;
     let default_namespace = kube_config.default_namespace.clone();
     let client: kube::Client = kube_config.try_into()?;
@@ -160,37 +283,103 @@ async fn main() -> Result<()> {
     let mut shutdown_signal_broadcast_rx2 = shutdown_signal_broadcast_tx.subscribe();
     let mut shutdown_signal_broadcast_rx3 = shutdown_signal_broadcast_tx.subscribe();
     let mut shutdown_signal_broadcast_rx4 = shutdown_signal_broadcast_tx.subscribe();
+    let mut shutdown_signal_broadcast_rx5 = shutdown_signal_broadcast_tx.subscribe();
+    let mut shutdown_signal_broadcast_rx6 = shutdown_signal_broadcast_tx.subscribe();
+    let mut shutdown_signal_broadcast_rx7 = shutdown_signal_broadcast_tx.subscribe();
+    let mut shutdown_signal_broadcast_rx8 = shutdown_signal_broadcast_tx.subscribe();
+    let lease_lost_broadcast_tx = shutdown_signal_broadcast_tx.clone();
     let shutdown_signal_fut = shutdown_signal(shutdown_signal_broadcast_tx, stopper.clone());
     tokio::spawn(async move {
         shutdown_signal_fut.await;
     });
 
+    // Watch CheckpointConfig, also on standby replicas to follow its log level
+    let dynamic_config = DynamicConfig::spawn(client.clone(), stopper.clone());
+
+    // Serve metrics and health endpoints
+    let metrics_addr: SocketAddr = config.metrics_listen_addr.parse()?;
+    let metrics_server = axum::Server::try_bind(&metrics_addr)?.serve(
+        checkpoint::metrics::create_app(config.log_level_token_path.clone()).into_make_service(),
+    );
+    tokio::spawn(async move {
+        if let Err(error) = metrics_server.await {
+            tracing::error!(%error, "metrics server failed");
+        }
+    });
+
     // Leader election
-    // Acquire lease
-    tracing::info!("attempting to acquire leader lease...");
     let hostname = hostname::get()?;
     let hostname = hostname.to_string_lossy();
-    let lease_fut = Lease::acquire_or_create(
-        client.clone(),
-        &default_namespace,
-        "checkpoint.devsisters.com",
-        &hostname,
-    );
-    let lease = tokio::select! {
-        lease = lease_fut => {
-            lease?
-        }
-        _ = shutdown_signal_broadcast_rx1.recv() => {
-            // Early exit when shutdown signal is received
-            return Ok(());
+    let lease = if config.enable_leader_election {
+        // Acquire lease
+        tracing::info!("attempting to acquire leader lease...");
+        let lease_duration = Duration::from_secs(config.leader_election_lease_duration_seconds);
+        let renew_deadline = Duration::from_secs(config.leader_election_renew_deadline_seconds);
+        if renew_deadline >= lease_duration {
+            bail!("leader election renew deadline must be shorter than lease duration");
         }
+        let lease_fut = Lease::acquire_or_create(
+            client.clone(),
+            config
+                .leader_election_lease_namespace
+                .as_deref()
+                .unwrap_or(&default_namespace),
+            &config.leader_election_lease_name,
+            &hostname,
+            lease_duration,
+            renew_deadline,
+        );
+        let lease = tokio::select! {
+            lease = lease_fut => {
+                lease?
+            }
+            _ = shutdown_signal_broadcast_rx1.recv() => {
+                // Early exit when shutdown signal is received
+                return Ok(());
+            }
+        };
+        tracing::info!("acquired lease");
+        Some(lease)
+    } else {
+        tracing::warn!("leader election is disabled. Make sure only one controller is running");
+        None
     };
-    tracing::info!("acquired lease");
+    CONTROLLER_METRICS.set_leader(true);
+
+    // Stop controllers when the lease is lost, so that the new leader does not reconcile concurrently
+    if let Some(lease) = &lease {
+        let lease_lost = lease.lost();
+        let stopper = stopper.clone();
+        tokio::spawn(async move {
+            lease_lost.await;
+            tracing::error!("lost leader lease, shutting down controllers...");
+            CONTROLLER_METRICS.set_leader(false);
+            let _ = lease_lost_broadcast_tx.send(());
+            stopper.stop();
+        });
+    }
+
+    // Clean up webhook configurations of rules deleted while the controller was not running
+    if let Err(error) = reconcile::rule::sweep_orphaned_webhook_configurations(&client).await {
+        tracing::error!(%error, "failed to sweep orphaned webhook configurations");
+    }
 
     tracing::info!("spawning controllers...");
 
-    let ca_bundle = tokio::fs::read_to_string(&config.ca_bundle_path).await?;
-    let ca_bundle = ByteString(ca_bundle.as_bytes().to_vec());
+    let bootstrap = config
+        .cert_bootstrap_secret_name
+        .as_deref()
+        .map(|secret_name| Bootstrap::new(client.clone(), &config, secret_name));
+    let ca_bundle = match (&bootstrap, &config.ca_bundle_path) {
+        (Some(bootstrap), _) => bootstrap.ensure().await?,
+        (None, Some(ca_bundle_path)) => read_ca_bundle(ca_bundle_path).await?,
+        (None, None) => bail!("either CA bundle path or bootstrap Secret name must be specified"),
+    };
+    if let Some(name) = &config.internal_webhook_configuration_name {
+        let vwc_api = Api::<ValidatingWebhookConfiguration>::all(client.clone());
+        let mwc_api = Api::<MutatingWebhookConfiguration>::all(client.clone());
+        inject_internal_ca_bundle(&vwc_api, &mwc_api, name, &ca_bundle).await?;
+    }
     let ca_bundle = Arc::new(RwLock::new(ca_bundle));
 
     // Prepare Kubernetes APIs
@@ -198,6 +387,9 @@ async fn main() -> Result<()> {
     let vwc_api = Api::<ValidatingWebhookConfiguration>::all(client.clone());
     let mr_api = Api::<MutatingRule>::all(client.clone());
     let mwc_api = Api::<MutatingWebhookConfiguration>::all(client.clone());
+    let nvr_api = Api::<NamespacedValidatingRule>::all(client.clone());
+    let nmr_api = Api::<NamespacedMutatingRule>::all(client.clone());
+    let rs_api = Api::<RuleSet>::all(client.clone());
     let cp_api = Api::<CronPolicy>::all(client.clone());
     let sa_api = Api::<ServiceAccount>::all(client.clone());
     let r_api = Api::<Role>::all(client.clone());
@@ -205,6 +397,8 @@ async fn main() -> Result<()> {
     let cr_api = Api::<ClusterRole>::all(client.clone());
     let crb_api = Api::<ClusterRoleBinding>::all(client.clone());
     let cj_api = Api::<CronJob>::all(client.clone());
+    let pol_api = Api::<Policy>::all(client.clone());
+    let job_api = Api::<Job>::all(client.clone());
 
     // Prepare TLS CA bundle reloader
     let mut watcher = checkpoint::filewatcher::FileWatcher::new(
@@ -219,8 +413,16 @@ async fn main() -> Result<()> {
                 let vwc_api = vwc_api.clone();
                 let mwc_api = mwc_api.clone();
                 async move {
+                    let Some(ca_bundle_path) = &config.ca_bundle_path else {
+                        return;
+                    };
                     tracing::info!("Reloading TLS CA bundle");
-                    let res = reload_ca_bundle(&config, &vwc_api, &mwc_api, &ca_bundle).await;
+                    let res = async {
+                        let new_ca_bundle = read_ca_bundle(ca_bundle_path).await?;
+                        reload_ca_bundle(&config, new_ca_bundle, &vwc_api, &mwc_api, &ca_bundle)
+                            .await
+                    }
+                    .await;
                     if let Err(error) = res {
                         tracing::error!(%error, "Failed to reload CA bundle");
                     }
@@ -228,21 +430,89 @@ async fn main() -> Result<()> {
             }
         },
         10,
-        stopper,
+        stopper.clone(),
     );
-    watcher.watch(config.ca_bundle_path.clone());
+    if bootstrap.is_none() {
+        if let Some(ca_bundle_path) = &config.ca_bundle_path {
+            watcher.watch(ca_bundle_path.clone());
+        }
+    }
     watcher.spawn()?;
 
+    // Check expiry of the CA bundle periodically
+    {
+        let ca_bundle = ca_bundle.clone();
+        let warning_threshold = chrono::Duration::days(config.cert_expiry_warning_days);
+        let stopper = stopper.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CERT_EXPIRY_CHECK_INTERVAL);
+            while stopper.stop_future(interval.tick()).await.is_some() {
+                let ca_bundle = ca_bundle.read().await.clone();
+                let res =
+                    checkpoint::cert::check_expiry("ca_bundle", &ca_bundle.0, warning_threshold);
+                if let Err(error) = res {
+                    tracing::error!(
+                        error = format!("{:#}", error),
+                        "Failed to check certificate expiry"
+                    );
+                }
+            }
+        });
+    }
+
+    // Rotate bootstrapped certificates before expiry
+    if let Some(bootstrap) = bootstrap {
+        let config = config.clone();
+        let ca_bundle = ca_bundle.clone();
+        let vwc_api = vwc_api.clone();
+        let mwc_api = mwc_api.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CERT_BOOTSTRAP_INTERVAL);
+            interval.tick().await;
+            while stopper.stop_future(interval.tick()).await.is_some() {
+                let res = async {
+                    let new_ca_bundle = bootstrap.ensure().await?;
+                    reload_ca_bundle(&config, new_ca_bundle, &vwc_api, &mwc_api, &ca_bundle).await
+                }
+                .await;
+                if let Err(error) = res {
+                    tracing::error!(
+                        error = format!("{:#}", error),
+                        "Failed to rotate certificates"
+                    );
+                }
+            }
+        });
+    }
+
     let controller_ctx = Arc::new(reconcile::ReconcilerContext {
         client,
         config,
+        dynamic_config,
         ca_bundle,
+        reporter: Reporter {
+            controller: "checkpoint-controller".to_string(),
+            instance: Some(hostname.to_string()),
+        },
+        reported_errors: Default::default(),
+        discovered_plurals: Default::default(),
     });
 
     // Spawn ValidatingRule controller
     let vr_controller_handle = tokio::spawn(
         Controller::new(vr_api, Default::default())
-            .owns(vwc_api, Default::default())
+            .reconcile_all_on(controller_ctx.dynamic_config.changes())
+            .owns(vwc_api.clone(), Default::default())
+            // Rules added to RuleSets give up their own webhooks. Rules removed from RuleSets get
+            // them back on the next resync.
+            .watches(rs_api.clone(), Default::default(), |rule_set| {
+                rule_set
+                    .spec
+                    .rules
+                    .iter()
+                    .map(|name| ObjectRef::new(name))
+                    .collect::<Vec<_>>()
+            })
             .graceful_shutdown_on(async move {
                 let _ = shutdown_signal_broadcast_rx2.recv().await;
             })
@@ -251,14 +521,32 @@ async fn main() -> Result<()> {
                 reconcile::error_policy,
                 controller_ctx.clone(),
             )
-            .for_each(controller_for_each),
+            .for_each(|res| controller_for_each("validatingrule", res)),
     );
     tracing::info!("spawned validatingrule controller");
 
+    // Spawn RuleSet controller
+    let rs_controller_handle = tokio::spawn(
+        Controller::new(rs_api, Default::default())
+            .reconcile_all_on(controller_ctx.dynamic_config.changes())
+            .owns(vwc_api.clone(), Default::default())
+            .graceful_shutdown_on(async move {
+                let _ = shutdown_signal_broadcast_rx8.recv().await;
+            })
+            .run(
+                reconcile::rule::reconcile_ruleset,
+                reconcile::error_policy,
+                controller_ctx.clone(),
+            )
+            .for_each(|res| controller_for_each("ruleset", res)),
+    );
+    tracing::info!("spawned ruleset controller");
+
     // Spawn MutatingRule controller
     let mr_controller_handle = tokio::spawn(
         Controller::new(mr_api, Default::default())
-            .owns(mwc_api, Default::default())
+            .reconcile_all_on(controller_ctx.dynamic_config.changes())
+            .owns(mwc_api.clone(), Default::default())
             .graceful_shutdown_on(async move {
                 let _ = shutdown_signal_broadcast_rx3.recv().await;
             })
@@ -267,46 +555,138 @@ async fn main() -> Result<()> {
                 reconcile::error_policy,
                 controller_ctx.clone(),
             )
-            .for_each(controller_for_each),
+            .for_each(|res| controller_for_each("mutatingrule", res)),
     );
     tracing::info!("spawned mutatingrule controller");
 
+    // Spawn NamespacedValidatingRule controller
+    let nvr_controller_handle = tokio::spawn(
+        Controller::new(nvr_api, Default::default())
+            .reconcile_all_on(controller_ctx.dynamic_config.changes())
+            .watches(
+                vwc_api.clone(),
+                watcher::Config::default()
+                    .labels(reconcile::rule::NAMESPACEDVALIDATINGRULE_OWNED_LABEL_KEY),
+                |vwc| {
+                    namespaced_rule_ref(
+                        vwc,
+                        reconcile::rule::NAMESPACEDVALIDATINGRULE_OWNED_LABEL_KEY,
+                    )
+                },
+            )
+            .graceful_shutdown_on(async move {
+                let _ = shutdown_signal_broadcast_rx5.recv().await;
+            })
+            .run(
+                reconcile::rule::reconcile_namespacedvalidatingrule,
+                reconcile::error_policy,
+                controller_ctx.clone(),
+            )
+            .for_each(|res| controller_for_each("namespacedvalidatingrule", res)),
+    );
+    tracing::info!("spawned namespacedvalidatingrule controller");
+
+    // Spawn NamespacedMutatingRule controller
+    let nmr_controller_handle = tokio::spawn(
+        Controller::new(nmr_api, Default::default())
+            .reconcile_all_on(controller_ctx.dynamic_config.changes())
+            .watches(
+                mwc_api.clone(),
+                watcher::Config::default()
+                    .labels(reconcile::rule::NAMESPACEDMUTATINGRULE_OWNED_LABEL_KEY),
+                |mwc| {
+                    namespaced_rule_ref(
+                        mwc,
+                        reconcile::rule::NAMESPACEDMUTATINGRULE_OWNED_LABEL_KEY,
+                    )
+                },
+            )
+            .graceful_shutdown_on(async move {
+                let _ = shutdown_signal_broadcast_rx6.recv().await;
+            })
+            .run(
+                reconcile::rule::reconcile_namespacedmutatingrule,
+                reconcile::error_policy,
+                controller_ctx.clone(),
+            )
+            .for_each(|res| controller_for_each("namespacedmutatingrule", res)),
+    );
+    tracing::info!("spawned namespacedmutatingrule controller");
+
     // Spawn CronPolicy controller
     let cp_controller_handle = tokio::spawn(
         Controller::new(cp_api, Default::default())
+            .owns(sa_api.clone(), Default::default())
+            .owns(r_api.clone(), Default::default())
+            .owns(rb_api.clone(), Default::default())
+            .owns(cr_api.clone(), Default::default())
+            .owns(crb_api.clone(), Default::default())
+            .owns(cj_api, Default::default())
+            .graceful_shutdown_on(async move {
+                let _ = shutdown_signal_broadcast_rx4.recv().await;
+            })
+            .run(
+                reconcile::policy::reconcile_cronpolicy,
+                reconcile::error_policy,
+                controller_ctx.clone(),
+            )
+            .for_each(|res| controller_for_each("cronpolicy", res)),
+    );
+    tracing::info!("spawned cronpolicy controller");
+
+    // Spawn Policy controller
+    let pol_controller_handle = tokio::spawn(
+        Controller::new(pol_api, Default::default())
             .owns(sa_api, Default::default())
             .owns(r_api, Default::default())
             .owns(rb_api, Default::default())
             .owns(cr_api, Default::default())
             .owns(crb_api, Default::default())
-            .owns(cj_api, Default::default())
+            .owns(job_api, Default::default())
             .graceful_shutdown_on(async move {
-                let _ = shutdown_signal_broadcast_rx4.recv().await;
+                let _ = shutdown_signal_broadcast_rx7.recv().await;
             })
             .run(
-                reconcile::policy::reconcile_cronpolicy,
+                reconcile::policy::reconcile_policy,
                 reconcile::error_policy,
                 controller_ctx,
             )
-            .for_each(controller_for_each),
+            .for_each(|res| controller_for_each("policy", res)),
     );
-    tracing::info!("spawned cronpolicy controller");
+    tracing::info!("spawned policy controller");
 
     // Await all spawned futures
     let res = tokio::try_join!(
         vr_controller_handle,
         mr_controller_handle,
-        cp_controller_handle
+        nvr_controller_handle,
+        nmr_controller_handle,
+        rs_controller_handle,
+        cp_controller_handle,
+        pol_controller_handle
     );
     tracing::info!("controllers terminated");
 
-    tracing::info!("releasing lease...");
-    // Release lease
-    lease.join().await?;
-    tracing::info!("lease released");
+    CONTROLLER_METRICS.set_leader(false);
+    let lease_lost = match lease {
+        Some(lease) => {
+            tracing::info!("releasing lease...");
+            // Release lease
+            let lease_lost = lease.is_lost();
+            lease.join().await?;
+            tracing::info!("lease released");
+            lease_lost
+        }
+        None => false,
+    };
 
     // Unwrap result
     res?;
 
+    // Exit with error to be restarted as a standby
+    if lease_lost {
+        bail!("leader lease lost");
+    }
+
     Ok(())
 }
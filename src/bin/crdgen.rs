@@ -1,34 +1,158 @@
 //! CRD generator
 //!
 //! Usage: `cargo run --bin crdgen > helm/template/customresourcedefinition.yaml`
+//!
+//! Pass `--raw` to generate plain manifests without Helm templates, and `--out-dir` to write a file
+//! per CRD.
+
+use std::{fs, path::PathBuf};
 
+use anyhow::{Context, Result};
+use clap::Parser;
 use itertools::Itertools;
-use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
-use kube::CustomResourceExt;
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::{
+    CustomResourceConversion, CustomResourceDefinition, ServiceReference, WebhookClientConfig,
+    WebhookConversion,
+};
+use kube::{core::crd::merge_crds, CustomResourceExt};
 
 use checkpoint::types::{
-    policy::CronPolicy,
-    rule::{MutatingRule, ValidatingRule},
+    config::CheckpointConfig,
+    denial::DenialRecord,
+    policy::{CronPolicy, Policy},
+    rule::{v2, MutatingRule, NamespacedMutatingRule, NamespacedValidatingRule, ValidatingRule},
+    ruleset::RuleSet,
 };
 
 static LABEL_PLACEHOLDER: &str = "CHECKPOINT_LABEL_PLACEHOLDER";
 static LABEL_REPLACE_TARGET: &str = "    {{- include \"checkpoint.labels\" . | nindent 4 }}";
 
-fn main() {
-    let mut crds = vec![
-        ValidatingRule::crd(),
-        MutatingRule::crd(),
-        CronPolicy::crd(),
+static ANNOTATION_PLACEHOLDER: &str = "CHECKPOINT_ANNOTATION_PLACEHOLDER";
+static ANNOTATION_REPLACE_TARGET: &str =
+    "    {{- include \"checkpoint.conversionAnnotations\" . | nindent 4 }}";
+static SERVICE_NAME_PLACEHOLDER: &str = "CHECKPOINT_SERVICE_NAME_PLACEHOLDER";
+static SERVICE_NAME_REPLACE_TARGET: &str =
+    "          name: {{ include \"checkpoint.fullname\" . }}-webhook";
+static SERVICE_NAMESPACE_PLACEHOLDER: &str = "CHECKPOINT_SERVICE_NAMESPACE_PLACEHOLDER";
+static SERVICE_NAMESPACE_REPLACE_TARGET: &str = "          namespace: {{ .Release.Namespace }}
+          port: {{ .Values.webhook.service.port }}";
+
+static HEADER: &str = "# This file is autogenerated by `src/bin/crdgen.rs`";
+
+/// Path of the conversion webhook of rules with multiple versions
+static CONVERSION_PATH: &str = "/internal/convert/rules";
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Generate manifests for the Helm chart, with the labels of the chart.  This is the default.
+    #[clap(long, conflicts_with = "raw")]
+    helm: bool,
+    /// Generate plain manifests without Helm templates
+    #[clap(long)]
+    raw: bool,
+    /// Directory to write a file per CRD to, named after the CRD.  Defaults to stdout.
+    #[clap(long, value_parser)]
+    out_dir: Option<PathBuf>,
+    /// Name of the webhook Service serving the conversion webhook, for raw manifests
+    #[clap(long, default_value = "checkpoint-webhook")]
+    service_name: String,
+    /// Namespace of the webhook Service serving the conversion webhook, for raw manifests
+    #[clap(long, default_value = "checkpoint")]
+    service_namespace: String,
+    /// Port of the webhook Service serving the conversion webhook, for raw manifests
+    #[clap(long, default_value_t = 443)]
+    service_port: i32,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let raw = args.raw && !args.helm;
+
+    let mut multi_version_crds = vec![
+        merge_crds(vec![ValidatingRule::crd(), v2::ValidatingRule::crd()], "v1")?,
+        merge_crds(vec![MutatingRule::crd(), v2::MutatingRule::crd()], "v1")?,
     ];
+    for crd in multi_version_crds.iter_mut() {
+        if raw {
+            add_conversion(
+                crd,
+                ServiceReference {
+                    name: args.service_name.clone(),
+                    namespace: args.service_namespace.clone(),
+                    path: Some(CONVERSION_PATH.to_string()),
+                    port: Some(args.service_port),
+                },
+            );
+        } else {
+            add_conversion(
+                crd,
+                ServiceReference {
+                    name: SERVICE_NAME_PLACEHOLDER.to_string(),
+                    namespace: SERVICE_NAMESPACE_PLACEHOLDER.to_string(),
+                    path: Some(CONVERSION_PATH.to_string()),
+                    port: None,
+                },
+            );
+            // CA bundle of the conversion webhook is injected by cert-manager or the controller
+            crd.metadata
+                .annotations
+                .get_or_insert_with(Default::default)
+                .insert(
+                    ANNOTATION_PLACEHOLDER.to_string(),
+                    ANNOTATION_PLACEHOLDER.to_string(),
+                );
+        }
+    }
 
-    println!("# This file is autogenerated by `src/bin/crdgen.rs`");
+    let mut crds = multi_version_crds;
+    crds.extend([
+        NamespacedValidatingRule::crd(),
+        NamespacedMutatingRule::crd(),
+        RuleSet::crd(),
+        CronPolicy::crd(),
+        Policy::crd(),
+        DenialRecord::crd(),
+        CheckpointConfig::crd(),
+    ]);
+
+    if args.out_dir.is_none() {
+        println!("{}", HEADER);
+    }
     for crd in crds.iter_mut() {
-        add_label_placeholder(crd);
-        let yaml_string = serde_yaml::to_string(crd).unwrap();
-        let yaml_string = replace_placeholder(yaml_string);
-        println!("{}", yaml_string);
-        println!("---");
+        let yaml_string = if raw {
+            serde_yaml::to_string(crd)?
+        } else {
+            add_label_placeholder(crd);
+            replace_placeholder(serde_yaml::to_string(crd)?)
+        };
+        match &args.out_dir {
+            Some(out_dir) => {
+                let name = crd.metadata.name.as_deref().unwrap_or_default();
+                let path = out_dir.join(format!("{}.yaml", name));
+                fs::write(&path, format!("{}\n{}", HEADER, yaml_string))
+                    .with_context(|| format!("failed to write `{}`", path.display()))?;
+            }
+            None => {
+                println!("{}", yaml_string);
+                println!("---");
+            }
+        }
     }
+    Ok(())
+}
+
+/// Convert objects between versions with the conversion webhook of the service
+fn add_conversion(crd: &mut CustomResourceDefinition, service: ServiceReference) {
+    crd.spec.conversion = Some(CustomResourceConversion {
+        strategy: "Webhook".to_string(),
+        webhook: Some(WebhookConversion {
+            client_config: Some(WebhookClientConfig {
+                service: Some(service),
+                ..Default::default()
+            }),
+            conversion_review_versions: vec!["v1".to_string()],
+        }),
+    });
 }
 
 fn add_label_placeholder(crd: &mut CustomResourceDefinition) {
@@ -44,6 +168,12 @@ fn replace_placeholder(yaml_string: String) -> String {
         .map(|line| {
             if line.contains(LABEL_PLACEHOLDER) {
                 LABEL_REPLACE_TARGET
+            } else if line.contains(ANNOTATION_PLACEHOLDER) {
+                ANNOTATION_REPLACE_TARGET
+            } else if line.contains(SERVICE_NAME_PLACEHOLDER) {
+                SERVICE_NAME_REPLACE_TARGET
+            } else if line.contains(SERVICE_NAMESPACE_PLACEHOLDER) {
+                SERVICE_NAMESPACE_REPLACE_TARGET
             } else {
                 line
             }
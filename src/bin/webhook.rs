@@ -1,13 +1,29 @@
-use std::{io, net::SocketAddr};
+use std::{net::SocketAddr, sync::Arc};
 
 use anyhow::Result;
 use axum_server::tls_rustls::RustlsConfig;
+use futures_util::StreamExt;
+use kube::runtime::{watcher, WatchStreamExt};
 use stopper::Stopper;
 
-use checkpoint::config::WebhookConfig;
+use checkpoint::{
+    config::{dynamic::DynamicConfig, WebhookConfig},
+    handler::{
+        inflight::IN_FLIGHT,
+        rule_source::{FileRules, RuleSource},
+    },
+    tls::CertSource,
+};
+
+/// Interval to check expiry of the serving certificate
+const CERT_EXPIRY_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
 
 /// Generate future that awaits shutdown signal
-async fn shutdown_signal(axum_server_handle: axum_server::Handle, stopper: Stopper) {
+async fn shutdown_signal(
+    axum_server_handle: axum_server::Handle,
+    stopper: Stopper,
+    drain_timeout: std::time::Duration,
+) {
     let ctrl_c = async {
         tokio::signal::ctrl_c()
             .await
@@ -32,68 +48,230 @@ async fn shutdown_signal(axum_server_handle: axum_server::Handle, stopper: Stopp
 
     tracing::info!("terminate signal received");
 
-    axum_server_handle.graceful_shutdown(Some(std::time::Duration::from_secs(30)));
+    // Stop accepting connections, and wait for in-flight requests and JS evaluations
+    axum_server_handle.graceful_shutdown(Some(drain_timeout));
     stopper.stop();
+    let summary = IN_FLIGHT.drain(drain_timeout).await;
+    if summary.remaining_requests == 0 && summary.remaining_js_tasks == 0 {
+        tracing::info!(
+            requests = summary.requests,
+            js_tasks = summary.js_tasks,
+            elapsed_ms = summary.elapsed.as_millis() as u64,
+            "drained in-flight requests",
+        );
+    } else {
+        tracing::warn!(
+            requests = summary.requests,
+            js_tasks = summary.js_tasks,
+            remaining_requests = summary.remaining_requests,
+            remaining_js_tasks = summary.remaining_js_tasks,
+            elapsed_ms = summary.elapsed.as_millis() as u64,
+            "in-flight requests are not drained in time",
+        );
+    }
+}
+
+async fn reload_rules(rules: Arc<FileRules>) {
+    tracing::info!("Reloading rules");
+    match rules.reload() {
+        Ok(()) => tracing::info!(count = rules.len(), "Rules reloaded"),
+        Err(error) => tracing::error!(%error, "Failed to reload rules, keeping the previous ones"),
+    }
 }
 
-async fn reload_config(config: WebhookConfig, tls_config: RustlsConfig) -> Result<(), io::Error> {
-    tls_config
-        .reload_from_pem_file(&config.cert_path, &config.key_path)
-        .await
+async fn read_tls_config(
+    cert_source: &CertSource,
+    config: &WebhookConfig,
+) -> Result<Arc<rustls::ServerConfig>> {
+    checkpoint::tls::read_server_config(cert_source, config.client_ca_path.as_deref()).await
+}
+
+async fn reload_config(cert_source: CertSource, config: WebhookConfig, tls_config: RustlsConfig) {
+    tracing::info!("Reloading TLS certificate");
+    match read_tls_config(&cert_source, &config).await {
+        Ok(server_config) => {
+            tls_config.reload_from_config(server_config);
+            tracing::info!("TLS certificate reloaded");
+        }
+        Err(error) => {
+            tracing::error!(error = format!("{:#}", error), "Failed to reload cert");
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    checkpoint::config::init_tracing()?;
 
-    let config = WebhookConfig::try_from_env()?;
+    let config = WebhookConfig::load()?;
     let kube_config = kube::Config::infer().await?;
     let client: kube::Client = kube_config.try_into()?;
 
+    let stopper = Stopper::new();
+
+    // Watch CheckpointConfig to apply it without restarting
+    let dynamic_config = DynamicConfig::spawn(client.clone(), stopper.clone());
+
+    // Load rules from files if configured, and reload them on updates
+    let rule_source = match &config.rules_dir {
+        Some(rules_dir) => {
+            let rules = Arc::new(FileRules::load(rules_dir.clone())?);
+            tracing::info!(
+                "loaded {} rules from `{}`",
+                rules.len(),
+                rules.dir().display()
+            );
+            let mut watcher = checkpoint::filewatcher::FileWatcher::new(
+                {
+                    let rules = rules.clone();
+                    move |_| reload_rules(rules.clone())
+                },
+                10,
+                stopper.clone(),
+            );
+            watcher.watch_dir(rules_dir.clone());
+            watcher.spawn()?;
+            RuleSource::Files(rules)
+        }
+        None => RuleSource::Kubernetes,
+    };
+
+    // Expose the cluster metadata to rules
+    checkpoint::handler::js::configure_cluster_info(
+        checkpoint::cluster::fetch_cluster_info(client.clone(), &config).await,
+    );
+
     // Prepare HTTP app
-    let http_app = checkpoint::handler::create_app(client);
+    let http_app = checkpoint::handler::create_app(
+        client.clone(),
+        config.clone(),
+        dynamic_config.clone(),
+        rule_source.clone(),
+    );
 
     // Prepare TLS config for HTTPS serving
-    let tls_config = RustlsConfig::from_pem_file(&config.cert_path, &config.key_path).await?;
-
-    let stopper = Stopper::new();
+    let cert_source = CertSource::from_config(client.clone(), &config)?;
+    let tls_config = RustlsConfig::from_config(read_tls_config(&cert_source, &config).await?);
 
     // Prepare TLS cert reloader
     let mut watcher = checkpoint::filewatcher::FileWatcher::new(
         {
+            let cert_source = cert_source.clone();
             let config = config.clone();
             let tls_config = tls_config.clone();
-            move |_| {
-                let config = config.clone();
-                let tls_config = tls_config.clone();
-                async move {
-                    tracing::info!("Reloading TLS certificate");
-                    let res = reload_config(config, tls_config).await;
-                    match res {
-                        Ok(_) => {
-                            tracing::info!("TLS certificate reloaded");
-                        }
-                        Err(error) => {
-                            tracing::error!(%error, "Failed to reload cert");
-                        }
-                    }
-                }
-            }
+            move |_| reload_config(cert_source.clone(), config.clone(), tls_config.clone())
         },
         10,
         stopper.clone(),
     );
-    watcher.watch(config.cert_path.clone());
-    watcher.watch(config.key_path.clone());
+    if let CertSource::Files {
+        cert_path,
+        key_path,
+    } = &cert_source
+    {
+        watcher.watch(cert_path.clone());
+        watcher.watch(key_path.clone());
+    }
+    if let Some(client_ca_path) = &config.client_ca_path {
+        watcher.watch(client_ca_path.clone());
+    }
     watcher.spawn()?;
 
+    // Reload TLS cert on updates of the Secret
+    if let CertSource::Secret { api, name } = &cert_source {
+        let secret_stream = watcher::watcher(
+            api.clone(),
+            watcher::Config::default().fields(&format!("metadata.name={}", name)),
+        )
+        .applied_objects();
+        let cert_source = cert_source.clone();
+        let config = config.clone();
+        let tls_config = tls_config.clone();
+        tokio::spawn(async move {
+            let mut secret_stream = std::pin::pin!(secret_stream);
+            while let Some(res) = secret_stream.next().await {
+                match res {
+                    Ok(_) => {
+                        reload_config(cert_source.clone(), config.clone(), tls_config.clone()).await
+                    }
+                    Err(error) => tracing::warn!(%error, "failed to watch TLS Secret"),
+                }
+            }
+        });
+    }
+
+    // Check expiry of the serving certificate periodically
+    {
+        let cert_source = cert_source.clone();
+        let warning_threshold = chrono::Duration::days(config.cert_expiry_warning_days);
+        let stopper = stopper.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CERT_EXPIRY_CHECK_INTERVAL);
+            while stopper.stop_future(interval.tick()).await.is_some() {
+                let res = async {
+                    let (cert, _) = cert_source.read_pem().await?;
+                    checkpoint::cert::check_expiry("serving", &cert, warning_threshold)
+                }
+                .await;
+                if let Err(error) = res {
+                    tracing::error!(
+                        error = format!("{:#}", error),
+                        "Failed to check certificate expiry"
+                    );
+                }
+            }
+        });
+    }
+
+    // Check the rules on startup and periodically, to discover broken ones before they break requests
+    {
+        let client = client.clone();
+        let rule_source = rule_source.clone();
+        let preflight_interval_seconds = config.preflight_interval_seconds;
+        let stopper = stopper.clone();
+        tokio::spawn(async move {
+            if preflight_interval_seconds == 0 {
+                checkpoint::handler::preflight::run(client, &rule_source).await;
+                return;
+            }
+            let mut interval =
+                tokio::time::interval(std::time::Duration::from_secs(preflight_interval_seconds));
+            while stopper.stop_future(interval.tick()).await.is_some() {
+                checkpoint::handler::preflight::run(client.clone(), &rule_source).await;
+            }
+        });
+    }
+
     // Prepare shutdown signal futures
     let axum_server_handle = axum_server::Handle::new();
-    let shutdown_signal_fut = shutdown_signal(axum_server_handle.clone(), stopper);
-    tokio::spawn(async move {
+    let shutdown_signal_fut = shutdown_signal(
+        axum_server_handle.clone(),
+        stopper,
+        std::time::Duration::from_secs(config.shutdown_drain_timeout_seconds),
+    );
+    let shutdown_signal_handle = tokio::spawn(async move {
         shutdown_signal_fut.await;
     });
 
+    // Serve health endpoints without TLS
+    if let Some(health_listen_addr) = &config.health_listen_addr {
+        let health_addr: SocketAddr = health_listen_addr.parse()?;
+        let health_server = axum::Server::try_bind(&health_addr)?.serve(
+            checkpoint::handler::create_health_app(
+                client,
+                config.clone(),
+                dynamic_config,
+                rule_source,
+            )
+            .into_make_service(),
+        );
+        tokio::spawn(async move {
+            if let Err(error) = health_server.await {
+                tracing::error!(%error, "health server failed");
+            }
+        });
+    }
+
     // Spawn HTTP server
     tracing::info!("starting web server...");
     let listen_addr: SocketAddr = config.listen_addr.parse()?;
@@ -104,5 +282,8 @@ async fn main() -> Result<()> {
         .await?;
     tracing::info!("web server terminated");
 
+    // Wait for the drain summary
+    shutdown_signal_handle.await?;
+
     Ok(())
 }
@@ -0,0 +1,250 @@
+//! Self-signed CA and serving certificate provisioning of the webhook
+//!
+//! The CA and the serving certificate are stored in Secrets, so that the webhook can serve from the Secret
+//! and every controller replica signs with the same CA.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use k8s_openapi::{api::core::v1::Secret, ByteString};
+use kube::{
+    api::{ObjectMeta, Patch, PatchParams},
+    Api,
+};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose,
+};
+
+use crate::{cert::parse_validity, config::ControllerConfig};
+
+pub const TLS_CERT_KEY: &str = "tls.crt";
+pub const TLS_KEY_KEY: &str = "tls.key";
+pub const CA_CERT_KEY: &str = "ca.crt";
+/// Key of the CA replaced by rotation, which stays in the CA bundle until it expires
+const PREVIOUS_CA_CERT_KEY: &str = "previous-ca.crt";
+
+const FIELD_MANAGER: &str = "checkpoint.devsisters.com";
+
+fn ca_validity() -> Duration {
+    Duration::days(3650)
+}
+
+fn ca_renew_before() -> Duration {
+    Duration::days(365)
+}
+
+fn serving_validity() -> Duration {
+    Duration::days(90)
+}
+
+fn serving_renew_before() -> Duration {
+    Duration::days(30)
+}
+
+/// PEM certificate and private key
+struct CertificatePem {
+    cert: String,
+    key: String,
+}
+
+fn to_offset_date_time(time: DateTime<Utc>) -> Result<time::OffsetDateTime> {
+    time::OffsetDateTime::from_unix_timestamp(time.timestamp()).context("time out of range")
+}
+
+/// Make CA certificate parameters. The same key and common name reproduce the issuer of the signed certificates.
+fn make_ca_params(
+    common_name: &str,
+    key_pair: Option<KeyPair>,
+    now: DateTime<Utc>,
+) -> Result<CertificateParams> {
+    let mut params = CertificateParams::default();
+    let mut distinguished_name = DistinguishedName::new();
+    distinguished_name.push(DnType::CommonName, common_name);
+    params.distinguished_name = distinguished_name;
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![
+        KeyUsagePurpose::KeyCertSign,
+        KeyUsagePurpose::CrlSign,
+        KeyUsagePurpose::DigitalSignature,
+    ];
+    params.not_before = to_offset_date_time(now - Duration::hours(1))?;
+    params.not_after = to_offset_date_time(now + ca_validity())?;
+    params.key_pair = key_pair;
+    Ok(params)
+}
+
+fn generate_ca(common_name: &str, now: DateTime<Utc>) -> Result<CertificatePem> {
+    let ca = Certificate::from_params(make_ca_params(common_name, None, now)?)
+        .context("failed to generate CA")?;
+    Ok(CertificatePem {
+        cert: ca.serialize_pem().context("failed to serialize CA")?,
+        key: ca.serialize_private_key_pem(),
+    })
+}
+
+fn load_ca(common_name: &str, key: &str, now: DateTime<Utc>) -> Result<Certificate> {
+    let key_pair = KeyPair::from_pem(key).context("failed to parse CA private key")?;
+    Certificate::from_params(make_ca_params(common_name, Some(key_pair), now)?)
+        .context("failed to load CA")
+}
+
+fn generate_serving_cert(
+    ca: &Certificate,
+    dns_names: Vec<String>,
+    now: DateTime<Utc>,
+) -> Result<CertificatePem> {
+    let mut params = CertificateParams::new(dns_names.clone());
+    let mut distinguished_name = DistinguishedName::new();
+    distinguished_name.push(DnType::CommonName, dns_names[0].clone());
+    params.distinguished_name = distinguished_name;
+    params.key_usages = vec![
+        KeyUsagePurpose::DigitalSignature,
+        KeyUsagePurpose::KeyEncipherment,
+    ];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    params.use_authority_key_identifier_extension = true;
+    params.not_before = to_offset_date_time(now - Duration::hours(1))?;
+    params.not_after = to_offset_date_time(now + serving_validity())?;
+    let cert = Certificate::from_params(params).context("failed to generate certificate")?;
+    Ok(CertificatePem {
+        cert: cert
+            .serialize_pem_with_signer(ca)
+            .context("failed to sign certificate")?,
+        key: cert.serialize_private_key_pem(),
+    })
+}
+
+/// Whether the PEM certificate is valid and does not need renewal yet
+fn is_fresh(cert: &[u8], renew_before: Duration, now: DateTime<Utc>) -> bool {
+    match parse_validity(cert) {
+        Ok(validity) => validity.is_valid_at(now) && now + renew_before < validity.not_after,
+        Err(_) => false,
+    }
+}
+
+/// Provisioner of the webhook CA and serving certificate
+pub struct Bootstrap {
+    secret_api: Api<Secret>,
+    ca_secret_name: String,
+    secret_name: String,
+    ca_common_name: String,
+    dns_names: Vec<String>,
+}
+
+impl Bootstrap {
+    pub fn new(kube_client: kube::Client, config: &ControllerConfig, secret_name: &str) -> Self {
+        Self {
+            secret_api: Api::namespaced(kube_client, &config.service_namespace),
+            ca_secret_name: config
+                .cert_bootstrap_ca_secret_name
+                .clone()
+                .unwrap_or_else(|| format!("{}-ca", secret_name)),
+            secret_name: secret_name.to_string(),
+            ca_common_name: format!("{}-ca", config.service_name),
+            dns_names: vec![
+                format!("{}.{}.svc", config.service_name, config.service_namespace),
+                format!("{}.{}", config.service_name, config.service_namespace),
+            ],
+        }
+    }
+
+    /// Create or rotate the CA and serving certificate as needed, and return the CA bundle for webhook configurations
+    pub async fn ensure(&self) -> Result<ByteString> {
+        let now = Utc::now();
+
+        // Prepare CA
+        let ca_data = self
+            .secret_api
+            .get_opt(&self.ca_secret_name)
+            .await
+            .context("failed to get CA Secret")?
+            .and_then(|secret| secret.data)
+            .unwrap_or_default();
+        let (ca_cert, ca_key, previous_ca_cert) =
+            match (ca_data.get(TLS_CERT_KEY), ca_data.get(TLS_KEY_KEY)) {
+                (Some(cert), Some(key)) if is_fresh(&cert.0, ca_renew_before(), now) => (
+                    String::from_utf8(cert.0.clone()).context("CA certificate is not UTF-8")?,
+                    String::from_utf8(key.0.clone()).context("CA private key is not UTF-8")?,
+                    ca_data.get(PREVIOUS_CA_CERT_KEY).cloned(),
+                ),
+                (current_cert, _) => {
+                    tracing::info!(secret = %self.ca_secret_name, "generating webhook CA");
+                    let ca = generate_ca(&self.ca_common_name, now)?;
+                    let previous_ca_cert = current_cert.cloned();
+                    let mut data = BTreeMap::from([
+                        (
+                            TLS_CERT_KEY.to_string(),
+                            ByteString(ca.cert.clone().into_bytes()),
+                        ),
+                        (
+                            TLS_KEY_KEY.to_string(),
+                            ByteString(ca.key.clone().into_bytes()),
+                        ),
+                    ]);
+                    if let Some(previous_ca_cert) = &previous_ca_cert {
+                        data.insert(PREVIOUS_CA_CERT_KEY.to_string(), previous_ca_cert.clone());
+                    }
+                    self.apply_secret(&self.ca_secret_name, data).await?;
+                    (ca.cert, ca.key, previous_ca_cert)
+                }
+            };
+
+        // Trust the previous CA until it expires, so that certificates signed by it keep working during rotation
+        let mut ca_bundle = ca_cert.clone().into_bytes();
+        if let Some(previous_ca_cert) = previous_ca_cert {
+            if is_fresh(&previous_ca_cert.0, Duration::zero(), now) {
+                ca_bundle.extend(previous_ca_cert.0);
+            }
+        }
+        let ca_bundle = ByteString(ca_bundle);
+
+        // Prepare serving certificate signed by the current CA
+        let data = self
+            .secret_api
+            .get_opt(&self.secret_name)
+            .await
+            .context("failed to get serving certificate Secret")?
+            .and_then(|secret| secret.data)
+            .unwrap_or_default();
+        let is_signed_by_current_ca = data.get(CA_CERT_KEY) == Some(&ca_bundle);
+        let is_serving_cert_fresh = data
+            .get(TLS_CERT_KEY)
+            .map_or(false, |cert| is_fresh(&cert.0, serving_renew_before(), now));
+        if !(is_signed_by_current_ca && is_serving_cert_fresh && data.contains_key(TLS_KEY_KEY)) {
+            tracing::info!(secret = %self.secret_name, "generating webhook serving certificate");
+            let ca = load_ca(&self.ca_common_name, &ca_key, now)?;
+            let cert = generate_serving_cert(&ca, self.dns_names.clone(), now)?;
+            let data = BTreeMap::from([
+                (TLS_CERT_KEY.to_string(), ByteString(cert.cert.into_bytes())),
+                (TLS_KEY_KEY.to_string(), ByteString(cert.key.into_bytes())),
+                (CA_CERT_KEY.to_string(), ca_bundle.clone()),
+            ]);
+            self.apply_secret(&self.secret_name, data).await?;
+        }
+
+        Ok(ca_bundle)
+    }
+
+    async fn apply_secret(&self, name: &str, data: BTreeMap<String, ByteString>) -> Result<()> {
+        let secret = Secret {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                ..Default::default()
+            },
+            type_: Some("kubernetes.io/tls".to_string()),
+            data: Some(data),
+            ..Default::default()
+        };
+        self.secret_api
+            .patch(
+                name,
+                &PatchParams::apply(FIELD_MANAGER).force(),
+                &Patch::Apply(&secret),
+            )
+            .await
+            .with_context(|| format!("failed to apply Secret {}", name))?;
+        Ok(())
+    }
+}
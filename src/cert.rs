@@ -0,0 +1,138 @@
+//! Inspection of PEM certificates
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+
+use crate::metrics::TLS_METRICS;
+
+/// Validity period of a certificate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Validity {
+    pub not_before: DateTime<Utc>,
+    pub not_after: DateTime<Utc>,
+}
+
+impl Validity {
+    pub fn is_valid_at(&self, time: DateTime<Utc>) -> bool {
+        self.not_before <= time && time <= self.not_after
+    }
+}
+
+/// Read the validity period of the first certificate in the PEM file
+pub async fn read_validity(path: &std::path::Path) -> Result<Validity> {
+    let pem = tokio::fs::read(path)
+        .await
+        .context("failed to read certificate file")?;
+    parse_validity(&pem)
+}
+
+/// Parse the validity period of the first certificate in PEM
+pub fn parse_validity(pem: &[u8]) -> Result<Validity> {
+    parse_validities(pem)?
+        .into_iter()
+        .next()
+        .context("no certificate in PEM")
+}
+
+/// Parse the validity periods of all certificates in PEM
+pub fn parse_validities(pem: &[u8]) -> Result<Vec<Validity>> {
+    rustls_pemfile::certs(&mut &*pem)
+        .context("failed to parse PEM")?
+        .iter()
+        .map(|der| parse_der_validity(der).context("malformed certificate"))
+        .collect()
+}
+
+/// Record the earliest expiry of the certificates in PEM to metrics, and warn if it is within the threshold
+pub fn check_expiry(name: &'static str, pem: &[u8], warning_threshold: Duration) -> Result<()> {
+    let not_after = parse_validities(pem)?
+        .into_iter()
+        .map(|validity| validity.not_after)
+        .min()
+        .context("no certificate in PEM")?;
+    TLS_METRICS.set_cert_expiry(name, not_after);
+
+    let remaining = not_after - Utc::now();
+    if remaining < warning_threshold {
+        tracing::warn!(
+            cert = name,
+            %not_after,
+            remaining_hours = remaining.num_hours(),
+            "certificate expires soon",
+        );
+    }
+    Ok(())
+}
+
+/// Read a DER TLV, and return (tag, contents, rest)
+fn read_tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, input) = input.split_first()?;
+    let (len, input) = if first & 0x80 == 0 {
+        (first as usize, input)
+    } else {
+        let octets = (first & 0x7f) as usize;
+        if octets == 0 || octets > std::mem::size_of::<usize>() || input.len() < octets {
+            return None;
+        }
+        let (len, input) = input.split_at(octets);
+        let len = len
+            .iter()
+            .fold(0usize, |acc, &octet| (acc << 8) | octet as usize);
+        (len, input)
+    };
+    if input.len() < len {
+        return None;
+    }
+    let (contents, rest) = input.split_at(len);
+    Some((tag, contents, rest))
+}
+
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_VERSION: u8 = 0xa0;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+
+/// Parse the validity of `Certificate ::= SEQUENCE { tbsCertificate SEQUENCE { [0] version, serialNumber, signature, issuer, validity, ... }, ... }`
+fn parse_der_validity(der: &[u8]) -> Option<Validity> {
+    let (TAG_SEQUENCE, certificate, _) = read_tlv(der)? else {
+        return None;
+    };
+    let (TAG_SEQUENCE, tbs_certificate, _) = read_tlv(certificate)? else {
+        return None;
+    };
+    let mut fields = tbs_certificate;
+    // Skip the optional version, serial number, signature algorithm, and issuer
+    let (tag, _, rest) = read_tlv(fields)?;
+    fields = if tag == TAG_VERSION {
+        read_tlv(rest)?.2
+    } else {
+        rest
+    };
+    fields = read_tlv(fields)?.2;
+    fields = read_tlv(fields)?.2;
+    let (TAG_SEQUENCE, validity, _) = read_tlv(fields)? else {
+        return None;
+    };
+    let (not_before_tag, not_before, rest) = read_tlv(validity)?;
+    let (not_after_tag, not_after, _) = read_tlv(rest)?;
+    Some(Validity {
+        not_before: parse_time(not_before_tag, not_before)?,
+        not_after: parse_time(not_after_tag, not_after)?,
+    })
+}
+
+fn parse_time(tag: u8, contents: &[u8]) -> Option<DateTime<Utc>> {
+    let time = std::str::from_utf8(contents).ok()?;
+    let time = match tag {
+        TAG_UTC_TIME => {
+            // Two-digit years are 19xx if 50 or greater, 20xx otherwise (RFC 5280)
+            let year: u32 = time.get(..2)?.parse().ok()?;
+            let century = if year >= 50 { "19" } else { "20" };
+            NaiveDateTime::parse_from_str(&format!("{}{}", century, time), "%Y%m%d%H%M%SZ").ok()?
+        }
+        TAG_GENERALIZED_TIME => NaiveDateTime::parse_from_str(time, "%Y%m%d%H%M%SZ").ok()?,
+        _ => return None,
+    };
+    Some(DateTime::from_utc(time, Utc))
+}
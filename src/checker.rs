@@ -1,29 +1,261 @@
-use std::collections::HashMap;
+pub mod helper;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::Arc,
+};
 
 use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
 use deno_core::JsRuntime;
 use futures_util::{stream::FuturesOrdered, TryFutureExt, TryStreamExt};
 use http::{header::HeaderName, HeaderMap, HeaderValue, Method};
 use interpolator::Formattable;
+use k8s_openapi::api::core::v1::{ConfigMap, SecretKeySelector};
 use kube::{
-    api::ListParams,
+    api::{ListParams, Patch, PatchParams},
+    config::{KubeConfigOptions, Kubeconfig},
     core::{DynamicObject, GroupVersionKind},
     discovery::ApiResource,
-    Api,
+    Api, ResourceExt,
+};
+#[cfg(feature = "kafka")]
+use rskafka::{
+    client::{
+        partition::{Compression, UnknownTopicHandling},
+        ClientBuilder, SaslConfig,
+    },
+    record::Record,
+};
+use serde::{Deserialize, Serialize};
+use slack_blocks::{
+    blocks::{Context as ContextBlock, Section},
+    text::ToSlackMarkdown,
+    Block,
 };
-use serde::Serialize;
-use slack_blocks::{blocks::Section, text::ToSlackMarkdown, Block};
 use tracing::Instrument;
+use url::Url;
 
+#[cfg(any(feature = "kafka", feature = "nats"))]
+use crate::tls::make_client_config;
 use crate::{
-    js::set_context,
+    js::{
+        set_context,
+        stub::{self, Stubs},
+    },
     types::policy::{
-        CronPolicyNotification, CronPolicyNotificationSlack, CronPolicyNotificationWebhook,
-        CronPolicyNotificationWebhookMethod, CronPolicyResource,
+        CronPolicyHttpResource, CronPolicyHttpResponseType, CronPolicyNotification,
+        CronPolicyNotificationKafka, CronPolicyNotificationNats, CronPolicyNotificationSlack,
+        CronPolicyNotificationThrottle, CronPolicyNotificationWebhook,
+        CronPolicyNotificationWebhookMethod, CronPolicyPrometheusQuery, CronPolicyResource,
+        CronPolicyValueFrom, Severity,
     },
     util::find_group_version_pairs_by_kind,
 };
 
+pub const FINDING_ANNOTATION_KEY: &str = "checkpoint.devsisters.com/finding";
+/// Data key of the state ConfigMap
+const STATE_KEY: &str = "state";
+/// Prefix of the environment variables holding the kubeconfig of other clusters, suffixed with the index of the resource
+pub const KUBECONFIG_ENV_PREFIX: &str = "CHECKPOINT_KUBECONFIG_";
+/// Prefix of the environment variables holding the bearer token of Prometheus queries, suffixed with the index of the resource
+pub const PROMETHEUS_BEARER_TOKEN_ENV_PREFIX: &str = "CHECKPOINT_PROMETHEUS_BEARER_TOKEN_";
+/// Prefix of the environment variables holding the basic auth password of Prometheus queries, suffixed with the index of the resource
+pub const PROMETHEUS_PASSWORD_ENV_PREFIX: &str = "CHECKPOINT_PROMETHEUS_PASSWORD_";
+/// Prefix of the environment variables holding the Slack bot token, suffixed with 0 for the default channels or with the index of the route plus 1
+pub const SLACK_BOT_TOKEN_ENV_PREFIX: &str = "CHECKPOINT_SLACK_BOT_TOKEN_";
+/// Prefix of the environment variables holding the credentials of Kafka notifications
+pub const KAFKA_ENV_PREFIX: &str = "CHECKPOINT_KAFKA_";
+/// Prefix of the environment variables holding the credentials of NATS notifications
+pub const NATS_ENV_PREFIX: &str = "CHECKPOINT_NATS_";
+/// Prefix of the environment variables holding the credentials of webhook notifications
+pub const WEBHOOK_ENV_PREFIX: &str = "CHECKPOINT_WEBHOOK_";
+/// Base URL of the Slack Web API
+const SLACK_API_URL: &str = "https://slack.com/api/";
+
+/// State of a policy persisted between checker runs
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckerState {
+    /// Output of the previous run
+    #[serde(default)]
+    pub output: Option<CheckerOutput>,
+    /// Notifications sent recently, to throttle notifications
+    #[serde(default)]
+    pub notifications: Vec<NotificationRecord>,
+    /// Timestamps of the Slack messages by channel, to reply in their threads until the check passes
+    #[serde(default)]
+    pub slack_threads: HashMap<String, String>,
+}
+
+/// Record of a sent notification
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NotificationRecord {
+    /// Fingerprint of the notified output
+    pub fingerprint: String,
+    pub time: DateTime<Utc>,
+}
+
+/// Output set with `setOutput` in JS code
+///
+/// Either a list of violations with optional values, or a flat map of values as before.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum CheckerOutput {
+    Structured(StructuredOutput),
+    Flat(HashMap<String, String>),
+}
+
+/// Output with per-resource violations
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StructuredOutput {
+    /// Values available in notification templates as `{output.<key>}`, same as the flat output
+    #[serde(default)]
+    pub values: HashMap<String, String>,
+    pub violations: Vec<Violation>,
+}
+
+/// Violation of the policy by a resource
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Violation {
+    pub resource: ResourceRef,
+    pub message: String,
+    #[serde(default)]
+    pub severity: Option<Severity>,
+}
+
+/// Reference to the resource of a violation
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceRef {
+    #[serde(default)]
+    pub api_version: Option<String>,
+    #[serde(default)]
+    pub kind: Option<String>,
+    #[serde(default)]
+    pub namespace: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub uid: Option<String>,
+}
+
+impl fmt::Display for ResourceRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(kind) = &self.kind {
+            write!(f, "{}/", kind)?;
+        }
+        if let Some(namespace) = &self.namespace {
+            write!(f, "{}/", namespace)?;
+        }
+        write!(f, "{}", self.name)
+    }
+}
+
+impl CheckerOutput {
+    /// Flat values of the output
+    pub fn values(&self) -> &HashMap<String, String> {
+        match self {
+            Self::Structured(output) => &output.values,
+            Self::Flat(values) => values,
+        }
+    }
+
+    /// Violations of the output, empty for the flat output
+    pub fn violations(&self) -> &[Violation] {
+        match self {
+            Self::Structured(output) => &output.violations,
+            Self::Flat(_) => &[],
+        }
+    }
+
+    /// Highest severity of the violations
+    pub fn max_violation_severity(&self) -> Option<Severity> {
+        self.violations()
+            .iter()
+            .filter_map(|violation| violation.severity)
+            .max()
+    }
+
+    /// Violations rendered as a Markdown list, one resource per line
+    pub fn render_violations(&self) -> String {
+        self.violations()
+            .iter()
+            .map(|violation| match violation.severity {
+                Some(severity) => format!(
+                    "- `{}` ({}): {}\n",
+                    violation.resource, severity, violation.message
+                ),
+                None => format!("- `{}`: {}\n", violation.resource, violation.message),
+            })
+            .collect()
+    }
+}
+
+/// Fingerprint of the output, which does not depend on the order of the keys
+///
+/// 64-bit FNV-1a hash of the output in JSON with sorted keys.
+pub fn fingerprint(output: &CheckerOutput) -> String {
+    let sorted = output.values().iter().collect::<BTreeMap<_, _>>();
+    let json = match output {
+        CheckerOutput::Structured(output) => {
+            serde_json::to_string(&(sorted, &output.violations)).unwrap_or_default()
+        }
+        CheckerOutput::Flat(_) => serde_json::to_string(&sorted).unwrap_or_default(),
+    };
+    let hash = json.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+impl CheckerState {
+    /// Forget notifications no longer relevant to the throttle
+    pub fn prune_notifications(
+        &mut self,
+        throttle: &CronPolicyNotificationThrottle,
+        now: DateTime<Utc>,
+    ) {
+        let retention = Duration::hours(1).max(Duration::seconds(
+            throttle.min_interval_seconds.unwrap_or_default() as i64,
+        ));
+        self.notifications
+            .retain(|record| now - record.time < retention);
+    }
+
+    /// Return why the notification of the output with the fingerprint is throttled, if throttled
+    pub fn throttle_reason(
+        &self,
+        throttle: &CronPolicyNotificationThrottle,
+        fingerprint: &str,
+        now: DateTime<Utc>,
+    ) -> Option<&'static str> {
+        if let Some(min_interval_seconds) = throttle.min_interval_seconds {
+            let min_interval = Duration::seconds(min_interval_seconds as i64);
+            if self
+                .notifications
+                .iter()
+                .any(|record| record.fingerprint == fingerprint && now - record.time < min_interval)
+            {
+                return Some("the same output was notified within the minimum interval");
+            }
+        }
+        if let Some(max_per_hour) = throttle.max_per_hour {
+            let notified = self
+                .notifications
+                .iter()
+                .filter(|record| now - record.time < Duration::hours(1))
+                .count();
+            if notified >= max_per_hour as usize {
+                return Some("maximum number of notifications per hour is reached");
+            }
+        }
+        None
+    }
+}
+
 async fn get_group_version_from_resource(
     resource: &CronPolicyResource,
     kube_client: kube::Client,
@@ -55,11 +287,163 @@ async fn get_group_version_from_resource(
     }
 }
 
+async fn get_api_resource_from_resource(
+    resource: &CronPolicyResource,
+    kube_client: kube::Client,
+) -> Result<ApiResource> {
+    let (group, version) = get_group_version_from_resource(resource, kube_client).await?;
+    let gvk = GroupVersionKind::gvk(&group, &version, &resource.kind);
+    let ar = if let Some(plural) = &resource.plural {
+        ApiResource::from_gvk_with_plural(&gvk, plural)
+    } else {
+        ApiResource::from_gvk(&gvk)
+    };
+    Ok(ar)
+}
+
+/// Make the client of the cluster to fetch the resource from
+///
+/// The kubeconfig of other clusters are read from the environment variables set by the controller.
+async fn make_cluster_client(
+    index: usize,
+    resource: &CronPolicyResource,
+    kube_client: kube::Client,
+) -> Result<kube::Client> {
+    if resource.cluster_ref.is_none() {
+        return Ok(kube_client);
+    }
+
+    let kubeconfig = std::env::var(format!("{}{}", KUBECONFIG_ENV_PREFIX, index))
+        .context("kubeconfig of the cluster is not provided")?;
+    let kubeconfig =
+        Kubeconfig::from_yaml(&kubeconfig).context("failed to parse kubeconfig of the cluster")?;
+    let config = kube::Config::from_custom_kubeconfig(kubeconfig, &KubeConfigOptions::default())
+        .await
+        .context("failed to make config from kubeconfig of the cluster")?;
+    kube::Client::try_from(config).context("failed to make client of the cluster")
+}
+
 #[derive(Serialize)]
 #[serde(untagged)]
 pub enum SingleOrList {
     Single(Option<DynamicObject>),
     List(Vec<DynamicObject>),
+    /// Data not from Kubernetes, such as the result of a Prometheus query or a HTTP response
+    Value(serde_json::Value),
+}
+
+impl SingleOrList {
+    fn objects(&self) -> Vec<&DynamicObject> {
+        match self {
+            Self::Single(object) => object.iter().collect(),
+            Self::List(objects) => objects.iter().collect(),
+            Self::Value(_) => Vec::new(),
+        }
+    }
+}
+
+/// Response of the Prometheus HTTP API
+#[derive(Deserialize)]
+struct PrometheusResponse {
+    status: String,
+    #[serde(default)]
+    data: serde_json::Value,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// Request to the HTTP endpoint, and return the response body
+async fn fetch_http(http: &CronPolicyHttpResource) -> Result<serde_json::Value> {
+    let method = http.method.as_ref().map(make_method).unwrap_or(Method::GET);
+
+    let client = reqwest::Client::new();
+    let mut request = client
+        .request(method, http.url.clone())
+        .headers(make_headers(&http.headers)?);
+    if let Some(body) = &http.body {
+        request = request.body(body.clone());
+    }
+
+    let response = request
+        .send()
+        .await
+        .context("failed to request to HTTP endpoint")?
+        .error_for_status()
+        .context("HTTP endpoint returned error")?;
+    let data = match http.response_type {
+        CronPolicyHttpResponseType::Json => response
+            .json()
+            .await
+            .context("failed to parse HTTP response as JSON")?,
+        CronPolicyHttpResponseType::Text => response
+            .text()
+            .await
+            .context("failed to read HTTP response")?
+            .into(),
+    };
+    Ok(data)
+}
+
+/// Evaluate the PromQL query, and return the `data` field of the response
+///
+/// Credentials are read from the environment variables set by the controller.
+async fn fetch_prometheus_query(
+    index: usize,
+    prometheus_query: &CronPolicyPrometheusQuery,
+) -> Result<serde_json::Value> {
+    let mut url = prometheus_query.endpoint.clone();
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("invalid Prometheus endpoint"))?
+        .pop_if_empty()
+        .extend(["api", "v1", "query"]);
+
+    let client = reqwest::Client::new();
+    let mut request = client.get(url).query(&[("query", &prometheus_query.query)]);
+    if let Some(auth) = &prometheus_query.auth {
+        if let Ok(token) = std::env::var(format!("{}{}", PROMETHEUS_BEARER_TOKEN_ENV_PREFIX, index))
+        {
+            request = request.bearer_auth(token);
+        } else if let Some(username) = &auth.username {
+            let password =
+                std::env::var(format!("{}{}", PROMETHEUS_PASSWORD_ENV_PREFIX, index)).ok();
+            request = request.basic_auth(username, password);
+        }
+    }
+
+    let response: PrometheusResponse = request
+        .send()
+        .await
+        .context("failed to request to Prometheus")?
+        .json()
+        .await
+        .context("failed to parse Prometheus response")?;
+    if response.status != "success" {
+        return Err(anyhow::anyhow!(
+            "Prometheus query failed: {}",
+            response.error.unwrap_or(response.status)
+        ));
+    }
+    Ok(response.data)
+}
+
+/// Finding reported with `addFinding` in JS code
+#[derive(Deserialize, Debug)]
+pub struct Finding {
+    #[serde(default)]
+    pub uid: Option<String>,
+    #[serde(default)]
+    pub namespace: Option<String>,
+    pub name: String,
+    pub summary: String,
+}
+
+impl Finding {
+    fn matches(&self, object: &DynamicObject) -> bool {
+        match (&self.uid, &object.metadata.uid) {
+            (Some(uid), Some(object_uid)) => uid == object_uid,
+            _ => self.namespace == object.namespace() && self.name == object.name_any(),
+        }
+    }
 }
 
 pub async fn fetch_resources(
@@ -68,17 +452,21 @@ pub async fn fetch_resources(
 ) -> Result<Vec<SingleOrList>> {
     resources
         .iter()
-        .map(|resource| {
+        .enumerate()
+        .map(|(index, resource)| {
             let kube_client = kube_client.clone();
             async move {
-                let (group, version) =
-                    get_group_version_from_resource(resource, kube_client.clone()).await?;
-                let gvk = GroupVersionKind::gvk(&group, &version, &resource.kind);
-                let ar = if let Some(plural) = &resource.plural {
-                    ApiResource::from_gvk_with_plural(&gvk, plural)
-                } else {
-                    ApiResource::from_gvk(&gvk)
-                };
+                if let Some(prometheus_query) = &resource.prometheus_query {
+                    let data = fetch_prometheus_query(index, prometheus_query).await?;
+                    return Ok(SingleOrList::Value(data));
+                }
+                if let Some(http) = &resource.http {
+                    let data = fetch_http(http).await?;
+                    return Ok(SingleOrList::Value(data));
+                }
+
+                let kube_client = make_cluster_client(index, resource, kube_client).await?;
+                let ar = get_api_resource_from_resource(resource, kube_client.clone()).await?;
                 let api = if let Some(namespace) = &resource.namespace {
                     Api::<DynamicObject>::namespaced_with(kube_client.clone(), namespace, &ar)
                 } else {
@@ -117,87 +505,756 @@ pub async fn fetch_resources(
         .await
 }
 
-pub fn prepare_js_runtime(resources: Vec<SingleOrList>) -> Result<JsRuntime> {
-    let mut js_runtime = crate::js::prepare_js_runtime(vec![])?;
+/// Prepare JS runtime with the resources, with `kubeGet` and `kubeList` replaced with the stubs if given
+///
+/// `kubePatch` and `kubeAnnotate` fail unless `allow_remediation` is set, and do nothing with the stubs.
+pub fn prepare_js_runtime(
+    resources: &[SingleOrList],
+    allow_remediation: bool,
+    stubs: Option<Arc<Stubs>>,
+) -> Result<JsRuntime> {
+    let stubbed = stubs.is_some();
+    let mut extensions = vec![helper::checkpoint_checker::init_ops(allow_remediation)];
+    if let Some(stubs) = stubs {
+        extensions.push(stub::checkpoint_stub::init_ops(stubs));
+    }
+    let mut js_runtime = crate::js::prepare_js_runtime(extensions)?;
 
-    set_context(&mut js_runtime, "resources", &resources)?;
+    set_context(&mut js_runtime, "resources", resources)?;
 
     // Prepare context
     js_runtime.execute_script_static("<checkpoint>", include_str!("checker/runtime.js"))?;
 
+    // Replace kubeGet, kubeList, kubePatch and Date with the stubs
+    if stubbed {
+        stub::replace_with_stubs(&mut js_runtime)?;
+        js_runtime.execute_script_static("<checkpoint>", include_str!("checker/stub.js"))?;
+    }
+
     Ok(js_runtime)
 }
 
+/// Annotate fetched objects with the findings of the policy, and clear the annotation of resolved ones.
+///
+/// The annotation value is a JSON object of policy name to finding summary,
+/// so that multiple policies can annotate the same object.
+pub async fn update_finding_annotations(
+    kube_client: kube::Client,
+    policy_name: &str,
+    resources: &[CronPolicyResource],
+    fetched_resources: &[SingleOrList],
+    findings: &[Finding],
+) -> Result<()> {
+    for (index, (resource, fetched)) in resources.iter().zip(fetched_resources).enumerate() {
+        if !resource.is_kubernetes() {
+            continue;
+        }
+        let kube_client = make_cluster_client(index, resource, kube_client.clone()).await?;
+        let ar = get_api_resource_from_resource(resource, kube_client.clone()).await?;
+
+        for object in fetched.objects() {
+            let summary = findings
+                .iter()
+                .find(|finding| finding.matches(object))
+                .map(|finding| finding.summary.clone());
+
+            let mut entries: BTreeMap<String, String> = object
+                .annotations()
+                .get(FINDING_ANNOTATION_KEY)
+                .and_then(|value| serde_json::from_str(value).ok())
+                .unwrap_or_default();
+            let changed = if let Some(summary) = summary {
+                entries.insert(policy_name.to_string(), summary.clone()) != Some(summary)
+            } else {
+                entries.remove(policy_name).is_some()
+            };
+            if !changed {
+                continue;
+            }
+
+            let value = if entries.is_empty() {
+                serde_json::Value::Null
+            } else {
+                serde_json::to_string(&entries)
+                    .context("failed to serialize finding annotation")?
+                    .into()
+            };
+            let patch = serde_json::json!({
+                "metadata": {
+                    "annotations": {
+                        FINDING_ANNOTATION_KEY: value,
+                    }
+                }
+            });
+            let api = if let Some(namespace) = object.namespace() {
+                Api::<DynamicObject>::namespaced_with(kube_client.clone(), &namespace, &ar)
+            } else {
+                Api::<DynamicObject>::all_with(kube_client.clone(), &ar)
+            };
+            let res = api
+                .patch(
+                    &object.name_any(),
+                    &PatchParams::default(),
+                    &Patch::Merge(patch),
+                )
+                .await;
+            if let Err(error) = res {
+                tracing::error!(%policy_name, name = %object.name_any(), namespace = ?object.namespace(), %error, "Failed to update finding annotation");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Load the state from the ConfigMap. Empty state is returned if nothing is stored yet.
+pub async fn load_state(
+    kube_client: kube::Client,
+    namespace: &str,
+    name: &str,
+) -> Result<CheckerState> {
+    let cm = Api::<ConfigMap>::namespaced(kube_client, namespace)
+        .get(name)
+        .await
+        .context("failed to get state ConfigMap")?;
+    match cm.data.as_ref().and_then(|data| data.get(STATE_KEY)) {
+        Some(state) => serde_json::from_str(state).context("failed to deserialize state"),
+        None => Ok(CheckerState::default()),
+    }
+}
+
+/// Store the state in the ConfigMap
+pub async fn save_state(
+    kube_client: kube::Client,
+    namespace: &str,
+    name: &str,
+    state: &CheckerState,
+) -> Result<()> {
+    let state = serde_json::to_string(state).context("failed to serialize state")?;
+    let patch = serde_json::json!({
+        "data": {
+            STATE_KEY: state,
+        }
+    });
+    Api::<ConfigMap>::namespaced(kube_client, namespace)
+        .patch(name, &PatchParams::default(), &Patch::Merge(patch))
+        .await
+        .context("failed to patch state ConfigMap")?;
+    Ok(())
+}
+
+/// Escape label value in Prometheus text exposition format
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render metrics of a checker run in Prometheus text exposition format
+///
+/// Output values that can be parsed as numbers are exported as `checkpoint_checker_output`.
+fn render_metrics(
+    duration: std::time::Duration,
+    success: bool,
+    output: Option<&CheckerOutput>,
+) -> String {
+    let mut metrics = format!(
+        "# TYPE checkpoint_checker_duration_seconds gauge
+checkpoint_checker_duration_seconds {}
+# TYPE checkpoint_checker_success gauge
+checkpoint_checker_success {}
+# TYPE checkpoint_checker_output_present gauge
+checkpoint_checker_output_present {}
+# TYPE checkpoint_checker_last_run_timestamp_seconds gauge
+checkpoint_checker_last_run_timestamp_seconds {}
+# TYPE checkpoint_checker_violations gauge
+checkpoint_checker_violations {}
+",
+        duration.as_secs_f64(),
+        success as u8,
+        output.is_some() as u8,
+        Utc::now().timestamp(),
+        output.map_or(0, |output| output.violations().len()),
+    );
+
+    let numeric_outputs = output
+        .into_iter()
+        .flat_map(CheckerOutput::values)
+        .filter_map(|(key, value)| Some((key, value.parse::<f64>().ok()?)))
+        .collect::<BTreeMap<_, _>>();
+    if !numeric_outputs.is_empty() {
+        metrics.push_str("# TYPE checkpoint_checker_output gauge\n");
+        for (key, value) in numeric_outputs {
+            metrics.push_str(&format!(
+                "checkpoint_checker_output{{key=\"{}\"}} {}\n",
+                escape_label_value(key),
+                value
+            ));
+        }
+    }
+
+    metrics
+}
+
+/// Push metrics of a checker run to Prometheus Pushgateway, replacing the ones of the previous run
+pub async fn push_metrics(
+    pushgateway_url: &Url,
+    policy_name: &str,
+    duration: std::time::Duration,
+    success: bool,
+    output: Option<&CheckerOutput>,
+) -> Result<()> {
+    let mut url = pushgateway_url.clone();
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("invalid Pushgateway URL"))?
+        .pop_if_empty()
+        .extend([
+            "metrics",
+            "job",
+            "checkpoint_checker",
+            "policy",
+            policy_name,
+        ]);
+
+    let client = reqwest::Client::new();
+    client
+        .put(url)
+        .header(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(render_metrics(duration, success, output))
+        .send()
+        .await
+        .context("failed to request to Pushgateway")?
+        .error_for_status()
+        .context("Pushgateway returned error")?;
+
+    Ok(())
+}
+
+/// Credentials of the notification channels with the names of the environment variables to provide them to the checker
+///
+/// Names of the variables are suffixed with 0 for the default channels, or with the index of the route plus 1.
+pub fn notification_credentials(
+    notifications: &CronPolicyNotification,
+) -> Vec<(String, CronPolicyValueFrom)> {
+    let channels = std::iter::once((
+        &notifications.slack,
+        &notifications.webhook,
+        &notifications.kafka,
+        &notifications.nats,
+    ))
+    .chain(
+        notifications
+            .routes
+            .iter()
+            .map(|route| (&route.slack, &route.webhook, &route.kafka, &route.nats)),
+    );
+
+    let mut credentials = Vec::new();
+    for (index, (slack, webhook, kafka, nats)) in channels.enumerate() {
+        let mut push = |prefix: &str, value_from: Option<&CronPolicyValueFrom>| {
+            if let Some(value_from) = value_from {
+                credentials.push((format!("{}{}", prefix, index), value_from.clone()));
+            }
+        };
+        if let Some(slack) = slack {
+            push(SLACK_BOT_TOKEN_ENV_PREFIX, slack.bot_token_from.as_ref());
+        }
+        if let Some(webhook) = webhook {
+            push(
+                &format!("{}BEARER_TOKEN_", WEBHOOK_ENV_PREFIX),
+                webhook.bearer_token_from.as_ref(),
+            );
+            push(
+                &format!("{}CA_", WEBHOOK_ENV_PREFIX),
+                webhook.ca_from.as_ref(),
+            );
+            if let Some(secret) = &webhook.basic_auth_from {
+                for key in ["username", "password"] {
+                    let value_from = CronPolicyValueFrom {
+                        config_map_key_ref: None,
+                        secret_key_ref: Some(SecretKeySelector {
+                            name: secret.name.clone(),
+                            key: key.to_string(),
+                            optional: None,
+                        }),
+                    };
+                    push(
+                        &format!("{}{}_", WEBHOOK_ENV_PREFIX, key.to_uppercase()),
+                        Some(&value_from),
+                    );
+                }
+            }
+        }
+        for (prefix, tls) in [
+            (
+                KAFKA_ENV_PREFIX,
+                kafka.as_ref().and_then(|kafka| kafka.tls.as_ref()),
+            ),
+            (
+                NATS_ENV_PREFIX,
+                nats.as_ref().and_then(|nats| nats.tls.as_ref()),
+            ),
+        ] {
+            if let Some(tls) = tls {
+                push(&format!("{}TLS_CA_", prefix), Some(&tls.ca_from));
+                push(&format!("{}TLS_CERT_", prefix), tls.cert_from.as_ref());
+                push(&format!("{}TLS_KEY_", prefix), tls.key_from.as_ref());
+            }
+        }
+        if let Some(sasl) = kafka.as_ref().and_then(|kafka| kafka.sasl.as_ref()) {
+            push(
+                &format!("{}SASL_PASSWORD_", KAFKA_ENV_PREFIX),
+                Some(&sasl.password_from),
+            );
+        }
+        if let Some(nats) = nats {
+            push(
+                &format!("{}PASSWORD_", NATS_ENV_PREFIX),
+                nats.password_from.as_ref(),
+            );
+            push(
+                &format!("{}TOKEN_", NATS_ENV_PREFIX),
+                nats.token_from.as_ref(),
+            );
+        }
+    }
+    credentials
+}
+
+/// Read the credential provided by the controller
+fn read_credential(prefix: &str, name: &str, index: usize) -> Result<String> {
+    std::env::var(format!("{}{}_{}", prefix, name, index))
+        .with_context(|| format!("{} credential is not provided", name))
+}
+
+/// Notify the output to the channels, and to the routes matching the severity
+///
+/// Without the severity set with `setOutput`, the highest severity of the violations is used.
+/// Violations are available in templates as `{output.violations}` and `{output.violationCount}`.
+/// Timestamps of the Slack messages to reply in their threads are kept in `slack_threads`.
 pub async fn notify(
     policy_name: String,
-    output: HashMap<String, String>,
+    output: CheckerOutput,
+    severity: Option<Severity>,
     notifications: CronPolicyNotification,
+    slack_threads: &mut HashMap<String, String>,
 ) {
+    let severity = severity.or_else(|| output.max_violation_severity());
+    let severity_string = severity
+        .map(|severity| severity.to_string())
+        .unwrap_or_default();
+    let violations = output.render_violations();
+    let violation_count = output.violations().len();
     let mut interpolator_context = output
+        .values()
         .iter()
         .map(|(key, value)| (format!("output.{}", key), Formattable::display(value)))
         .collect::<HashMap<_, _>>();
+    if let CheckerOutput::Structured(_) = &output {
+        interpolator_context.insert(
+            "output.violations".to_string(),
+            Formattable::display(&violations),
+        );
+        interpolator_context.insert(
+            "output.violationCount".to_string(),
+            Formattable::display(&violation_count),
+        );
+    }
     interpolator_context.insert(
         "policy.name".to_string(),
         Formattable::display(&policy_name),
     );
+    interpolator_context.insert(
+        "severity".to_string(),
+        Formattable::display(&severity_string),
+    );
     let interpolator_context = interpolator_context;
 
-    if let Some(slack_notification) = notifications.slack {
-        let slack_span = tracing::info_span!("notify-slack", %policy_name);
-        let res = notify_slack(&policy_name, &interpolator_context, slack_notification)
+    let routes = notifications
+        .routes
+        .into_iter()
+        .enumerate()
+        .filter(|(_, route)| {
+            severity
+                .map(|severity| route.severities.contains(&severity))
+                .unwrap_or(false)
+        });
+    let channels = std::iter::once((
+        0,
+        notifications.slack,
+        notifications.webhook,
+        notifications.kafka,
+        notifications.nats,
+    ))
+    .chain(routes.map(|(index, route)| {
+        (
+            index + 1,
+            route.slack,
+            route.webhook,
+            route.kafka,
+            route.nats,
+        )
+    }));
+    let event = NotificationEvent {
+        policy_name: &policy_name,
+        severity,
+        output: &output,
+        time: Utc::now(),
+    };
+
+    for (index, slack_notification, webhook_notification, kafka_notification, nats_notification) in
+        channels
+    {
+        if let Some(slack_notification) = slack_notification {
+            let slack_span = tracing::info_span!("notify-slack", %policy_name);
+            let bot_token = slack_notification.bot_token_from.as_ref().and_then(|_| {
+                std::env::var(format!("{}{}", SLACK_BOT_TOKEN_ENV_PREFIX, index)).ok()
+            });
+            let res = notify_slack(
+                &policy_name,
+                &interpolator_context,
+                slack_notification,
+                bot_token,
+                severity,
+                &output,
+                slack_threads,
+            )
             .instrument(slack_span)
             .await;
-        if let Err(error) = res {
-            tracing::error!(%policy_name, %error, "Failed to notify slack");
+            if let Err(error) = res {
+                tracing::error!(%policy_name, %error, "Failed to notify slack");
+            }
+        }
+        if let Some(webhook_notification) = webhook_notification {
+            let slack_span = tracing::info_span!("notify-webhook", %policy_name);
+            let res = notify_webhook(&interpolator_context, webhook_notification, index)
+                .instrument(slack_span)
+                .await;
+            if let Err(error) = res {
+                tracing::error!(%policy_name, %error, "Failed to notify webhook");
+            }
+        }
+        if let Some(kafka_notification) = kafka_notification {
+            let kafka_span = tracing::info_span!("notify-kafka", %policy_name);
+            let res = notify_kafka(&interpolator_context, &event, kafka_notification, index)
+                .instrument(kafka_span)
+                .await;
+            if let Err(error) = res {
+                tracing::error!(%policy_name, %error, "Failed to notify kafka");
+            }
+        }
+        if let Some(nats_notification) = nats_notification {
+            let nats_span = tracing::info_span!("notify-nats", %policy_name);
+            let res = notify_nats(&interpolator_context, &event, nats_notification, index)
+                .instrument(nats_span)
+                .await;
+            if let Err(error) = res {
+                tracing::error!(%policy_name, %error, "Failed to notify nats");
+            }
         }
     }
-    if let Some(webhook_notification) = notifications.webhook {
-        let slack_span = tracing::info_span!("notify-webhook", %policy_name);
-        let res = notify_webhook(&interpolator_context, webhook_notification)
-            .instrument(slack_span)
-            .await;
-        if let Err(error) = res {
-            tracing::error!(%policy_name, %error, "Failed to notify webhook");
+}
+
+/// Default message of the notifications to message buses
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NotificationEvent<'a> {
+    policy_name: &'a str,
+    severity: Option<Severity>,
+    output: &'a CheckerOutput,
+    time: DateTime<Utc>,
+}
+
+/// Make the message to publish to a message bus from the template, or the event in JSON by default
+#[cfg(any(feature = "kafka", feature = "nats"))]
+fn make_bus_message(
+    template: Option<&str>,
+    context: &HashMap<String, Formattable<'_>>,
+    event: &NotificationEvent,
+) -> Result<String> {
+    match template {
+        Some(template) => {
+            interpolator::format(template, context).context("failed to make message from template")
         }
+        None => serde_json::to_string(event).context("failed to serialize notification event"),
+    }
+}
+
+/// Make the TLS client configuration from the certificates provided by the controller
+#[cfg(any(feature = "kafka", feature = "nats"))]
+fn make_bus_tls_config(prefix: &str, index: usize) -> Result<rustls::ClientConfig> {
+    let ca = read_credential(prefix, "TLS_CA", index)?;
+    let cert = std::env::var(format!("{}TLS_CERT_{}", prefix, index)).ok();
+    let key = std::env::var(format!("{}TLS_KEY_{}", prefix, index)).ok();
+    let cert_and_key = cert
+        .as_deref()
+        .zip(key.as_deref())
+        .map(|(cert, key)| (cert.as_bytes(), key.as_bytes()));
+    make_client_config(ca.as_bytes(), cert_and_key)
+}
+
+#[cfg(feature = "kafka")]
+async fn notify_kafka(
+    context: &HashMap<String, Formattable<'_>>,
+    event: &NotificationEvent<'_>,
+    config: CronPolicyNotificationKafka,
+    index: usize,
+) -> Result<()> {
+    let message = make_bus_message(config.message.as_deref(), context, event)?;
+
+    let mut builder = ClientBuilder::new(config.brokers);
+    if config.tls.is_some() {
+        builder = builder.tls_config(Arc::new(make_bus_tls_config(KAFKA_ENV_PREFIX, index)?));
     }
+    if let Some(sasl) = config.sasl {
+        builder = builder.sasl_config(SaslConfig::Plain {
+            username: sasl.username,
+            password: read_credential(KAFKA_ENV_PREFIX, "SASL_PASSWORD", index)?,
+        });
+    }
+    let client = builder
+        .build()
+        .await
+        .context("failed to connect to Kafka")?;
+    let partition_client = client
+        .partition_client(config.topic, config.partition, UnknownTopicHandling::Error)
+        .await
+        .context("failed to make Kafka partition client")?;
+
+    let record = Record {
+        key: Some(event.policy_name.as_bytes().to_vec()),
+        value: Some(message.into_bytes()),
+        headers: BTreeMap::new(),
+        timestamp: event.time,
+    };
+    partition_client
+        .produce(vec![record], Compression::NoCompression)
+        .await
+        .context("failed to produce to Kafka")?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "kafka"))]
+async fn notify_kafka(
+    _context: &HashMap<String, Formattable<'_>>,
+    _event: &NotificationEvent<'_>,
+    _config: CronPolicyNotificationKafka,
+    _index: usize,
+) -> Result<()> {
+    anyhow::bail!("Kafka notifications are not supported by this build without `kafka` feature")
+}
+
+#[cfg(feature = "nats")]
+async fn notify_nats(
+    context: &HashMap<String, Formattable<'_>>,
+    event: &NotificationEvent<'_>,
+    config: CronPolicyNotificationNats,
+    index: usize,
+) -> Result<()> {
+    let message = make_bus_message(config.message.as_deref(), context, event)?;
+
+    let mut options = async_nats::ConnectOptions::new();
+    if config.tls.is_some() {
+        options = options
+            .require_tls(true)
+            .tls_client_config(make_bus_tls_config(NATS_ENV_PREFIX, index)?);
+    }
+    if let Some(username) = config.username {
+        let password = read_credential(NATS_ENV_PREFIX, "PASSWORD", index)?;
+        options = options.user_and_password(username, password);
+    }
+    if config.token_from.is_some() {
+        options = options.token(read_credential(NATS_ENV_PREFIX, "TOKEN", index)?);
+    }
+    let client = options
+        .connect(config.url)
+        .await
+        .context("failed to connect to NATS")?;
+
+    client
+        .publish(config.subject, message.into())
+        .await
+        .context("failed to publish to NATS")?;
+    // Publishing is buffered, so flush before the checker exits
+    client.flush().await.context("failed to flush to NATS")?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "nats"))]
+async fn notify_nats(
+    _context: &HashMap<String, Formattable<'_>>,
+    _event: &NotificationEvent<'_>,
+    _config: CronPolicyNotificationNats,
+    _index: usize,
+) -> Result<()> {
+    anyhow::bail!("NATS notifications are not supported by this build without `nats` feature")
 }
 
 #[derive(Serialize)]
 struct SlackReq<'a> {
     text: String,
     blocks: Vec<Block<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thread_ts: Option<&'a str>,
+}
+
+/// Response of the Slack Web API
+#[derive(Deserialize)]
+struct SlackResp {
+    ok: bool,
+    #[serde(default)]
+    ts: Option<String>,
+    #[serde(default)]
+    error: Option<String>,
 }
 
+impl SlackResp {
+    fn into_result(self) -> Result<Option<String>> {
+        if self.ok {
+            Ok(self.ts)
+        } else {
+            Err(anyhow::anyhow!(
+                "Slack API returned error: {}",
+                self.error.unwrap_or_default()
+            ))
+        }
+    }
+}
+
+/// Notify to Slack with the incoming webhook, or with the Web API if the bot token is provided
 async fn notify_slack(
     policy_name: &str,
     context: &HashMap<String, Formattable<'_>>,
     config: CronPolicyNotificationSlack,
+    bot_token: Option<String>,
+    severity: Option<Severity>,
+    output: &CheckerOutput,
+    slack_threads: &mut HashMap<String, String>,
 ) -> Result<()> {
     let message = interpolator::format(&config.message, context)
         .context("failed to make Slack message from template")?;
-    let blocks = vec![Section::builder().text(message.markdown()).build().into()];
+    let mentions = config
+        .mention_users
+        .iter()
+        .map(|user| format!("<@{}>", user))
+        .chain(
+            config
+                .mention_groups
+                .iter()
+                .map(|group| format!("<!subteam^{}>", group)),
+        )
+        .collect::<Vec<_>>();
+    let mut footer = format!("Policy: `{}`", policy_name);
+    if let Some(severity) = severity {
+        footer.push_str(&format!(" | Severity: *{}*", severity));
+    }
+
+    let mut blocks: Vec<Block> = Vec::new();
+    if !mentions.is_empty() {
+        blocks.push(
+            Section::builder()
+                .text(mentions.join(" ").markdown())
+                .build()
+                .into(),
+        );
+    }
+    blocks.push(Section::builder().text(message.markdown()).build().into());
+    blocks.push(
+        ContextBlock::builder()
+            .element(footer.markdown())
+            .build()
+            .into(),
+    );
+    let text = format!("{} is firing", policy_name);
+
+    let client = reqwest::Client::new();
+    let Some(bot_token) = bot_token else {
+        let webhook_url = config
+            .webhook_url
+            .context("Slack incoming webhook URL or bot token is required")?;
+        let body = SlackReq {
+            text,
+            blocks,
+            channel: None,
+            thread_ts: None,
+        };
+        client
+            .post(webhook_url)
+            .json(&body)
+            .send()
+            .await
+            .context("failed to request to Slack webhook")?;
+        return Ok(());
+    };
+
+    let channel = severity
+        .and_then(|severity| config.severity_channels.get(&severity))
+        .or(config.channel.as_ref())
+        .context("Slack channel is required to notify with the bot token")?
+        .clone();
+    let thread_ts = if config.thread {
+        slack_threads.get(&channel).cloned()
+    } else {
+        None
+    };
     let body = SlackReq {
-        text: format!("{} is firing", policy_name),
+        text,
         blocks,
+        channel: Some(&channel),
+        thread_ts: thread_ts.as_deref(),
     };
-
-    let client = reqwest::Client::new();
-    client
-        .post(config.webhook_url)
+    let ts = client
+        .post(format!("{}chat.postMessage", SLACK_API_URL))
+        .bearer_auth(&bot_token)
         .json(&body)
         .send()
         .await
-        .context("failed to request to Slack webhook")?;
+        .context("failed to request to Slack API")?
+        .json::<SlackResp>()
+        .await
+        .context("failed to parse Slack API response")?
+        .into_result()?;
+    if config.thread && thread_ts.is_none() {
+        if let Some(ts) = &ts {
+            slack_threads.insert(channel.clone(), ts.clone());
+        }
+    }
+
+    if config.upload_output {
+        let content = serde_json::to_string_pretty(output).context("failed to serialize output")?;
+        let mut form = vec![
+            ("channels", channel.as_str()),
+            ("content", content.as_str()),
+            ("filename", "output.json"),
+            ("filetype", "json"),
+        ];
+        // Keep the snippet next to the message
+        if let Some(ts) = thread_ts.as_deref().or(ts.as_deref()) {
+            form.push(("thread_ts", ts));
+        }
+        client
+            .post(format!("{}files.upload", SLACK_API_URL))
+            .bearer_auth(&bot_token)
+            .form(&form)
+            .send()
+            .await
+            .context("failed to request to Slack API")?
+            .json::<SlackResp>()
+            .await
+            .context("failed to parse Slack API response")?
+            .into_result()?;
+    }
 
     Ok(())
 }
 
-async fn notify_webhook(
-    context: &HashMap<String, Formattable<'_>>,
-    config: CronPolicyNotificationWebhook,
-) -> Result<()> {
-    let method = match config.method {
+fn make_method(method: &CronPolicyNotificationWebhookMethod) -> Method {
+    match method {
         CronPolicyNotificationWebhookMethod::Get => Method::GET,
         CronPolicyNotificationWebhookMethod::Head => Method::HEAD,
         CronPolicyNotificationWebhookMethod::Post => Method::POST,
@@ -207,22 +1264,59 @@ async fn notify_webhook(
         CronPolicyNotificationWebhookMethod::Options => Method::OPTIONS,
         CronPolicyNotificationWebhookMethod::Trace => Method::TRACE,
         CronPolicyNotificationWebhookMethod::Patch => Method::PATCH,
-    };
-    let mut headers = HeaderMap::<HeaderValue>::with_capacity(config.headers.len());
-    for (name, value) in config.headers {
-        headers.insert(
+    }
+}
+
+fn make_headers(headers: &HashMap<String, String>) -> Result<HeaderMap> {
+    let mut header_map = HeaderMap::<HeaderValue>::with_capacity(headers.len());
+    for (name, value) in headers {
+        header_map.insert(
             HeaderName::from_lowercase(name.to_lowercase().as_bytes())
                 .context("failed to parse header name")?,
             value.parse().context("failed to parse header value")?,
         );
     }
+    Ok(header_map)
+}
+
+/// Make the HTTP client trusting the CA bundle provided by the controller
+fn make_webhook_client(
+    config: &CronPolicyNotificationWebhook,
+    index: usize,
+) -> Result<reqwest::Client> {
+    let mut builder =
+        reqwest::Client::builder().danger_accept_invalid_certs(config.insecure_skip_verify);
+    if config.ca_from.is_some() {
+        let ca = read_credential(WEBHOOK_ENV_PREFIX, "CA", index)?;
+        for cert in rustls_pemfile::certs(&mut ca.as_bytes()).context("failed to parse CA")? {
+            let cert = reqwest::Certificate::from_der(&cert).context("invalid CA certificate")?;
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    builder.build().context("failed to make HTTP client")
+}
+
+async fn notify_webhook(
+    context: &HashMap<String, Formattable<'_>>,
+    config: CronPolicyNotificationWebhook,
+    index: usize,
+) -> Result<()> {
+    let method = make_method(&config.method);
+    let headers = make_headers(&config.headers)?;
     let body =
         interpolator::format(&config.body, context).context("failed to make body from template")?;
 
-    let client = reqwest::Client::new();
-    client
-        .request(method, config.url)
-        .headers(headers)
+    let client = make_webhook_client(&config, index)?;
+    let mut request = client.request(method, config.url).headers(headers);
+    if config.bearer_token_from.is_some() {
+        request = request.bearer_auth(read_credential(WEBHOOK_ENV_PREFIX, "BEARER_TOKEN", index)?);
+    } else if config.basic_auth_from.is_some() {
+        request = request.basic_auth(
+            read_credential(WEBHOOK_ENV_PREFIX, "USERNAME", index)?,
+            Some(read_credential(WEBHOOK_ENV_PREFIX, "PASSWORD", index)?),
+        );
+    }
+    request
         .body(body)
         .send()
         .await
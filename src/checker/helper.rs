@@ -0,0 +1,102 @@
+//! JS helper functions for checkers
+
+use std::{cell::RefCell, rc::Rc};
+
+use anyhow::{anyhow, Context};
+use deno_core::{op, OpState};
+use kube::{
+    api::{Patch, PatchParams},
+    core::{DynamicObject, GroupVersionKind, ObjectList},
+    discovery::ApiResource,
+    Api,
+};
+
+use crate::handler::js::helper::{kube_get, kube_list, KubeGetArgument, KubeListArgument};
+
+deno_core::extension!(
+    checkpoint_checker,
+    ops = [
+        ops_checker_kube_get,
+        ops_checker_kube_list,
+        ops_checker_kube_patch
+    ],
+    options = { allow_remediation: bool },
+    state = |state, config| {
+        state.put(Remediation(config.allow_remediation));
+    },
+);
+
+/// Whether the policy allows JS code to modify objects
+struct Remediation(bool);
+
+/// Prepare Kubernetes client of the checker ServiceAccount
+async fn prepare_kube_client() -> anyhow::Result<kube::Client> {
+    kube::Client::try_default()
+        .await
+        .context("failed to prepare Kubernetes client")
+}
+
+/// JS helper function to get a Kubernetes resource
+#[op]
+async fn ops_checker_kube_get(args: KubeGetArgument) -> anyhow::Result<Option<DynamicObject>> {
+    kube_get(prepare_kube_client().await?, args).await
+}
+
+/// JS helper function to list Kubernetes resources
+#[op]
+async fn ops_checker_kube_list(
+    args: KubeListArgument,
+) -> anyhow::Result<ObjectList<DynamicObject>> {
+    kube_list(prepare_kube_client().await?, args).await
+}
+
+/// JS helper function to apply a JSON merge patch to a Kubernetes resource, if the policy allows remediation
+#[op]
+async fn ops_checker_kube_patch(
+    state: Rc<RefCell<OpState>>,
+    args: KubeGetArgument,
+    patch: serde_json::Value,
+) -> anyhow::Result<DynamicObject> {
+    if !state.borrow().borrow::<Remediation>().0 {
+        return Err(anyhow!(
+            "`allowRemediation` of the policy must be true to modify objects"
+        ));
+    }
+    kube_patch(prepare_kube_client().await?, args, patch).await
+}
+
+/// Apply a JSON merge patch to a Kubernetes resource
+pub async fn kube_patch(
+    client: kube::Client,
+    KubeGetArgument {
+        group,
+        version,
+        kind,
+        plural,
+        namespace,
+        name,
+    }: KubeGetArgument,
+    patch: serde_json::Value,
+) -> anyhow::Result<DynamicObject> {
+    // Prepare GroupVersionKind and ApiResource from argument
+    let gvk = GroupVersionKind::gvk(&group, &version, &kind);
+    let ar = if let Some(plural) = plural {
+        ApiResource::from_gvk_with_plural(&gvk, &plural)
+    } else {
+        ApiResource::from_gvk(&gvk)
+    };
+
+    // Prepare Kubernetes API with or without namespace
+    let api = if let Some(namespace) = namespace {
+        Api::<DynamicObject>::namespaced_with(client, &namespace, &ar)
+    } else {
+        Api::<DynamicObject>::all_with(client, &ar)
+    };
+
+    let object = api
+        .patch(&name, &PatchParams::default(), &Patch::Merge(patch))
+        .await
+        .context("failed to patch in Kubernetes cluster")?;
+
+    Ok(object)
+}
@@ -1,6 +1,61 @@
 function getResources() {
   return __checkpoint_get_context("resources");
 }
-function setOutput(args) {
+function setOutput(args, severity) {
   __checkpoint_set_context("output", args);
+  __checkpoint_set_context("severity", severity ?? null);
 }
+const __checkpoint_severities = ["info", "warning", "critical"];
+function addViolation(object, message, severity) {
+  let output = __checkpoint_get_context("output");
+  if (output === null || output === undefined) {
+    output = { values: {}, violations: [] };
+  } else if (!Array.isArray(output.violations)) {
+    output = { values: output, violations: [] };
+  }
+  output.violations.push({
+    resource: {
+      apiVersion: object.apiVersion,
+      kind: object.kind,
+      namespace: object.metadata.namespace,
+      name: object.metadata.name,
+      uid: object.metadata.uid,
+    },
+    message,
+    severity: severity ?? null,
+  });
+  __checkpoint_set_context("output", output);
+  const current = __checkpoint_get_context("severity") ?? null;
+  if (
+    severity != null &&
+    __checkpoint_severities.indexOf(severity) >
+      __checkpoint_severities.indexOf(current)
+  ) {
+    __checkpoint_set_context("severity", severity);
+  }
+}
+function kubeGet(args) {
+  return Deno.core.ops.ops_checker_kube_get(args);
+}
+function kubeList(args) {
+  return Deno.core.ops.ops_checker_kube_list(args);
+}
+function kubePatch(args, patch) {
+  return Deno.core.ops.ops_checker_kube_patch(args, patch);
+}
+function kubeAnnotate(args, annotations) {
+  return kubePatch(args, { metadata: { annotations } });
+}
+function getPreviousOutput() {
+  return __checkpoint_get_context("previousOutput") ?? null;
+}
+function addFinding(object, summary) {
+  const findings = __checkpoint_get_context("findings");
+  findings.push({
+    uid: object.metadata.uid,
+    namespace: object.metadata.namespace,
+    name: object.metadata.name,
+    summary,
+  });
+}
+__checkpoint_set_context("findings", []);
@@ -0,0 +1,3 @@
+function kubePatch(args, patch) {
+  return Promise.resolve(null);
+}
@@ -0,0 +1,64 @@
+//! Metadata of the cluster the webhook runs in, exposed to rules as `getCluster()`
+
+use k8s_openapi::api::core::v1::Node;
+use kube::{api::ListParams, Api, ResourceExt};
+use serde::Serialize;
+
+use crate::config::WebhookConfig;
+
+/// Cluster metadata, populated once at startup
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ClusterInfo {
+    /// Name of the cluster from the config
+    pub name: Option<String>,
+    /// Git version of the API server, e.g. `v1.27.3`
+    pub server_version: Option<String>,
+    /// Cloud provider of the cluster, e.g. `aws`
+    pub provider: Option<String>,
+    /// Region of the cluster, e.g. `us-east-1`
+    pub region: Option<String>,
+}
+
+/// Fetch the cluster metadata
+///
+/// Metadata that fails to be fetched is left empty, not to keep the webhook from starting.
+pub async fn fetch_cluster_info(kube_client: kube::Client, config: &WebhookConfig) -> ClusterInfo {
+    let mut cluster_info = ClusterInfo {
+        name: config.cluster_name.clone(),
+        ..Default::default()
+    };
+
+    match kube_client.apiserver_version().await {
+        Ok(info) => cluster_info.server_version = Some(info.git_version),
+        Err(error) => tracing::warn!(%error, "failed to get version of the API server"),
+    }
+
+    // Provider and region are read from any Node, as Nodes of a cluster share them
+    let node = match Api::<Node>::all(kube_client)
+        .list(&ListParams::default().limit(1))
+        .await
+    {
+        Ok(nodes) => nodes.items.into_iter().next(),
+        Err(error) => {
+            tracing::warn!(%error, "failed to list Nodes");
+            None
+        }
+    };
+    if let Some(node) = node {
+        cluster_info.provider = match &config.cluster_provider_label {
+            Some(label) => node.labels().get(label).cloned(),
+            // e.g. `aws:///us-east-1a/i-0123456789abcdef0`
+            None => node
+                .spec
+                .as_ref()
+                .and_then(|spec| spec.provider_id.as_deref())
+                .and_then(|provider_id| provider_id.split_once("://"))
+                .map(|(provider, _)| provider.to_string()),
+        };
+        cluster_info.region = node.labels().get(&config.cluster_region_label).cloned();
+    }
+
+    tracing::info!(?cluster_info, "fetched cluster metadata");
+    cluster_info
+}
@@ -1,16 +1,271 @@
-use std::{borrow::Cow, path::PathBuf};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
+use once_cell::sync::OnceCell;
 use serde::{
     de::{self, DeserializeOwned},
     Deserialize, Deserializer,
 };
+use tracing_subscriber::{reload, EnvFilter};
+use url::Url;
 
 use crate::types::policy::{CronPolicyNotification, CronPolicyResource};
 
+pub mod dynamic;
+
+/// Prefix of environment variables for configuration
+const ENV_PREFIX: &str = "CONF_";
+/// Environment variable of the config file path, which can also be given with `--config` argument
+const CONFIG_FILE_ENV: &str = "CONF_FILE";
+
+/// Errors can be raised when loading configuration
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to read config file: {0}")]
+    ReadFile(#[source] std::io::Error),
+    #[error("failed to parse YAML config file: {0}")]
+    ParseYaml(#[source] serde_yaml::Error),
+    #[error("failed to parse TOML config file: {0}")]
+    ParseToml(#[source] toml::de::Error),
+    #[error("config file must be a mapping of field names to values")]
+    NotMapping,
+    #[error("invalid config: {0}")]
+    Envy(#[source] envy::Error),
+    #[error("invalid log level: {0}")]
+    ParseLogLevel(#[source] tracing_subscriber::filter::ParseError),
+    #[error("failed to reload log level: {0}")]
+    ReloadLogLevel(#[source] reload::Error),
+}
+
+/// Path of the config file from `--config` argument or `CONF_FILE`
+fn config_file_path() -> Option<PathBuf> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os(CONFIG_FILE_ENV).map(PathBuf::from)
+}
+
+/// Read the config file as environment variables, so that it is deserialized the same way
+///
+/// Field names are the keys in snake case. Arrays of scalars are joined with commas, and other non-scalar values are
+/// written in JSON, as the corresponding environment variables are.
+fn read_config_file(path: &Path) -> Result<BTreeMap<String, String>, ConfigError> {
+    let contents = std::fs::read_to_string(path).map_err(ConfigError::ReadFile)?;
+    let value: serde_json::Value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&contents).map_err(ConfigError::ParseToml)?,
+        _ => serde_yaml::from_str(&contents).map_err(ConfigError::ParseYaml)?,
+    };
+    let serde_json::Value::Object(fields) = value else {
+        return Err(ConfigError::NotMapping);
+    };
+
+    let to_scalar_string = |value: &serde_json::Value| match value {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Bool(_) | serde_json::Value::Number(_) => Some(value.to_string()),
+        _ => None,
+    };
+    Ok(fields
+        .into_iter()
+        .filter(|(_, value)| !value.is_null())
+        .map(|(key, value)| {
+            let value = match &value {
+                serde_json::Value::Array(items) => items
+                    .iter()
+                    .map(to_scalar_string)
+                    .collect::<Option<Vec<_>>>()
+                    .map(|items| items.join(",")),
+                _ => to_scalar_string(&value),
+            }
+            .unwrap_or_else(|| value.to_string());
+            (format!("{}{}", ENV_PREFIX, key.to_uppercase()), value)
+        })
+        .collect())
+}
+
+/// Load configuration from the config file if given, overridden by environment variables
+fn load<T>() -> Result<T, ConfigError>
+where
+    T: DeserializeOwned,
+{
+    let mut vars = match config_file_path() {
+        Some(path) => read_config_file(&path)?,
+        None => BTreeMap::new(),
+    };
+    vars.extend(std::env::vars().filter(|(key, _)| key != CONFIG_FILE_ENV));
+    envy::prefixed(ENV_PREFIX)
+        .from_iter(vars)
+        .map_err(ConfigError::Envy)
+}
+
 fn default_listen_addr() -> String {
     "[::]:3000".to_string()
 }
 
+fn default_js_queue_size() -> usize {
+    crate::handler::js::DEFAULT_JS_QUEUE_SIZE
+}
+
+fn default_preflight_interval_seconds() -> u64 {
+    300
+}
+
+fn default_max_request_body_size() -> usize {
+    // AdmissionReview carries both the object and the old object, each up to the API server request limit of 3 MiB
+    6 * 1024 * 1024
+}
+
+fn default_max_json_depth() -> usize {
+    100
+}
+
+fn default_metrics_listen_addr() -> String {
+    "[::]:8080".to_string()
+}
+
+fn default_checker_namespace() -> String {
+    "default".to_string()
+}
+
+fn default_resync_interval_seconds() -> u64 {
+    300
+}
+
+fn default_cert_expiry_warning_days() -> i64 {
+    14
+}
+
+fn default_shutdown_drain_timeout_seconds() -> u64 {
+    25
+}
+
+fn default_enable_leader_election() -> bool {
+    true
+}
+
+fn default_target_object() -> bool {
+    true
+}
+
+fn default_cluster_region_label() -> String {
+    "topology.kubernetes.io/region".to_string()
+}
+
+fn default_leader_election_lease_name() -> String {
+    "checkpoint.devsisters.com".to_string()
+}
+
+fn default_leader_election_lease_duration_seconds() -> u64 {
+    15
+}
+
+fn default_leader_election_renew_deadline_seconds() -> u64 {
+    10
+}
+
+/// Output format of logs
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable text
+    #[default]
+    Text,
+    /// JSON object per line, for log aggregation
+    Json,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Text => write!(f, "text"),
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Configuration of logging, common to all binaries
+#[derive(Deserialize, Clone, Debug)]
+pub struct LogConfig {
+    #[serde(default)]
+    pub log_format: LogFormat,
+}
+
+impl LogConfig {
+    pub fn try_from_env() -> Result<Self, envy::Error> {
+        envy::prefixed(ENV_PREFIX).from_env()
+    }
+
+    /// Load from the config file given with `--config` or `CONF_FILE`, overridden by `CONF_` environment variables
+    pub fn load() -> Result<Self, ConfigError> {
+        load()
+    }
+}
+
+/// Replaces the log filter of the tracing subscriber
+type LogFilterReloader = Box<dyn Fn(EnvFilter) -> Result<(), reload::Error> + Send + Sync>;
+
+static LOG_FILTER_RELOADER: OnceCell<LogFilterReloader> = OnceCell::new();
+/// Directives of the log level set with [`set_log_level`], or `None` if `RUST_LOG` is used
+static LOG_LEVEL: Mutex<Option<String>> = Mutex::new(None);
+
+/// Initialize tracing subscriber with the format configured with `CONF_LOG_FORMAT` or the config file
+///
+/// Log level is configured with `RUST_LOG`, and can be changed later with [`set_log_level`].
+pub fn init_tracing() -> Result<(), ConfigError> {
+    let config = LogConfig::load()?;
+    let builder = tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env());
+    match config.log_format {
+        LogFormat::Text => {
+            let builder = builder.with_filter_reloading();
+            let handle = builder.reload_handle();
+            let _ = LOG_FILTER_RELOADER.set(Box::new(move |filter| handle.reload(filter)));
+            builder.init()
+        }
+        LogFormat::Json => {
+            let builder = builder.json().with_filter_reloading();
+            let handle = builder.reload_handle();
+            let _ = LOG_FILTER_RELOADER.set(Box::new(move |filter| handle.reload(filter)));
+            builder.init()
+        }
+    }
+    Ok(())
+}
+
+/// Change the log level to the directives in `RUST_LOG` format, or back to `RUST_LOG` if not given
+///
+/// Does nothing if tracing is not initialized with [`init_tracing`].
+pub fn set_log_level(directives: Option<&str>) -> Result<(), ConfigError> {
+    let Some(reloader) = LOG_FILTER_RELOADER.get() else {
+        return Ok(());
+    };
+    let filter = match directives {
+        Some(directives) => EnvFilter::try_new(directives).map_err(ConfigError::ParseLogLevel)?,
+        None => EnvFilter::from_default_env(),
+    };
+    reloader(filter).map_err(ConfigError::ReloadLogLevel)?;
+    *LOG_LEVEL.lock().unwrap() = directives.map(str::to_string);
+    tracing::info!(log_level = directives, "log level changed");
+    Ok(())
+}
+
+/// Current log level directives in `RUST_LOG` format
+pub fn log_level() -> String {
+    match &*LOG_LEVEL.lock().unwrap() {
+        Some(directives) => directives.clone(),
+        None => std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_default(),
+    }
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct ControllerConfig {
     /// Installed Kubernetes Service namespace of the checkpoint webhook
@@ -19,34 +274,192 @@ pub struct ControllerConfig {
     pub service_name: String,
     /// Installed Kubernetes Service port of the checkpoint webhook
     pub service_port: i32,
+    /// Base URL of the checkpoint webhook for the API server to call instead of the Service, for checkpoint deployed out of the cluster
+    #[serde(default)]
+    pub webhook_external_url: Option<Url>,
+    /// Exclude the namespace of the webhook Service from the webhook configurations of Rules that do not set
+    /// `includeCheckpointNamespace`, so that checkpoint can restart itself when the Rules fail
+    #[serde(default)]
+    pub exclude_own_namespace: bool,
 
     /// Base64 encoded PEM CA bundle file path for the checkpoint webhook
-    pub ca_bundle_path: PathBuf,
+    #[serde(default)]
+    pub ca_bundle_path: Option<PathBuf>,
+    /// Provision a self-signed CA and the serving certificate of the webhook to this Secret, instead of reading the CA bundle file
+    #[serde(default)]
+    pub cert_bootstrap_secret_name: Option<String>,
+    /// Secret to store the self-signed CA. Defaults to the serving certificate Secret name suffixed with `-ca`.
+    #[serde(default)]
+    pub cert_bootstrap_ca_secret_name: Option<String>,
+    /// MutatingWebhookConfiguration of the internal webhooks to inject the CA bundle into
+    #[serde(default)]
+    pub internal_webhook_configuration_name: Option<String>,
+    /// Warn if a certificate in the CA bundle expires within the days
+    #[serde(default = "default_cert_expiry_warning_days")]
+    pub cert_expiry_warning_days: i64,
 
     /// Container image URL for checker
     pub checker_image: String,
+    /// Namespace for checker workloads of CronPolicies that do not specify one
+    #[serde(default = "default_checker_namespace")]
+    pub default_checker_namespace: String,
+    /// Prometheus Pushgateway URL for checkers to push metrics of each run
+    #[serde(default)]
+    pub checker_pushgateway_url: Option<Url>,
+    /// Kind to plural name map in JSON string, for kinds API discovery cannot resolve
+    #[serde(default, deserialize_with = "deserialize_json_string")]
+    pub plural_overrides: HashMap<String, String>,
+    /// Interval to re-check generated webhook configurations for drift. 0 disables periodic resync
+    #[serde(default = "default_resync_interval_seconds")]
+    pub resync_interval_seconds: u64,
+    /// Listen address of the plain HTTP server for metrics and health endpoints
+    #[serde(default = "default_metrics_listen_addr")]
+    pub metrics_listen_addr: String,
+    /// Log format, which is also passed to checkers
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// File of the bearer token to authenticate requests to `/internal/loglevel`. The endpoint is disabled if not set.
+    #[serde(default)]
+    pub log_level_token_path: Option<PathBuf>,
+
+    /// Elect a leader among controller replicas with a Lease. Disable only if a single controller runs.
+    #[serde(default = "default_enable_leader_election")]
+    pub enable_leader_election: bool,
+    /// Name of the Lease for leader election
+    #[serde(default = "default_leader_election_lease_name")]
+    pub leader_election_lease_name: String,
+    /// Namespace of the Lease for leader election. Defaults to the namespace of the controller.
+    #[serde(default)]
+    pub leader_election_lease_namespace: Option<String>,
+    /// Duration that standby replicas wait before taking over the lease not renewed
+    #[serde(default = "default_leader_election_lease_duration_seconds")]
+    pub leader_election_lease_duration_seconds: u64,
+    /// Duration the leader keeps retrying renewal before giving up the lease. Must be shorter than the lease duration.
+    #[serde(default = "default_leader_election_renew_deadline_seconds")]
+    pub leader_election_renew_deadline_seconds: u64,
 }
 
 impl ControllerConfig {
     pub fn try_from_env() -> Result<Self, envy::Error> {
-        envy::prefixed("CONF_").from_env()
+        envy::prefixed(ENV_PREFIX).from_env()
+    }
+
+    /// Load from the config file given with `--config` or `CONF_FILE`, overridden by `CONF_` environment variables
+    pub fn load() -> Result<Self, ConfigError> {
+        load()
     }
 }
 
 #[derive(Deserialize, Clone, Debug)]
 pub struct WebhookConfig {
     /// Certificate path for HTTPS
-    pub cert_path: PathBuf,
+    #[serde(default)]
+    pub cert_path: Option<PathBuf>,
     /// Certificate key path for HTTPS
-    pub key_path: PathBuf,
+    #[serde(default)]
+    pub key_path: Option<PathBuf>,
+    /// Name of the `kubernetes.io/tls` Secret to read certificate and key from, instead of the paths
+    #[serde(default)]
+    pub tls_secret_name: Option<String>,
+    /// Namespace of the TLS Secret. Defaults to the namespace of the webhook.
+    #[serde(default)]
+    pub tls_secret_namespace: Option<String>,
+    /// Warn if the serving certificate expires within the days
+    #[serde(default = "default_cert_expiry_warning_days")]
+    pub cert_expiry_warning_days: i64,
+    /// CA certificate path to verify client certificates with. Clients without a valid certificate are rejected if set.
+    #[serde(default)]
+    pub client_ca_path: Option<PathBuf>,
 
     #[serde(default = "default_listen_addr")]
     pub listen_addr: String,
+    /// Prefix of the paths of Rules (e.g. `/canary`), matching `clientConfig.pathPrefix` of the Rules pinned to this webhook
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+    /// Address to serve health endpoints over plain HTTP
+    #[serde(default)]
+    pub health_listen_addr: Option<String>,
+    /// File of the bearer token to authenticate requests to `/internal/loglevel`, `/internal/rules`, and `/internal/dryrun`. The endpoints are disabled if not set.
+    #[serde(default)]
+    pub log_level_token_path: Option<PathBuf>,
+    /// Maximum duration to wait for in-flight requests and JS evaluations on shutdown
+    #[serde(default = "default_shutdown_drain_timeout_seconds")]
+    pub shutdown_drain_timeout_seconds: u64,
+
+    /// Maximum size of admission request bodies in bytes
+    #[serde(default = "default_max_request_body_size")]
+    pub max_request_body_size: usize,
+    /// Maximum nesting depth of JSON in admission request bodies
+    #[serde(default = "default_max_json_depth")]
+    pub max_json_depth: usize,
+    /// Number of threads evaluating JS code. Defaults to the number of available CPUs.
+    #[serde(default)]
+    pub js_workers: Option<usize>,
+    /// Maximum JS evaluations waiting for a worker. Requests beyond it fail by the failurePolicy of the rule.
+    #[serde(default = "default_js_queue_size")]
+    pub js_queue_size: usize,
+    /// Expose the object of requests to JS code as `targetObject`, which is `oldObject` for DELETE
+    /// requests and `object` for the others
+    #[serde(default = "default_target_object")]
+    pub target_object: bool,
+
+    /// Reject CronPolicies and Policies that do not specify the namespace explicitly
+    #[serde(default)]
+    pub require_cronpolicy_namespace: bool,
+
+    /// Directory of YAML files to load rules from, instead of the Kubernetes API. The files are
+    /// reloaded when they change.
+    #[serde(default)]
+    pub rules_dir: Option<PathBuf>,
+
+    /// Publish Kubernetes Events on rules when they deny requests
+    #[serde(default)]
+    pub deny_events: bool,
+
+    /// Interval to check all rules compile and their ServiceAccounts exist. The rules are checked only on startup if 0.
+    #[serde(default = "default_preflight_interval_seconds")]
+    pub preflight_interval_seconds: u64,
+    /// Allow requests to rules that do not exist with a warning, instead of failing them by the failurePolicy.
+    /// Requests can reach a deleted rule until its webhook configuration is removed.
+    #[serde(default)]
+    pub allow_missing_rules: bool,
+
+    /// Record denials to the log stream
+    #[serde(default)]
+    pub audit_log: bool,
+    /// Record denials as DenialRecord objects in the namespace
+    #[serde(default)]
+    pub audit_denial_record_namespace: Option<String>,
+    /// Post denials in JSON to the HTTP endpoint
+    #[serde(default)]
+    pub audit_webhook_url: Option<Url>,
+    /// Number of recent denials to keep in the status of each rule. Disabled if 0
+    #[serde(default)]
+    pub audit_recent_denials: usize,
+
+    /// Record requests sampled by the recording of rules as ConfigMaps in the namespace
+    #[serde(default)]
+    pub recording_namespace: Option<String>,
+
+    /// Name of the cluster, exposed to rules as `getCluster().name`
+    #[serde(default)]
+    pub cluster_name: Option<String>,
+    /// Node label to read the cloud provider of the cluster from. Defaults to the scheme of `spec.providerID` of Nodes.
+    #[serde(default)]
+    pub cluster_provider_label: Option<String>,
+    /// Node label to read the region of the cluster from
+    #[serde(default = "default_cluster_region_label")]
+    pub cluster_region_label: String,
 }
 
 impl WebhookConfig {
     pub fn try_from_env() -> Result<Self, envy::Error> {
-        envy::prefixed("CONF_").from_env()
+        envy::prefixed(ENV_PREFIX).from_env()
+    }
+
+    /// Load from the config file given with `--config` or `CONF_FILE`, overridden by `CONF_` environment variables
+    pub fn load() -> Result<Self, ConfigError> {
+        load()
     }
 }
 
@@ -71,10 +484,33 @@ pub struct CheckerConfig {
     /// Notification configurations
     #[serde(deserialize_with = "deserialize_json_string")]
     pub notifications: CronPolicyNotification,
+    /// Annotate objects reported with `addFinding` in JS code
+    #[serde(default)]
+    pub finding_annotation: bool,
+    /// Allow JS code to modify objects with `kubePatch` and `kubeAnnotate`
+    #[serde(default)]
+    pub allow_remediation: bool,
+    /// Namespace the checker runs in
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// Name of the ConfigMap to persist the state between runs. State is not persisted if not specified
+    #[serde(default)]
+    pub state_configmap_name: Option<String>,
+    /// Notify only when the output differs from the previous run
+    #[serde(default)]
+    pub notify_only_on_change: bool,
+    /// Prometheus Pushgateway URL to push metrics of the run. Metrics are not pushed if not specified
+    #[serde(default)]
+    pub pushgateway_url: Option<Url>,
 }
 
 impl CheckerConfig {
     pub fn try_from_env() -> Result<Self, envy::Error> {
-        envy::prefixed("CONF_").from_env()
+        envy::prefixed(ENV_PREFIX).from_env()
+    }
+
+    /// Load from the config file given with `--config` or `CONF_FILE`, overridden by `CONF_` environment variables
+    pub fn load() -> Result<Self, ConfigError> {
+        load()
     }
 }
@@ -0,0 +1,101 @@
+//! Runtime configuration from the singleton CheckpointConfig, applied without restarting pods
+
+use std::{sync::Arc, time::Duration};
+
+use futures_util::{stream, Stream, StreamExt};
+use kube::{runtime::watcher, Api};
+use stopper::Stopper;
+use tokio::sync::watch;
+
+use crate::types::config::{CheckpointConfig, CheckpointConfigSpec, CHECKPOINT_CONFIG_NAME};
+
+/// Interval to retry watching the CheckpointConfig after an error
+const WATCH_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Latest spec of the CheckpointConfig, or the default if it does not exist
+#[derive(Clone)]
+pub struct DynamicConfig {
+    receiver: watch::Receiver<Arc<CheckpointConfigSpec>>,
+}
+
+impl DynamicConfig {
+    /// Watch the CheckpointConfig in background until stopped
+    ///
+    /// The log level of the process follows the CheckpointConfig as well.
+    pub fn spawn(client: kube::Client, stopper: Stopper) -> Self {
+        let (sender, receiver) = watch::channel(Arc::new(CheckpointConfigSpec::default()));
+
+        let api = Api::<CheckpointConfig>::all(client);
+        let config_stream = watcher::watcher(
+            api,
+            watcher::Config::default().fields(&format!("metadata.name={}", CHECKPOINT_CONFIG_NAME)),
+        );
+        tokio::spawn(async move {
+            let mut config_stream = std::pin::pin!(stopper.stop_stream(config_stream));
+            while let Some(res) = config_stream.next().await {
+                let spec = match res {
+                    Ok(watcher::Event::Applied(config)) => config.spec,
+                    Ok(watcher::Event::Deleted(_)) => Default::default(),
+                    Ok(watcher::Event::Restarted(configs)) => configs
+                        .into_iter()
+                        .next()
+                        .map(|config| config.spec)
+                        .unwrap_or_default(),
+                    Err(error) => {
+                        tracing::warn!(%error, "failed to watch CheckpointConfig");
+                        // Wait before the watcher retries, not to flood the API server
+                        tokio::time::sleep(WATCH_RETRY_INTERVAL).await;
+                        continue;
+                    }
+                };
+                update(&sender, spec);
+            }
+        });
+
+        Self { receiver }
+    }
+
+    /// Current spec of the CheckpointConfig
+    pub fn current(&self) -> Arc<CheckpointConfigSpec> {
+        self.receiver.borrow().clone()
+    }
+
+    /// Stream yielding whenever the CheckpointConfig changes
+    pub fn changes(&self) -> impl Stream<Item = ()> + Send + Sync + 'static {
+        stream::unfold(self.receiver.clone(), |mut receiver| async move {
+            receiver.changed().await.ok()?;
+            Some(((), receiver))
+        })
+    }
+}
+
+impl Default for DynamicConfig {
+    /// CheckpointConfig that is never updated, for processes not watching it
+    fn default() -> Self {
+        let (_, receiver) = watch::channel(Arc::new(CheckpointConfigSpec::default()));
+        Self { receiver }
+    }
+}
+
+/// Publish the spec if changed, and apply its log level
+fn update(sender: &watch::Sender<Arc<CheckpointConfigSpec>>, spec: CheckpointConfigSpec) {
+    let mut log_level_changed = false;
+    let changed = sender.send_if_modified(|current| {
+        if **current == spec {
+            return false;
+        }
+        log_level_changed = current.log_level != spec.log_level;
+        *current = Arc::new(spec.clone());
+        true
+    });
+    if !changed {
+        return;
+    }
+
+    tracing::info!(?spec, "CheckpointConfig updated");
+    if log_level_changed {
+        if let Err(error) = crate::config::set_log_level(spec.log_level.as_deref()) {
+            tracing::error!(%error, "failed to apply log level of CheckpointConfig");
+        }
+    }
+}
@@ -0,0 +1,17 @@
+use kube::{
+    runtime::events::{Event, Recorder, Reporter},
+    Resource,
+};
+
+/// Publish Kubernetes Event regarding the object
+///
+/// Events are informational, so failure is logged and ignored.
+pub async fn publish<K>(client: kube::Client, reporter: Reporter, object: &K, event: Event)
+where
+    K: Resource<DynamicType = ()>,
+{
+    let recorder = Recorder::new(client, reporter, object.object_ref(&()));
+    if let Err(error) = recorder.publish(event).await {
+        tracing::warn!(%error, "failed to publish event");
+    }
+}
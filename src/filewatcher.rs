@@ -1,14 +1,30 @@
-use std::{collections::HashSet, future::Future, path::PathBuf};
+//! Watcher of files mounted from ConfigMaps and Secrets
+//!
+//! Kubernetes updates mounted files by swapping the `..data` symlink in the parent directory, which is
+//! reported as various events on the directory rather than modification of the files. So parent directories
+//! are watched, bursts of events are debounced, and the handler is called only when contents actually change.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    future::Future,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::Result;
 use notify::{RecursiveMode, Watcher};
 use stopper::Stopper;
 
+/// Duration to wait for following events before checking the files
+const DEBOUNCE_DURATION: Duration = Duration::from_secs(1);
+
 pub struct FileWatcher<H> {
     handler: H,
     buffer: usize,
     stopper: Stopper,
     paths: HashSet<PathBuf>,
+    dirs: HashSet<PathBuf>,
 }
 
 impl<H> FileWatcher<H> {
@@ -18,47 +34,128 @@ impl<H> FileWatcher<H> {
             buffer,
             stopper,
             paths: Default::default(),
+            dirs: Default::default(),
         }
     }
 
     pub fn watch(&mut self, path: PathBuf) {
         self.paths.insert(path);
     }
+
+    /// Watch files in the directory, including ones added or removed later
+    pub fn watch_dir(&mut self, dir: PathBuf) {
+        self.dirs.insert(dir);
+    }
+}
+
+/// Hash file contents, or `None` if the file cannot be read
+async fn hash_file(path: &Path) -> Option<u64> {
+    let contents = tokio::fs::read(path).await.ok()?;
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// Hash names and contents of files in the directory, or `None` if the directory cannot be read
+async fn hash_dir(dir: &Path) -> Option<u64> {
+    let mut entries = tokio::fs::read_dir(dir).await.ok()?;
+    let mut paths = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        paths.push(entry.path());
+    }
+    paths.sort();
+
+    let mut hasher = DefaultHasher::new();
+    for path in paths {
+        // Subdirectories, e.g. `..data` of mounted ConfigMaps, cannot be read as files
+        if let Ok(contents) = tokio::fs::read(&path).await {
+            path.hash(&mut hasher);
+            contents.hash(&mut hasher);
+        }
+    }
+    Some(hasher.finish())
 }
 
 impl<H, F> FileWatcher<H>
 where
-    H: Fn(notify::Event) -> F + Send + Sync + 'static,
+    H: Fn(Vec<PathBuf>) -> F + Send + Sync + 'static,
     F: Future + Send,
 {
+    /// Spawn the watcher. The handler is called with the paths whose contents changed, including
+    /// directories watched with [`FileWatcher::watch_dir`].
     pub fn spawn(self) -> Result<()> {
         let (sender, mut receiver) = tokio::sync::mpsc::channel(self.buffer);
 
         let mut watcher = notify::recommended_watcher(move |event_res| {
             let _ = sender.blocking_send(event_res);
         })?;
-        for path in self.paths {
-            watcher.watch(&path, RecursiveMode::NonRecursive)?;
+        let dirs = self
+            .paths
+            .iter()
+            .filter_map(|path| path.parent())
+            .map(|dir| {
+                if dir.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    dir
+                }
+            })
+            .chain(self.dirs.iter().map(PathBuf::as_path))
+            .collect::<HashSet<_>>();
+        for dir in dirs {
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
         }
 
         tokio::spawn(async move {
+            // Keep watching while the task runs
+            let _watcher = watcher;
+
+            let mut hashes = HashMap::new();
+            for path in &self.paths {
+                hashes.insert(path.clone(), hash_file(path).await);
+            }
+            for dir in &self.dirs {
+                hashes.insert(dir.clone(), hash_dir(dir).await);
+            }
+
+            // Wait for an event
             while let Some(Some(event_res)) = self.stopper.stop_future(receiver.recv()).await {
-                match event_res {
-                    Ok(event) => {
-                        if event.kind.is_remove() {
-                            for path in &event.paths {
-                                let res = watcher.watch(path, RecursiveMode::NonRecursive);
-                                if let Err(error) = res {
-                                    tracing::error!(%error, path = %path.display(), "Failed to re-watch file");
-                                }
-                            }
+                if let Err(error) = event_res {
+                    tracing::error!(%error, "Failed to watch files");
+                }
+
+                // Wait until events stop arriving
+                loop {
+                    let event_res = self
+                        .stopper
+                        .stop_future(tokio::time::timeout(DEBOUNCE_DURATION, receiver.recv()))
+                        .await;
+                    match event_res {
+                        Some(Ok(Some(Ok(_)))) => {}
+                        Some(Ok(Some(Err(error)))) => {
+                            tracing::error!(%error, "Failed to watch files");
                         }
-                        (self.handler)(event).await;
+                        Some(Err(_)) => break,
+                        Some(Ok(None)) | None => return,
+                    }
+                }
+
+                let mut changed_paths = Vec::new();
+                for path in &self.paths {
+                    let hash = hash_file(path).await;
+                    if hashes.insert(path.clone(), hash) != Some(hash) {
+                        changed_paths.push(path.clone());
                     }
-                    Err(error) => {
-                        tracing::error!(%error, "Failed to watch files");
+                }
+                for dir in &self.dirs {
+                    let hash = hash_dir(dir).await;
+                    if hashes.insert(dir.clone(), hash) != Some(hash) {
+                        changed_paths.push(dir.clone());
                     }
                 }
+                if !changed_paths.is_empty() {
+                    (self.handler)(changed_paths).await;
+                }
             }
         });
 
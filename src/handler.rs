@@ -1,55 +1,312 @@
-mod internal;
+mod body;
+pub mod dry_run;
+pub mod inflight;
+pub(crate) mod internal;
 pub mod js;
+mod limit;
+pub mod preflight;
+pub mod rule_health;
+pub mod rule_source;
 
-use axum::{extract, http::StatusCode, response, routing, Router};
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{body::Bytes, extract, http::StatusCode, response, routing, Router};
+use chrono::Utc;
 use json_patch::Patch;
 use kube::{
     core::{
         admission::{AdmissionRequest, AdmissionResponse, AdmissionReview, SerializePatchError},
         DynamicObject,
     },
-    Api,
+    runtime::events::{Event, EventType, Reporter},
+    Api, Resource, ResourceExt,
 };
+use once_cell::sync::Lazy;
 use serde::Deserialize;
 use tokio::task::JoinError;
+use tracing::Instrument;
 
-use crate::types::rule::{MutatingRule, RuleSpec, ValidatingRule};
+use crate::{
+    config::{dynamic::DynamicConfig, WebhookConfig},
+    handler::{
+        body::BodyError,
+        limit::{LimitError, Permit, RuleLimiters},
+        rule_source::RuleSource,
+    },
+    js::stub::Stubs,
+    metrics::{RuleDecision, RuleDecisionLabels, RULE_METRICS},
+    tls::CertSource,
+    types::{
+        rule::{
+            MutatingRule, NamespacedMutatingRule, NamespacedValidatingRule, RuleSpec,
+            ValidatingRule,
+        },
+        ruleset::RuleSet,
+    },
+};
 
 #[derive(Clone)]
 pub struct AppState {
     kube_client: kube::Client,
+    config: WebhookConfig,
+    dynamic_config: DynamicConfig,
+    rule_source: RuleSource,
+    reporter: Reporter,
+    limiters: Arc<RuleLimiters>,
+}
+
+impl AppState {
+    fn new(
+        kube_client: kube::Client,
+        config: WebhookConfig,
+        dynamic_config: DynamicConfig,
+        rule_source: RuleSource,
+    ) -> Self {
+        Self {
+            kube_client,
+            config,
+            dynamic_config,
+            rule_source,
+            reporter: Reporter {
+                controller: "checkpoint-webhook".to_string(),
+                instance: hostname::get()
+                    .ok()
+                    .map(|hostname| hostname.to_string_lossy().into_owned()),
+            },
+            limiters: Default::default(),
+        }
+    }
+
+    /// Deserialize AdmissionReview from the request body within the configured JSON depth
+    fn parse_review<T>(&self, body: &[u8]) -> Result<AdmissionReview<T>, BodyError>
+    where
+        T: Resource + serde::de::DeserializeOwned,
+    {
+        body::parse_review(body, self.config.max_json_depth).map_err(|error| {
+            tracing::error!(%error, "invalid request body");
+            error
+        })
+    }
+
+    /// Whether the request is in a namespace excluded by the CheckpointConfig
+    ///
+    /// Such requests are allowed until the webhook configurations are updated to exclude the namespace.
+    fn is_excluded(&self, req: &AdmissionRequest<DynamicObject>) -> bool {
+        req.namespace.as_deref().map_or(false, |namespace| {
+            self.dynamic_config
+                .current()
+                .is_excluded_namespace(namespace)
+        })
+    }
+
+    /// Count the request to a rule that does not exist, and allow it with a warning if configured to
+    ///
+    /// `rule` is prefixed by its namespace if namespaced. Fails with `Error::RuleNotFound` otherwise.
+    fn allow_missing_rule(
+        &self,
+        rule_kind: &str,
+        rule: String,
+        req: &AdmissionRequest<DynamicObject>,
+    ) -> Result<AdmissionResponse, Error> {
+        tracing::warn!(%rule_kind, %rule, "rule does not exist");
+        RULE_METRICS.record_missing_rule(rule_kind.to_string(), rule.clone());
+        if !self.config.allow_missing_rules {
+            return Err(Error::RuleNotFound);
+        }
+        let mut resp: AdmissionResponse = req.into();
+        resp.warnings = Some(vec![format!(
+            "{} `{}` does not exist, so the request is allowed",
+            rule_kind, rule
+        )]);
+        Ok(resp)
+    }
+
+    /// Acquire a permit to evaluate JS code of the rule within its limits
+    fn acquire_permit<K>(&self, rule: &K, rule_spec: &RuleSpec) -> Result<Permit, Error>
+    where
+        K: Resource<DynamicType = ()>,
+    {
+        self.limiters
+            .acquire(limiter_key(rule), rule_spec.limits.as_ref())
+            .map_err(|error| {
+                tracing::warn!(%error, "request is rejected");
+                Error::Limited(error)
+            })
+    }
+
+    /// Allow the request with a warning without evaluating JS code, if the circuit breaker of the rule is open
+    fn bypass_open_circuit<K>(
+        &self,
+        rule: &K,
+        req: &AdmissionRequest<DynamicObject>,
+    ) -> Option<AdmissionResponse>
+    where
+        K: Resource<DynamicType = ()>,
+    {
+        let remaining = self.limiters.open_circuit(&limiter_key(rule))?;
+        tracing::warn!(?remaining, "circuit breaker of the rule is open");
+        record_decision(rule, req, RuleDecision::CircuitOpen);
+        let mut resp: AdmissionResponse = req.into();
+        resp.warnings = Some(vec![format!(
+            "{} `{}` is skipped for {} seconds after consecutive errors, so the request is allowed",
+            K::kind(&()),
+            rule.name_any(),
+            remaining.as_secs() + 1,
+        )]);
+        Some(resp)
+    }
+
+    /// Count the result of the evaluation of the rule, alerting if it opens the circuit breaker of the rule
+    ///
+    /// Rejections by the JS worker pool are not counted, as they are not errors of the rule.
+    fn record_evaluation<K>(&self, rule: &K, result: &Result<AdmissionResponse, Error>)
+    where
+        K: Resource<DynamicType = ()> + Clone + Send + Sync + 'static,
+    {
+        let success = match result {
+            Ok(_) => true,
+            Err(Error::JsQueueFull | Error::JsWorkersStopped) => return,
+            Err(_) => false,
+        };
+        if !self.limiters.record_result(&limiter_key(rule), success) {
+            return;
+        }
+        tracing::error!("circuit breaker of the rule is opened after consecutive errors");
+
+        let client = self.kube_client.clone();
+        let reporter = self.reporter.clone();
+        let rule = rule.clone();
+        tokio::spawn(async move {
+            crate::events::publish(
+                client,
+                reporter,
+                &rule,
+                Event {
+                    type_: EventType::Warning,
+                    reason: "CircuitOpened".to_string(),
+                    note: Some(
+                        "JS code failed consecutively, so requests are allowed without evaluating it until the cool-down ends"
+                            .to_string(),
+                    ),
+                    action: "Admit".to_string(),
+                    secondary: None,
+                },
+            )
+            .await;
+        });
+    }
 }
 
 /// Prepare HTTP router
-pub fn create_app(kube_client: kube::Client) -> Router {
-    let app_state = AppState { kube_client };
+pub fn create_app(
+    kube_client: kube::Client,
+    config: WebhookConfig,
+    dynamic_config: DynamicConfig,
+    rule_source: RuleSource,
+) -> Router {
+    let app_state = AppState::new(kube_client, config, dynamic_config, rule_source);
 
-    let internal = internal::create_router();
+    // The server runtime outlives requests, so restricted clients can be reused across them
+    js::helper::enable_kube_client_cache();
+    js::configure_js_worker_pool(app_state.config.js_workers, app_state.config.js_queue_size);
+    js::configure_target_object(app_state.config.target_object);
 
-    Router::new()
+    let max_request_body_size = app_state.config.max_request_body_size;
+
+    let internal = internal::create_router(app_state.config.log_level_token_path.clone());
+
+    let rules = Router::new()
         .route("/validate/:rule_name", routing::post(validate_handler))
         .route("/mutate/:rule_name", routing::post(mutate_handler))
+        .route(
+            "/validate-set/:set_name",
+            routing::post(validate_set_handler),
+        )
+        .route(
+            "/namespaced/validate/:namespace/:rule_name",
+            routing::post(namespaced_validate_handler),
+        )
+        .route(
+            "/namespaced/mutate/:namespace/:rule_name",
+            routing::post(namespaced_mutate_handler),
+        );
+    // Internal webhooks are not prefixed, as they are installed with the chart
+    let path_prefix = app_state.config.path_prefix.as_deref().unwrap_or_default();
+    let path_prefix = path_prefix.trim_matches('/');
+    let rules = if path_prefix.is_empty() {
+        rules
+    } else {
+        Router::new().nest(&format!("/{}", path_prefix), rules)
+    };
+
+    Router::new()
+        .merge(rules)
         .nest("/internal", internal)
+        .route_layer(axum::middleware::from_fn(inflight::track_in_flight))
+        .route("/readyz", routing::get(readyz))
         .with_state(app_state)
         .route("/ping", routing::get(ping))
+        .route("/metrics", routing::get(metrics))
+        .layer(extract::DefaultBodyLimit::max(max_request_body_size))
         .layer(tower_http::trace::TraceLayer::new_for_http())
 }
 
+/// Prepare HTTP router of the health endpoints only
+///
+/// Kubelet probes do not present client certificates, so this is served without TLS when client certificates are required.
+pub fn create_health_app(
+    kube_client: kube::Client,
+    config: WebhookConfig,
+    dynamic_config: DynamicConfig,
+    rule_source: RuleSource,
+) -> Router {
+    Router::new()
+        .route("/readyz", routing::get(readyz))
+        .with_state(AppState::new(
+            kube_client,
+            config,
+            dynamic_config,
+            rule_source,
+        ))
+        .route("/ping", routing::get(ping))
+        .route("/metrics", routing::get(metrics))
+}
+
 /// Errors can be raised within HTTP handler
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Rule is not found")]
     RuleNotFound,
+    #[error("invalid request body: {0}")]
+    InvalidBody(#[source] BodyError),
+    #[error("limit of the rule is reached: {0}")]
+    Limited(#[source] LimitError),
     #[error("Kubernetes error: {0}")]
     Kubernetes(#[source] kube::Error),
     #[error("Kubernetes Kubeconfig error: {0}")]
     KubernetesKubeconfig(#[source] kube::config::KubeconfigError),
     #[error("failed to create Tokio runtime: {0}")]
     CreateTokioRuntime(#[source] std::io::Error),
+    #[error("failed to spawn JavaScript worker thread: {0}")]
+    SpawnJsWorker(#[source] std::io::Error),
+    #[error("queue of JavaScript workers is full")]
+    JsQueueFull,
+    #[error("JavaScript workers are stopped")]
+    JsWorkersStopped,
+    #[error("evaluation of JavaScript code is terminated after {0:?}")]
+    JsTimeout(std::time::Duration),
     #[error("failed to receive from JavaScript thread: {0}")]
     RecvJsThread(#[source] tokio::sync::oneshot::error::RecvError),
     #[error("failed to serialize Patch object: {0}")]
     SerializePatch(#[source] SerializePatchError),
+    #[error("failed to serialize request object: {0}")]
+    SerializeObject(#[source] serde_json::Error),
+    #[error("patch from JavaScript code does not apply to request object: {0}")]
+    ApplyPatch(#[source] json_patch::PatchError),
     #[error("failed to join JavaScript task: {0}")]
     JoinJsTask(#[source] JoinError),
     #[error("failed to prepare JavaScript runtime: {0}")]
@@ -64,6 +321,8 @@ impl response::IntoResponse for Error {
     fn into_response(self) -> response::Response {
         let status_code = match self {
             Self::RuleNotFound => StatusCode::NOT_FOUND,
+            Self::InvalidBody(_) => StatusCode::BAD_REQUEST,
+            Self::Limited(_) | Self::JsQueueFull => StatusCode::TOO_MANY_REQUESTS,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
         (status_code, self.to_string()).into_response()
@@ -74,6 +333,289 @@ async fn ping() -> &'static str {
     "ok"
 }
 
+async fn metrics() -> String {
+    crate::metrics::TLS_METRICS.render()
+        + &inflight::IN_FLIGHT.render()
+        + &crate::metrics::RULE_METRICS.render()
+}
+
+/// Name of the rule to get for readiness check. It does not need to exist.
+const READINESS_PROBE_RULE_NAME: &str = "checkpoint-readiness-probe";
+
+/// How long the result of the readiness check is reused for
+const READINESS_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Result of the last readiness check with when it was checked, shared by concurrent probes
+static READINESS: Lazy<tokio::sync::Mutex<Option<(Instant, Result<(), String>)>>> =
+    Lazy::new(Default::default);
+
+/// Check whether rules can be loaded from Kubernetes, and the serving certificate is valid
+///
+/// Rules loaded from files are always available.
+async fn check_readiness(state: &AppState) -> Result<(), String> {
+    macro_rules! check_rule {
+        ($kind:ty) => {
+            async {
+                Api::<$kind>::all(state.kube_client.clone())
+                    .get_opt(READINESS_PROBE_RULE_NAME)
+                    .await
+                    .map_err(|error| format!("failed to load {}: {}", <$kind>::kind(&()), error))
+            }
+        };
+    }
+    if let RuleSource::Kubernetes = state.rule_source {
+        tokio::try_join!(
+            check_rule!(ValidatingRule),
+            check_rule!(MutatingRule),
+            check_rule!(NamespacedValidatingRule),
+            check_rule!(NamespacedMutatingRule),
+        )?;
+    }
+
+    let validity = async {
+        let cert_source = CertSource::from_config(state.kube_client.clone(), &state.config)?;
+        let (cert, _) = cert_source.read_pem().await?;
+        crate::cert::parse_validity(&cert)
+    }
+    .await
+    .map_err(|error| format!("failed to read serving certificate: {:#}", error))?;
+    if !validity.is_valid_at(Utc::now()) {
+        return Err(format!(
+            "serving certificate is not valid now (valid from {} to {})",
+            validity.not_before, validity.not_after
+        ));
+    }
+
+    Ok(())
+}
+
+/// Whether the webhook can serve admission requests: rules can be loaded from Kubernetes and the
+/// serving certificate is valid
+///
+/// The result is reused for `READINESS_CACHE_TTL` not to hit the API server on every probe, so it
+/// reflects the state up to a minute ago.
+async fn readyz(extract::State(state): extract::State<AppState>) -> (StatusCode, String) {
+    let mut readiness = READINESS.lock().await;
+    let result = match &*readiness {
+        Some((checked_at, result)) if checked_at.elapsed() < READINESS_CACHE_TTL => result.clone(),
+        _ => {
+            let result = check_readiness(&state).await;
+            *readiness = Some((Instant::now(), result.clone()));
+            result
+        }
+    };
+    drop(readiness);
+
+    match result {
+        Ok(()) => (StatusCode::OK, "ok".to_string()),
+        Err(reason) => {
+            tracing::warn!(%reason, "not ready");
+            (StatusCode::SERVICE_UNAVAILABLE, reason)
+        }
+    }
+}
+
+/// Record the denial to the audit sinks if the request is denied
+///
+/// The denial is queued and recorded in background not to delay the admission response.
+fn record_denial<K>(
+    state: &AppState,
+    rule: &K,
+    req: &AdmissionRequest<DynamicObject>,
+    resp: &AdmissionResponse,
+    would_deny: bool,
+) where
+    K: Resource<DynamicType = ()>,
+{
+    if resp.allowed || !crate::audit::is_enabled(&state.config) {
+        return;
+    }
+
+    let record = crate::audit::make_denial_record(rule, req, resp, would_deny);
+    crate::audit::record_denial(&state.kube_client, &state.config, record);
+}
+
+/// Notify the denial to the notification channels of the rule if the request is denied
+///
+/// The notification is queued and sent in background not to delay the admission response.
+fn notify_denial<K>(
+    rule: &K,
+    spec: &RuleSpec,
+    req: &AdmissionRequest<DynamicObject>,
+    resp: &AdmissionResponse,
+) where
+    K: Resource<DynamicType = ()>,
+{
+    let Some(notifications) = &spec.notifications else {
+        return;
+    };
+    if resp.allowed {
+        return;
+    }
+
+    let record = crate::audit::make_denial_record(rule, req, resp, false);
+    crate::audit::queue_denial_notification(notifications.clone(), record);
+}
+
+/// Record the request with the decision of the rule if the recording of the rule samples it
+///
+/// The request is recorded in background not to delay the admission response.
+fn record_request<K>(
+    state: &AppState,
+    rule: &K,
+    spec: &RuleSpec,
+    req: &AdmissionRequest<DynamicObject>,
+    resp: &AdmissionResponse,
+) where
+    K: Resource<DynamicType = ()>,
+{
+    let (Some(namespace), Some(recording)) = (&state.config.recording_namespace, &spec.recording)
+    else {
+        return;
+    };
+    if !crate::recording::samples(recording, req) {
+        return;
+    }
+
+    let recording = match crate::recording::make_recording(rule, req, resp) {
+        Ok(recording) => recording,
+        Err(error) => {
+            tracing::error!(%error, "failed to make recording");
+            return;
+        }
+    };
+    let client = state.kube_client.clone();
+    let namespace = namespace.clone();
+    tokio::spawn(
+        async move {
+            let res = crate::recording::record(client, &namespace, recording).await;
+            if let Err(error) = res {
+                tracing::error!(%error, "failed to record request");
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+/// Key of the limiter of the rule, by its kind, namespace, and name
+fn limiter_key<K>(rule: &K) -> String
+where
+    K: Resource<DynamicType = ()>,
+{
+    match rule.namespace() {
+        Some(namespace) => format!("{}/{}/{}", K::kind(&()), namespace, rule.name_any()),
+        None => format!("{}/{}", K::kind(&()), rule.name_any()),
+    }
+}
+
+/// Count the decision of the rule on the request
+fn record_decision<K>(rule: &K, req: &AdmissionRequest<DynamicObject>, decision: RuleDecision)
+where
+    K: Resource<DynamicType = ()>,
+{
+    let rule_name = match rule.namespace() {
+        Some(namespace) => format!("{}/{}", namespace, rule.name_any()),
+        None => rule.name_any(),
+    };
+    RULE_METRICS.record_decision(RuleDecisionLabels {
+        rule_kind: K::kind(&()).into_owned(),
+        rule: rule_name,
+        operation: serde_json::to_value(&req.operation)
+            .ok()
+            .and_then(|operation| operation.as_str().map(str::to_string))
+            .unwrap_or_default(),
+        kind: req.kind.kind.clone(),
+        decision,
+    });
+}
+
+/// Whether the rollout of the rule enforces the denial of the request
+///
+/// Requests on the same object are decided the same way, not to deny a workload intermittently.
+fn rollout_enforces<K>(rule: &K, spec: &RuleSpec, req: &AdmissionRequest<DynamicObject>) -> bool
+where
+    K: Resource<DynamicType = ()>,
+{
+    let Some(rollout) = &spec.rollout else {
+        return true;
+    };
+    // Objects with generated names do not have names yet
+    let object_name = if req.name.is_empty() {
+        &req.uid
+    } else {
+        &req.name
+    };
+    let key = format!(
+        "{}/{}/{}/{}",
+        rule.namespace().unwrap_or_default(),
+        rule.name_any(),
+        req.namespace.as_deref().unwrap_or_default(),
+        object_name,
+    );
+    rollout.enforces(&key)
+}
+
+/// Allow the denied request with a warning, as the rollout of the rule does not enforce the denial
+fn allow_would_deny(mut resp: AdmissionResponse) -> AdmissionResponse {
+    tracing::info!(
+        reason = %resp.result.message,
+        "request would be denied, but allowed by rollout",
+    );
+    resp.warnings
+        .get_or_insert_with(Vec::new)
+        .push(format!("request would be denied: {}", resp.result.message));
+    resp.allowed = true;
+    resp.result = Default::default();
+    resp
+}
+
+/// Publish Warning Event on the rule if the request is denied
+///
+/// The Event is published in background not to delay the admission response.
+fn publish_deny_event<K>(
+    state: &AppState,
+    rule: K,
+    req: &AdmissionRequest<DynamicObject>,
+    resp: &AdmissionResponse,
+) where
+    K: Resource<DynamicType = ()> + Send + Sync + 'static,
+{
+    if !state.config.deny_events || resp.allowed {
+        return;
+    }
+
+    let object = match &req.namespace {
+        Some(namespace) => format!("{}/{}", namespace, req.name),
+        None => req.name.clone(),
+    };
+    let note = format!(
+        "{:?} of {} {} by {} is denied: {}",
+        req.operation,
+        req.kind.kind,
+        object,
+        req.user_info.username.as_deref().unwrap_or("unknown"),
+        resp.result.message,
+    );
+
+    let client = state.kube_client.clone();
+    let reporter = state.reporter.clone();
+    tokio::spawn(async move {
+        crate::events::publish(
+            client,
+            reporter,
+            &rule,
+            Event {
+                type_: EventType::Warning,
+                reason: "Denied".to_string(),
+                note: Some(note),
+                action: "Admit".to_string(),
+                secondary: None,
+            },
+        )
+        .await;
+    });
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct JsOutput {
@@ -83,57 +625,261 @@ struct JsOutput {
     patch: Option<Patch>,
 }
 
-/// Validate HTTP API handler
-async fn validate_handler(
-    extract::State(state): extract::State<AppState>,
-    extract::Path(rule_name): extract::Path<String>,
-    extract::Json(req): extract::Json<AdmissionReview<DynamicObject>>,
-) -> Result<response::Json<AdmissionReview<DynamicObject>>, Error> {
+/// Span of an admission request, attached to every log line emitted during handling
+fn admission_span(
+    rule_name: &str,
+    rule_namespace: Option<&str>,
+    req: &AdmissionRequest<DynamicObject>,
+) -> tracing::Span {
+    tracing::info_span!(
+        "admission",
+        uid = %req.uid,
+        rule = %rule_name,
+        rule_namespace,
+        operation = ?req.operation,
+        kind = %req.kind.kind,
+        name = %req.name,
+        namespace = req.namespace.as_deref(),
+    )
+}
+
+/// Parse the AdmissionReview in the body, and handle its request in the admission span of the rule
+///
+/// Requests in namespaces excluded by the CheckpointConfig are allowed without being handled.
+async fn handle_review<F, Fut>(
+    state: &AppState,
+    rule_name: &str,
+    rule_namespace: Option<&str>,
+    body: &[u8],
+    handle: F,
+) -> Result<response::Json<AdmissionReview<DynamicObject>>, Error>
+where
+    F: FnOnce(AdmissionRequest<DynamicObject>) -> Fut,
+    Fut: Future<Output = Result<AdmissionResponse, Error>>,
+{
+    let req = state.parse_review(body).map_err(Error::InvalidBody)?;
+    let types = req.types.clone();
     // Convert AdmissionReview into AdmissionRequest
     // and reject if fails
     let req: AdmissionRequest<_> = match req.try_into() {
         Ok(req) => req,
         Err(error) => {
             tracing::error!(%error, "invalid request");
-            return Ok(response::Json(
-                AdmissionResponse::invalid(error.to_string()).into_review(),
-            ));
+            return Ok(response::Json(body::invalid_review(&types, error)));
         }
     };
 
-    // Prepare Kubernetes API
-    let vr_api = Api::<ValidatingRule>::all(state.kube_client.clone());
+    let span = admission_span(rule_name, rule_namespace, &req);
+    async {
+        // Allow requests in namespaces excluded by the CheckpointConfig
+        if state.is_excluded(&req) {
+            tracing::debug!("request is in an excluded namespace");
+            let resp: AdmissionResponse = (&req).into();
+            return Ok(response::Json(resp.into_review()));
+        }
+
+        let resp = handle(req).await?;
+        Ok(response::Json(resp.into_review()))
+    }
+    .instrument(span)
+    .await
+}
 
-    // Get matching ValidatingRule
-    let vr = vr_api
-        .get_opt(&rule_name)
-        .await
-        .map_err(Error::Kubernetes)?
-        .ok_or(Error::RuleNotFound)?;
+/// Evaluate the rule on the request, and count, record, and report its decision
+///
+/// `rule_name` is prefixed by its namespace if namespaced, to report the rule if it does not exist.
+/// Requests to missing rules or rules with open circuit breakers, and denials the rollout of the rule does not
+/// enforce, are allowed with warnings.
+async fn handle_rule<K>(
+    state: &AppState,
+    rule_name: String,
+    rule: Result<K, Error>,
+    spec_of: impl FnOnce(&K) -> RuleSpec,
+    mutating: bool,
+    req: &AdmissionRequest<DynamicObject>,
+) -> Result<AdmissionResponse, Error>
+where
+    K: Resource<DynamicType = ()> + Clone + Send + Sync + 'static,
+{
+    let rule = match rule {
+        Err(Error::RuleNotFound) => {
+            js::forget_code(&K::kind(&()), &rule_name);
+            return state.allow_missing_rule(&K::kind(&()), rule_name, req);
+        }
+        rule => rule?,
+    };
 
-    let resp = validate(&vr.spec.0, &req, String::new()).await;
+    let spec = state
+        .dynamic_config
+        .current()
+        .apply_to_rule(&spec_of(&rule));
+    if let Some(resp) = state.bypass_open_circuit(&rule, req) {
+        return Ok(resp);
+    }
+    let _permit = state.acquire_permit(&rule, &spec)?;
+
+    let script_key = Some(js::ScriptKey::of(&rule, &spec.code));
+    let resp = if mutating {
+        mutate(&spec, req, script_key, None).await
+    } else {
+        validate(&spec, req, script_key, None).await
+    };
+    state.record_evaluation(&rule, &resp);
 
     // Log if error happens
     if let Err(error) = &resp {
-        tracing::error!(%req.name, ?req.namespace, %rule_name, %error, "failed to validate");
+        tracing::error!(%error, rule = %rule.name_any(), "failed to evaluate rule");
+        record_decision(&rule, req, RuleDecision::Error);
+        rule_health::record_evaluation_error(&rule, error);
+    }
+
+    let resp = resp?;
+    record_request(state, &rule, &spec, req, &resp);
+    if !resp.allowed && !rollout_enforces(&rule, &spec, req) {
+        record_decision(&rule, req, RuleDecision::WouldDeny);
+        record_denial(state, &rule, req, &resp, true);
+        return Ok(allow_would_deny(resp));
     }
+    let decision = if resp.allowed {
+        RuleDecision::Allow
+    } else {
+        RuleDecision::Deny
+    };
+    record_decision(&rule, req, decision);
+    record_denial(state, &rule, req, &resp, false);
+    notify_denial(&rule, &spec, req, &resp);
+    publish_deny_event(state, rule, req, &resp);
 
-    Ok(response::Json(resp?.into_review()))
+    Ok(resp)
+}
+
+/// Validate HTTP API handler
+async fn validate_handler(
+    extract::State(state): extract::State<AppState>,
+    extract::Path(rule_name): extract::Path<String>,
+    body: Bytes,
+) -> Result<response::Json<AdmissionReview<DynamicObject>>, Error> {
+    let (state, rule_name) = (&state, &rule_name);
+    handle_review(state, rule_name, None, &body, |req| async move {
+        let vr = state
+            .rule_source
+            .get::<ValidatingRule>(&state.kube_client, rule_name)
+            .await;
+        let spec_of = |vr: &ValidatingRule| vr.spec.0.clone();
+        handle_rule(state, rule_name.clone(), vr, spec_of, false, &req).await
+    })
+    .await
+}
+
+/// Validate HTTP API handler for NamespacedValidatingRule
+async fn namespaced_validate_handler(
+    extract::State(state): extract::State<AppState>,
+    extract::Path((namespace, rule_name)): extract::Path<(String, String)>,
+    body: Bytes,
+) -> Result<response::Json<AdmissionReview<DynamicObject>>, Error> {
+    let (state, namespace, rule_name) = (&state, &namespace, &rule_name);
+    handle_review(state, rule_name, Some(namespace), &body, |req| async move {
+        let nvr = state
+            .rule_source
+            .get_namespaced::<NamespacedValidatingRule>(&state.kube_client, namespace, rule_name)
+            .await;
+        let spec_of = |nvr: &NamespacedValidatingRule| nvr.spec.0.pin_namespace(namespace);
+        let rule = format!("{}/{}", namespace, rule_name);
+        handle_rule(state, rule, nvr, spec_of, false, &req).await
+    })
+    .await
+}
+
+/// Validate HTTP API handler for RuleSet
+///
+/// Rules of the RuleSet are evaluated in order, and the first denial is returned.
+async fn validate_set_handler(
+    extract::State(state): extract::State<AppState>,
+    extract::Path(set_name): extract::Path<String>,
+    body: Bytes,
+) -> Result<response::Json<AdmissionReview<DynamicObject>>, Error> {
+    let (state, set_name) = (&state, &set_name);
+    handle_review(state, set_name, None, &body, |req| async move {
+        // Get matching RuleSet
+        let rule_set: RuleSet = match state.rule_source.get(&state.kube_client, set_name).await {
+            Err(Error::RuleNotFound) => {
+                return state.allow_missing_rule(&RuleSet::kind(&()), set_name.clone(), &req);
+            }
+            rule_set => rule_set?,
+        };
+
+        let mut warnings = Vec::new();
+        for rule_name in &rule_set.spec.rules {
+            let vr = state
+                .rule_source
+                .get::<ValidatingRule>(&state.kube_client, rule_name)
+                .await;
+            let spec_of = |vr: &ValidatingRule| vr.spec.0.clone();
+            let resp = handle_rule(state, rule_name.clone(), vr, spec_of, false, &req).await?;
+            if !resp.allowed {
+                return Ok(resp);
+            }
+            // Keep the warnings of the rules skipped or not enforcing their denials
+            warnings.extend(resp.warnings.unwrap_or_default());
+        }
+
+        let mut resp: AdmissionResponse = (&req).into();
+        if !warnings.is_empty() {
+            resp.warnings = Some(warnings);
+        }
+        Ok(resp)
+    })
+    .await
+}
+
+/// First field required by the rule which the request does not have, if any
+fn missing_required_field(
+    rule_spec: &RuleSpec,
+    req: &AdmissionRequest<DynamicObject>,
+) -> Result<Option<String>, Error> {
+    let Some(requires) = rule_spec.requires.as_ref().filter(|r| !r.is_empty()) else {
+        return Ok(None);
+    };
+    let value = serde_json::to_value(req).map_err(Error::SerializeObject)?;
+    for path in requires {
+        let field =
+            path.split('.')
+                .try_fold(&value, |value, segment| match segment.parse::<usize>() {
+                    Ok(index) if value.is_array() => value.get(index),
+                    _ => value.get(segment),
+                });
+        if field.map_or(true, serde_json::Value::is_null) {
+            return Ok(Some(path.clone()));
+        }
+    }
+    Ok(None)
 }
 
 /// Actual validating function
 pub async fn validate(
     rule_spec: &RuleSpec,
     req: &AdmissionRequest<DynamicObject>,
-    js_context: String, // required for CLI
+    script_key: Option<js::ScriptKey>,
+    stubs: Option<Arc<Stubs>>, // required for CLI
 ) -> Result<AdmissionResponse, Error> {
+    // Deny requests without the fields the code requires
+    if let Some(field) = missing_required_field(rule_spec, req)? {
+        let resp: AdmissionResponse = req.into();
+        return Ok(resp.deny(format!(
+            "request does not have `{}` required by the rule",
+            field
+        )));
+    }
+
     // Evaluate JS code
     let output = js::eval_js_code(
         rule_spec.service_account.clone(),
+        rule_spec.kube_client_mode.unwrap_or_default(),
         rule_spec.timeout_seconds,
         rule_spec.code.clone(),
+        script_key,
         req.clone(),
-        js_context,
+        stubs,
     )
     .await?;
 
@@ -150,56 +896,68 @@ pub async fn validate(
     Ok(resp)
 }
 
+/// Mutate HTTP API handler
 async fn mutate_handler(
     extract::State(state): extract::State<AppState>,
     extract::Path(rule_name): extract::Path<String>,
-    extract::Json(req): extract::Json<AdmissionReview<DynamicObject>>,
+    body: Bytes,
 ) -> Result<response::Json<AdmissionReview<DynamicObject>>, Error> {
-    // Convert AdmissionReview into AdmissionRequest
-    // and reject if fails
-    let req: AdmissionRequest<_> = match req.try_into() {
-        Ok(req) => req,
-        Err(error) => {
-            tracing::error!(%error, "invalid request");
-            return Ok(response::Json(
-                AdmissionResponse::invalid(error.to_string()).into_review(),
-            ));
-        }
-    };
-
-    // Prepare Kubernetes API
-    let mr_api = Api::<MutatingRule>::all(state.kube_client.clone());
-
-    // Get matching MutatingRule
-    let mr = mr_api
-        .get_opt(&rule_name)
-        .await
-        .map_err(Error::Kubernetes)?
-        .ok_or(Error::RuleNotFound)?;
-
-    let resp = mutate(&mr.spec.0, &req, String::new()).await;
-
-    // Log if error happens
-    if let Err(error) = &resp {
-        tracing::error!(%req.name, ?req.namespace, %rule_name, %error, "failed to mutate");
-    }
+    let (state, rule_name) = (&state, &rule_name);
+    handle_review(state, rule_name, None, &body, |req| async move {
+        let mr = state
+            .rule_source
+            .get::<MutatingRule>(&state.kube_client, rule_name)
+            .await;
+        let spec_of = |mr: &MutatingRule| mr.spec.0.clone();
+        handle_rule(state, rule_name.clone(), mr, spec_of, true, &req).await
+    })
+    .await
+}
 
-    Ok(response::Json(resp?.into_review()))
+/// Mutate HTTP API handler for NamespacedMutatingRule
+async fn namespaced_mutate_handler(
+    extract::State(state): extract::State<AppState>,
+    extract::Path((namespace, rule_name)): extract::Path<(String, String)>,
+    body: Bytes,
+) -> Result<response::Json<AdmissionReview<DynamicObject>>, Error> {
+    let (state, namespace, rule_name) = (&state, &namespace, &rule_name);
+    handle_review(state, rule_name, Some(namespace), &body, |req| async move {
+        let nmr = state
+            .rule_source
+            .get_namespaced::<NamespacedMutatingRule>(&state.kube_client, namespace, rule_name)
+            .await;
+        let spec_of = |nmr: &NamespacedMutatingRule| nmr.spec.0.pin_namespace(namespace);
+        let rule = format!("{}/{}", namespace, rule_name);
+        handle_rule(state, rule, nmr, spec_of, true, &req).await
+    })
+    .await
 }
 
 /// Actual mutating function
 pub async fn mutate(
     rule_spec: &RuleSpec,
     req: &AdmissionRequest<DynamicObject>,
-    js_context: String, // required for CLI
+    script_key: Option<js::ScriptKey>,
+    stubs: Option<Arc<Stubs>>, // required for CLI
 ) -> Result<AdmissionResponse, Error> {
+    // Deny requests without the fields the code requires
+    if let Some(field) = missing_required_field(rule_spec, req)? {
+        let resp: AdmissionResponse = req.into();
+        return Ok(resp.deny(format!(
+            "request does not have `{}` required by the rule",
+            field
+        )));
+    }
+
     // Evaluate JS code
     let output = js::eval_js_code(
         rule_spec.service_account.clone(),
+        rule_spec.kube_client_mode.unwrap_or_default(),
         rule_spec.timeout_seconds,
         rule_spec.code.clone(),
+        script_key,
         req.clone(),
-        js_context,
+        stubs,
     )
     .await?;
 
@@ -215,6 +973,12 @@ pub async fn mutate(
 
     // Set patch if exists
     let resp = if let Some(patch) = output.patch {
+        // Make sure the patch applies to the object before the API server does
+        if let Some(object) = &req.object {
+            let mut value = serde_json::to_value(object).map_err(Error::SerializeObject)?;
+            json_patch::patch(&mut value, &patch).map_err(Error::ApplyPatch)?;
+        }
+
         resp.with_patch(Patch(patch.0))
             .map_err(Error::SerializePatch)?
     } else {
@@ -223,3 +987,63 @@ pub async fn mutate(
 
     Ok(resp)
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn configmap_request() -> AdmissionRequest<DynamicObject> {
+        let review: AdmissionReview<DynamicObject> = serde_json::from_value(json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "00000000-0000-0000-0000-000000000000",
+                "kind": {"group": "", "version": "v1", "kind": "ConfigMap"},
+                "resource": {"group": "", "version": "v1", "resource": "configmaps"},
+                "name": "config",
+                "namespace": "default",
+                "operation": "CREATE",
+                "userInfo": {"username": "kubernetes-admin"},
+                "object": {
+                    "apiVersion": "v1",
+                    "kind": "ConfigMap",
+                    "metadata": {"name": "config", "namespace": "default"},
+                },
+                "dryRun": false,
+            },
+        }))
+        .unwrap();
+        review.try_into().unwrap()
+    }
+
+    fn mutating_rule_spec(code: &str) -> RuleSpec {
+        serde_json::from_value(json!({ "code": code })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_mutate_with_patch_not_applying() {
+        let spec = mutating_rule_spec(
+            r#"mutate([{op: "replace", path: "/metadata/labels/app", value: "checkpoint"}]);"#,
+        );
+        let result = mutate(&spec, &configmap_request(), None, None).await;
+        assert!(
+            matches!(result, Err(Error::ApplyPatch(_))),
+            "unexpected result: {:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mutate_with_patch_applying() {
+        let spec = mutating_rule_spec(
+            r#"mutate([{op: "add", path: "/metadata/labels", value: {app: "checkpoint"}}]);"#,
+        );
+        let resp = mutate(&spec, &configmap_request(), None, None)
+            .await
+            .unwrap();
+        assert!(resp.allowed);
+        assert!(resp.patch.is_some());
+    }
+}
@@ -0,0 +1,156 @@
+//! Guarded deserialization of admission request bodies
+//!
+//! Both `admission.k8s.io/v1` and `admission.k8s.io/v1beta1` reviews are accepted, and responses are made in the
+//! version of the request, as the API server requires.
+
+use kube::{
+    core::{
+        admission::{
+            AdmissionResponse, AdmissionReview, META_API_VERSION_V1, META_API_VERSION_V1BETA1,
+        },
+        DynamicObject, TypeMeta,
+    },
+    Resource,
+};
+use serde::de::DeserializeOwned;
+
+/// Reason the request body is rejected
+#[derive(thiserror::Error, Debug)]
+pub enum BodyError {
+    #[error("JSON nesting is deeper than {0}")]
+    TooDeep(usize),
+    #[error("failed to deserialize AdmissionReview: {0}")]
+    Deserialize(#[source] serde_json::Error),
+    #[error("unsupported AdmissionReview version `{0}`")]
+    UnsupportedVersion(String),
+}
+
+/// Whether JSON in `body` nests arrays and objects deeper than `max_depth`
+///
+/// This only tracks brackets outside of strings, so that it runs in constant memory before the body is deserialized.
+/// Malformed JSON is left to the deserializer to report.
+pub fn exceeds_depth(body: &[u8], max_depth: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for &byte in body {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if byte == b'\\' {
+                escaped = true;
+            } else if byte == b'"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > max_depth {
+                    return true;
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Deserialize AdmissionReview from the request body, rejecting JSON nested deeper than `max_depth`
+pub fn parse_review<T>(body: &[u8], max_depth: usize) -> Result<AdmissionReview<T>, BodyError>
+where
+    T: Resource + DeserializeOwned,
+{
+    if exceeds_depth(body, max_depth) {
+        return Err(BodyError::TooDeep(max_depth));
+    }
+    let review: AdmissionReview<T> =
+        serde_json::from_slice(body).map_err(BodyError::Deserialize)?;
+    if !is_supported_version(&review.types.api_version) {
+        return Err(BodyError::UnsupportedVersion(review.types.api_version));
+    }
+    Ok(review)
+}
+
+fn is_supported_version(api_version: &str) -> bool {
+    api_version == META_API_VERSION_V1 || api_version == META_API_VERSION_V1BETA1
+}
+
+/// Respond to a review without a request, in the version of the review
+pub fn invalid_review<T: ToString>(types: &TypeMeta, reason: T) -> AdmissionReview<DynamicObject> {
+    let mut resp = AdmissionResponse::invalid(reason);
+    if is_supported_version(&types.api_version) {
+        resp.types = types.clone();
+    }
+    resp.into_review()
+}
+
+#[cfg(test)]
+mod tests {
+    use kube::core::admission::AdmissionRequest;
+    use serde_json::json;
+
+    use super::*;
+
+    fn review_body(api_version: &str) -> Vec<u8> {
+        serde_json::to_vec(&json!({
+            "apiVersion": api_version,
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                "kind": {"group": "", "version": "v1", "kind": "ConfigMap"},
+                "resource": {"group": "", "version": "v1", "resource": "configmaps"},
+                "name": "config",
+                "namespace": "default",
+                "operation": "CREATE",
+                "userInfo": {"username": "admin"},
+                "object": {
+                    "apiVersion": "v1",
+                    "kind": "ConfigMap",
+                    "metadata": {"name": "config", "namespace": "default"},
+                },
+                "dryRun": false,
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_response_in_version_of_request() {
+        for api_version in [META_API_VERSION_V1, META_API_VERSION_V1BETA1] {
+            let review: AdmissionReview<DynamicObject> =
+                parse_review(&review_body(api_version), 64).unwrap();
+            let req: AdmissionRequest<DynamicObject> = review.try_into().unwrap();
+
+            let resp = serde_json::to_value(AdmissionResponse::from(&req).into_review()).unwrap();
+            assert_eq!(resp["apiVersion"], api_version);
+            assert_eq!(resp["kind"], "AdmissionReview");
+            assert_eq!(
+                resp["response"]["uid"],
+                "705ab4f5-6393-11e8-b7cc-42010a800002"
+            );
+            assert_eq!(resp["response"]["allowed"], true);
+        }
+    }
+
+    #[test]
+    fn test_invalid_review_in_version_of_request() {
+        let types = TypeMeta {
+            api_version: META_API_VERSION_V1BETA1.to_string(),
+            kind: "AdmissionReview".to_string(),
+        };
+        let resp = serde_json::to_value(invalid_review(&types, "invalid")).unwrap();
+        assert_eq!(resp["apiVersion"], META_API_VERSION_V1BETA1);
+        assert_eq!(resp["kind"], "AdmissionReview");
+        assert_eq!(resp["response"]["allowed"], false);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let error = parse_review::<DynamicObject>(&review_body("admission.k8s.io/v2"), 64);
+        assert!(matches!(error, Err(BodyError::UnsupportedVersion(_))));
+    }
+}
@@ -0,0 +1,157 @@
+//! Endpoint evaluating a rule on an AdmissionReview without side effects, for debugging
+//!
+//! `POST /internal/dryrun/:rule_name` is authenticated with the same bearer token as `/internal/loglevel`.
+//! The rule is a ValidatingRule unless `kind` query parameter says otherwise, and namespaced rules need
+//! `namespace` query parameter. The decision is the one the rule would make, including its rollout,
+//! but no limits apply and no metrics, denial records, notifications, or events are produced.
+
+use axum::{body::Bytes, extract, response, Json};
+use http::{HeaderMap, StatusCode};
+use kube::{
+    core::{
+        admission::{AdmissionRequest, AdmissionResponse, AdmissionReview},
+        DynamicObject,
+    },
+    Resource,
+};
+use serde::Deserialize;
+use tracing::Instrument;
+
+use crate::types::rule::{
+    MutatingRule, NamespacedMutatingRule, NamespacedValidatingRule, RuleSpec, ValidatingRule,
+};
+
+use super::{
+    admission_span, allow_would_deny, body, mutate, rollout_enforces, validate, AppState, Error,
+};
+
+/// Kind of the rule to evaluate
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+pub enum DryRunKind {
+    #[default]
+    ValidatingRule,
+    MutatingRule,
+    NamespacedValidatingRule,
+    NamespacedMutatingRule,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DryRunQuery {
+    #[serde(default)]
+    kind: DryRunKind,
+    namespace: Option<String>,
+}
+
+/// Evaluate the code of the rule without the code cache, and apply its rollout
+///
+/// The concurrency and rate limits of the rule are not applied, so dry runs do not take from the
+/// capacity of the live rule.
+async fn evaluate<K>(
+    rule: &K,
+    spec: RuleSpec,
+    mutating: bool,
+    req: &AdmissionRequest<DynamicObject>,
+) -> Result<AdmissionResponse, Error>
+where
+    K: Resource<DynamicType = ()>,
+{
+    let resp = if mutating {
+        mutate(&spec, req, None, None).await?
+    } else {
+        validate(&spec, req, None, None).await?
+    };
+    if !resp.allowed && !rollout_enforces(rule, &spec, req) {
+        return Ok(allow_would_deny(resp));
+    }
+    Ok(resp)
+}
+
+async fn dry_run(
+    state: &AppState,
+    kind: DryRunKind,
+    namespace: &str,
+    rule_name: &str,
+    req: &AdmissionRequest<DynamicObject>,
+) -> Result<AdmissionResponse, Error> {
+    let source = &state.rule_source;
+    let kube_client = &state.kube_client;
+    let config = state.dynamic_config.current();
+    match kind {
+        DryRunKind::ValidatingRule => {
+            let vr: ValidatingRule = source.get(kube_client, rule_name).await?;
+            evaluate(&vr, config.apply_to_rule(&vr.spec.0), false, req).await
+        }
+        DryRunKind::MutatingRule => {
+            let mr: MutatingRule = source.get(kube_client, rule_name).await?;
+            evaluate(&mr, config.apply_to_rule(&mr.spec.0), true, req).await
+        }
+        DryRunKind::NamespacedValidatingRule => {
+            let nvr: NamespacedValidatingRule = source
+                .get_namespaced(kube_client, namespace, rule_name)
+                .await?;
+            let spec = config.apply_to_rule(&nvr.spec.0.pin_namespace(namespace));
+            evaluate(&nvr, spec, false, req).await
+        }
+        DryRunKind::NamespacedMutatingRule => {
+            let nmr: NamespacedMutatingRule = source
+                .get_namespaced(kube_client, namespace, rule_name)
+                .await?;
+            let spec = config.apply_to_rule(&nmr.spec.0.pin_namespace(namespace));
+            evaluate(&nmr, spec, true, req).await
+        }
+    }
+}
+
+pub(super) async fn dry_run_handler(
+    extract::State(state): extract::State<AppState>,
+    extract::Path(rule_name): extract::Path<String>,
+    extract::Query(query): extract::Query<DryRunQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<AdmissionReview<DynamicObject>>, response::Response> {
+    crate::log_level::authorize(&state.config.log_level_token_path, &headers).await?;
+
+    let namespaced = matches!(
+        query.kind,
+        DryRunKind::NamespacedValidatingRule | DryRunKind::NamespacedMutatingRule
+    );
+    let namespace = match (namespaced, query.namespace) {
+        (true, Some(namespace)) => namespace,
+        (true, None) => {
+            return Err(response::IntoResponse::into_response((
+                StatusCode::BAD_REQUEST,
+                "namespace must be specified for namespaced rules",
+            )))
+        }
+        (false, _) => String::new(),
+    };
+
+    let into_response = response::IntoResponse::into_response;
+    let req = state
+        .parse_review(&body)
+        .map_err(|error| into_response(Error::InvalidBody(error)))?;
+    let types = req.types.clone();
+    let req: AdmissionRequest<_> = match req.try_into() {
+        Ok(req) => req,
+        Err(error) => {
+            return Ok(Json(body::invalid_review(&types, error)));
+        }
+    };
+
+    let span = admission_span(&rule_name, namespaced.then_some(namespace.as_str()), &req);
+    async {
+        // Allow requests in namespaces excluded by the CheckpointConfig, as the rule would
+        if state.is_excluded(&req) {
+            let resp: AdmissionResponse = (&req).into();
+            return Ok(Json(resp.into_review()));
+        }
+
+        tracing::debug!(kind = ?query.kind, "dry-running rule");
+        let resp = dry_run(&state, query.kind, &namespace, &rule_name, &req)
+            .await
+            .map_err(into_response)?;
+        Ok(Json(resp.into_review()))
+    }
+    .instrument(span)
+    .await
+}
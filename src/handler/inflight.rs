@@ -0,0 +1,134 @@
+//! Tracking of in-flight admission requests and JS evaluations, to drain them on shutdown
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use axum::{http::Request, middleware::Next, response::Response};
+use tokio::sync::Notify;
+
+/// Counts of in-flight works
+pub struct InFlight {
+    requests: AtomicUsize,
+    js_tasks: AtomicUsize,
+    /// Notified whenever a work finishes
+    finished: Notify,
+}
+
+pub static IN_FLIGHT: InFlight = InFlight::new();
+
+/// Decrements the count when dropped
+pub struct InFlightGuard {
+    count: &'static AtomicUsize,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+        IN_FLIGHT.finished.notify_waiters();
+    }
+}
+
+/// Result of draining in-flight works
+#[derive(Debug)]
+pub struct DrainSummary {
+    pub requests: usize,
+    pub js_tasks: usize,
+    pub remaining_requests: usize,
+    pub remaining_js_tasks: usize,
+    pub elapsed: Duration,
+}
+
+impl InFlight {
+    const fn new() -> Self {
+        Self {
+            requests: AtomicUsize::new(0),
+            js_tasks: AtomicUsize::new(0),
+            finished: Notify::const_new(),
+        }
+    }
+
+    pub fn track_request(&'static self) -> InFlightGuard {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard {
+            count: &self.requests,
+        }
+    }
+
+    pub fn track_js_task(&'static self) -> InFlightGuard {
+        self.js_tasks.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard {
+            count: &self.js_tasks,
+        }
+    }
+
+    fn counts(&self) -> (usize, usize) {
+        (
+            self.requests.load(Ordering::SeqCst),
+            self.js_tasks.load(Ordering::SeqCst),
+        )
+    }
+
+    /// Wait until all in-flight works finish, or the timeout elapses
+    pub async fn drain(&self, timeout: Duration) -> DrainSummary {
+        let start = Instant::now();
+        let (requests, js_tasks) = self.counts();
+
+        let _ = tokio::time::timeout(timeout, async {
+            loop {
+                // Register before checking the counts not to miss notifications in between
+                let finished = self.finished.notified();
+                if self.counts() == (0, 0) {
+                    break;
+                }
+                finished.await;
+            }
+        })
+        .await;
+
+        let (remaining_requests, remaining_js_tasks) = self.counts();
+        DrainSummary {
+            requests,
+            js_tasks,
+            remaining_requests,
+            remaining_js_tasks,
+            elapsed: start.elapsed(),
+        }
+    }
+
+    /// Render the metrics in Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let (requests, js_tasks) = self.counts();
+        let mut metrics = String::new();
+
+        metrics.push_str(
+            "# HELP checkpoint_webhook_in_flight_requests Number of admission requests being handled.\n",
+        );
+        metrics.push_str("# TYPE checkpoint_webhook_in_flight_requests gauge\n");
+        let _ = writeln!(
+            metrics,
+            "checkpoint_webhook_in_flight_requests {}",
+            requests
+        );
+
+        metrics.push_str(
+            "# HELP checkpoint_webhook_in_flight_js_tasks Number of JS evaluations running.\n",
+        );
+        metrics.push_str("# TYPE checkpoint_webhook_in_flight_js_tasks gauge\n");
+        let _ = writeln!(
+            metrics,
+            "checkpoint_webhook_in_flight_js_tasks {}",
+            js_tasks
+        );
+
+        metrics
+    }
+}
+
+/// Middleware counting in-flight requests
+pub async fn track_in_flight<B>(req: Request<B>, next: Next<B>) -> Response {
+    let _guard = IN_FLIGHT.track_request();
+    next.run(req).await
+}
@@ -1,21 +1,42 @@
-use axum::{extract, response, routing, Json, Router};
+use std::path::PathBuf;
+
+use axum::{body::Bytes, extract, response, routing, Json, Router};
 use http::StatusCode;
-use itertools::join;
+use itertools::{join, Itertools};
+use k8s_openapi::api::admissionregistration::v1::RuleWithOperations;
 use kube::core::{
     admission::{AdmissionRequest, AdmissionResponse, AdmissionReview, SerializePatchError},
-    DynamicObject,
+    conversion::{ConversionRequest, ConversionResponse, ConversionReview},
+    DynamicObject, Status,
 };
 
-use crate::{types::policy::CronPolicy, util::find_group_version_pairs_by_kind};
+use crate::{
+    lint::{lint_document, Severity},
+    types::{
+        config::CheckpointConfigSpec,
+        policy::{CronPolicy, CronPolicyResource, CronPolicyValueFrom, Policy},
+        rule::{v2::convert_rule, RuleSpec},
+    },
+    util::{find_group_version_pairs_by_kind, resource_exists},
+};
 
-use super::AppState;
+use super::{
+    body::{self, BodyError},
+    AppState,
+};
 
 #[derive(thiserror::Error, Debug)]
 enum Error {
+    #[error("invalid request body: {0}")]
+    InvalidBody(#[source] BodyError),
+    #[error("failed to deserialize ConversionReview: {0}")]
+    InvalidConversionReview(#[source] serde_json::Error),
     #[error("object field in admission request does not exists")]
     ObjectNotExists,
     #[error("Kubernetes error: {0}")]
     Kubernetes(#[source] kube::Error),
+    #[error("failed to deserialize spec: {0}")]
+    InvalidSpec(#[source] serde_json::Error),
     #[error("failed to serialize to JSON value: {0}")]
     SerializeToJson(#[source] serde_json::Error),
     #[error("failed to serialize JSON patch: {0}")]
@@ -25,31 +46,100 @@ enum Error {
 impl response::IntoResponse for Error {
     fn into_response(self) -> response::Response {
         let status_code = match self {
-            Self::ObjectNotExists => StatusCode::BAD_REQUEST,
+            Self::InvalidBody(_) | Self::InvalidConversionReview(_) | Self::ObjectNotExists => {
+                StatusCode::BAD_REQUEST
+            }
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
         (status_code, self.to_string()).into_response()
     }
 }
 
-pub fn create_router() -> Router<AppState> {
-    Router::new().route(
-        "/mutate/cronpolicies",
-        routing::post(post_mutate_cronpolicy),
-    )
+pub fn create_router(log_level_token_path: Option<PathBuf>) -> Router<AppState> {
+    Router::new()
+        .route(
+            "/mutate/cronpolicies",
+            routing::post(post_mutate_cronpolicy),
+        )
+        .route("/mutate/policies", routing::post(post_mutate_policy))
+        .route("/mutate/rules", routing::post(post_mutate_rule))
+        .route("/validate/rules", routing::post(post_validate_rule))
+        .route("/convert/rules", routing::post(post_convert_rules))
+        .route("/rules", routing::get(super::rule_health::get_rules))
+        .route(
+            "/dryrun/:rule_name",
+            routing::post(super::dry_run::dry_run_handler),
+        )
+        .merge(crate::log_level::create_router(log_level_token_path))
 }
 
-async fn mutate_cronpolicy(
-    req: AdmissionRequest<CronPolicy>,
-    kube_client: kube::Client,
-) -> Result<AdmissionResponse, Error> {
-    let resp: AdmissionResponse = (&req).into();
+/// Check exactly one of inline code and code reference is specified, and the references are valid
+///
+/// Returns the reason to deny the request if invalid.
+pub(crate) fn check_value_from(
+    code: &str,
+    code_from: Option<&CronPolicyValueFrom>,
+    notifications_from: Option<&CronPolicyValueFrom>,
+) -> Option<String> {
+    match (code.is_empty(), code_from) {
+        (true, None) => return Some("either code or codeFrom must be specified".to_string()),
+        (false, Some(_)) => {
+            return Some("code and codeFrom cannot be specified together".to_string())
+        }
+        _ => {}
+    }
+    for (field, value_from) in [
+        ("codeFrom", code_from),
+        ("notificationsFrom", notifications_from),
+    ] {
+        if let Some(value_from) = value_from {
+            if value_from.config_map_key_ref.is_some() == value_from.secret_key_ref.is_some() {
+                return Some(format!(
+                    "exactly one of configMapKeyRef and secretKeyRef must be specified in {}",
+                    field
+                ));
+            }
+        }
+    }
+    None
+}
 
-    let mut cp = req.object.ok_or(Error::ObjectNotExists)?;
-    // Original cronpolicy spec to be diffed after
-    let orig_cp = cp.clone();
+/// Fill in group and version of the resources, checking they exist
+///
+/// Returns the reason to deny the request if the resources are invalid.
+async fn resolve_group_versions(
+    resources: &mut [CronPolicyResource],
+    kube_client: kube::Client,
+) -> Result<Option<String>, Error> {
+    for resource in resources.iter_mut() {
+        let sources = [
+            !resource.kind.is_empty(),
+            resource.prometheus_query.is_some(),
+            resource.http.is_some(),
+        ];
+        if sources.into_iter().filter(|specified| *specified).count() != 1 {
+            return Ok(Some(
+                "exactly one of kind, prometheusQuery, and http must be specified".to_string(),
+            ));
+        }
+        if !resource.is_kubernetes() {
+            if resource.cluster_ref.is_some() {
+                return Ok(Some(
+                    "clusterRef can only be specified with kind".to_string(),
+                ));
+            }
+            continue;
+        }
+        if resource.cluster_ref.is_some() {
+            // Other clusters cannot be discovered
+            if resource.group.is_none() || resource.version.is_none() {
+                return Ok(Some(
+                    "group and version must be specified with clusterRef".to_string(),
+                ));
+            }
+            continue;
+        }
 
-    for resource in cp.spec.resources.iter_mut() {
         if resource.group.is_some() && resource.version.is_some() {
             // The user specified group and version
             // Check the GVK actually exists
@@ -61,7 +151,7 @@ async fn mutate_cronpolicy(
                 .any(|(g, v)| Some(g) == resource.group && Some(v) == resource.version)
             {
                 // It does not exists!
-                return Ok(resp.deny(format!(
+                return Ok(Some(format!(
                     "specified group, version, and kind (`{}`, `{}`, `{}`) do not exists",
                     resource.group.as_ref().unwrap(),
                     resource.version.as_ref().unwrap(),
@@ -69,7 +159,7 @@ async fn mutate_cronpolicy(
                 )));
             }
         } else if resource.group.is_none() && resource.version.is_some() {
-            return Ok(resp.deny("only specifying version is not allowed"));
+            return Ok(Some("only specifying version is not allowed".to_string()));
         } else {
             // The user did not specify group or version
             // checkpoint have to find it
@@ -77,7 +167,7 @@ async fn mutate_cronpolicy(
                 .await
                 .map_err(Error::Kubernetes)?;
             if gvs.is_empty() {
-                return Ok(resp.deny(format!(
+                return Ok(Some(format!(
                     "specified kind ({}) does not have matching group/versions",
                     resource.kind
                 )));
@@ -87,13 +177,13 @@ async fn mutate_cronpolicy(
                     resource.group = Some(gv.0);
                     resource.version = Some(gv.1);
                 } else {
-                    return Ok(resp.deny(format!(
+                    return Ok(Some(format!(
                         "specified group and kind ({}, {}) does not have matching group/versions",
                         group, resource.kind
                     )));
                 }
             } else if gvs.len() > 1 {
-                return Ok(resp.deny(format!(
+                return Ok(Some(format!(
                     "specified kind ({}) has multiple matching group/versions: {}",
                     resource.kind,
                     join(gvs.into_iter().map(|(g, v)| format!("`{}/{}`", g, v)), ",")
@@ -107,6 +197,198 @@ async fn mutate_cronpolicy(
         }
     }
 
+    Ok(None)
+}
+
+/// Check the group, version, and resource of object rules exist
+///
+/// Returns the reason to deny the request if the object rules are invalid. Wildcards are not checked.
+async fn check_object_rules(
+    spec: &RuleSpec,
+    kube_client: kube::Client,
+) -> Result<Option<String>, Error> {
+    let webhooks = spec.webhooks.iter().flatten();
+    let object_rules = spec
+        .object_rules
+        .iter()
+        .map(|object_rules| ("spec.objectRules".to_string(), object_rules))
+        .chain(webhooks.enumerate().filter_map(|(i, webhook)| {
+            let path = format!("spec.webhooks[{}].objectRules", i);
+            webhook
+                .object_rules
+                .as_ref()
+                .map(|object_rules| (path, object_rules))
+        }));
+    for (path, object_rules) in object_rules {
+        for (i, object_rule) in object_rules.iter().enumerate() {
+            let groups = object_rule.api_groups.iter().flatten();
+            let versions = object_rule.api_versions.iter().flatten();
+            let resources = object_rule.resources.iter().flatten();
+            for ((group, version), resource) in groups
+                .cartesian_product(versions)
+                .cartesian_product(resources)
+            {
+                if group.contains('*') || version.contains('*') || resource.contains('*') {
+                    continue;
+                }
+                if !resource_exists(group, version, resource, kube_client.clone())
+                    .await
+                    .map_err(Error::Kubernetes)?
+                {
+                    return Ok(Some(format!(
+                        "{}[{}]: resource `{}` of group and version `{}/{}` does not exist",
+                        path, i, resource, group, version
+                    )));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+async fn validate_rule(
+    req: AdmissionRequest<DynamicObject>,
+    kube_client: kube::Client,
+) -> Result<AdmissionResponse, Error> {
+    let mut resp: AdmissionResponse = (&req).into();
+
+    let object = req.object.ok_or(Error::ObjectNotExists)?;
+    let document = serde_json::to_value(&object).map_err(Error::SerializeToJson)?;
+
+    // Compile the code and check the spec as `checkpoint lint` does
+    let (errors, warnings): (Vec<_>, Vec<_>) = lint_document(&document)
+        .into_iter()
+        .partition(|finding| finding.severity == Severity::Error);
+    if !errors.is_empty() {
+        return Ok(resp.deny(join(
+            errors
+                .into_iter()
+                .map(|finding| format!("{}: {}", finding.path, finding.message)),
+            "; ",
+        )));
+    }
+
+    let spec: RuleSpec =
+        serde_json::from_value(document["spec"].clone()).map_err(Error::InvalidSpec)?;
+    if let Some(reason) = check_object_rules(&spec, kube_client).await? {
+        return Ok(resp.deny(reason));
+    }
+
+    if !warnings.is_empty() {
+        resp.warnings = Some(
+            warnings
+                .into_iter()
+                .map(|finding| format!("{}: {}", finding.path, finding.message))
+                .collect(),
+        );
+    }
+    Ok(resp)
+}
+
+/// Timeout of webhooks when neither the Rule nor the CheckpointConfig specifies one
+const DEFAULT_TIMEOUT_SECONDS: i32 = 10;
+
+/// Sort and deduplicate the lists of object rules, collapsing lists with wildcards into a wildcard
+fn normalize_object_rules(object_rules: &mut [RuleWithOperations]) {
+    let normalize = |values: &mut Option<Vec<String>>| {
+        if let Some(values) = values {
+            if values.iter().any(|value| value == "*") {
+                *values = vec!["*".to_string()];
+            } else {
+                values.sort();
+                values.dedup();
+            }
+        }
+    };
+    for object_rule in object_rules {
+        normalize(&mut object_rule.api_groups);
+        normalize(&mut object_rule.api_versions);
+        normalize(&mut object_rule.resources);
+        normalize(&mut object_rule.operations);
+        if object_rule.scope.is_none() {
+            object_rule.scope = Some("*".to_string());
+        }
+    }
+}
+
+/// Fill in the defaults the Rule does not specify, so that its webhook configuration is explicit
+fn default_rule_spec(spec: &mut RuleSpec, config: &CheckpointConfigSpec) {
+    if spec.failure_policy.is_none() {
+        spec.failure_policy = Some(config.default_failure_policy.clone().unwrap_or_default());
+    }
+    if spec.timeout_seconds.is_none() {
+        spec.timeout_seconds = Some(
+            config
+                .default_timeout_seconds
+                .unwrap_or(DEFAULT_TIMEOUT_SECONDS),
+        );
+    }
+    if spec.side_effects.is_none() {
+        spec.side_effects = Some(spec.side_effect_class());
+    }
+
+    if let Some(object_rules) = &mut spec.object_rules {
+        normalize_object_rules(object_rules);
+    }
+    for webhook in spec.webhooks.iter_mut().flatten() {
+        if let Some(object_rules) = &mut webhook.object_rules {
+            normalize_object_rules(object_rules);
+        }
+    }
+}
+
+fn mutate_rule(
+    req: AdmissionRequest<DynamicObject>,
+    config: &CheckpointConfigSpec,
+) -> Result<AdmissionResponse, Error> {
+    let resp: AdmissionResponse = (&req).into();
+
+    let object = req.object.ok_or(Error::ObjectNotExists)?;
+    let mut document = serde_json::to_value(&object).map_err(Error::SerializeToJson)?;
+
+    let mut spec: RuleSpec = match serde_json::from_value(document["spec"].clone()) {
+        Ok(spec) => spec,
+        Err(error) => return Ok(resp.deny(format!("invalid spec: {}", error))),
+    };
+    // Original document with the spec serialized the same way, to be diffed after
+    document["spec"] = serde_json::to_value(&spec).map_err(Error::SerializeToJson)?;
+    let orig_document = document.clone();
+
+    default_rule_spec(&mut spec, config);
+    document["spec"] = serde_json::to_value(&spec).map_err(Error::SerializeToJson)?;
+
+    let patch = json_patch::diff(&orig_document, &document);
+
+    resp.with_patch(patch).map_err(Error::SerializePatch)
+}
+
+async fn mutate_cronpolicy(
+    req: AdmissionRequest<CronPolicy>,
+    kube_client: kube::Client,
+    require_namespace: bool,
+) -> Result<AdmissionResponse, Error> {
+    let resp: AdmissionResponse = (&req).into();
+
+    let mut cp = req.object.ok_or(Error::ObjectNotExists)?;
+
+    if require_namespace && cp.spec.namespace.is_none() {
+        return Ok(resp.deny("namespace field must be specified explicitly"));
+    }
+    // Original cronpolicy spec to be diffed after
+    let orig_cp = cp.clone();
+
+    if let Some(reason) = check_value_from(
+        &cp.spec.code,
+        cp.spec.code_from.as_ref(),
+        cp.spec.notifications_from.as_ref(),
+    ) {
+        return Ok(resp.deny(reason));
+    }
+
+    if let Some(reason) = resolve_group_versions(&mut cp.spec.resources, kube_client).await? {
+        return Ok(resp.deny(reason));
+    }
+
     let patch = json_patch::diff(
         &serde_json::to_value(&orig_cp).map_err(Error::SerializeToJson)?,
         &serde_json::to_value(&cp).map_err(Error::SerializeToJson)?,
@@ -115,19 +397,54 @@ async fn mutate_cronpolicy(
     resp.with_patch(patch).map_err(Error::SerializePatch)
 }
 
+async fn mutate_policy(
+    req: AdmissionRequest<Policy>,
+    kube_client: kube::Client,
+    require_namespace: bool,
+) -> Result<AdmissionResponse, Error> {
+    let resp: AdmissionResponse = (&req).into();
+
+    let mut policy = req.object.ok_or(Error::ObjectNotExists)?;
+
+    if require_namespace && policy.spec.namespace.is_none() {
+        return Ok(resp.deny("namespace field must be specified explicitly"));
+    }
+    // Original policy spec to be diffed after
+    let orig_policy = policy.clone();
+
+    if let Some(reason) = check_value_from(
+        &policy.spec.code,
+        policy.spec.code_from.as_ref(),
+        policy.spec.notifications_from.as_ref(),
+    ) {
+        return Ok(resp.deny(reason));
+    }
+
+    if let Some(reason) = resolve_group_versions(&mut policy.spec.resources, kube_client).await? {
+        return Ok(resp.deny(reason));
+    }
+
+    let patch = json_patch::diff(
+        &serde_json::to_value(&orig_policy).map_err(Error::SerializeToJson)?,
+        &serde_json::to_value(&policy).map_err(Error::SerializeToJson)?,
+    );
+
+    resp.with_patch(patch).map_err(Error::SerializePatch)
+}
+
 async fn post_mutate_cronpolicy(
     extract::State(state): extract::State<AppState>,
-    Json(req): Json<AdmissionReview<CronPolicy>>,
+    body: Bytes,
 ) -> Result<Json<AdmissionReview<DynamicObject>>, Error> {
+    let req: AdmissionReview<CronPolicy> = state.parse_review(&body).map_err(Error::InvalidBody)?;
+    let types = req.types.clone();
     // Convert AdmissionReview into AdmissionRequest
     // and reject if fails
     let req: AdmissionRequest<_> = match req.try_into() {
         Ok(req) => req,
         Err(error) => {
             tracing::error!(%error, "invalid request");
-            return Ok(Json(
-                AdmissionResponse::invalid(error.to_string()).into_review(),
-            ));
+            return Ok(Json(body::invalid_review(&types, error)));
         }
     };
 
@@ -136,7 +453,13 @@ async fn post_mutate_cronpolicy(
     let req_namespace = req.namespace.clone();
 
     // Mutate cronpolicy and check error
-    match mutate_cronpolicy(req, state.kube_client).await {
+    match mutate_cronpolicy(
+        req,
+        state.kube_client,
+        state.config.require_cronpolicy_namespace,
+    )
+    .await
+    {
         Ok(resp) => Ok(Json(resp.into_review())),
         Err(error) => {
             // Log error
@@ -145,3 +468,146 @@ async fn post_mutate_cronpolicy(
         }
     }
 }
+
+async fn post_mutate_policy(
+    extract::State(state): extract::State<AppState>,
+    body: Bytes,
+) -> Result<Json<AdmissionReview<DynamicObject>>, Error> {
+    let req: AdmissionReview<Policy> = state.parse_review(&body).map_err(Error::InvalidBody)?;
+    let types = req.types.clone();
+    // Convert AdmissionReview into AdmissionRequest
+    // and reject if fails
+    let req: AdmissionRequest<_> = match req.try_into() {
+        Ok(req) => req,
+        Err(error) => {
+            tracing::error!(%error, "invalid request");
+            return Ok(Json(body::invalid_review(&types, error)));
+        }
+    };
+
+    // Clones name and namespace needed when error occurs
+    let req_name = req.name.clone();
+    let req_namespace = req.namespace.clone();
+
+    // Mutate policy and check error
+    match mutate_policy(
+        req,
+        state.kube_client,
+        state.config.require_cronpolicy_namespace,
+    )
+    .await
+    {
+        Ok(resp) => Ok(Json(resp.into_review())),
+        Err(error) => {
+            // Log error
+            tracing::error!(%req_name, ?req_namespace, %error, "failed to mutate policy");
+            Err(error)
+        }
+    }
+}
+
+async fn post_mutate_rule(
+    extract::State(state): extract::State<AppState>,
+    body: Bytes,
+) -> Result<Json<AdmissionReview<DynamicObject>>, Error> {
+    let req: AdmissionReview<DynamicObject> =
+        state.parse_review(&body).map_err(Error::InvalidBody)?;
+    let types = req.types.clone();
+    // Convert AdmissionReview into AdmissionRequest
+    // and reject if fails
+    let req: AdmissionRequest<_> = match req.try_into() {
+        Ok(req) => req,
+        Err(error) => {
+            tracing::error!(%error, "invalid request");
+            return Ok(Json(body::invalid_review(&types, error)));
+        }
+    };
+
+    // Clones name and namespace needed when error occurs
+    let req_name = req.name.clone();
+    let req_namespace = req.namespace.clone();
+
+    // Mutate rule and check error
+    match mutate_rule(req, &state.dynamic_config.current()) {
+        Ok(resp) => Ok(Json(resp.into_review())),
+        Err(error) => {
+            // Log error
+            tracing::error!(%req_name, ?req_namespace, %error, "failed to mutate rule");
+            Err(error)
+        }
+    }
+}
+
+async fn post_validate_rule(
+    extract::State(state): extract::State<AppState>,
+    body: Bytes,
+) -> Result<Json<AdmissionReview<DynamicObject>>, Error> {
+    let req: AdmissionReview<DynamicObject> =
+        state.parse_review(&body).map_err(Error::InvalidBody)?;
+    let types = req.types.clone();
+    // Convert AdmissionReview into AdmissionRequest
+    // and reject if fails
+    let req: AdmissionRequest<_> = match req.try_into() {
+        Ok(req) => req,
+        Err(error) => {
+            tracing::error!(%error, "invalid request");
+            return Ok(Json(body::invalid_review(&types, error)));
+        }
+    };
+
+    // Clones name and namespace needed when error occurs
+    let req_name = req.name.clone();
+    let req_namespace = req.namespace.clone();
+
+    // Validate rule and check error
+    match validate_rule(req, state.kube_client).await {
+        Ok(resp) => Ok(Json(resp.into_review())),
+        Err(error) => {
+            // Log error
+            tracing::error!(%req_name, ?req_namespace, %error, "failed to validate rule");
+            Err(error)
+        }
+    }
+}
+
+async fn post_convert_rules(
+    extract::State(state): extract::State<AppState>,
+    body: Bytes,
+) -> Result<Json<ConversionReview>, Error> {
+    let max_json_depth = state.config.max_json_depth;
+    if body::exceeds_depth(&body, max_json_depth) {
+        return Err(Error::InvalidBody(BodyError::TooDeep(max_json_depth)));
+    }
+    let review: ConversionReview =
+        serde_json::from_slice(&body).map_err(Error::InvalidConversionReview)?;
+    let mut req = match ConversionRequest::from_review(review) {
+        Ok(req) => req,
+        Err(error) => {
+            tracing::error!(?error, "invalid conversion request");
+            return Ok(Json(
+                ConversionResponse::invalid(Status::failure(
+                    "conversion request is missing",
+                    "InvalidRequest",
+                ))
+                .into_review(),
+            ));
+        }
+    };
+
+    let desired_api_version = req.desired_api_version.clone();
+    let converted = std::mem::take(&mut req.objects)
+        .into_iter()
+        .map(|object| convert_rule(object, &desired_api_version))
+        .collect::<Result<Vec<_>, _>>();
+    let resp = ConversionResponse::for_request(req);
+    match converted {
+        Ok(objects) => Ok(Json(resp.success(objects).into_review())),
+        Err(error) => {
+            tracing::error!(%desired_api_version, %error, "failed to convert rules");
+            Ok(Json(
+                resp.failure(Status::failure(&error.to_string(), "ConversionFailed"))
+                    .into_review(),
+            ))
+        }
+    }
+}
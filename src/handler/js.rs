@@ -1,53 +1,212 @@
 pub mod helper;
+pub mod retry;
 
-use kube::core::{admission::AdmissionRequest, DynamicObject};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures_util::future::{FutureExt, LocalBoxFuture};
+use kube::{
+    core::{
+        admission::{AdmissionRequest, Operation},
+        DynamicObject,
+    },
+    Resource,
+};
+use once_cell::sync::{Lazy, OnceCell};
+use tokio::task::JoinHandle;
+use tracing::Instrument;
 
 use crate::{
-    js::{eval, set_context},
-    types::rule::ServiceAccountInfo,
+    cluster::ClusterInfo,
+    js::{
+        eval, set_context,
+        stub::{self, Stubs},
+    },
+    types::rule::{KubeClientMode, ServiceAccountInfo},
 };
 
 use super::{Error, JsOutput};
 
+/// Cluster metadata exposed to JS code as `cluster`
+static CLUSTER_INFO: OnceCell<ClusterInfo> = OnceCell::new();
+
+/// Set the cluster metadata exposed to JS code
+///
+/// Has no effect once it is set.
+pub fn configure_cluster_info(cluster_info: ClusterInfo) {
+    let _ = CLUSTER_INFO.set(cluster_info);
+}
+
+/// Whether `targetObject` is exposed to JS code
+static TARGET_OBJECT: AtomicBool = AtomicBool::new(true);
+
+/// Set whether `targetObject` is exposed to JS code
+pub fn configure_target_object(enabled: bool) {
+    TARGET_OBJECT.store(enabled, Ordering::Relaxed);
+}
+
+/// Object the request is about, which is `oldObject` for DELETE requests as `object` is null
+fn target_object(admission_req: &AdmissionRequest<DynamicObject>) -> Option<&DynamicObject> {
+    if admission_req.operation == Operation::Delete {
+        admission_req.old_object.as_ref()
+    } else {
+        admission_req.object.as_ref()
+    }
+}
+
+/// Code caches of rules, with the generation of the rule each was produced from
+static CODE_CACHE: Lazy<Mutex<HashMap<String, (String, Arc<[u8]>)>>> = Lazy::new(Default::default);
+
+/// Rule and its generation to cache the compiled code of the rule by
+#[derive(Clone, Debug)]
+pub struct ScriptKey {
+    rule: String,
+    generation: String,
+}
+
+impl ScriptKey {
+    /// Key by the generation of the rule and the hash of the code
+    ///
+    /// Writes to the status of the rule change its resourceVersion but not its generation, so they do not
+    /// invalidate the cache. Rules not from the Kubernetes API have no generation, and are keyed by the code.
+    pub fn of<K: Resource<DynamicType = ()>>(rule: &K, code: &str) -> Self {
+        let mut hasher = DefaultHasher::new();
+        code.hash(&mut hasher);
+        let generation = format!(
+            "{}/{:x}",
+            rule.meta().generation.unwrap_or_default(),
+            hasher.finish()
+        );
+        Self {
+            rule: rule_key(rule),
+            generation,
+        }
+    }
+}
+
+/// Kind, namespace, and name of the rule joined with slashes
+pub(super) fn rule_key<K: Resource<DynamicType = ()>>(rule: &K) -> String {
+    format!(
+        "{}/{}/{}",
+        K::kind(&()),
+        rule.meta().namespace.as_deref().unwrap_or_default(),
+        rule.meta().name.as_deref().unwrap_or_default(),
+    )
+}
+
+/// Get the code cache of the current generation of the rule
+fn cached_code(script_key: &ScriptKey) -> Option<Arc<[u8]>> {
+    let cache = CODE_CACHE.lock().unwrap();
+    let (generation, code_cache) = cache.get(&script_key.rule)?;
+    (*generation == script_key.generation).then(|| code_cache.clone())
+}
+
+/// Whether the code of the current generation of the rule has been compiled and cached
+pub(super) fn is_code_cached(script_key: &ScriptKey) -> bool {
+    cached_code(script_key).is_some()
+}
+
+/// Drop the code cache of the rule, e.g. when the rule is deleted
+///
+/// `rule_name` is prefixed by its namespace if namespaced, as the handlers get it.
+pub(super) fn forget_code(kind: &str, rule_name: &str) {
+    let key = match rule_name.split_once('/') {
+        Some((namespace, name)) => format!("{}/{}/{}", kind, namespace, name),
+        None => format!("{}//{}", kind, rule_name),
+    };
+    CODE_CACHE.lock().unwrap().remove(&key);
+}
+
+/// Drop the code caches of the rules other than the ones with the keys
+pub(super) fn retain_code<F: Fn(&str) -> bool>(keep: F) {
+    CODE_CACHE.lock().unwrap().retain(|key, _| keep(key));
+}
+
+/// Cache the code cache of the rule, replacing the one of the previous generation
+fn cache_code(script_key: ScriptKey, code_cache: Arc<[u8]>) {
+    tracing::debug!(
+        rule = %script_key.rule,
+        generation = %script_key.generation,
+        "caching compiled code"
+    );
+    CODE_CACHE
+        .lock()
+        .unwrap()
+        .insert(script_key.rule, (script_key.generation, code_cache));
+}
+
 /// Evaluate JavaScript code and return its output
+#[allow(clippy::too_many_arguments)]
 async fn eval_js_code_inner<T>(
     serviceaccount_info: Option<ServiceAccountInfo>,
+    kube_client_mode: KubeClientMode,
     timeout_seconds: Option<i32>,
     code: String,
+    script_key: Option<ScriptKey>,
     admission_req: AdmissionRequest<DynamicObject>,
-    js_context: String,
+    stubs: Option<Arc<Stubs>>,
+    watchdog: &mut Watchdog,
 ) -> Result<T, Error>
 where
     for<'a> T: serde::Deserialize<'a> + Send + 'static,
 {
-    // Prepare JS runtime
-    let mut js_runtime = crate::js::prepare_js_runtime(vec![helper::checkpoint_rule::init_ops()])
-        .map_err(Error::PrepareJsRuntime)?;
+    // Prepare JS runtime, with the identities for kubeGet and kubeList out of reach of JS code
+    let stubbed = stubs.is_some();
+    let impersonate = (kube_client_mode == KubeClientMode::ImpersonateRequester)
+        .then(|| admission_req.user_info.clone());
+    let mut extensions = vec![helper::checkpoint_rule::init_ops(
+        serviceaccount_info,
+        impersonate,
+    )];
+    if let Some(stubs) = stubs {
+        extensions.push(stub::checkpoint_stub::init_ops(stubs));
+    }
+    let mut js_runtime =
+        crate::js::prepare_js_runtime(extensions).map_err(Error::PrepareJsRuntime)?;
+    watchdog.arm(&mut js_runtime);
 
     // Set context for kubeGet and kubeList
-    set_context(&mut js_runtime, "serviceAccountInfo", &serviceaccount_info)
-        .map_err(Error::PrepareJsRuntime)?;
     set_context(&mut js_runtime, "timeoutSeconds", &timeout_seconds)
         .map_err(Error::PrepareJsRuntime)?;
     set_context(&mut js_runtime, "admissionRequest", &admission_req)
         .map_err(Error::PrepareJsRuntime)?;
+    set_context(&mut js_runtime, "cluster", &CLUSTER_INFO.get())
+        .map_err(Error::PrepareJsRuntime)?;
+    if TARGET_OBJECT.load(Ordering::Relaxed) {
+        set_context(
+            &mut js_runtime,
+            "targetObject",
+            &target_object(&admission_req),
+        )
+        .map_err(Error::PrepareJsRuntime)?;
+    }
 
     // Prepare context
     js_runtime
         .execute_script_static("<checkpoint>", include_str!("runtime.js"))
         .map_err(Error::PrepareJsRuntime)?;
 
-    // Add additional context
-    if !js_context.is_empty() {
-        js_runtime
-            .execute_script("<checkpoint>", js_context.into())
-            .map_err(Error::PrepareJsRuntime)?;
+    // Replace kubeGet, kubeList and Date with the stubs
+    if stubbed {
+        stub::replace_with_stubs(&mut js_runtime).map_err(Error::PrepareJsRuntime)?;
     }
 
-    // Run code
-    js_runtime
-        .execute_script("<checkpoint>", code.into())
-        .map_err(Error::EvalJs)?;
+    // Run code, with the code cache of the rule if any
+    let code_cache = script_key.as_ref().and_then(cached_code);
+    let produced =
+        crate::js::execute_script_with_code_cache(&mut js_runtime, &code, code_cache.as_deref())
+            .map_err(Error::EvalJs)?;
+    if let (Some(script_key), Some(produced)) = (script_key, produced) {
+        cache_code(script_key, produced.into());
+    }
     js_runtime
         .run_event_loop(false)
         .await
@@ -57,40 +216,200 @@ where
     eval::<T>(&mut js_runtime, "__checkpoint_get_context(\"output\")").map_err(Error::EvalJs)
 }
 
-/// wrapper function to spawn JS runtime into local thread
+/// Evaluate JavaScript code on the worker pool
+///
+/// Fails fast with `Error::JsQueueFull` if the queue of the pool is full, so the API server applies
+/// the failurePolicy of the rule instead of waiting for the timeout.
 pub(super) async fn eval_js_code(
     serviceaccount_info: Option<ServiceAccountInfo>,
+    kube_client_mode: KubeClientMode,
     timeout_seconds: Option<i32>,
     code: String,
+    script_key: Option<ScriptKey>,
     admission_req: AdmissionRequest<DynamicObject>,
-    js_context: String,
+    stubs: Option<Arc<Stubs>>,
 ) -> Result<JsOutput, Error> {
     let (sender, receiver) = tokio::sync::oneshot::channel();
+    let timeout = js_timeout(timeout_seconds);
+    let watchdog_runtime = tokio::runtime::Handle::current();
+
+    // Run JS runtime in the span of the request
+    let span = tracing::Span::current();
+    // Only evaluations of admission requests have a script key, so dry runs are not counted
+    let in_flight_guard = script_key
+        .is_some()
+        .then(|| super::inflight::IN_FLIGHT.track_js_task());
+    let job: JsJob = Box::new(move || {
+        async move {
+            let _in_flight_guard = in_flight_guard;
+
+            // Skip requests nobody waits for anymore, e.g. timed out while queued
+            if sender.is_closed() {
+                tracing::debug!("skipping JavaScript evaluation as the request is gone");
+                return;
+            }
 
-    // Build tokio runtime
-    let rt = tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()
-        .map_err(Error::CreateTokioRuntime)?;
-
-    // Spawn JS runtime into dedicated thread
-    std::thread::spawn(move || {
-        let local = tokio::task::LocalSet::new();
-
-        local.spawn_local(async move {
-            let res = eval_js_code_inner(
-                serviceaccount_info,
-                timeout_seconds,
-                code,
-                admission_req,
-                js_context,
+            // The watchdog stops code busy on the isolate, and the timeout stops code waiting
+            // for async operations
+            let mut watchdog = Watchdog::new(watchdog_runtime, timeout);
+            let res = tokio::time::timeout(
+                timeout,
+                eval_js_code_inner(
+                    serviceaccount_info,
+                    kube_client_mode,
+                    timeout_seconds,
+                    code,
+                    script_key,
+                    admission_req,
+                    stubs,
+                    &mut watchdog,
+                ),
             )
             .await;
+            let res = match res {
+                Ok(_) if watchdog.fired() => Err(Error::JsTimeout(timeout)),
+                Ok(res) => res,
+                Err(_) => Err(Error::JsTimeout(timeout)),
+            };
             let _ = sender.send(res);
-        });
-
-        rt.block_on(local);
+        }
+        .instrument(span)
+        .boxed_local()
     });
+    js_worker_pool()?.submit(job)?;
 
     receiver.await.map_err(Error::RecvJsThread)?
 }
+
+/// Default timeout of JS evaluations, the default timeoutSeconds of webhooks
+const DEFAULT_JS_TIMEOUT_SECONDS: u64 = 10;
+
+/// Timeout of JS evaluations of the rule with the timeoutSeconds
+fn js_timeout(timeout_seconds: Option<i32>) -> Duration {
+    let seconds = timeout_seconds
+        .and_then(|seconds| u64::try_from(seconds).ok())
+        .filter(|seconds| *seconds > 0)
+        .unwrap_or(DEFAULT_JS_TIMEOUT_SECONDS);
+    Duration::from_secs(seconds)
+}
+
+/// Terminates the execution of JS code on an isolate once the timeout elapses
+///
+/// The timer runs on another runtime, as code busy on the isolate blocks the worker thread.
+struct Watchdog {
+    runtime: tokio::runtime::Handle,
+    timeout: Duration,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    fn new(runtime: tokio::runtime::Handle, timeout: Duration) -> Self {
+        Self {
+            runtime,
+            timeout,
+            timer: None,
+        }
+    }
+
+    /// Start the timer for the isolate of the JS runtime
+    fn arm(&mut self, js_runtime: &mut deno_core::JsRuntime) {
+        let isolate = js_runtime.v8_isolate().thread_safe_handle();
+        let timeout = self.timeout;
+        self.timer = Some(self.runtime.spawn(async move {
+            tokio::time::sleep(timeout).await;
+            isolate.terminate_execution();
+        }));
+    }
+
+    /// Whether the execution is terminated by the watchdog
+    fn fired(&self) -> bool {
+        self.timer.as_ref().map_or(false, JoinHandle::is_finished)
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        if let Some(timer) = &self.timer {
+            timer.abort();
+        }
+    }
+}
+
+/// Default size of the queue of JS evaluations waiting for a worker
+pub const DEFAULT_JS_QUEUE_SIZE: usize = 256;
+
+/// Job creating the future to evaluate JS code on a worker
+type JsJob = Box<dyn FnOnce() -> LocalBoxFuture<'static, ()> + Send>;
+
+/// Number of workers and queue size of the pool, set before the pool starts
+static JS_WORKER_POOL_SIZE: OnceCell<(usize, usize)> = OnceCell::new();
+static JS_WORKER_POOL: OnceCell<JsWorkerPool> = OnceCell::new();
+
+/// Set the number of workers and the queue size of the pool evaluating JS code
+///
+/// Has no effect once the pool started.
+pub fn configure_js_worker_pool(workers: Option<usize>, queue_size: usize) {
+    let workers = workers.unwrap_or_else(default_js_workers).max(1);
+    let _ = JS_WORKER_POOL_SIZE.set((workers, queue_size));
+}
+
+fn default_js_workers() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4)
+}
+
+fn js_worker_pool() -> Result<&'static JsWorkerPool, Error> {
+    JS_WORKER_POOL.get_or_try_init(|| {
+        let (workers, queue_size) =
+            *JS_WORKER_POOL_SIZE.get_or_init(|| (default_js_workers(), DEFAULT_JS_QUEUE_SIZE));
+        JsWorkerPool::start(workers, queue_size)
+    })
+}
+
+/// Fixed threads evaluating JS code from a bounded queue
+///
+/// JS runtimes are not `Send`, so each worker runs them on its own single-threaded Tokio runtime.
+struct JsWorkerPool {
+    sender: SyncSender<JsJob>,
+}
+
+impl JsWorkerPool {
+    fn start(workers: usize, queue_size: usize) -> Result<Self, Error> {
+        let (sender, receiver) = std::sync::mpsc::sync_channel::<JsJob>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..workers {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(Error::CreateTokioRuntime)?;
+            let receiver = receiver.clone();
+            std::thread::Builder::new()
+                .name(format!("js-worker-{i}"))
+                .spawn(move || loop {
+                    // Release the lock before running the job, so idle workers take the next one
+                    let job = receiver.lock().unwrap().recv();
+                    let Ok(job) = job else {
+                        break;
+                    };
+
+                    // Wait for tasks spawned by the JS runtime as well
+                    let local = tokio::task::LocalSet::new();
+                    local.spawn_local(job());
+                    rt.block_on(local);
+                })
+                .map_err(Error::SpawnJsWorker)?;
+        }
+        tracing::info!(workers, queue_size, "started JavaScript worker pool");
+
+        Ok(Self { sender })
+    }
+
+    fn submit(&self, job: JsJob) -> Result<(), Error> {
+        self.sender.try_send(job).map_err(|error| match error {
+            TrySendError::Full(_) => Error::JsQueueFull,
+            TrySendError::Disconnected(_) => Error::JsWorkersStopped,
+        })
+    }
+}
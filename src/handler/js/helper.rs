@@ -1,23 +1,136 @@
 //! JS helper functions for rules
 
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    rc::Rc,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
 use anyhow::Context;
-use deno_core::op;
-use k8s_openapi::api::authentication::v1::{TokenRequest, TokenRequestSpec};
+use deno_core::{op, OpState};
+use k8s_openapi::api::authentication::v1::{TokenRequest, TokenRequestSpec, UserInfo};
 use kube::{
     api::ListParams,
+    client::ClientBuilder,
     config::AuthInfo,
     core::{DynamicObject, GroupVersionKind, ObjectList},
-    discovery::ApiResource,
-    Api,
+    discovery::{ApiResource, Scope},
+    Api, Discovery,
 };
-use serde::Deserialize;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Handle;
+
+use super::retry::RetryLayer;
+use crate::{types::rule::ServiceAccountInfo, util::glob_match};
+
+deno_core::extension!(
+    checkpoint_rule,
+    ops = [
+        ops_kube_get,
+        ops_kube_list,
+        ops_kube_discover,
+        ops_request_user_in_group,
+        ops_request_user_matches
+    ],
+    options = {
+        serviceaccount_info: Option<ServiceAccountInfo>,
+        impersonate: Option<UserInfo>,
+    },
+    state = |state, config| {
+        state.put(RuleServiceAccount(config.serviceaccount_info));
+        state.put(Impersonate(config.impersonate));
+    },
+);
+
+/// ServiceAccount of the rule, pinned to the namespace of namespaced rules
+///
+/// Kept in the op state rather than passed from JS code, so rules cannot use other ServiceAccounts.
+struct RuleServiceAccount(Option<ServiceAccountInfo>);
+
+/// User and groups of the request to impersonate, if the rule is in `impersonateRequester` mode
+///
+/// Kept in the op state rather than passed from JS code, so rules cannot impersonate anyone else.
+struct Impersonate(Option<UserInfo>);
+
+/// Prepare the client of the identity of the rule in the op state
+async fn rule_kube_client(
+    state: &Rc<RefCell<OpState>>,
+    timeout_seconds: Option<i32>,
+) -> anyhow::Result<kube::Client> {
+    let (serviceaccount_info, impersonate) = {
+        let state = state.borrow();
+        (
+            state.borrow::<RuleServiceAccount>().0.clone(),
+            state.borrow::<Impersonate>().0.clone(),
+        )
+    };
+    match impersonate {
+        Some(user_info) => prepare_impersonating_kube_client(user_info, timeout_seconds),
+        None => cached_kube_client(serviceaccount_info, timeout_seconds).await,
+    }
+}
+
+/// Duration to reuse a restricted client, shorter than the minimum expiration of its token
+const KUBE_CLIENT_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// ServiceAccount namespace and name, and the timeout of the rule the retry budget of the client is made from
+type KubeClientKey = (String, String, Option<i32>);
+
+/// Restricted clients by ServiceAccount and timeout, with their expiry
+static KUBE_CLIENT_CACHE: Lazy<Mutex<HashMap<KubeClientKey, (kube::Client, Instant)>>> =
+    Lazy::new(Default::default);
+
+/// Duration to reuse the discovered resources
+const DISCOVERY_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Resources discovered from the API server, with the expiry
+static DISCOVERY_CACHE: Lazy<Mutex<Option<(Vec<DiscoveredResource>, Instant)>>> =
+    Lazy::new(Default::default);
 
-use crate::types::rule::ServiceAccountInfo;
+/// Runtime to run the connection pools of cached clients on
+///
+/// JS code is evaluated on a runtime per request, which would tear down the connections of clients created on it.
+static KUBE_CLIENT_RUNTIME: OnceCell<Handle> = OnceCell::new();
 
-deno_core::extension!(checkpoint_rule, ops = [ops_kube_get, ops_kube_list]);
+/// Cache restricted clients, running their connection pools on the current runtime
+pub fn enable_kube_client_cache() {
+    KUBE_CLIENT_RUNTIME.get_or_init(Handle::current);
+}
+
+/// Get the restricted client of the ServiceAccount and the timeout from the cache, or prepare and cache one
+///
+/// Clients are not cached unless enabled with `enable_kube_client_cache`.
+async fn cached_kube_client(
+    serviceaccount_info: Option<ServiceAccountInfo>,
+    timeout_seconds: Option<i32>,
+) -> anyhow::Result<kube::Client> {
+    let (Some(runtime), Some(info)) = (KUBE_CLIENT_RUNTIME.get(), &serviceaccount_info) else {
+        return prepare_kube_client(serviceaccount_info, timeout_seconds).await;
+    };
+    let key = (info.namespace.clone(), info.name.clone(), timeout_seconds);
+    if let Some((client, expiry)) = KUBE_CLIENT_CACHE.lock().unwrap().get(&key) {
+        if *expiry > Instant::now() {
+            return Ok(client.clone());
+        }
+    }
+
+    let client = runtime
+        .spawn(prepare_kube_client(serviceaccount_info, timeout_seconds))
+        .await
+        .context("failed to prepare restricted Kubernetes client")??;
+
+    let now = Instant::now();
+    let mut cache = KUBE_CLIENT_CACHE.lock().unwrap();
+    cache.retain(|_, (_, expiry)| *expiry > now);
+    cache.insert(key, (client.clone(), now + KUBE_CLIENT_CACHE_TTL));
+    Ok(client)
+}
 
 /// Prepare Kubernetes client with specified ServiceAccount info in Rule spec
-async fn prepare_kube_client(
+pub async fn prepare_kube_client(
     serviceaccount_info: Option<ServiceAccountInfo>,
     timeout_seconds: Option<i32>,
 ) -> anyhow::Result<kube::Client> {
@@ -71,13 +184,43 @@ async fn prepare_kube_client(
         ..Default::default()
     };
 
-    let new_client = kube::Client::try_from(kube_config)
-        .context("failed to create restricted Kubernetes client")?;
+    // Retry throttled requests within a part of the timeout, leaving the rest to evaluate JS code
+    let retry_budget = Duration::from_secs(timeout_seconds.unwrap_or(10).max(1) as u64) / 4;
+    let new_client = ClientBuilder::try_from(kube_config)
+        .context("failed to create restricted Kubernetes client")?
+        .with_layer(&RetryLayer::new(retry_budget))
+        .build();
+
+    Ok(new_client)
+}
+
+/// Prepare Kubernetes client impersonating the user and groups of the request
+///
+/// The webhook itself must be permitted to impersonate them.
+fn prepare_impersonating_kube_client(
+    user_info: UserInfo,
+    timeout_seconds: Option<i32>,
+) -> anyhow::Result<kube::Client> {
+    let username = user_info
+        .username
+        .context("username of the request is not provided, so it cannot be impersonated")?;
+
+    let mut kube_config =
+        kube::Config::incluster().context("failed to get Kubernetes in-cluster config")?;
+    kube_config.auth_info.impersonate = Some(username);
+    kube_config.auth_info.impersonate_groups = user_info.groups;
+
+    // Retry throttled requests within a part of the timeout, leaving the rest to evaluate JS code
+    let retry_budget = Duration::from_secs(timeout_seconds.unwrap_or(10).max(1) as u64) / 4;
+    let new_client = ClientBuilder::try_from(kube_config)
+        .context("failed to create impersonating Kubernetes client")?
+        .with_layer(&RetryLayer::new(retry_budget))
+        .build();
 
     Ok(new_client)
 }
 
-#[derive(Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct KubeGetArgument {
     pub group: String,
@@ -91,8 +234,17 @@ pub struct KubeGetArgument {
 /// JS helper function to get a Kubernetes resource
 #[op]
 async fn ops_kube_get(
-    serviceaccount_info: Option<ServiceAccountInfo>,
+    state: Rc<RefCell<OpState>>,
     timeout_seconds: Option<i32>,
+    args: KubeGetArgument,
+) -> anyhow::Result<Option<DynamicObject>> {
+    let client = rule_kube_client(&state, timeout_seconds).await?;
+    kube_get(client, args).await
+}
+
+/// Get a Kubernetes resource with the client
+pub async fn kube_get(
+    client: kube::Client,
     KubeGetArgument {
         group,
         version,
@@ -110,8 +262,6 @@ async fn ops_kube_get(
         ApiResource::from_gvk(&gvk)
     };
 
-    let client = prepare_kube_client(serviceaccount_info, timeout_seconds).await?;
-
     // Prepare Kubernetes API with or without namespace
     let api = if let Some(namespace) = namespace {
         Api::<DynamicObject>::namespaced_with(client, &namespace, &ar)
@@ -128,7 +278,7 @@ async fn ops_kube_get(
     Ok(object)
 }
 
-#[derive(Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct KubeListArgument {
     pub group: String,
@@ -139,13 +289,13 @@ pub struct KubeListArgument {
     pub list_params: Option<KubeListArgumentListParams>,
 }
 
-#[derive(Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
 pub enum KubeListArgumentListParamsVersionMatch {
     NotOlderThan,
     Exact,
 }
 
-#[derive(Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Hash, PartialEq, Eq)]
 #[serde(deny_unknown_fields, rename_all = "camelCase")]
 pub struct KubeListArgumentListParams {
     pub label_selector: Option<String>,
@@ -160,8 +310,17 @@ pub struct KubeListArgumentListParams {
 /// JS helper function to list Kubernetes resources
 #[op]
 async fn ops_kube_list(
-    serviceaccount_info: Option<ServiceAccountInfo>,
+    state: Rc<RefCell<OpState>>,
     timeout_seconds: Option<i32>,
+    args: KubeListArgument,
+) -> anyhow::Result<ObjectList<DynamicObject>> {
+    let client = rule_kube_client(&state, timeout_seconds).await?;
+    kube_list(client, args).await
+}
+
+/// List Kubernetes resources with the client
+pub async fn kube_list(
+    client: kube::Client,
     KubeListArgument {
         group,
         version,
@@ -207,8 +366,6 @@ async fn ops_kube_list(
         ApiResource::from_gvk(&gvk)
     };
 
-    let client = prepare_kube_client(serviceaccount_info, timeout_seconds).await?;
-
     // Prepare Kubernetes API with or without namespace
     let api = if let Some(namespace) = namespace {
         Api::<DynamicObject>::namespaced_with(client, &namespace, &ar)
@@ -224,3 +381,75 @@ async fn ops_kube_list(
 
     Ok(object_list)
 }
+
+/// JS helper function to check whether the user of the request is in the group
+#[op]
+fn ops_request_user_in_group(user_info: UserInfo, group: String) -> bool {
+    user_info.groups.iter().flatten().any(|g| *g == group)
+}
+
+/// JS helper function to check whether the username of the request matches any of the glob
+/// patterns, e.g. `system:serviceaccount:kube-system:*`
+#[op]
+fn ops_request_user_matches(user_info: UserInfo, patterns: Vec<String>) -> bool {
+    let username = user_info.username.unwrap_or_default();
+    patterns
+        .iter()
+        .any(|pattern| glob_match(pattern, &username))
+}
+
+/// Resource served by the API server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DiscoveredResource {
+    pub group: String,
+    pub version: String,
+    pub kind: String,
+    pub plural: String,
+    pub namespaced: bool,
+}
+
+/// JS helper function to list the resources served by the API server
+///
+/// Discovery runs with the identity of the webhook, and its result is cached for a while.
+#[op]
+async fn ops_kube_discover() -> anyhow::Result<Vec<DiscoveredResource>> {
+    if let Some((resources, expiry)) = &*DISCOVERY_CACHE.lock().unwrap() {
+        if *expiry > Instant::now() {
+            return Ok(resources.clone());
+        }
+    }
+
+    let client = kube::Client::try_default()
+        .await
+        .context("failed to prepare Kubernetes client")?;
+    let resources = kube_discover(client).await?;
+    *DISCOVERY_CACHE.lock().unwrap() =
+        Some((resources.clone(), Instant::now() + DISCOVERY_CACHE_TTL));
+    Ok(resources)
+}
+
+/// List the resources of all versions of all groups served by the API server
+pub async fn kube_discover(client: kube::Client) -> anyhow::Result<Vec<DiscoveredResource>> {
+    let discovery = Discovery::new(client)
+        .run()
+        .await
+        .context("failed to discover API resources")?;
+    let resources = discovery
+        .groups()
+        .flat_map(|group| {
+            group.versions().flat_map(|version| {
+                group.versioned_resources(version).into_iter().map(
+                    |(api_resource, capabilities)| DiscoveredResource {
+                        group: api_resource.group,
+                        version: api_resource.version,
+                        kind: api_resource.kind,
+                        plural: api_resource.plural,
+                        namespaced: capabilities.scope == Scope::Namespaced,
+                    },
+                )
+            })
+        })
+        .collect();
+    Ok(resources)
+}
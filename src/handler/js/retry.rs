@@ -0,0 +1,129 @@
+//! Retry of Kubernetes API requests made by JS code
+//!
+//! The API server throttles clients with 429 under load, and a transient error would otherwise fail the
+//! evaluation and deny the request by failurePolicy.
+
+use std::{
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::future::BoxFuture;
+use http::{header::RETRY_AFTER, Method, Request, Response, StatusCode};
+use hyper::Body;
+use tokio::{sync::Mutex, time::Instant};
+use tower::{BoxError, Layer, Service, ServiceExt};
+
+/// Delay before the first retry, doubled on each retry
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// Maximum delay between retries
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Retry GET requests on 429 and 5xx responses and connection errors, within the budget of each request
+#[derive(Clone, Debug)]
+pub struct RetryLayer {
+    budget: Duration,
+}
+
+impl RetryLayer {
+    /// Total duration of retries of a request, including `Retry-After` waits
+    pub fn new(budget: Duration) -> Self {
+        Self { budget }
+    }
+}
+
+impl<S> Layer<S> for RetryLayer {
+    type Service = Retry<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Retry {
+            inner: Arc::new(Mutex::new(inner)),
+            budget: self.budget,
+        }
+    }
+}
+
+pub struct Retry<S> {
+    // The inner service is called again from the response future, so it is shared
+    inner: Arc<Mutex<S>>,
+    budget: Duration,
+}
+
+impl<S, B> Service<Request<Body>> for Retry<S>
+where
+    S: Service<Request<Body>, Response = Response<B>> + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError>,
+    B: Send + 'static,
+{
+    type Response = Response<B>;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Readiness of the inner service is awaited on each attempt
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let inner = self.inner.clone();
+        let budget = self.budget;
+        Box::pin(async move {
+            // Only GET requests are retried, as their bodies are empty and they are idempotent
+            if req.method() != Method::GET {
+                return call_inner(&inner, req).await;
+            }
+
+            let deadline = Instant::now() + budget;
+            let mut backoff = INITIAL_BACKOFF;
+            loop {
+                let res = call_inner(&inner, clone_request(&req)).await;
+                let delay = match &res {
+                    Ok(resp) if is_retryable(resp.status()) => retry_after(resp).unwrap_or(backoff),
+                    Ok(_) => return res,
+                    Err(_) => backoff,
+                };
+                if Instant::now() + delay > deadline {
+                    return res;
+                }
+
+                tracing::debug!(uri = %req.uri(), ?delay, "retrying Kubernetes API request");
+                tokio::time::sleep(delay).await;
+                backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+            }
+        })
+    }
+}
+
+async fn call_inner<S, B>(inner: &Mutex<S>, req: Request<Body>) -> Result<Response<B>, BoxError>
+where
+    S: Service<Request<Body>, Response = Response<B>>,
+    S::Error: Into<BoxError>,
+{
+    // Hold the lock only until the request is sent
+    let fut = {
+        let mut inner = inner.lock().await;
+        inner.ready().await.map_err(Into::into)?.call(req)
+    };
+    fut.await.map_err(Into::into)
+}
+
+fn clone_request(req: &Request<Body>) -> Request<Body> {
+    let mut cloned = Request::new(Body::empty());
+    *cloned.method_mut() = req.method().clone();
+    *cloned.uri_mut() = req.uri().clone();
+    *cloned.version_mut() = req.version();
+    *cloned.headers_mut() = req.headers().clone();
+    cloned
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Delay requested by the API server in seconds
+fn retry_after<B>(resp: &Response<B>) -> Option<Duration> {
+    let seconds = resp.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    seconds.trim().parse().ok().map(Duration::from_secs)
+}
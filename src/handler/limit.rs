@@ -0,0 +1,178 @@
+//! Per-rule limits of JS code evaluation
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::types::rule::RuleLimits;
+
+/// Token bucket refilled with `rate` tokens per second, holding at most `rate` tokens
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: rate.into(),
+            tokens: rate.into(),
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn try_take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Duration the circuit breaker stays open when the rule does not specify one
+const DEFAULT_ERROR_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// Circuit breaker opened by consecutive evaluation errors
+#[derive(Default)]
+struct CircuitBreaker {
+    consecutive_errors: u32,
+    open_until: Option<Instant>,
+}
+
+/// Limiter of a rule
+struct RuleLimiter {
+    limits: RuleLimits,
+    semaphore: Option<Arc<Semaphore>>,
+    bucket: Option<Mutex<TokenBucket>>,
+    breaker: Mutex<CircuitBreaker>,
+}
+
+impl RuleLimiter {
+    fn new(limits: RuleLimits) -> Self {
+        Self {
+            semaphore: limits
+                .max_concurrency
+                .map(|max_concurrency| Arc::new(Semaphore::new(max_concurrency as usize))),
+            bucket: limits
+                .max_requests_per_second
+                .map(TokenBucket::new)
+                .map(Mutex::new),
+            breaker: Default::default(),
+            limits,
+        }
+    }
+}
+
+/// Reason the evaluation is rejected
+#[derive(thiserror::Error, Debug)]
+pub enum LimitError {
+    #[error("maximum concurrency of the rule is reached")]
+    Concurrency,
+    #[error("maximum requests per second of the rule is reached")]
+    Rate,
+}
+
+/// Permit to evaluate JS code of a rule. The concurrency slot is released when dropped.
+pub struct Permit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Limiters of all rules, keyed by kind, namespace, and name of the rule
+#[derive(Default)]
+pub struct RuleLimiters {
+    limiters: Mutex<HashMap<String, Arc<RuleLimiter>>>,
+}
+
+impl RuleLimiters {
+    /// Acquire a permit to evaluate JS code of the rule, failing immediately if the limits are reached
+    pub fn acquire(&self, key: String, limits: Option<&RuleLimits>) -> Result<Permit, LimitError> {
+        let Some(limits) = limits else {
+            return Ok(Permit { _permit: None });
+        };
+
+        let limiter = {
+            let mut limiters = self.limiters.lock().unwrap();
+            let limiter = limiters
+                .entry(key)
+                .or_insert_with(|| Arc::new(RuleLimiter::new(limits.clone())));
+            // The limits of the rule are updated
+            if &limiter.limits != limits {
+                *limiter = Arc::new(RuleLimiter::new(limits.clone()));
+            }
+            limiter.clone()
+        };
+
+        let permit = match &limiter.semaphore {
+            Some(semaphore) => Some(
+                semaphore
+                    .clone()
+                    .try_acquire_owned()
+                    .map_err(|_| LimitError::Concurrency)?,
+            ),
+            None => None,
+        };
+        if let Some(bucket) = &limiter.bucket {
+            if !bucket.lock().unwrap().try_take() {
+                return Err(LimitError::Rate);
+            }
+        }
+
+        Ok(Permit { _permit: permit })
+    }
+
+    fn limiter(&self, key: &str) -> Option<Arc<RuleLimiter>> {
+        self.limiters.lock().unwrap().get(key).cloned()
+    }
+
+    /// Remaining duration of the circuit breaker of the rule if it is open
+    pub fn open_circuit(&self, key: &str) -> Option<Duration> {
+        let limiter = self.limiter(key)?;
+        let mut breaker = limiter.breaker.lock().unwrap();
+        let open_until = breaker.open_until?;
+        let remaining = open_until.checked_duration_since(Instant::now());
+        if remaining.is_none() {
+            // Evaluate again after the cool-down
+            breaker.open_until = None;
+        }
+        remaining
+    }
+
+    /// Count the result of an evaluation of the rule, returning whether it opened the circuit breaker
+    ///
+    /// Only rules with limits have circuit breakers, since their limiters are made by `acquire`.
+    pub fn record_result(&self, key: &str, success: bool) -> bool {
+        let Some(limiter) = self.limiter(key) else {
+            return false;
+        };
+        let Some(max_consecutive_errors) = limiter.limits.max_consecutive_errors else {
+            return false;
+        };
+        let mut breaker = limiter.breaker.lock().unwrap();
+        if success {
+            breaker.consecutive_errors = 0;
+            return false;
+        }
+        breaker.consecutive_errors += 1;
+        if breaker.consecutive_errors < max_consecutive_errors {
+            return false;
+        }
+        let cooldown = limiter
+            .limits
+            .error_cooldown_seconds
+            .map_or(DEFAULT_ERROR_COOLDOWN, Duration::from_secs);
+        breaker.consecutive_errors = 0;
+        breaker.open_until = Some(Instant::now() + cooldown);
+        true
+    }
+}
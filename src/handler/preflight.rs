@@ -0,0 +1,128 @@
+//! Preflight checks of rules, to discover broken rules before they break admission requests
+//!
+//! The controller reports the result in `PreflightPassed` condition of each rule when it changes, and the webhook
+//! checks all rules on startup and periodically, logging broken rules. A broken rule only fails its own requests,
+//! so it does not affect the readiness of the webhook.
+
+use k8s_openapi::api::core::v1::ServiceAccount;
+use kube::{Api, Resource, ResourceExt};
+
+use crate::types::rule::{
+    KubeClientMode, MutatingRule, NamespacedMutatingRule, NamespacedValidatingRule, RuleSpec,
+    ValidatingRule,
+};
+
+use super::{rule_source::RuleSource, Error};
+
+/// Reason of the condition of rules whose code does not compile
+pub const COMPILE_FAILED_REASON: &str = "CompileFailed";
+
+/// Check the code of the rule compiles and its ServiceAccount exists
+///
+/// Returns status, reason, and message of the condition.
+pub async fn check_rule(
+    client: &kube::Client,
+    spec: &RuleSpec,
+) -> (&'static str, &'static str, String) {
+    let code = spec.code.clone();
+    let compiled = tokio::task::spawn_blocking(move || crate::js::check_syntax(&code)).await;
+    match compiled {
+        Ok(Ok(())) => {}
+        Ok(Err(error)) => return ("False", COMPILE_FAILED_REASON, error),
+        Err(error) => return ("Unknown", COMPILE_FAILED_REASON, error.to_string()),
+    }
+    check_service_account(client, spec).await
+}
+
+/// Check the ServiceAccount of the rule exists, for rules whose code is known to compile
+///
+/// Returns status, reason, and message of the condition.
+pub async fn check_service_account(
+    client: &kube::Client,
+    spec: &RuleSpec,
+) -> (&'static str, &'static str, String) {
+    if let Some(service_account) = &spec.service_account {
+        // The ServiceAccount is not used to access resources
+        if spec.kube_client_mode.unwrap_or_default() == KubeClientMode::ServiceAccount {
+            let sa_api =
+                Api::<ServiceAccount>::namespaced(client.clone(), &service_account.namespace);
+            match sa_api.get_opt(&service_account.name).await {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    return (
+                        "False",
+                        "ServiceAccountNotFound",
+                        format!(
+                            "ServiceAccount `{}` does not exist in namespace `{}`",
+                            service_account.name, service_account.namespace
+                        ),
+                    )
+                }
+                Err(error) => return ("Unknown", "ServiceAccountLookupFailed", error.to_string()),
+            }
+        }
+    }
+
+    ("True", "Passed", "preflight checks passed".to_string())
+}
+
+/// Check the rules of the kind, returning the broken ones with the reasons
+async fn check_rules<K>(
+    client: &kube::Client,
+    rule_source: &RuleSource,
+    spec_of: impl Fn(&K) -> RuleSpec,
+) -> Result<Vec<String>, Error>
+where
+    K: Resource<DynamicType = ()> + Clone + serde::de::DeserializeOwned + std::fmt::Debug,
+{
+    let mut broken = Vec::new();
+    for rule in rule_source.list::<K>(client).await? {
+        let (status, reason, message) = check_rule(client, &spec_of(&rule)).await;
+        if status != "False" {
+            continue;
+        }
+        let name = match rule.namespace() {
+            Some(namespace) => format!("{}/{}", namespace, rule.name_any()),
+            None => rule.name_any(),
+        };
+        tracing::warn!(rule_kind = %K::kind(&()), rule = %name, %reason, %message, "rule is broken");
+        broken.push(format!("{} `{}`: {}", K::kind(&()), name, message));
+    }
+    Ok(broken)
+}
+
+/// Check all rules, logging the broken ones
+pub async fn run(client: kube::Client, rule_source: &RuleSource) {
+    let broken = async {
+        let mut broken = check_rules(&client, rule_source, |vr: &ValidatingRule| {
+            vr.spec.0.clone()
+        })
+        .await?;
+        broken.extend(
+            check_rules(&client, rule_source, |mr: &MutatingRule| mr.spec.0.clone()).await?,
+        );
+        broken.extend(
+            check_rules(&client, rule_source, |nvr: &NamespacedValidatingRule| {
+                nvr.spec
+                    .0
+                    .pin_namespace(&nvr.namespace().unwrap_or_default())
+            })
+            .await?,
+        );
+        broken.extend(
+            check_rules(&client, rule_source, |nmr: &NamespacedMutatingRule| {
+                nmr.spec
+                    .0
+                    .pin_namespace(&nmr.namespace().unwrap_or_default())
+            })
+            .await?,
+        );
+        Ok::<_, Error>(broken)
+    }
+    .await;
+
+    match broken {
+        Ok(broken) => tracing::info!(broken = broken.len(), "preflight of rules finished"),
+        Err(error) => tracing::error!(%error, "failed to list rules for preflight"),
+    }
+}
@@ -0,0 +1,184 @@
+//! Endpoint listing the rules known to the webhook and their health, for debugging
+//!
+//! `GET /internal/rules` is authenticated with the same bearer token as `/internal/loglevel`.
+//! Evaluation errors are kept in memory, so each replica only reports the errors it has seen.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use axum::{extract, response, Json};
+use chrono::{DateTime, Utc};
+use http::HeaderMap;
+use kube::{Resource, ResourceExt};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::types::rule::{
+    v2::{self, RuleEngine},
+    MutatingRule, NamespacedMutatingRule, NamespacedValidatingRule, RuleSpec, ValidatingRule,
+};
+
+use super::{js, AppState, Error};
+
+/// Last evaluation errors of rules, keyed by kind, namespace, and name of the rule
+static EVALUATION_ERRORS: Lazy<Mutex<HashMap<String, EvaluationError>>> =
+    Lazy::new(Default::default);
+
+/// Syntax errors of rules with the code each was checked for, keyed like `EVALUATION_ERRORS`
+static COMPILE_ERRORS: Lazy<Mutex<HashMap<String, (String, Option<String>)>>> =
+    Lazy::new(Default::default);
+
+/// Error of the last failed evaluation of a rule
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EvaluationError {
+    pub message: String,
+    pub time: DateTime<Utc>,
+}
+
+/// Health of a rule on this replica
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleHealth {
+    pub kind: String,
+    pub namespace: Option<String>,
+    pub name: String,
+    pub resource_version: Option<String>,
+    pub engine: RuleEngine,
+    /// Whether the code of the current generation has been compiled and cached on this replica
+    pub code_cached: bool,
+    /// Syntax error of the code, if it does not compile
+    pub compile_error: Option<String>,
+    pub last_error: Option<EvaluationError>,
+}
+
+/// Remember the error of the evaluation of the rule, replacing the previous one
+pub(super) fn record_evaluation_error<K>(rule: &K, error: &Error)
+where
+    K: Resource<DynamicType = ()>,
+{
+    EVALUATION_ERRORS.lock().unwrap().insert(
+        js::rule_key(rule),
+        EvaluationError {
+            message: error.to_string(),
+            time: Utc::now(),
+        },
+    );
+}
+
+/// Health of the rule, with the code of the rule to check the syntax of
+fn rule_health<K>(rule: &K, spec: &RuleSpec) -> (RuleHealth, String)
+where
+    K: Resource<DynamicType = ()>,
+{
+    let script_key = js::ScriptKey::of(rule, &spec.code);
+    let health = RuleHealth {
+        kind: K::kind(&()).into_owned(),
+        namespace: rule.namespace(),
+        name: rule.name_any(),
+        resource_version: rule.resource_version(),
+        engine: v2::RuleSpec::from(spec.clone()).engine,
+        code_cached: js::is_code_cached(&script_key),
+        compile_error: None,
+        last_error: EVALUATION_ERRORS
+            .lock()
+            .unwrap()
+            .get(&js::rule_key(rule))
+            .cloned(),
+    };
+    (health, spec.code.clone())
+}
+
+/// Syntax error of the code of the rule
+///
+/// Code cached by evaluations is known to compile, and the result is remembered until the code
+/// changes, so only new code is compiled.
+fn compile_error(key: &str, health: &RuleHealth, code: String) -> Option<String> {
+    if health.code_cached {
+        return None;
+    }
+    if let Some((checked, error)) = COMPILE_ERRORS.lock().unwrap().get(key) {
+        if *checked == code {
+            return error.clone();
+        }
+    }
+    let error = crate::js::check_syntax(&code).err();
+    COMPILE_ERRORS
+        .lock()
+        .unwrap()
+        .insert(key.to_string(), (code, error.clone()));
+    error
+}
+
+pub(super) async fn get_rules(
+    extract::State(state): extract::State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<RuleHealth>>, response::Response> {
+    crate::log_level::authorize(&state.config.log_level_token_path, &headers).await?;
+
+    macro_rules! list_rules {
+        ($kind:ty) => {
+            async {
+                let rules: Vec<$kind> = state.rule_source.list(&state.kube_client).await?;
+                Ok::<_, Error>(
+                    rules
+                        .iter()
+                        .map(|rule| rule_health(rule, &rule.spec.0))
+                        .collect::<Vec<_>>(),
+                )
+            }
+        };
+    }
+    let (vrs, mrs, nvrs, nmrs) = tokio::try_join!(
+        list_rules!(ValidatingRule),
+        list_rules!(MutatingRule),
+        list_rules!(NamespacedValidatingRule),
+        list_rules!(NamespacedMutatingRule),
+    )
+    .map_err(response::IntoResponse::into_response)?;
+
+    let rules = [vrs, mrs, nvrs, nmrs].concat();
+    let keys = rules
+        .iter()
+        .map(|(rule, _)| {
+            format!(
+                "{}/{}/{}",
+                rule.kind,
+                rule.namespace.as_deref().unwrap_or_default(),
+                rule.name
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // Compile off the async runtime, as it blocks for a while with large code
+    let task_keys = keys.clone();
+    let mut rules = tokio::task::spawn_blocking(move || {
+        rules
+            .into_iter()
+            .zip(&task_keys)
+            .map(|((mut rule, code), key)| {
+                rule.compile_error = compile_error(key, &rule, code);
+                rule
+            })
+            .collect::<Vec<_>>()
+    })
+    .await
+    .map_err(|error| response::IntoResponse::into_response(Error::JoinJsTask(error)))?;
+    rules.sort_by(|a, b| (&a.kind, &a.namespace, &a.name).cmp(&(&b.kind, &b.namespace, &b.name)));
+
+    // Forget the errors and the code caches of the rules that no longer exist
+    let keys = keys.into_iter().collect::<HashSet<_>>();
+    EVALUATION_ERRORS
+        .lock()
+        .unwrap()
+        .retain(|key, _| keys.contains(key));
+    COMPILE_ERRORS
+        .lock()
+        .unwrap()
+        .retain(|key, _| keys.contains(key));
+    js::retain_code(|key| keys.contains(key));
+
+    Ok(Json(rules))
+}
@@ -0,0 +1,247 @@
+//! Source of the rules the webhook evaluates, either the Kubernetes API or local YAML files
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use kube::{api::ListParams, core::NamespaceResourceScope, Api, Resource};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::types::{
+    rule::{MutatingRule, NamespacedMutatingRule, NamespacedValidatingRule, ValidatingRule},
+    ruleset::RuleSet,
+};
+
+use super::Error;
+
+/// API version of rules in files
+const API_VERSION: &str = "checkpoint.devsisters.com/v1";
+
+/// Errors can be raised when loading rules from files
+#[derive(thiserror::Error, Debug)]
+pub enum RuleFileError {
+    #[error("failed to read directory `{0}`: {1}")]
+    ReadDir(PathBuf, #[source] std::io::Error),
+    #[error("failed to read file `{0}`: {1}")]
+    ReadFile(PathBuf, #[source] std::io::Error),
+    #[error("failed to parse document {1} of `{0}`: {2}")]
+    Parse(PathBuf, usize, #[source] serde_yaml::Error),
+    #[error("invalid {1} in document {2} of `{0}`: {3}")]
+    InvalidRule(PathBuf, String, usize, #[source] serde_json::Error),
+    #[error("{1} in document {2} of `{0}` does not have name")]
+    MissingName(PathBuf, String, usize),
+    #[error("{1} in document {2} of `{0}` does not have namespace")]
+    MissingNamespace(PathBuf, String, usize),
+    #[error("{1} `{2}` in `{0}` is defined more than once")]
+    Duplicated(PathBuf, String, String),
+}
+
+/// Kind, namespace, and name of a rule. Namespace is given only for namespaced rules.
+type RuleKey = (String, Option<String>, String);
+
+/// Rules loaded from YAML files in a directory
+///
+/// Documents of other kinds, e.g. test cases next to rules, are ignored.
+#[derive(Debug)]
+pub struct FileRules {
+    dir: PathBuf,
+    rules: RwLock<HashMap<RuleKey, serde_json::Value>>,
+}
+
+impl FileRules {
+    /// Load `*.yaml` and `*.yml` files in the directory, not recursively
+    pub fn load(dir: PathBuf) -> Result<Self, RuleFileError> {
+        let rules = read_rules(&dir)?;
+        Ok(Self {
+            dir,
+            rules: RwLock::new(rules),
+        })
+    }
+
+    /// Read the files again. The previous rules are kept if any of the files is invalid.
+    pub fn reload(&self) -> Result<(), RuleFileError> {
+        let rules = read_rules(&self.dir)?;
+        *self.rules.write().unwrap() = rules;
+        Ok(())
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Number of rules loaded
+    pub fn len(&self) -> usize {
+        self.rules.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn get<K>(&self, namespace: Option<&str>, name: &str) -> Option<K>
+    where
+        K: Resource<DynamicType = ()> + DeserializeOwned,
+    {
+        let key = (
+            K::kind(&()).into_owned(),
+            namespace.map(str::to_string),
+            name.to_string(),
+        );
+        let rules = self.rules.read().unwrap();
+        // Rules are validated when loaded
+        serde_json::from_value(rules.get(&key)?.clone()).ok()
+    }
+
+    fn list<K>(&self) -> Vec<K>
+    where
+        K: Resource<DynamicType = ()> + DeserializeOwned,
+    {
+        let kind = K::kind(&());
+        let rules = self.rules.read().unwrap();
+        rules
+            .iter()
+            .filter(|((rule_kind, _, _), _)| *rule_kind == kind)
+            .filter_map(|(_, document)| serde_json::from_value(document.clone()).ok())
+            .collect()
+    }
+}
+
+fn read_rules(dir: &Path) -> Result<HashMap<RuleKey, serde_json::Value>, RuleFileError> {
+    let entries = fs::read_dir(dir).map_err(|error| RuleFileError::ReadDir(dir.into(), error))?;
+    let mut paths = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            matches!(
+                path.extension().and_then(|ext| ext.to_str()),
+                Some("yaml" | "yml")
+            )
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    let mut rules = HashMap::new();
+    for path in paths {
+        let contents = fs::read_to_string(&path)
+            .map_err(|error| RuleFileError::ReadFile(path.clone(), error))?;
+        for (i, document) in serde_yaml::Deserializer::from_str(&contents).enumerate() {
+            let document = serde_json::Value::deserialize(document)
+                .map_err(|error| RuleFileError::Parse(path.clone(), i, error))?;
+            if document["apiVersion"] != API_VERSION {
+                continue;
+            }
+            let kind = document["kind"].as_str().unwrap_or_default().to_string();
+            let invalid = |error| RuleFileError::InvalidRule(path.clone(), kind.clone(), i, error);
+            match kind.as_str() {
+                "ValidatingRule" => check_rule::<ValidatingRule>(&document).map_err(invalid)?,
+                "MutatingRule" => check_rule::<MutatingRule>(&document).map_err(invalid)?,
+                "NamespacedValidatingRule" => {
+                    check_rule::<NamespacedValidatingRule>(&document).map_err(invalid)?
+                }
+                "NamespacedMutatingRule" => {
+                    check_rule::<NamespacedMutatingRule>(&document).map_err(invalid)?
+                }
+                "RuleSet" => check_rule::<RuleSet>(&document).map_err(invalid)?,
+                _ => continue,
+            }
+
+            let name = document
+                .pointer("/metadata/name")
+                .and_then(serde_json::Value::as_str)
+                .ok_or_else(|| RuleFileError::MissingName(path.clone(), kind.clone(), i))?;
+            let namespace = document
+                .pointer("/metadata/namespace")
+                .and_then(serde_json::Value::as_str);
+            let namespace = if kind.starts_with("Namespaced") {
+                let namespace = namespace.ok_or_else(|| {
+                    RuleFileError::MissingNamespace(path.clone(), kind.clone(), i)
+                })?;
+                Some(namespace.to_string())
+            } else {
+                None
+            };
+
+            let display_name = match &namespace {
+                Some(namespace) => format!("{}/{}", namespace, name),
+                None => name.to_string(),
+            };
+            let key = (kind.clone(), namespace, name.to_string());
+            if rules.insert(key, document.clone()).is_some() {
+                return Err(RuleFileError::Duplicated(path, kind, display_name));
+            }
+        }
+    }
+    Ok(rules)
+}
+
+/// Check the document is a valid rule of the kind
+fn check_rule<K: DeserializeOwned>(document: &serde_json::Value) -> Result<(), serde_json::Error> {
+    serde_json::from_value::<K>(document.clone())?;
+    Ok(())
+}
+
+/// Where the webhook looks up rules
+#[derive(Clone, Debug)]
+pub enum RuleSource {
+    /// Rules in the Kubernetes API
+    Kubernetes,
+    /// Rules loaded from files, without CRDs
+    Files(Arc<FileRules>),
+}
+
+impl RuleSource {
+    /// Get a cluster-scoped rule
+    pub(super) async fn get<K>(&self, kube_client: &kube::Client, name: &str) -> Result<K, Error>
+    where
+        K: Resource<DynamicType = ()> + Clone + DeserializeOwned + std::fmt::Debug,
+    {
+        match self {
+            Self::Kubernetes => Api::<K>::all(kube_client.clone())
+                .get_opt(name)
+                .await
+                .map_err(Error::Kubernetes)?
+                .ok_or(Error::RuleNotFound),
+            Self::Files(rules) => rules.get(None, name).ok_or(Error::RuleNotFound),
+        }
+    }
+
+    /// Get a namespaced rule
+    pub(super) async fn get_namespaced<K>(
+        &self,
+        kube_client: &kube::Client,
+        namespace: &str,
+        name: &str,
+    ) -> Result<K, Error>
+    where
+        K: Resource<DynamicType = (), Scope = NamespaceResourceScope>
+            + Clone
+            + DeserializeOwned
+            + std::fmt::Debug,
+    {
+        match self {
+            Self::Kubernetes => Api::<K>::namespaced(kube_client.clone(), namespace)
+                .get_opt(name)
+                .await
+                .map_err(Error::Kubernetes)?
+                .ok_or(Error::RuleNotFound),
+            Self::Files(rules) => rules.get(Some(namespace), name).ok_or(Error::RuleNotFound),
+        }
+    }
+
+    /// List all rules of the kind, in all namespaces for namespaced rules
+    pub(super) async fn list<K>(&self, kube_client: &kube::Client) -> Result<Vec<K>, Error>
+    where
+        K: Resource<DynamicType = ()> + Clone + DeserializeOwned + std::fmt::Debug,
+    {
+        match self {
+            Self::Kubernetes => Ok(Api::<K>::all(kube_client.clone())
+                .list(&ListParams::default())
+                .await
+                .map_err(Error::Kubernetes)?
+                .items),
+            Self::Files(rules) => Ok(rules.list()),
+        }
+    }
+}
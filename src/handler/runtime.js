@@ -1,16 +1,38 @@
 function kubeGet(args) {
-  const serviceAccountInfo = __checkpoint_get_context("serviceAccountInfo");
   const timeoutSeconds = __checkpoint_get_context("timeoutSeconds");
-  return Deno.core.ops.ops_kube_get(serviceAccountInfo, timeoutSeconds, args);
+  return Deno.core.ops.ops_kube_get(timeoutSeconds, args);
 }
 function kubeList(args) {
-  const serviceAccountInfo = __checkpoint_get_context("serviceAccountInfo");
   const timeoutSeconds = __checkpoint_get_context("timeoutSeconds");
-  return Deno.core.ops.ops_kube_list(serviceAccountInfo, timeoutSeconds, args);
+  return Deno.core.ops.ops_kube_list(timeoutSeconds, args);
+}
+function kubeDiscover() {
+  return Deno.core.ops.ops_kube_discover();
 }
 function getRequest() {
   return __checkpoint_get_context("admissionRequest");
 }
+function getTargetObject() {
+  return __checkpoint_get_context("targetObject");
+}
+function isDeleteRequest() {
+  return getRequest().operation === "DELETE";
+}
+function getSubResource() {
+  return getRequest().subResource;
+}
+function getCluster() {
+  return __checkpoint_get_context("cluster");
+}
+function getUserInfo() {
+  return getRequest().userInfo;
+}
+function requestUserInGroup(group) {
+  return Deno.core.ops.ops_request_user_in_group(getUserInfo(), group);
+}
+function requestUserMatches(patterns) {
+  return Deno.core.ops.ops_request_user_matches(getUserInfo(), [].concat(patterns));
+}
 function allow() {
   const output = __checkpoint_get_context("output");
   __checkpoint_set_context("output", { ...output, denyReason: undefined });
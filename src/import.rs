@@ -0,0 +1,556 @@
+//! Conversion of Kyverno policies and Gatekeeper constraints into ValidatingRule skeletons
+//!
+//! Only match criteria are converted. Validation logic is copied into the JS code as a TODO comment,
+//! along with the parts of the match criteria that could not be converted.
+
+use std::collections::{BTreeMap, HashMap};
+
+use k8s_openapi::{
+    api::admissionregistration::v1::RuleWithOperations,
+    apimachinery::pkg::apis::meta::v1::{LabelSelector, LabelSelectorRequirement, ObjectMeta},
+};
+use kube::{core::GroupVersionKind, discovery::ApiResource};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::types::rule::{
+    FailurePolicy, RuleSpec, RuleWebhook, ValidatingRule, ValidatingRuleSpec,
+};
+
+/// Errors can be raised while importing policies
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to deserialize {kind} `{name}`: {source}")]
+    Deserialize {
+        kind: String,
+        name: String,
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("failed to serialize rule (This is a bug): {0}")]
+    Serialize(#[source] serde_json::Error),
+}
+
+/// Operations Kyverno and Gatekeeper validate if not specified
+const DEFAULT_OPERATIONS: [&str; 2] = ["CREATE", "UPDATE"];
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct KyvernoPolicy {
+    metadata: ObjectMeta,
+    spec: KyvernoPolicySpec,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct KyvernoPolicySpec {
+    #[serde(default)]
+    validation_failure_action: Option<String>,
+    #[serde(default)]
+    failure_policy: Option<FailurePolicy>,
+    #[serde(default)]
+    rules: Vec<KyvernoRule>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct KyvernoRule {
+    name: String,
+    #[serde(rename = "match")]
+    match_: KyvernoMatch,
+    #[serde(default)]
+    exclude: Option<KyvernoMatch>,
+    #[serde(default)]
+    validate: Option<Value>,
+}
+
+/// `match` or `exclude` block of a Kyverno rule
+#[derive(Deserialize, Debug)]
+struct KyvernoMatch {
+    #[serde(default)]
+    any: Vec<KyvernoFilter>,
+    #[serde(default)]
+    all: Vec<KyvernoFilter>,
+    /// Filter directly in the block, of the legacy syntax
+    #[serde(flatten)]
+    filter: KyvernoFilter,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct KyvernoFilter {
+    #[serde(default)]
+    resources: Option<KyvernoResources>,
+    /// Filters on subjects, roles, and so on
+    #[serde(flatten)]
+    others: BTreeMap<String, Value>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct KyvernoResources {
+    #[serde(default)]
+    kinds: Vec<String>,
+    #[serde(default)]
+    namespaces: Vec<String>,
+    #[serde(default)]
+    operations: Vec<String>,
+    #[serde(default)]
+    selector: Option<LabelSelector>,
+    #[serde(default)]
+    namespace_selector: Option<LabelSelector>,
+    /// Filters on names, annotations, and so on
+    #[serde(flatten)]
+    others: BTreeMap<String, Value>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GatekeeperConstraint {
+    kind: String,
+    metadata: ObjectMeta,
+    spec: GatekeeperConstraintSpec,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct GatekeeperConstraintSpec {
+    #[serde(default, rename = "match")]
+    match_: GatekeeperMatch,
+    #[serde(default)]
+    parameters: Option<Value>,
+    #[serde(default)]
+    enforcement_action: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct GatekeeperMatch {
+    #[serde(default)]
+    kinds: Vec<GatekeeperKinds>,
+    #[serde(default)]
+    scope: Option<String>,
+    #[serde(default)]
+    namespaces: Vec<String>,
+    #[serde(default)]
+    excluded_namespaces: Vec<String>,
+    #[serde(default)]
+    label_selector: Option<LabelSelector>,
+    #[serde(default)]
+    namespace_selector: Option<LabelSelector>,
+    /// Filters on names, sources, and so on
+    #[serde(flatten)]
+    others: BTreeMap<String, Value>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GatekeeperKinds {
+    #[serde(default)]
+    api_groups: Vec<String>,
+    #[serde(default)]
+    kinds: Vec<String>,
+}
+
+/// Match criteria of a webhook entry being converted
+#[derive(Default)]
+struct Entry {
+    object_rules: Vec<RuleWithOperations>,
+    namespace_selector: Option<LabelSelector>,
+    object_selector: Option<LabelSelector>,
+}
+
+/// Parts of the source that are not converted, written in the TODO comment
+#[derive(Default)]
+struct Notes(Vec<String>);
+
+impl Notes {
+    fn add(&mut self, note: impl Into<String>) {
+        self.0.push(note.into());
+    }
+}
+
+/// Convert Kyverno ClusterPolicy or Policy into a ValidatingRule for each of its validate rules
+///
+/// Rules without `validate`, e.g. mutate or generate rules, are skipped.
+pub fn from_kyverno(document: &Value) -> Result<Vec<ValidatingRule>, Error> {
+    let policy: KyvernoPolicy = deserialize(document)?;
+    let policy_name = policy.metadata.name.clone().unwrap_or_default();
+
+    let mut rules = Vec::new();
+    for rule in policy.spec.rules {
+        let Some(validate) = rule.validate else {
+            tracing::warn!(policy = policy_name, rule = rule.name, "skipped rule without validate");
+            continue;
+        };
+
+        let mut notes = Notes::default();
+        let action = policy.spec.validation_failure_action.as_deref();
+        if action.map_or(false, |action| action.eq_ignore_ascii_case("audit")) {
+            notes.add("validationFailureAction is Audit, but the rule denies requests");
+        }
+
+        let mut entries = Vec::new();
+        if let Some(entry) = kyverno_entry(&rule.match_.filter, "match", &mut notes) {
+            entries.push(entry);
+        }
+        for (i, filter) in rule.match_.any.iter().enumerate() {
+            let path = format!("match.any[{}]", i);
+            entries.extend(kyverno_entry(filter, &path, &mut notes));
+        }
+        if !rule.match_.all.is_empty() {
+            // Criteria of later filters override earlier ones, so they must be reviewed
+            notes.add("match.all is merged into a single webhook, review the criteria");
+            let mut merged = Entry::default();
+            for (i, filter) in rule.match_.all.iter().enumerate() {
+                let path = format!("match.all[{}]", i);
+                if let Some(entry) = kyverno_entry(filter, &path, &mut notes) {
+                    merged.object_rules.extend(entry.object_rules);
+                    merged.namespace_selector =
+                        entry.namespace_selector.or(merged.namespace_selector);
+                    merged.object_selector = entry.object_selector.or(merged.object_selector);
+                }
+            }
+            entries.push(merged);
+        }
+
+        if let Some(exclude) = &rule.exclude {
+            let filters = exclude
+                .any
+                .iter()
+                .enumerate()
+                .map(|(i, filter)| (format!("exclude.any[{}]", i), filter))
+                .chain(
+                    exclude
+                        .all
+                        .iter()
+                        .enumerate()
+                        .map(|(i, filter)| (format!("exclude.all[{}]", i), filter)),
+                )
+                .chain([("exclude".to_string(), &exclude.filter)]);
+            let mut excluded_namespaces = Vec::new();
+            for (path, filter) in filters {
+                for key in filter.others.keys() {
+                    notes.add(format!("{}.{} is not converted", path, key));
+                }
+                let Some(resources) = &filter.resources else {
+                    continue;
+                };
+                excluded_namespaces.extend(resources.namespaces.iter().cloned());
+                let unconverted = [
+                    ("kinds", !resources.kinds.is_empty()),
+                    ("operations", !resources.operations.is_empty()),
+                    ("selector", resources.selector.is_some()),
+                    ("namespaceSelector", resources.namespace_selector.is_some()),
+                ]
+                .into_iter()
+                .filter_map(|(key, set)| set.then_some(key))
+                .chain(resources.others.keys().map(String::as_str));
+                for key in unconverted {
+                    notes.add(format!("{}.resources.{} is not converted", path, key));
+                }
+            }
+            if !excluded_namespaces.is_empty() {
+                for entry in &mut entries {
+                    exclude_namespaces(entry, &excluded_namespaces, &mut notes);
+                }
+            }
+        }
+
+        let code = todo_code(
+            &format!("Kyverno rule `{}` of `{}`", rule.name, policy_name),
+            "validate",
+            &validate,
+            &notes,
+        )?;
+        rules.push(make_rule(
+            format!("{}-{}", policy_name, rule.name),
+            entries,
+            policy.spec.failure_policy.clone(),
+            code,
+        ));
+    }
+    Ok(rules)
+}
+
+/// Convert a Kyverno match filter, or `None` if it has no resource filter
+fn kyverno_entry(filter: &KyvernoFilter, path: &str, notes: &mut Notes) -> Option<Entry> {
+    for key in filter.others.keys() {
+        notes.add(format!("{}.{} is not converted", path, key));
+    }
+    let resources = filter.resources.as_ref()?;
+    for key in resources.others.keys() {
+        notes.add(format!("{}.resources.{} is not converted", path, key));
+    }
+
+    let operations = if resources.operations.is_empty() {
+        DEFAULT_OPERATIONS.iter().map(|op| op.to_string()).collect()
+    } else {
+        resources.operations.clone()
+    };
+    let object_rules = resources
+        .kinds
+        .iter()
+        .map(|kind| {
+            // Kinds are in `Kind`, `Version/Kind`, or `Group/Version/Kind` format
+            let (group, version, kind) = match kind.split('/').collect::<Vec<_>>()[..] {
+                [kind] => ("*", "*", kind),
+                [version, kind] => ("", version, kind),
+                [group, version, kind] => (group, version, kind),
+                _ => {
+                    notes.add(format!("kind `{}` of {} is not converted", kind, path));
+                    ("*", "*", "*")
+                }
+            };
+            object_rule(group, version, kind, operations.clone(), None)
+        })
+        .collect();
+
+    let mut entry = Entry {
+        object_rules,
+        namespace_selector: resources.namespace_selector.clone(),
+        object_selector: resources.selector.clone(),
+    };
+    if !resources.namespaces.is_empty() {
+        select_namespaces(&mut entry, &resources.namespaces, notes);
+    }
+    Some(entry)
+}
+
+/// Convert a Gatekeeper constraint into a ValidatingRule, with the Rego of its template if given
+///
+/// Templates are looked up by the kind of the constraint.
+pub fn from_gatekeeper(
+    document: &Value,
+    templates: &HashMap<String, Value>,
+) -> Result<ValidatingRule, Error> {
+    let constraint: GatekeeperConstraint = deserialize(document)?;
+    let match_ = constraint.spec.match_;
+
+    let mut notes = Notes::default();
+    for key in match_.others.keys() {
+        notes.add(format!("match.{} is not converted", key));
+    }
+    if let Some(action) = &constraint.spec.enforcement_action {
+        if action != "deny" {
+            notes.add(format!(
+                "enforcementAction is {}, but the rule denies requests",
+                action
+            ));
+        }
+    }
+
+    let operations = DEFAULT_OPERATIONS
+        .iter()
+        .map(|op| op.to_string())
+        .collect::<Vec<_>>();
+    let scope = match_.scope.filter(|scope| scope != "*");
+    let mut object_rules = Vec::new();
+    for kinds in &match_.kinds {
+        let groups = if kinds.api_groups.is_empty() {
+            vec!["*".to_string()]
+        } else {
+            kinds.api_groups.clone()
+        };
+        for group in &groups {
+            for kind in &kinds.kinds {
+                object_rules.push(object_rule(
+                    group,
+                    "*",
+                    kind,
+                    operations.clone(),
+                    scope.clone(),
+                ));
+            }
+        }
+    }
+    let mut entry = Entry {
+        object_rules,
+        namespace_selector: match_.namespace_selector,
+        object_selector: match_.label_selector,
+    };
+    if !match_.namespaces.is_empty() {
+        select_namespaces(&mut entry, &match_.namespaces, &mut notes);
+    }
+    if !match_.excluded_namespaces.is_empty() {
+        exclude_namespaces(&mut entry, &match_.excluded_namespaces, &mut notes);
+    }
+
+    let mut source = serde_json::json!({ "parameters": constraint.spec.parameters });
+    match templates.get(&constraint.kind) {
+        Some(template) => {
+            source["rego"] = template
+                .pointer("/spec/targets")
+                .and_then(Value::as_array)
+                .map(|targets| {
+                    targets
+                        .iter()
+                        .filter_map(|target| target.get("rego"))
+                        .cloned()
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+                .into();
+        }
+        None => notes.add(format!(
+            "ConstraintTemplate of `{}` is not given",
+            constraint.kind
+        )),
+    }
+
+    let constraint_name = constraint.metadata.name.unwrap_or_default();
+    let code = todo_code(
+        &format!(
+            "Gatekeeper constraint `{}` of `{}`",
+            constraint_name, constraint.kind
+        ),
+        "parameters and rego",
+        &source,
+        &notes,
+    )?;
+    Ok(make_rule(constraint_name, vec![entry], None, code))
+}
+
+fn deserialize<T: serde::de::DeserializeOwned>(document: &Value) -> Result<T, Error> {
+    serde_json::from_value(document.clone()).map_err(|source| Error::Deserialize {
+        kind: document["kind"].as_str().unwrap_or_default().to_string(),
+        name: document
+            .pointer("/metadata/name")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        source,
+    })
+}
+
+/// Object rule matching the kind, with the plural guessed from the kind
+fn object_rule(
+    group: &str,
+    version: &str,
+    kind: &str,
+    operations: Vec<String>,
+    scope: Option<String>,
+) -> RuleWithOperations {
+    let resource = if kind == "*" {
+        kind.to_string()
+    } else {
+        ApiResource::from_gvk(&GroupVersionKind::gvk(group, version, kind)).plural
+    };
+    RuleWithOperations {
+        api_groups: Some(vec![group.to_string()]),
+        api_versions: Some(vec![version.to_string()]),
+        operations: Some(operations),
+        resources: Some(vec![resource]),
+        scope,
+    }
+}
+
+/// Whether the namespaces can be matched by `kubernetes.io/metadata.name` label, without wildcards
+fn exact_namespaces(namespaces: &[String], notes: &mut Notes) -> bool {
+    let exact = namespaces
+        .iter()
+        .all(|namespace| !namespace.contains(['*', '?']));
+    if !exact {
+        notes.add(format!(
+            "namespaces with wildcards are not converted: {:?}",
+            namespaces
+        ));
+    }
+    exact
+}
+
+fn select_namespaces(entry: &mut Entry, namespaces: &[String], notes: &mut Notes) {
+    if exact_namespaces(namespaces, notes) {
+        add_namespace_requirement(entry, "In", namespaces);
+    }
+}
+
+fn exclude_namespaces(entry: &mut Entry, namespaces: &[String], notes: &mut Notes) {
+    if exact_namespaces(namespaces, notes) {
+        add_namespace_requirement(entry, "NotIn", namespaces);
+    }
+}
+
+fn add_namespace_requirement(entry: &mut Entry, operator: &str, namespaces: &[String]) {
+    entry
+        .namespace_selector
+        .get_or_insert_with(Default::default)
+        .match_expressions
+        .get_or_insert_with(Vec::new)
+        .push(LabelSelectorRequirement {
+            key: "kubernetes.io/metadata.name".to_string(),
+            operator: operator.to_string(),
+            values: Some(namespaces.to_vec()),
+        });
+}
+
+/// JS code that allows every request, with the source logic and the notes in a TODO comment
+fn todo_code(origin: &str, label: &str, source: &Value, notes: &Notes) -> Result<String, Error> {
+    let source = serde_json::to_string_pretty(source).map_err(Error::Serialize)?;
+    let mut code = format!("// TODO: Port {} to JS.\n//\n// {}:\n", origin, label);
+    for line in source.lines() {
+        code += &format!("//   {}\n", line);
+    }
+    if !notes.0.is_empty() {
+        code += "//\n// Review before enabling the rule:\n";
+        for note in &notes.0 {
+            code += &format!("// - {}\n", note);
+        }
+    }
+    code += "const request = getRequest();\n";
+    Ok(code)
+}
+
+/// ValidatingRule with the entries as its match criteria, or as webhook entries if there are many
+fn make_rule(
+    name: String,
+    mut entries: Vec<Entry>,
+    failure_policy: Option<FailurePolicy>,
+    code: String,
+) -> ValidatingRule {
+    let mut spec = RuleSpec {
+        failure_policy,
+        namespace_selector: None,
+        object_selector: None,
+        object_rules: None,
+        timeout_seconds: None,
+        reinvocation_policy: None,
+        priority: None,
+        match_policy: None,
+        match_conditions: None,
+        webhooks: None,
+        side_effects: None,
+        client_config: None,
+        include_checkpoint_namespace: false,
+        service_account: None,
+        kube_client_mode: None,
+        access: None,
+        limits: None,
+        rollout: None,
+        recording: None,
+        notifications: None,
+        requires: None,
+        code,
+    };
+    if entries.len() == 1 {
+        let entry = entries.remove(0);
+        spec.object_rules = Some(entry.object_rules);
+        spec.namespace_selector = entry.namespace_selector;
+        spec.object_selector = entry.object_selector;
+    } else if !entries.is_empty() {
+        spec.webhooks = Some(
+            entries
+                .into_iter()
+                .enumerate()
+                .map(|(i, entry)| RuleWebhook {
+                    name: format!("match-{}", i),
+                    namespace_selector: entry.namespace_selector,
+                    object_selector: entry.object_selector,
+                    object_rules: Some(entry.object_rules),
+                    timeout_seconds: None,
+                    match_conditions: None,
+                })
+                .collect(),
+        );
+    }
+    ValidatingRule::new(&name, ValidatingRuleSpec(spec))
+}
@@ -1,4 +1,5 @@
 pub mod helper;
+pub mod stub;
 
 use deno_core::{Extension, JsRuntime, RuntimeOptions};
 use serde::Serialize;
@@ -37,7 +38,7 @@ pub fn set_context<T>(
     value: &T,
 ) -> anyhow::Result<()>
 where
-    T: Serialize,
+    T: Serialize + ?Sized,
 {
     js_runtime.execute_script(
         "<checkpoint>",
@@ -50,3 +51,102 @@ where
     )?;
     Ok(())
 }
+
+/// Execute the code as a script, consuming the V8 code cache of a previous execution if given
+///
+/// Returns the code cache after the execution if none is given, which includes the functions
+/// compiled while running, to pass to later executions of the same code.
+pub fn execute_script_with_code_cache(
+    js_runtime: &mut JsRuntime,
+    code: &str,
+    code_cache: Option<&[u8]>,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    use deno_core::v8::{
+        self,
+        script_compiler::{CompileOptions, NoCacheReason, Source},
+    };
+
+    let scope = &mut js_runtime.handle_scope();
+    let source = v8::String::new(scope, code)
+        .ok_or_else(|| anyhow::anyhow!("failed to allocate the code"))?;
+    let name = v8::String::new(scope, "<checkpoint>").unwrap();
+    let source_map_url = v8::undefined(scope).into();
+    let origin = v8::ScriptOrigin::new(
+        scope,
+        name.into(),
+        0,
+        0,
+        false,
+        0,
+        source_map_url,
+        false,
+        false,
+        false,
+    );
+
+    // V8 compiles the code again if the cache does not match the code
+    let (source, options) = match code_cache {
+        Some(code_cache) => (
+            Source::new_with_cached_data(source, Some(&origin), v8::CachedData::new(code_cache)),
+            CompileOptions::ConsumeCodeCache,
+        ),
+        None => (
+            Source::new(source, Some(&origin)),
+            CompileOptions::NoCompileOptions,
+        ),
+    };
+
+    let scope = &mut v8::TryCatch::new(scope);
+    let script = v8::script_compiler::compile(scope, source, options, NoCacheReason::NoReason);
+    let Some(script) = script else {
+        return Err(caught_exception(scope));
+    };
+    if script.run(scope).is_none() {
+        return Err(caught_exception(scope));
+    }
+
+    if code_cache.is_some() {
+        return Ok(None);
+    }
+    let code_cache = script.get_unbound_script(scope).create_code_cache();
+    Ok(code_cache.map(|code_cache| code_cache.to_vec()))
+}
+
+fn caught_exception(
+    scope: &mut deno_core::v8::TryCatch<deno_core::v8::HandleScope>,
+) -> anyhow::Error {
+    match scope.exception() {
+        Some(exception) => deno_core::error::JsError::from_v8_exception(scope, exception).into(),
+        None => anyhow::anyhow!("failed to execute the code"),
+    }
+}
+
+/// Whether the code calls `kubeGet` or `kubeList` to access Kubernetes resources
+pub fn uses_kube_api(code: &str) -> bool {
+    code.contains("kubeGet") || code.contains("kubeList")
+}
+
+/// Compile the code as a script without executing it, returning the syntax error if any
+pub fn check_syntax(code: &str) -> Result<(), String> {
+    let mut js_runtime = JsRuntime::new(RuntimeOptions::default());
+    let scope = &mut js_runtime.handle_scope();
+    let scope = &mut deno_core::v8::TryCatch::new(scope);
+
+    let source = deno_core::v8::String::new(scope, code)
+        .ok_or_else(|| "failed to allocate the code".to_string())?;
+    if deno_core::v8::Script::compile(scope, source, None).is_some() {
+        return Ok(());
+    }
+
+    let exception = scope
+        .exception()
+        .map(|exception| exception.to_rust_string_lossy(scope))
+        .unwrap_or_else(|| "unknown syntax error".to_string());
+    match scope
+        .message()
+        .and_then(|message| message.get_line_number(scope))
+    {
+        Some(line) => Err(format!("line {}: {}", line, exception)),
+        None => Err(exception),
+    }
+}
@@ -0,0 +1,26 @@
+function kubeGet(args) {
+  return Deno.core.ops.ops_stub_kube_get(args);
+}
+function kubeList(args) {
+  return Deno.core.ops.ops_stub_kube_list(args);
+}
+function kubeDiscover() {
+  return Deno.core.ops.ops_stub_kube_discover();
+}
+{
+  const now = Deno.core.ops.ops_stub_now();
+  if (now !== null) {
+    const RealDate = Date;
+    // A function rather than a class, as `Date()` is also called without `new`
+    const StubDate = function Date(...args) {
+      if (new.target === undefined) {
+        return new RealDate(now).toString();
+      }
+      return Reflect.construct(RealDate, args.length === 0 ? [now] : args, new.target);
+    };
+    Object.setPrototypeOf(StubDate, RealDate);
+    StubDate.prototype = RealDate.prototype;
+    StubDate.now = () => now;
+    globalThis.Date = StubDate;
+  }
+}
@@ -0,0 +1,161 @@
+//! Stubs of `kubeGet`, `kubeList`, `kubeDiscover` and the current time to evaluate rules and policies without a cluster, for tests
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use deno_core::{op, JsRuntime, OpState};
+use kube::core::{DynamicObject, ObjectList};
+
+use crate::handler::js::helper::{DiscoveredResource, KubeGetArgument, KubeListArgument};
+
+deno_core::extension!(
+    checkpoint_stub,
+    ops = [
+        ops_stub_kube_get,
+        ops_stub_kube_list,
+        ops_stub_kube_discover,
+        ops_stub_now
+    ],
+    options = { stubs: Arc<Stubs> },
+    state = |state, config| {
+        state.put(config.stubs);
+    },
+);
+
+/// Objects returned by `kubeGet` and `kubeList` for the arguments
+#[derive(Debug, Default)]
+pub struct Stubs {
+    pub kube_get: HashMap<KubeGetArgument, Option<DynamicObject>>,
+    pub kube_list: HashMap<KubeListArgument, ObjectList<DynamicObject>>,
+    /// Resources returned by `kubeDiscover`
+    pub kube_discover: Option<Vec<DiscoveredResource>>,
+    /// Current time seen by `Date`, or the real time if not given
+    pub now: Option<DateTime<Utc>>,
+    calls: Mutex<StubCalls>,
+}
+
+/// Number of calls for each argument, including the ones without stubs
+#[derive(Debug, Default, Clone)]
+pub struct StubCalls {
+    pub kube_get: HashMap<KubeGetArgument, usize>,
+    pub kube_list: HashMap<KubeListArgument, usize>,
+}
+
+impl StubCalls {
+    /// Total number of `kubeGet` calls
+    pub fn kube_get_total(&self) -> usize {
+        self.kube_get.values().sum()
+    }
+
+    /// Total number of `kubeList` calls
+    pub fn kube_list_total(&self) -> usize {
+        self.kube_list.values().sum()
+    }
+}
+
+impl Stubs {
+    pub fn new(
+        kube_get: HashMap<KubeGetArgument, Option<DynamicObject>>,
+        kube_list: HashMap<KubeListArgument, ObjectList<DynamicObject>>,
+    ) -> Self {
+        Self {
+            kube_get,
+            kube_list: kube_list
+                .into_iter()
+                .map(|(args, list)| (normalize_kube_list_argument(args), list))
+                .collect(),
+            kube_discover: None,
+            now: None,
+            calls: Default::default(),
+        }
+    }
+
+    /// Calls recorded so far
+    pub fn calls(&self) -> StubCalls {
+        self.calls.lock().unwrap().clone()
+    }
+}
+
+/// Treat empty `listParams` same as the one not given
+fn normalize_kube_list_argument(mut args: KubeListArgument) -> KubeListArgument {
+    if args.list_params == Some(Default::default()) {
+        args.list_params = None;
+    }
+    args
+}
+
+/// Replace `kubeGet`, `kubeList`, `kubeDiscover` and `Date` with the stubs
+///
+/// The runtime must have the `checkpoint_stub` extension, and the code defining them must have run already.
+pub fn replace_with_stubs(js_runtime: &mut JsRuntime) -> anyhow::Result<()> {
+    js_runtime.execute_script_static("<checkpoint>", include_str!("stub.js"))?;
+    Ok(())
+}
+
+/// Stub of `kubeGet`, failing if no stub matches the argument
+#[op]
+fn ops_stub_kube_get(
+    state: &mut OpState,
+    args: KubeGetArgument,
+) -> anyhow::Result<Option<DynamicObject>> {
+    let stubs = state.borrow::<Arc<Stubs>>();
+    *stubs
+        .calls
+        .lock()
+        .unwrap()
+        .kube_get
+        .entry(args.clone())
+        .or_default() += 1;
+    stubs
+        .kube_get
+        .get(&args)
+        .cloned()
+        .ok_or_else(|| anyhow!("kubeGet stub not found for {}", serde_json::json!(args)))
+}
+
+/// Stub of `kubeList`, failing if no stub matches the argument
+#[op]
+fn ops_stub_kube_list(
+    state: &mut OpState,
+    args: KubeListArgument,
+) -> anyhow::Result<ObjectList<DynamicObject>> {
+    let args = normalize_kube_list_argument(args);
+    let stubs = state.borrow::<Arc<Stubs>>();
+    *stubs
+        .calls
+        .lock()
+        .unwrap()
+        .kube_list
+        .entry(args.clone())
+        .or_default() += 1;
+    // ObjectList is not Clone
+    stubs
+        .kube_list
+        .get(&args)
+        .map(|list| ObjectList {
+            metadata: list.metadata.clone(),
+            items: list.items.clone(),
+        })
+        .ok_or_else(|| anyhow!("kubeList stub not found for {}", serde_json::json!(args)))
+}
+
+/// Stub of `kubeDiscover`, failing if no stub is given
+#[op]
+fn ops_stub_kube_discover(state: &mut OpState) -> anyhow::Result<Vec<DiscoveredResource>> {
+    let stubs = state.borrow::<Arc<Stubs>>();
+    stubs
+        .kube_discover
+        .clone()
+        .ok_or_else(|| anyhow!("kubeDiscover stub not found"))
+}
+
+/// Stubbed current time in milliseconds since the Unix epoch, as `Date.now()` returns
+#[op]
+fn ops_stub_now(state: &mut OpState) -> Option<i64> {
+    let stubs = state.borrow::<Arc<Stubs>>();
+    stubs.now.map(|now| now.timestamp_millis())
+}
@@ -11,26 +11,41 @@ use kube::{
     api::{Api, ObjectMeta, Patch, PatchParams, PostParams},
     Client,
 };
-use tokio::{sync::oneshot::Sender, task::JoinHandle};
+use rand::Rng;
+use tokio::{
+    sync::{oneshot::Sender, watch},
+    task::JoinHandle,
+};
 
-const LEASE_DURATION_SECONDS: u64 = 5;
+/// Renew the lease at this fraction of the renew deadline, so that a few failed renewals are tolerated
+const RENEW_INTERVAL_FRACTION: f64 = 1.0 / 3.0;
+/// Maximum jitter of the renew interval as a fraction of it, to spread renewals of replicas
+const RENEW_INTERVAL_JITTER: f64 = 0.1;
 
 pub struct Lease {
     join_handle: JoinHandle<()>,
     sender: Sender<()>,
+    lost: watch::Receiver<bool>,
 }
 
 impl Lease {
+    /// Acquire the lease, waiting until the current holder releases it or it expires
+    ///
+    /// The holder gives up the lease if it fails to renew it within `renew_deadline`,
+    /// which should be shorter than `lease_duration` so that it stops before others acquire the lease.
     pub async fn acquire_or_create(
         kube_api_client: Client,
         ns: &str,
         lease_name: &str,
         identity: &str,
+        lease_duration: Duration,
+        renew_deadline: Duration,
     ) -> Result<Lease, kube::Error> {
+        let lease_duration_seconds = lease_duration.as_secs() as i32;
         let lease_api: Api<KubeLease> = kube::Api::namespaced(kube_api_client.clone(), ns);
 
         // check for lease
-        let lease = loop {
+        loop {
             let get_lease = lease_api.get_opt(lease_name).await?;
 
             // If lease exists
@@ -51,17 +66,17 @@ impl Lease {
                     }
                     spec.acquire_time = Some(now());
                     spec.renew_time = None;
-                    spec.lease_duration_seconds = Some(LEASE_DURATION_SECONDS as i32);
+                    spec.lease_duration_seconds = Some(lease_duration_seconds);
                     spec.holder_identity = Some(identity.to_string());
 
-                    lease = lease_api
+                    lease_api
                         .patch(
                             lease_name,
                             &PatchParams::apply("checkpoint.devsisters.com").force(),
                             &Patch::Apply(&lease),
                         )
                         .await?;
-                    break lease;
+                    break;
                 } else {
                     // If the existing lease is not expired, wait until lease is expired
                     let wait_time = match lease.spec {
@@ -69,14 +84,14 @@ impl Lease {
                             lease_duration_seconds: Some(lds),
                             ..
                         }) => lds as u64,
-                        _ => LEASE_DURATION_SECONDS,
+                        _ => lease_duration.as_secs(),
                     };
                     tokio::time::sleep(Duration::from_secs(wait_time)).await;
                     continue;
                 }
             } else {
                 // If lease is not exists, create one
-                let lease = lease_api
+                lease_api
                     .create(
                         &PostParams::default(),
                         &KubeLease {
@@ -87,7 +102,7 @@ impl Lease {
                             },
                             spec: Some(KubeLeaseSpec {
                                 acquire_time: Some(now()),
-                                lease_duration_seconds: Some(LEASE_DURATION_SECONDS as i32),
+                                lease_duration_seconds: Some(lease_duration_seconds),
                                 holder_identity: Some(identity.to_string()),
                                 lease_transitions: Some(1),
                                 ..Default::default()
@@ -95,47 +110,54 @@ impl Lease {
                         },
                     )
                     .await?;
-                break lease;
+                break;
             }
-        };
+        }
 
         // Oneshot channel to shutdown task
         let (sender, mut recv) = tokio::sync::oneshot::channel();
+        // Watch channel to notify losing the lease
+        let (lost_sender, lost) = watch::channel(false);
 
         // Prepare fields for renewed lease resource
         let renew_object_name = lease_name.to_string();
-        let renew_lease_duration_seconds =
-            lease.spec.as_ref().unwrap().lease_duration_seconds.unwrap();
-        // Subtract one second to ensure no time lag issue
-        let renew_lease_duration_seconds = renew_lease_duration_seconds - 1;
+        let identity = identity.to_string();
+        let renew_interval = renew_deadline.mul_f64(RENEW_INTERVAL_FRACTION);
 
         // Spawn a task that renews lease object
         let join_handle = tokio::spawn(async move {
-            let mut interval = tokio::time::interval(std::time::Duration::from_secs(
-                renew_lease_duration_seconds as u64,
-            ));
+            let mut last_renew = tokio::time::Instant::now();
 
             loop {
+                let jitter = rand::thread_rng()
+                    .gen_range(1.0 - RENEW_INTERVAL_JITTER..=1.0 + RENEW_INTERVAL_JITTER);
                 tokio::select! {
-                    _ = interval.tick() => (),
+                    _ = tokio::time::sleep(renew_interval.mul_f64(jitter)) => (),
                     _ = &mut recv => {
                         // If shutdown signal is received, break
                         break
                     }
                 }
 
-                // Renew lease
-                let patch_params = PatchParams::apply("checkpoint.devsisters.com");
-                let patch = serde_json::json!({
-                    "spec": {
-                        "renewTime": now(),
+                // Renew lease if still held
+                match renew(&lease_api, &renew_object_name, &identity).await {
+                    Ok(true) => {
+                        last_renew = tokio::time::Instant::now();
+                    }
+                    Ok(false) => {
+                        tracing::error!("lease is taken by another holder");
+                        let _ = lost_sender.send(true);
+                        return;
+                    }
+                    Err(error) => {
+                        tracing::error!(%error, "failed to renew lease");
+                        // Others may acquire the lease after the lease duration from the last renewal
+                        if last_renew.elapsed() >= renew_deadline {
+                            tracing::error!("lease expired without renewal");
+                            let _ = lost_sender.send(true);
+                            return;
+                        }
                     }
-                });
-                if let Err(error) = lease_api
-                    .patch(&renew_object_name, &patch_params, &Patch::Merge(patch))
-                    .await
-                {
-                    tracing::error!(%error, "failed to renew lease");
                 }
             }
 
@@ -159,15 +181,56 @@ impl Lease {
         Ok(Lease {
             join_handle,
             sender,
+            lost,
         })
     }
 
+    /// Wait until the lease is lost to another holder or expires without renewal
+    pub fn lost(&self) -> impl std::future::Future<Output = ()> {
+        let mut lost = self.lost.clone();
+        async move {
+            // The sender is dropped without sending when the lease is released
+            if lost.wait_for(|lost| *lost).await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// Whether the lease has been lost
+    pub fn is_lost(&self) -> bool {
+        *self.lost.borrow()
+    }
+
+    /// Stop renewing and release the lease unless it has been lost
     pub async fn join(self) -> Result<(), tokio::task::JoinError> {
-        self.sender.send(()).unwrap();
+        // The task has already finished if the lease is lost
+        let _ = self.sender.send(());
         self.join_handle.await
     }
 }
 
+/// Renew the lease if it is held by the identity, returning whether it is still held
+///
+/// Replacing with the fetched resource version fails if another holder updated the lease in between.
+async fn renew(
+    lease_api: &Api<KubeLease>,
+    lease_name: &str,
+    identity: &str,
+) -> Result<bool, kube::Error> {
+    let mut lease = lease_api.get(lease_name).await?;
+    let Some(spec) = lease.spec.as_mut() else {
+        return Ok(false);
+    };
+    if spec.holder_identity.as_deref() != Some(identity) {
+        return Ok(false);
+    }
+    spec.renew_time = Some(now());
+    lease_api
+        .replace(lease_name, &PostParams::default(), &lease)
+        .await?;
+    Ok(true)
+}
+
 fn now() -> MicroTime {
     let local_now = Local::now();
     MicroTime(local_now.with_timezone(&Utc))
@@ -1,9 +1,20 @@
+pub mod audit;
+pub mod bootstrap;
+pub mod cert;
 pub mod checker;
+pub mod cluster;
 pub mod config;
+pub mod events;
 pub mod filewatcher;
 pub mod handler;
+pub mod import;
 pub mod js;
 pub mod leader_election;
+pub mod lint;
+pub mod log_level;
+pub mod metrics;
 pub mod reconcile;
+pub mod recording;
+pub mod tls;
 pub mod types;
 pub mod util;
@@ -0,0 +1,608 @@
+//! Static checks of Rules and Policies, to catch mistakes before applying them to a cluster
+
+use std::fmt;
+
+use k8s_openapi::{
+    api::admissionregistration::v1::RuleWithOperations,
+    apiextensions_apiserver::pkg::apis::apiextensions::v1::{
+        JSONSchemaProps, JSONSchemaPropsOrArray, JSONSchemaPropsOrBool,
+    },
+};
+use kube::CustomResourceExt;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{
+    handler::internal::check_value_from,
+    types::{
+        policy::{
+            CronPolicy, CronPolicyNotification, CronPolicyNotificationSlack,
+            CronPolicyNotificationTls, CronPolicyResource, CronPolicyValueFrom, Policy,
+        },
+        rule::{
+            KubeClientMode, MutatingRule, NamespacedMutatingRule, NamespacedValidatingRule,
+            RuleAccess, RuleSpec, ValidatingRule,
+        },
+    },
+};
+
+/// API version of all resources of checkpoint
+const API_VERSION: &str = "checkpoint.devsisters.com/v1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// The resource is rejected or does not work at all
+    Error,
+    /// The resource works, but probably not as intended
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// Problem found in a resource
+#[derive(Clone, Debug)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Path to the field with the problem, e.g. `spec.objectRules[0]`
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.severity, self.path, self.message)
+    }
+}
+
+#[derive(Default)]
+struct Findings(Vec<Finding>);
+
+impl Findings {
+    fn error(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Error, path, message);
+    }
+
+    fn warning(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Warning, path, message);
+    }
+
+    fn push(&mut self, severity: Severity, path: impl Into<String>, message: impl Into<String>) {
+        self.0.push(Finding {
+            severity,
+            path: path.into(),
+            message: message.into(),
+        });
+    }
+
+    fn has_error(&self) -> bool {
+        self.0
+            .iter()
+            .any(|finding| finding.severity == Severity::Error)
+    }
+}
+
+/// Check a ValidatingRule, MutatingRule, NamespacedValidatingRule, NamespacedMutatingRule, CronPolicy, or Policy
+///
+/// JS code is compiled but never executed.
+pub fn lint_document(document: &Value) -> Vec<Finding> {
+    let mut findings = Findings::default();
+
+    let api_version = document.get("apiVersion").and_then(Value::as_str);
+    if api_version != Some(API_VERSION) {
+        findings.error(
+            "apiVersion",
+            format!("apiVersion must be `{}`", API_VERSION),
+        );
+        return findings.0;
+    }
+
+    match document.get("kind").and_then(Value::as_str) {
+        Some("ValidatingRule") => {
+            lint_resource::<ValidatingRule>(document, &mut findings, |rule, findings| {
+                lint_rule(&rule.spec.0, findings)
+            })
+        }
+        Some("MutatingRule") => {
+            lint_resource::<MutatingRule>(document, &mut findings, |rule, findings| {
+                lint_rule(&rule.spec.0, findings)
+            })
+        }
+        Some("NamespacedValidatingRule") => {
+            lint_resource::<NamespacedValidatingRule>(document, &mut findings, |rule, findings| {
+                lint_rule(&rule.spec.0, findings);
+                lint_namespaced_rule(&rule.spec.0, findings);
+            })
+        }
+        Some("NamespacedMutatingRule") => {
+            lint_resource::<NamespacedMutatingRule>(document, &mut findings, |rule, findings| {
+                lint_rule(&rule.spec.0, findings);
+                lint_namespaced_rule(&rule.spec.0, findings);
+            })
+        }
+        Some("CronPolicy") => {
+            lint_resource::<CronPolicy>(document, &mut findings, |policy, findings| {
+                let spec = &policy.spec;
+                lint_policy(
+                    &spec.code,
+                    spec.code_from.as_ref(),
+                    spec.notifications_from.as_ref(),
+                    &spec.notifications,
+                    &spec.resources,
+                    spec.access.as_deref(),
+                    findings,
+                )
+            })
+        }
+        Some("Policy") => lint_resource::<Policy>(document, &mut findings, |policy, findings| {
+            let spec = &policy.spec;
+            lint_policy(
+                &spec.code,
+                spec.code_from.as_ref(),
+                spec.notifications_from.as_ref(),
+                &spec.notifications,
+                &spec.resources,
+                spec.access.as_deref(),
+                findings,
+            )
+        }),
+        Some(kind) => findings.error("kind", format!("unsupported kind `{}`", kind)),
+        None => findings.error("kind", "kind is not specified"),
+    }
+
+    findings.0
+}
+
+/// Validate the spec against the CRD schema, then run the checks on the deserialized resource
+fn lint_resource<K>(document: &Value, findings: &mut Findings, lint: impl FnOnce(&K, &mut Findings))
+where
+    K: CustomResourceExt + DeserializeOwned,
+{
+    let crd = K::crd();
+    let spec_schema = crd
+        .spec
+        .versions
+        .first()
+        .and_then(|version| version.schema.as_ref())
+        .and_then(|validation| validation.open_api_v3_schema.as_ref())
+        .and_then(|schema| schema.properties.as_ref())
+        .and_then(|properties| properties.get("spec"));
+    match (spec_schema, document.get("spec")) {
+        (Some(schema), Some(spec)) => check_schema(schema, spec, "spec", findings),
+        (_, None) => findings.error("spec", "spec is not specified"),
+        (None, _) => {}
+    }
+
+    match serde_json::from_value::<K>(document.clone()) {
+        Ok(resource) => lint(&resource, findings),
+        // Schema errors already explain why it cannot be deserialized
+        Err(_) if findings.has_error() => {}
+        Err(error) => findings.error("", format!("failed to deserialize: {}", error)),
+    }
+}
+
+/// Validate the value against the structural schema, as the API server would
+fn check_schema(schema: &JSONSchemaProps, value: &Value, path: &str, findings: &mut Findings) {
+    // The API server prunes nulls of non-nullable fields
+    if value.is_null() {
+        return;
+    }
+    if schema.x_kubernetes_int_or_string == Some(true) {
+        if !value.is_i64() && !value.is_u64() && !value.is_string() {
+            findings.error(path, "must be an integer or a string");
+        }
+        return;
+    }
+    if let Some(enum_) = &schema.enum_ {
+        if !enum_.iter().any(|variant| &variant.0 == value) {
+            let variants = enum_.iter().map(|variant| variant.0.to_string());
+            findings.error(
+                path,
+                format!("must be one of {}", itertools::join(variants, ", ")),
+            );
+            return;
+        }
+    }
+
+    match schema.type_.as_deref() {
+        Some("object") => {
+            let Some(object) = value.as_object() else {
+                findings.error(path, "must be an object");
+                return;
+            };
+            for required in schema.required.iter().flatten() {
+                if !object.contains_key(required) {
+                    findings.error(
+                        format!("{}.{}", path, required),
+                        "required field is missing",
+                    );
+                }
+            }
+            for (key, field) in object {
+                let field_path = format!("{}.{}", path, key);
+                if let Some(field_schema) = schema
+                    .properties
+                    .as_ref()
+                    .and_then(|properties| properties.get(key))
+                {
+                    check_schema(field_schema, field, &field_path, findings);
+                    continue;
+                }
+                match &schema.additional_properties {
+                    Some(JSONSchemaPropsOrBool::Schema(field_schema)) => {
+                        check_schema(field_schema, field, &field_path, findings)
+                    }
+                    Some(JSONSchemaPropsOrBool::Bool(true)) => {}
+                    _ if schema.x_kubernetes_preserve_unknown_fields == Some(true) => {}
+                    _ => findings.warning(field_path, "unknown field is dropped when applied"),
+                }
+            }
+        }
+        Some("array") => {
+            let Some(array) = value.as_array() else {
+                findings.error(path, "must be an array");
+                return;
+            };
+            if let Some(JSONSchemaPropsOrArray::Schema(item_schema)) = &schema.items {
+                for (i, item) in array.iter().enumerate() {
+                    check_schema(item_schema, item, &format!("{}[{}]", path, i), findings);
+                }
+            }
+        }
+        Some("string") if !value.is_string() => findings.error(path, "must be a string"),
+        Some("integer") if !value.is_i64() && !value.is_u64() => {
+            findings.error(path, "must be an integer")
+        }
+        Some("number") if !value.is_number() => findings.error(path, "must be a number"),
+        Some("boolean") if !value.is_boolean() => findings.error(path, "must be a boolean"),
+        _ => {}
+    }
+
+    if let (Some(minimum), Some(number)) = (schema.minimum, value.as_f64()) {
+        if number < minimum {
+            findings.error(
+                path,
+                format!("must be greater than or equal to {}", minimum),
+            );
+        }
+    }
+}
+
+/// Whether the code calls `kubeGet` or `kubeList`
+/// Compile the code to find syntax errors
+fn check_code(code: &str, path: &str, findings: &mut Findings) {
+    if let Err(error) = crate::js::check_syntax(code) {
+        findings.error(path, format!("syntax error: {}", error));
+    }
+}
+
+/// Fields of admission requests, which `spec.requires` can start with
+const REQUEST_FIELDS: &[&str] = &[
+    "uid",
+    "kind",
+    "resource",
+    "subResource",
+    "requestKind",
+    "requestResource",
+    "requestSubResource",
+    "name",
+    "namespace",
+    "operation",
+    "userInfo",
+    "object",
+    "oldObject",
+    "dryRun",
+    "options",
+];
+
+/// Check the fields namespaced Rules ignore are not set
+fn lint_namespaced_rule(spec: &RuleSpec, findings: &mut Findings) {
+    if spec.notifications.is_some() {
+        findings.error(
+            "spec.notifications",
+            "notifications are not allowed in namespaced rules",
+        );
+    }
+}
+
+fn lint_rule(spec: &RuleSpec, findings: &mut Findings) {
+    check_code(&spec.code, "spec.code", findings);
+
+    if let Some(notifications) = &spec.notifications {
+        if !notifications.routes.is_empty() {
+            findings.warning(
+                "spec.notifications.routes",
+                "routes are not used, as denials have no severity",
+            );
+        }
+        if !crate::checker::notification_credentials(notifications).is_empty() {
+            findings.error(
+                "spec.notifications",
+                "credentials read from ConfigMaps or Secrets are not available to the webhook",
+            );
+        }
+    }
+
+    for (i, path) in spec.requires.iter().flatten().enumerate() {
+        let root = path.split('.').next().unwrap_or_default();
+        if !REQUEST_FIELDS.contains(&root) {
+            findings.warning(
+                format!("spec.requires[{}]", i),
+                format!(
+                    "`{}` is not a field of admission requests, so no request has it",
+                    root
+                ),
+            );
+        }
+    }
+
+    let no_object_rules = |object_rules: &Option<Vec<_>>| {
+        object_rules
+            .as_ref()
+            .map_or(true, |object_rules| object_rules.is_empty())
+    };
+    match &spec.webhooks {
+        Some(webhooks) if !webhooks.is_empty() => {
+            for (i, webhook) in webhooks.iter().enumerate() {
+                if no_object_rules(&webhook.object_rules) && no_object_rules(&spec.object_rules) {
+                    findings.warning(
+                        format!("spec.webhooks[{}].objectRules", i),
+                        "objectRules is not specified, so the webhook matches no request",
+                    );
+                }
+            }
+        }
+        _ => {
+            if no_object_rules(&spec.object_rules) {
+                findings.warning(
+                    "spec.objectRules",
+                    "objectRules is not specified, so the Rule matches no request",
+                );
+            }
+        }
+    }
+
+    let webhooks = spec.webhooks.iter().flatten();
+    let object_rules = spec
+        .object_rules
+        .iter()
+        .map(|object_rules| ("spec.objectRules".to_string(), object_rules))
+        .chain(webhooks.enumerate().filter_map(|(i, webhook)| {
+            let path = format!("spec.webhooks[{}].objectRules", i);
+            webhook
+                .object_rules
+                .as_ref()
+                .map(|object_rules| (path, object_rules))
+        }));
+    for (path, object_rules) in object_rules {
+        for (i, object_rule) in object_rules.iter().enumerate() {
+            check_subresources(object_rule, &format!("{}[{}]", path, i), findings);
+        }
+    }
+
+    let impersonates =
+        spec.kube_client_mode.unwrap_or_default() == KubeClientMode::ImpersonateRequester;
+    match (&spec.service_account, crate::js::uses_kube_api(&spec.code)) {
+        (Some(_), false) => findings.warning(
+            "spec.serviceAccount",
+            "serviceAccount is unused, as the code calls neither kubeGet nor kubeList",
+        ),
+        (Some(_), true) if impersonates => findings.warning(
+            "spec.serviceAccount",
+            "serviceAccount is unused, as kubeGet and kubeList impersonate the requester",
+        ),
+        (None, true) if !impersonates => findings.error(
+            "spec.serviceAccount",
+            "serviceAccount is required to call kubeGet or kubeList",
+        ),
+        _ => {}
+    }
+    if spec.access.is_some() && impersonates {
+        findings.warning(
+            "spec.access",
+            "access is not checked when impersonating the requester",
+        );
+    } else if spec.service_account.is_none() && spec.access.is_some() {
+        findings.warning(
+            "spec.access",
+            "access is not checked without serviceAccount",
+        );
+    }
+
+    if let Some(url) = spec
+        .client_config
+        .as_ref()
+        .and_then(|client_config| client_config.url.as_deref())
+    {
+        // The API server only calls webhooks over HTTPS without query or fragment
+        match url::Url::parse(url) {
+            Ok(url) if url.scheme() != "https" => {
+                findings.error("spec.clientConfig.url", "scheme must be https")
+            }
+            Ok(url) if url.query().is_some() || url.fragment().is_some() => findings.error(
+                "spec.clientConfig.url",
+                "query and fragment are not allowed",
+            ),
+            Ok(_) => {}
+            Err(error) => {
+                findings.error("spec.clientConfig.url", format!("invalid URL: {}", error))
+            }
+        }
+    }
+}
+
+/// Subresources only admitted on CONNECT, as streaming connections to Pods, Services, or Nodes
+const CONNECT_SUBRESOURCES: &[&str] = &["attach", "exec", "portforward", "proxy"];
+
+/// Check subresources of the object rule are written as `<resource>/<subresource>`, and the ones
+/// admitted on CONNECT only are matched with CONNECT
+fn check_subresources(object_rule: &RuleWithOperations, path: &str, findings: &mut Findings) {
+    let connects = object_rule
+        .operations
+        .iter()
+        .flatten()
+        .any(|operation| operation == "CONNECT" || operation == "*");
+    for (i, resource) in object_rule.resources.iter().flatten().enumerate() {
+        let resource_path = format!("{}.resources[{}]", path, i);
+        let Some((parent, subresource)) = resource.split_once('/') else {
+            continue;
+        };
+        if parent.is_empty() || subresource.is_empty() || subresource.contains('/') {
+            findings.error(
+                resource_path,
+                format!(
+                    "`{}` must be in `<resource>/<subresource>` format",
+                    resource
+                ),
+            );
+            continue;
+        }
+        if CONNECT_SUBRESOURCES.contains(&subresource) && !connects {
+            findings.warning(
+                resource_path,
+                format!(
+                    "`{}` is only admitted on CONNECT, which is not in operations",
+                    resource
+                ),
+            );
+        }
+    }
+}
+
+fn lint_policy(
+    code: &str,
+    code_from: Option<&CronPolicyValueFrom>,
+    notifications_from: Option<&CronPolicyValueFrom>,
+    notifications: &CronPolicyNotification,
+    resources: &[CronPolicyResource],
+    access: Option<&[RuleAccess]>,
+    findings: &mut Findings,
+) {
+    if let Some(error) = check_value_from(code, code_from, notifications_from) {
+        findings.error("spec.code", error);
+    }
+    // Notifications in ConfigMaps or Secrets are not available without a cluster
+    if notifications_from.is_none() {
+        let channels = std::iter::once((
+            "spec.notifications".to_string(),
+            &notifications.slack,
+            &notifications.webhook,
+            &notifications.kafka,
+            &notifications.nats,
+        ))
+        .chain(notifications.routes.iter().enumerate().map(|(i, route)| {
+            (
+                format!("spec.notifications.routes[{}]", i),
+                &route.slack,
+                &route.webhook,
+                &route.kafka,
+                &route.nats,
+            )
+        }));
+        for (path, slack, webhook, kafka, nats) in channels {
+            if let Some(slack) = slack {
+                check_slack_notification(slack, &format!("{}.slack", path), findings);
+            }
+            if let Some(webhook) = webhook {
+                if webhook.bearer_token_from.is_some() && webhook.basic_auth_from.is_some() {
+                    findings.warning(
+                        format!("{}.webhook.basicAuthFrom", path),
+                        "basicAuthFrom is ignored with bearerTokenFrom",
+                    );
+                }
+                if webhook.insecure_skip_verify && webhook.ca_from.is_some() {
+                    findings.warning(
+                        format!("{}.webhook.caFrom", path),
+                        "caFrom is ignored with insecureSkipVerify",
+                    );
+                }
+            }
+            if let Some(kafka) = kafka {
+                if kafka.brokers.is_empty() {
+                    findings.error(
+                        format!("{}.kafka.brokers", path),
+                        "no brokers are specified",
+                    );
+                }
+                check_notification_tls(
+                    kafka.tls.as_ref(),
+                    &format!("{}.kafka.tls", path),
+                    findings,
+                );
+            }
+            if let Some(nats) = nats {
+                if nats.username.is_some() != nats.password_from.is_some() {
+                    findings.error(
+                        format!("{}.nats", path),
+                        "username and passwordFrom must be specified together",
+                    );
+                }
+                check_notification_tls(nats.tls.as_ref(), &format!("{}.nats.tls", path), findings);
+            }
+        }
+    }
+    // Code in ConfigMaps or Secrets is not available without a cluster
+    if code_from.is_none() {
+        check_code(code, "spec.code", findings);
+    }
+    if resources.is_empty() {
+        findings.warning("spec.resources", "no resources are passed to the code");
+    }
+    let has_access = access.map_or(false, |access| !access.is_empty());
+    if has_access && code_from.is_none() && !crate::js::uses_kube_api(code) {
+        findings.warning(
+            "spec.access",
+            "access is unused, as the code calls neither kubeGet nor kubeList",
+        );
+    }
+}
+
+/// Check that the Slack notification has the credential for its options
+fn check_slack_notification(
+    slack: &CronPolicyNotificationSlack,
+    path: &str,
+    findings: &mut Findings,
+) {
+    if slack.bot_token_from.is_none() {
+        if slack.webhook_url.is_none() {
+            findings.error(path, "either webhookUrl or botTokenFrom is required");
+        }
+        for (option, used) in [
+            ("channel", slack.channel.is_some()),
+            ("severityChannels", !slack.severity_channels.is_empty()),
+            ("uploadOutput", slack.upload_output),
+            ("thread", slack.thread),
+        ] {
+            if used {
+                findings.warning(
+                    format!("{}.{}", path, option),
+                    format!("{} is ignored without botTokenFrom", option),
+                );
+            }
+        }
+    } else if slack.channel.is_none() && slack.severity_channels.is_empty() {
+        findings.error(
+            format!("{}.channel", path),
+            "channel or severityChannels is required with botTokenFrom",
+        );
+    }
+}
+
+/// Check that the client certificate and its private key are specified together
+fn check_notification_tls(
+    tls: Option<&CronPolicyNotificationTls>,
+    path: &str,
+    findings: &mut Findings,
+) {
+    if let Some(tls) = tls {
+        if tls.cert_from.is_some() != tls.key_from.is_some() {
+            findings.error(path, "certFrom and keyFrom must be specified together");
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Endpoint to change the log level at runtime, shared by the controller and the webhook
+//!
+//! `GET /loglevel` returns the current directives, and `PUT /loglevel` replaces them with the request body
+//! in `RUST_LOG` format (e.g. `info,[admission{rule=my-rule}]=debug`). An empty body restores `RUST_LOG`.
+//! The log level is replaced again when `logLevel` of the CheckpointConfig changes.
+
+use std::{path::PathBuf, sync::Arc};
+
+use axum::{extract, response, routing, Router};
+use http::{header, HeaderMap, StatusCode};
+
+use crate::config::ConfigError;
+
+/// Errors can be raised within log level handlers
+#[derive(thiserror::Error, Debug)]
+enum Error {
+    #[error("endpoint is disabled")]
+    Disabled,
+    #[error("missing or invalid bearer token")]
+    Unauthorized,
+    #[error("failed to read token file: {0}")]
+    ReadToken(#[source] std::io::Error),
+    #[error("{0}")]
+    SetLogLevel(#[source] ConfigError),
+}
+
+impl response::IntoResponse for Error {
+    fn into_response(self) -> response::Response {
+        let status_code = match self {
+            Self::Disabled => StatusCode::NOT_FOUND,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::SetLogLevel(ConfigError::ParseLogLevel(_)) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status_code, self.to_string()).into_response()
+    }
+}
+
+/// Prepare HTTP router of the log level endpoint, authenticated with the bearer token in the file
+///
+/// The token file is read on every request, so that it can be rotated without restarting.
+pub fn create_router<S>(token_path: Option<PathBuf>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/loglevel", routing::get(get_log_level).put(put_log_level))
+        .with_state(Arc::new(token_path))
+}
+
+/// Compare in constant time, not to leak the token through response timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Check the request has the bearer token in the token file
+async fn authenticate(token_path: &Option<PathBuf>, headers: &HeaderMap) -> Result<(), Error> {
+    let token_path = token_path.as_ref().ok_or(Error::Disabled)?;
+    let token = tokio::fs::read_to_string(token_path)
+        .await
+        .map_err(Error::ReadToken)?;
+    let token = token.trim();
+
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(Error::Unauthorized)?;
+    if token.is_empty() || !constant_time_eq(bearer.as_bytes(), token.as_bytes()) {
+        return Err(Error::Unauthorized);
+    }
+    Ok(())
+}
+
+/// Check the request has the bearer token in the token file, for the other internal endpoints
+pub(crate) async fn authorize(
+    token_path: &Option<PathBuf>,
+    headers: &HeaderMap,
+) -> Result<(), response::Response> {
+    authenticate(token_path, headers)
+        .await
+        .map_err(response::IntoResponse::into_response)
+}
+
+async fn get_log_level(
+    extract::State(token_path): extract::State<Arc<Option<PathBuf>>>,
+    headers: HeaderMap,
+) -> Result<String, Error> {
+    authenticate(&token_path, &headers).await?;
+    Ok(crate::config::log_level())
+}
+
+async fn put_log_level(
+    extract::State(token_path): extract::State<Arc<Option<PathBuf>>>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<String, Error> {
+    authenticate(&token_path, &headers).await?;
+
+    let directives = body.trim();
+    let directives = (!directives.is_empty()).then_some(directives);
+    crate::config::set_log_level(directives).map_err(Error::SetLogLevel)?;
+    Ok(crate::config::log_level())
+}
@@ -0,0 +1,265 @@
+//! Metrics and health endpoints of the controller, and metrics shared with the webhook
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use axum::{http::StatusCode, routing, Router};
+use chrono::{DateTime, Utc};
+
+/// Metrics of the controller process
+pub struct ControllerMetrics {
+    /// Controller name -> (succeeded, failed) reconcile counts
+    reconciles: Mutex<BTreeMap<&'static str, (u64, u64)>>,
+    /// Whether this instance holds the leader lease
+    leader: AtomicBool,
+    /// Whether this instance is shutting down
+    shutting_down: AtomicBool,
+}
+
+pub static CONTROLLER_METRICS: ControllerMetrics = ControllerMetrics::new();
+
+impl ControllerMetrics {
+    const fn new() -> Self {
+        Self {
+            reconciles: Mutex::new(BTreeMap::new()),
+            leader: AtomicBool::new(false),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    /// Count a reconcile of the controller
+    pub fn record_reconcile(&self, controller: &'static str, success: bool) {
+        let mut reconciles = self.reconciles.lock().unwrap();
+        let (succeeded, failed) = reconciles.entry(controller).or_default();
+        if success {
+            *succeeded += 1;
+        } else {
+            *failed += 1;
+        }
+    }
+
+    pub fn set_leader(&self, leader: bool) {
+        self.leader.store(leader, Ordering::Relaxed);
+    }
+
+    pub fn set_shutting_down(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+    }
+
+    /// Render the metrics in Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut metrics = String::new();
+
+        metrics.push_str("# HELP checkpoint_controller_reconciles_total Number of reconciles.\n");
+        metrics.push_str("# TYPE checkpoint_controller_reconciles_total counter\n");
+        for (controller, (succeeded, failed)) in self.reconciles.lock().unwrap().iter() {
+            let _ = writeln!(
+                metrics,
+                "checkpoint_controller_reconciles_total{{controller=\"{}\",result=\"success\"}} {}",
+                controller, succeeded
+            );
+            let _ = writeln!(
+                metrics,
+                "checkpoint_controller_reconciles_total{{controller=\"{}\",result=\"error\"}} {}",
+                controller, failed
+            );
+        }
+
+        metrics.push_str(
+            "# HELP checkpoint_controller_leader Whether this instance holds the leader lease.\n",
+        );
+        metrics.push_str("# TYPE checkpoint_controller_leader gauge\n");
+        let _ = writeln!(
+            metrics,
+            "checkpoint_controller_leader {}",
+            self.leader.load(Ordering::Relaxed) as u8
+        );
+
+        metrics
+    }
+}
+
+/// Metrics of TLS certificates, shared by the controller and the webhook
+pub struct TlsMetrics {
+    /// Certificate name -> expiry as Unix timestamp
+    cert_expiries: Mutex<BTreeMap<&'static str, i64>>,
+}
+
+pub static TLS_METRICS: TlsMetrics = TlsMetrics::new();
+
+impl TlsMetrics {
+    const fn new() -> Self {
+        Self {
+            cert_expiries: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn set_cert_expiry(&self, cert: &'static str, not_after: DateTime<Utc>) {
+        self.cert_expiries
+            .lock()
+            .unwrap()
+            .insert(cert, not_after.timestamp());
+    }
+
+    /// Render the metrics in Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut metrics = String::new();
+
+        metrics.push_str(
+            "# HELP checkpoint_tls_cert_expiry_timestamp Expiry of the certificate in Unix time.\n",
+        );
+        metrics.push_str("# TYPE checkpoint_tls_cert_expiry_timestamp gauge\n");
+        for (cert, not_after) in self.cert_expiries.lock().unwrap().iter() {
+            let _ = writeln!(
+                metrics,
+                "checkpoint_tls_cert_expiry_timestamp{{cert=\"{}\"}} {}",
+                cert, not_after
+            );
+        }
+
+        metrics
+    }
+}
+
+/// Decision of a rule on an admission request
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RuleDecision {
+    Allow,
+    Deny,
+    /// Denied by the rule, but allowed by its rollout
+    WouldDeny,
+    Error,
+    /// Allowed without evaluation, as the circuit breaker of the rule is open
+    CircuitOpen,
+}
+
+impl RuleDecision {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Allow => "allow",
+            Self::Deny => "deny",
+            Self::WouldDeny => "would_deny",
+            Self::Error => "error",
+            Self::CircuitOpen => "circuit_open",
+        }
+    }
+}
+
+/// Labels of the decision counts of rules
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RuleDecisionLabels {
+    pub rule_kind: String,
+    /// Name of the rule, prefixed by its namespace if namespaced
+    pub rule: String,
+    pub operation: String,
+    /// Kind of the object of the request
+    pub kind: String,
+    pub decision: RuleDecision,
+}
+
+/// Metrics of rule evaluations in the webhook
+pub struct RuleMetrics {
+    decisions: Mutex<BTreeMap<RuleDecisionLabels, u64>>,
+    /// Requests to rules that do not exist, by kind and name of the rule
+    missing_rules: Mutex<BTreeMap<(String, String), u64>>,
+}
+
+pub static RULE_METRICS: RuleMetrics = RuleMetrics::new();
+
+impl RuleMetrics {
+    const fn new() -> Self {
+        Self {
+            decisions: Mutex::new(BTreeMap::new()),
+            missing_rules: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Count a decision of the rule
+    pub fn record_decision(&self, labels: RuleDecisionLabels) {
+        *self.decisions.lock().unwrap().entry(labels).or_default() += 1;
+    }
+
+    /// Count a request to the rule that does not exist
+    pub fn record_missing_rule(&self, rule_kind: String, rule: String) {
+        *self
+            .missing_rules
+            .lock()
+            .unwrap()
+            .entry((rule_kind, rule))
+            .or_default() += 1;
+    }
+
+    /// Render the metrics in Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut metrics = String::new();
+
+        metrics.push_str(
+            "# HELP checkpoint_rule_decisions_total Number of admission requests decided by rules.\n",
+        );
+        metrics.push_str("# TYPE checkpoint_rule_decisions_total counter\n");
+        for (labels, count) in self.decisions.lock().unwrap().iter() {
+            let _ = writeln!(
+                metrics,
+                "checkpoint_rule_decisions_total{{rule_kind=\"{}\",rule=\"{}\",operation=\"{}\",kind=\"{}\",decision=\"{}\"}} {}",
+                labels.rule_kind,
+                labels.rule,
+                labels.operation,
+                labels.kind,
+                labels.decision.as_str(),
+                count
+            );
+        }
+
+        metrics.push_str(
+            "# HELP checkpoint_rule_not_found_total Number of admission requests to rules that do not exist.\n",
+        );
+        metrics.push_str("# TYPE checkpoint_rule_not_found_total counter\n");
+        for ((rule_kind, rule), count) in self.missing_rules.lock().unwrap().iter() {
+            let _ = writeln!(
+                metrics,
+                "checkpoint_rule_not_found_total{{rule_kind=\"{}\",rule=\"{}\"}} {}",
+                rule_kind, rule, count
+            );
+        }
+
+        metrics
+    }
+}
+
+async fn metrics() -> String {
+    CONTROLLER_METRICS.render() + &TLS_METRICS.render()
+}
+
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Ready both when leading and when standing by for the lease, so that rollouts are not blocked by the old leader
+async fn readyz() -> (StatusCode, &'static str) {
+    if CONTROLLER_METRICS.shutting_down.load(Ordering::Relaxed) {
+        (StatusCode::SERVICE_UNAVAILABLE, "shutting down")
+    } else if CONTROLLER_METRICS.leader.load(Ordering::Relaxed) {
+        (StatusCode::OK, "leader")
+    } else {
+        (StatusCode::OK, "standby")
+    }
+}
+
+/// Prepare HTTP router of the metrics and health endpoints, and the log level endpoint
+pub fn create_app(log_level_token_path: Option<PathBuf>) -> Router {
+    Router::new()
+        .route("/metrics", routing::get(metrics))
+        .route("/healthz", routing::get(healthz))
+        .route("/readyz", routing::get(readyz))
+        .nest(
+            "/internal",
+            crate::log_level::create_router(log_level_token_path),
+        )
+}
@@ -1,10 +1,21 @@
-use std::{fmt::Display, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use k8s_openapi::ByteString;
-use kube::runtime::controller::Action;
+use k8s_openapi::{apimachinery::pkg::apis::meta::v1::Condition, ByteString};
+use kube::{
+    runtime::{
+        controller::Action,
+        events::{Event, EventType, Reporter},
+    },
+    Resource, ResourceExt,
+};
 use tokio::sync::RwLock;
 
-use crate::config::ControllerConfig;
+use crate::config::{dynamic::DynamicConfig, ControllerConfig};
 
 pub mod policy;
 pub mod rule;
@@ -12,14 +23,196 @@ pub mod rule;
 pub struct ReconcilerContext {
     pub client: kube::Client,
     pub config: ControllerConfig,
+    /// Runtime configuration from the CheckpointConfig, applied to generated webhook configurations
+    pub dynamic_config: DynamicConfig,
     pub ca_bundle: Arc<RwLock<ByteString>>,
+    pub reporter: Reporter,
+    /// Last reported reconcile error and its time for each object UID
+    pub reported_errors: Mutex<HashMap<String, (String, Instant)>>,
+    /// Plural names found by the last API discovery and its time
+    pub discovered_plurals: tokio::sync::Mutex<Option<(Instant, Arc<policy::DiscoveredPlurals>)>>,
 }
 
-/// When error occurred, log it and requeue after three seconds
-pub fn error_policy<T, E>(_rule: Arc<T>, error: &E, _ctx: Arc<ReconcilerContext>) -> Action
+/// Interval to suppress Events of repeated identical reconcile errors
+const REPORTED_ERROR_SUPPRESS_INTERVAL: Duration = Duration::from_secs(300);
+
+impl ReconcilerContext {
+    /// Record the error of the object and return whether it should be published as an Event
+    ///
+    /// The same error is published at most once in [`REPORTED_ERROR_SUPPRESS_INTERVAL`]
+    /// since failed reconciliation is retried every few seconds.
+    fn should_report_error(&self, uid: String, message: &str) -> bool {
+        let now = Instant::now();
+        let mut reported_errors = self.reported_errors.lock().unwrap();
+        reported_errors
+            .retain(|_, (_, reported_at)| now - *reported_at < REPORTED_ERROR_SUPPRESS_INTERVAL);
+        match reported_errors.get(&uid) {
+            Some((reported_message, _)) if reported_message == message => false,
+            _ => {
+                reported_errors.insert(uid, (message.to_string(), now));
+                true
+            }
+        }
+    }
+
+    /// Forget the reported error of the deleted object
+    pub fn forget_reported_error(&self, uid: Option<String>) {
+        if let Some(uid) = uid {
+            self.reported_errors.lock().unwrap().remove(&uid);
+        }
+    }
+
+    /// Action after successful reconciliation, which requeues for periodic resync if enabled
+    pub fn resync_action(&self) -> Action {
+        match self.config.resync_interval_seconds {
+            0 => Action::await_change(),
+            secs => Action::requeue(Duration::from_secs(secs)),
+        }
+    }
+}
+
+/// When error occurred, log it, publish Warning Event on the object, and requeue after three seconds
+pub fn error_policy<T, E>(object: Arc<T>, error: &E, ctx: Arc<ReconcilerContext>) -> Action
 where
+    T: Resource<DynamicType = ()> + Send + Sync + 'static,
     E: Display,
 {
     tracing::error!(%error);
+
+    let message = error.to_string();
+    if let Some(uid) = object.uid() {
+        if ctx.should_report_error(uid, &message) {
+            tokio::spawn(async move {
+                crate::events::publish(
+                    ctx.client.clone(),
+                    ctx.reporter.clone(),
+                    &*object,
+                    Event {
+                        type_: EventType::Warning,
+                        reason: "ReconcileFailed".to_string(),
+                        note: Some(message),
+                        action: "Reconcile".to_string(),
+                        secondary: None,
+                    },
+                )
+                .await;
+            });
+        }
+    }
+
     Action::requeue(Duration::from_secs(3))
 }
+
+/// Check whether every field set in `desired` has the same value in `actual`.
+///
+/// Fields only present in `actual` (e.g. defaulted by the API server) are ignored,
+/// while arrays must have the same length.
+pub fn json_contains(actual: &serde_json::Value, desired: &serde_json::Value) -> bool {
+    use serde_json::Value;
+
+    match (actual, desired) {
+        (Value::Object(actual), Value::Object(desired)) => {
+            desired.iter().all(|(key, desired)| match actual.get(key) {
+                Some(actual) => json_contains(actual, desired),
+                None => desired.is_null(),
+            })
+        }
+        (Value::Array(actual), Value::Array(desired)) => {
+            actual.len() == desired.len()
+                && actual
+                    .iter()
+                    .zip(desired)
+                    .all(|(actual, desired)| json_contains(actual, desired))
+        }
+        (actual, desired) => actual == desired,
+    }
+}
+
+/// Insert or replace the condition with the same type.
+///
+/// Last transition time is kept if the status did not change.
+/// Returns whether the conditions are changed.
+pub fn set_condition(conditions: &mut Vec<Condition>, mut condition: Condition) -> bool {
+    if let Some(existing) = conditions.iter_mut().find(|c| c.type_ == condition.type_) {
+        if existing.status == condition.status {
+            condition.last_transition_time = existing.last_transition_time.clone();
+        }
+        if *existing == condition {
+            return false;
+        }
+        *existing = condition;
+    } else {
+        conditions.push(condition);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+
+    use super::*;
+
+    #[test]
+    fn test_json_contains() {
+        use serde_json::json;
+
+        let actual = json!({
+            "metadata": {"name": "rule", "uid": "1234"},
+            "webhooks": [{"name": "a", "timeoutSeconds": 10, "sideEffects": "None"}],
+        });
+
+        // Fields defaulted by the API server are ignored
+        assert!(json_contains(
+            &actual,
+            &json!({"metadata": {"name": "rule"}, "webhooks": [{"name": "a", "timeoutSeconds": 10}]}),
+        ));
+        // Unset fields are treated as null
+        assert!(json_contains(
+            &actual,
+            &json!({"metadata": {"labels": null}})
+        ));
+        // Changed fields are drift
+        assert!(!json_contains(
+            &actual,
+            &json!({"webhooks": [{"name": "a", "timeoutSeconds": 5}]}),
+        ));
+        // Added or removed webhooks are drift
+        assert!(!json_contains(
+            &actual,
+            &json!({"webhooks": [{"name": "a"}, {"name": "b"}]}),
+        ));
+        assert!(!json_contains(&actual, &json!({"webhooks": []})));
+    }
+
+    #[test]
+    fn test_set_condition() {
+        let condition = |status: &str, reason: &str, secs: i64| Condition {
+            last_transition_time: Time(Utc.timestamp_opt(secs, 0).unwrap()),
+            message: String::new(),
+            observed_generation: None,
+            reason: reason.to_string(),
+            status: status.to_string(),
+            type_: "Ready".to_string(),
+        };
+
+        let mut conditions = Vec::new();
+        assert!(set_condition(&mut conditions, condition("True", "A", 1)));
+        assert_eq!(conditions.len(), 1);
+
+        // Same condition does not change anything
+        assert!(!set_condition(&mut conditions, condition("True", "A", 2)));
+        assert_eq!(conditions[0].last_transition_time.0.timestamp(), 1);
+
+        // Same status keeps last transition time
+        assert!(set_condition(&mut conditions, condition("True", "B", 3)));
+        assert_eq!(conditions[0].reason, "B");
+        assert_eq!(conditions[0].last_transition_time.0.timestamp(), 1);
+
+        // Different status updates last transition time
+        assert!(set_condition(&mut conditions, condition("False", "C", 4)));
+        assert_eq!(conditions.len(), 1);
+        assert_eq!(conditions[0].last_transition_time.0.timestamp(), 4);
+    }
+}
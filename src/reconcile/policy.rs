@@ -1,32 +1,61 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use futures_util::{stream::FuturesUnordered, TryStreamExt};
+use chrono::Utc;
+use futures_util::{
+    stream::{FuturesOrdered, FuturesUnordered},
+    TryStreamExt,
+};
 use k8s_openapi::{
     api::{
-        batch::v1::{CronJob, CronJobSpec, JobSpec, JobTemplateSpec},
-        core::v1::{Container, EnvVar, PodSpec, PodTemplateSpec, ServiceAccount},
+        batch::v1::{CronJob, CronJobSpec, Job, JobSpec, JobTemplateSpec},
+        core::v1::{
+            ConfigMap, Container, EnvVar, EnvVarSource, ObjectFieldSelector, PodSpec,
+            PodTemplateSpec, ServiceAccount,
+        },
         rbac::v1::{
             ClusterRole, ClusterRoleBinding, PolicyRule, Role, RoleBinding, RoleRef, Subject,
         },
     },
-    apimachinery::pkg::apis::meta::v1::OwnerReference,
+    apimachinery::pkg::apis::meta::v1::{OwnerReference, Time},
 };
 use kube::{
-    api::{Patch, PatchParams},
+    api::{Patch, PatchParams, PostParams},
     core::ObjectMeta,
+    discovery::Discovery,
     runtime::controller::Action,
     Api, Resource, ResourceExt,
 };
 
 use crate::{
+    checker::{
+        notification_credentials, KUBECONFIG_ENV_PREFIX, PROMETHEUS_BEARER_TOKEN_ENV_PREFIX,
+        PROMETHEUS_PASSWORD_ENV_PREFIX,
+    },
     config::ControllerConfig,
-    types::policy::{CronPolicy, CronPolicyResource, CronPolicySpec},
+    types::{
+        policy::{
+            CronPolicy, CronPolicyManualTrigger, CronPolicyNotification, CronPolicyPodTemplate,
+            CronPolicyResource, CronPolicySpec, CronPolicyStatus, CronPolicyValueFrom, Policy,
+            PolicyPhase, PolicySpec, PolicyStatus, RestartPolicy,
+        },
+        rule::RuleAccess,
+    },
     util::find_group_version_pairs_by_kind,
 };
 
 use super::ReconcilerContext;
 
+/// Duration to reuse API discovery results for resolving plural names
+const DISCOVERY_CACHE_TTL: Duration = Duration::from_secs(300);
+
 const CRONPOLICY_OWNED_LABEL_KEY: &str = "checkpoint.devsisters.com/cronpolicy";
+const POLICY_OWNED_LABEL_KEY: &str = "checkpoint.devsisters.com/policy";
+/// Setting or changing the value of this annotation on a CronPolicy runs the checker immediately
+pub const TRIGGER_ANNOTATION_KEY: &str = "checkpoint.devsisters.com/trigger";
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -42,10 +71,20 @@ pub enum Error {
     PatchClusterRoleBinding(#[source] kube::Error),
     #[error("Failed to patch CronJob: {0}")]
     PatchCronJob(#[source] kube::Error),
+    #[error("Failed to patch ConfigMap: {0}")]
+    PatchConfigMap(#[source] kube::Error),
+    #[error("Failed to create Job: {0}")]
+    CreateJob(#[source] kube::Error),
+    #[error("Failed to patch Job: {0}")]
+    PatchJob(#[source] kube::Error),
+    #[error("Failed to patch status: {0}")]
+    PatchStatus(#[source] kube::Error),
     #[error("Failed to serialize resources (This is a bug): {0}")]
     SerializeResources(#[source] serde_json::Error),
     #[error("Failed to serialize notifications (This is a bug): {0}")]
     SerializeNotifications(#[source] serde_json::Error),
+    #[error("Failed to serialize CronJob (This is a bug): {0}")]
+    SerializeCronJob(#[source] serde_json::Error),
     #[error("Kubernetes error: {0}")]
     Kubernetes(#[source] kube::Error),
     #[error("Specifed kind (`{0}`) does not have matching group/versions")]
@@ -54,18 +93,335 @@ pub enum Error {
     MultipleGroupVersion(String),
 }
 
-/// Set a label that indicates the object is owned by a CronPolicy
-fn make_labels(name: String) -> BTreeMap<String, String> {
+/// Set a label that indicates the object is owned by a CronPolicy or a Policy
+fn make_labels(owned_label_key: &str, name: String) -> BTreeMap<String, String> {
     let mut labels = BTreeMap::new();
-    labels.insert(CRONPOLICY_OWNED_LABEL_KEY.to_string(), name);
+    labels.insert(owned_label_key.to_string(), name);
     labels
 }
 
+/// Checker job fields shared by CronPolicy and Policy
+struct CheckerJob<'a> {
+    code: &'a str,
+    code_from: Option<&'a CronPolicyValueFrom>,
+    notifications: &'a CronPolicyNotification,
+    notifications_from: Option<&'a CronPolicyValueFrom>,
+    finding_annotation: bool,
+    allow_remediation: bool,
+    restart_policy: &'a RestartPolicy,
+    pod_template: Option<&'a CronPolicyPodTemplate>,
+    backoff_limit: Option<i32>,
+    active_deadline_seconds: Option<i64>,
+    persist_state: bool,
+    notify_only_on_change: bool,
+}
+
+impl<'a> From<&'a CronPolicySpec> for CheckerJob<'a> {
+    fn from(spec: &'a CronPolicySpec) -> Self {
+        Self {
+            code: &spec.code,
+            code_from: spec.code_from.as_ref(),
+            notifications: &spec.notifications,
+            notifications_from: spec.notifications_from.as_ref(),
+            finding_annotation: spec.finding_annotation,
+            allow_remediation: spec.allow_remediation,
+            restart_policy: &spec.restart_policy,
+            pod_template: spec.pod_template.as_ref(),
+            backoff_limit: spec.backoff_limit,
+            active_deadline_seconds: spec.active_deadline_seconds,
+            persist_state: spec.persists_state(),
+            notify_only_on_change: spec.notify_only_on_change,
+        }
+    }
+}
+
+impl<'a> From<&'a PolicySpec> for CheckerJob<'a> {
+    fn from(spec: &'a PolicySpec) -> Self {
+        Self {
+            code: &spec.code,
+            code_from: spec.code_from.as_ref(),
+            notifications: &spec.notifications,
+            notifications_from: spec.notifications_from.as_ref(),
+            finding_annotation: spec.finding_annotation,
+            allow_remediation: spec.allow_remediation,
+            restart_policy: &spec.restart_policy,
+            pod_template: spec.pod_template.as_ref(),
+            backoff_limit: spec.backoff_limit,
+            active_deadline_seconds: spec.active_deadline_seconds,
+            persist_state: false,
+            notify_only_on_change: false,
+        }
+    }
+}
+
+/// Name of the ConfigMap to persist the checker state
+fn state_configmap_name(name: &str) -> String {
+    format!("{}-state", name)
+}
+
+/// Make the ConfigMap to persist the checker state, and Role and RoleBinding that allow the checker to update it
+///
+/// Data of the ConfigMap is managed by the checker, so only the metadata is applied.
+fn make_state_objects(
+    name: String,
+    namespace: String,
+    oref: OwnerReference,
+) -> (ConfigMap, Role, RoleBinding) {
+    let state_name = state_configmap_name(&name);
+    let metadata = ObjectMeta {
+        name: Some(state_name.clone()),
+        namespace: Some(namespace.clone()),
+        owner_references: Some(vec![oref.clone()]),
+        labels: Some(make_labels(CRONPOLICY_OWNED_LABEL_KEY, name.clone())),
+        ..Default::default()
+    };
+    let cm = ConfigMap {
+        metadata: metadata.clone(),
+        ..Default::default()
+    };
+    let r = Role {
+        metadata: metadata.clone(),
+        rules: Some(vec![PolicyRule {
+            api_groups: Some(vec!["".to_string()]),
+            resources: Some(vec!["configmaps".to_string()]),
+            resource_names: Some(vec![state_name.clone()]),
+            verbs: vec!["get".to_string(), "patch".to_string()],
+            ..Default::default()
+        }]),
+    };
+    let rb = RoleBinding {
+        metadata,
+        role_ref: RoleRef {
+            api_group: Role::group(&()).into_owned(),
+            kind: Role::kind(&()).into_owned(),
+            name: state_name,
+        },
+        subjects: Some(vec![Subject {
+            api_group: Some(ServiceAccount::group(&()).into_owned()),
+            kind: ServiceAccount::kind(&()).into_owned(),
+            name,
+            namespace: Some(namespace),
+        }]),
+    };
+    (cm, r, rb)
+}
+
+/// Let the kubelet read the value from the ConfigMap or the Secret when starting the checker,
+/// so that the controller does not need to read them
+fn make_env_var_source(value_from: &CronPolicyValueFrom) -> EnvVarSource {
+    EnvVarSource {
+        config_map_key_ref: value_from.config_map_key_ref.clone(),
+        secret_key_ref: value_from.secret_key_ref.clone(),
+        ..Default::default()
+    }
+}
+
+/// Environment variables to provide credentials of the resources to the checker
+///
+/// Names of the variables are suffixed with the index of the resource.
+fn make_resource_credentials_env(resources: &[CronPolicyResource]) -> Vec<EnvVar> {
+    let mut env = Vec::new();
+    for (index, resource) in resources.iter().enumerate() {
+        if let Some(cluster_ref) = &resource.cluster_ref {
+            env.push(EnvVar {
+                name: format!("{}{}", KUBECONFIG_ENV_PREFIX, index),
+                value: None,
+                value_from: Some(EnvVarSource {
+                    secret_key_ref: Some(cluster_ref.clone()),
+                    ..Default::default()
+                }),
+            });
+        }
+        let Some(auth) = resource
+            .prometheus_query
+            .as_ref()
+            .and_then(|prometheus_query| prometheus_query.auth.as_ref())
+        else {
+            continue;
+        };
+        for (prefix, value_from) in [
+            (PROMETHEUS_BEARER_TOKEN_ENV_PREFIX, &auth.bearer_token_from),
+            (PROMETHEUS_PASSWORD_ENV_PREFIX, &auth.password_from),
+        ] {
+            if let Some(value_from) = value_from {
+                env.push(EnvVar {
+                    name: format!("{}{}", prefix, index),
+                    value: None,
+                    value_from: Some(make_env_var_source(value_from)),
+                });
+            }
+        }
+    }
+    env
+}
+
+/// Environment variables to provide the credentials of the notifications to the checker
+///
+/// Notifications read from `notificationsFrom` are not known to the controller, so they are not provided.
+fn make_notification_credentials_env(notifications: &CronPolicyNotification) -> Vec<EnvVar> {
+    notification_credentials(notifications)
+        .into_iter()
+        .map(|(name, value_from)| EnvVar {
+            name,
+            value: None,
+            value_from: Some(make_env_var_source(&value_from)),
+        })
+        .collect()
+}
+
+fn make_job_spec(
+    policy_name: String,
+    serviceaccount_name: String,
+    checker: CheckerJob,
+    resources: &[CronPolicyResource],
+    controller_config: &ControllerConfig,
+) -> Result<JobSpec, Error> {
+    let pod_template = checker.pod_template.cloned().unwrap_or_default();
+
+    Ok(JobSpec {
+        template: PodTemplateSpec {
+            metadata: Some(ObjectMeta {
+                labels: pod_template.labels,
+                annotations: pod_template.annotations,
+                ..Default::default()
+            }),
+            spec: Some(PodSpec {
+                service_account_name: Some(serviceaccount_name.clone()),
+                containers: vec![Container {
+                    command: Some(vec!["checkpoint-checker".to_string()]),
+                    env: Some(
+                        vec![
+                            EnvVar {
+                                name: "RUST_LOG".to_string(),
+                                value: Some("info".to_string()),
+                                value_from: None,
+                            },
+                            EnvVar {
+                                name: "CONF_POLICY_NAME".to_string(),
+                                value: Some(policy_name),
+                                value_from: None,
+                            },
+                            EnvVar {
+                                name: "CONF_RESOURCES".to_string(),
+                                value: Some(
+                                    serde_json::to_string(resources)
+                                        .map_err(Error::SerializeResources)?,
+                                ),
+                                value_from: None,
+                            },
+                            match checker.code_from {
+                                Some(code_from) => EnvVar {
+                                    name: "CONF_CODE".to_string(),
+                                    value: None,
+                                    value_from: Some(make_env_var_source(code_from)),
+                                },
+                                None => EnvVar {
+                                    name: "CONF_CODE".to_string(),
+                                    value: Some(checker.code.to_string()),
+                                    value_from: None,
+                                },
+                            },
+                            match checker.notifications_from {
+                                Some(notifications_from) => EnvVar {
+                                    name: "CONF_NOTIFICATIONS".to_string(),
+                                    value: None,
+                                    value_from: Some(make_env_var_source(notifications_from)),
+                                },
+                                None => EnvVar {
+                                    name: "CONF_NOTIFICATIONS".to_string(),
+                                    value: Some(
+                                        serde_json::to_string(checker.notifications)
+                                            .map_err(Error::SerializeNotifications)?,
+                                    ),
+                                    value_from: None,
+                                },
+                            },
+                            EnvVar {
+                                name: "CONF_FINDING_ANNOTATION".to_string(),
+                                value: Some(checker.finding_annotation.to_string()),
+                                value_from: None,
+                            },
+                            EnvVar {
+                                name: "CONF_ALLOW_REMEDIATION".to_string(),
+                                value: Some(checker.allow_remediation.to_string()),
+                                value_from: None,
+                            },
+                            EnvVar {
+                                name: "CONF_NAMESPACE".to_string(),
+                                value: None,
+                                value_from: Some(EnvVarSource {
+                                    field_ref: Some(ObjectFieldSelector {
+                                        api_version: None,
+                                        field_path: "metadata.namespace".to_string(),
+                                    }),
+                                    ..Default::default()
+                                }),
+                            },
+                            EnvVar {
+                                name: "CONF_LOG_FORMAT".to_string(),
+                                value: Some(controller_config.log_format.to_string()),
+                                value_from: None,
+                            },
+                            EnvVar {
+                                name: "CONF_NOTIFY_ONLY_ON_CHANGE".to_string(),
+                                value: Some(checker.notify_only_on_change.to_string()),
+                                value_from: None,
+                            },
+                        ]
+                        .into_iter()
+                        .chain(checker.persist_state.then(|| EnvVar {
+                            name: "CONF_STATE_CONFIGMAP_NAME".to_string(),
+                            value: Some(state_configmap_name(&serviceaccount_name)),
+                            value_from: None,
+                        }))
+                        .chain(make_resource_credentials_env(resources))
+                        .chain(
+                            checker
+                                .notifications_from
+                                .is_none()
+                                .then(|| make_notification_credentials_env(checker.notifications))
+                                .into_iter()
+                                .flatten(),
+                        )
+                        .chain(
+                            controller_config
+                                .checker_pushgateway_url
+                                .as_ref()
+                                .map(|url| EnvVar {
+                                    name: "CONF_PUSHGATEWAY_URL".to_string(),
+                                    value: Some(url.to_string()),
+                                    value_from: None,
+                                }),
+                        )
+                        .collect(),
+                    ),
+                    image: Some(controller_config.checker_image.clone()),
+                    name: "checkpoint-checker".to_string(),
+                    resources: pod_template.resources,
+                    ..Default::default()
+                }],
+                restart_policy: Some(checker.restart_policy.to_string()),
+                node_selector: pod_template.node_selector,
+                tolerations: pod_template.tolerations,
+                affinity: pod_template.affinity,
+                priority_class_name: pod_template.priority_class_name,
+                security_context: pod_template.security_context,
+                image_pull_secrets: pod_template.image_pull_secrets,
+                ..Default::default()
+            }),
+        },
+        backoff_limit: checker.backoff_limit,
+        active_deadline_seconds: checker.active_deadline_seconds,
+        ..Default::default()
+    })
+}
+
 fn make_cronjob(
     cp_name: String,
     namespace: String,
     oref: OwnerReference,
     spec: &CronPolicySpec,
+    resources: &[CronPolicyResource],
     controller_config: &ControllerConfig,
 ) -> Result<CronJob, Error> {
     Ok(CronJob {
@@ -73,78 +429,85 @@ fn make_cronjob(
             name: Some(cp_name.clone()),
             namespace: Some(namespace),
             owner_references: Some(vec![oref]),
-            labels: Some(make_labels(cp_name.clone())),
+            labels: Some(make_labels(CRONPOLICY_OWNED_LABEL_KEY, cp_name.clone())),
             ..Default::default()
         },
         spec: Some(CronJobSpec {
             suspend: Some(spec.suspend),
             schedule: spec.schedule.clone(),
+            concurrency_policy: spec.concurrency_policy.as_ref().map(ToString::to_string),
+            starting_deadline_seconds: spec.starting_deadline_seconds,
+            successful_jobs_history_limit: spec.successful_jobs_history_limit,
+            failed_jobs_history_limit: spec.failed_jobs_history_limit,
             job_template: JobTemplateSpec {
                 metadata: None,
-                spec: Some(JobSpec {
-                    template: PodTemplateSpec {
-                        metadata: None,
-                        spec: Some(PodSpec {
-                            service_account_name: Some(cp_name.clone()),
-                            containers: vec![Container {
-                                command: Some(vec!["checkpoint-checker".to_string()]),
-                                env: Some(vec![
-                                    EnvVar {
-                                        name: "RUST_LOG".to_string(),
-                                        value: Some("info".to_string()),
-                                        value_from: None,
-                                    },
-                                    EnvVar {
-                                        name: "CONF_POLICY_NAME".to_string(),
-                                        value: Some(cp_name),
-                                        value_from: None,
-                                    },
-                                    EnvVar {
-                                        name: "CONF_RESOURCES".to_string(),
-                                        value: Some(
-                                            serde_json::to_string(&spec.resources)
-                                                .map_err(Error::SerializeResources)?,
-                                        ),
-                                        value_from: None,
-                                    },
-                                    EnvVar {
-                                        name: "CONF_CODE".to_string(),
-                                        value: Some(spec.code.clone()),
-                                        value_from: None,
-                                    },
-                                    EnvVar {
-                                        name: "CONF_NOTIFICATIONS".to_string(),
-                                        value: Some(
-                                            serde_json::to_string(&spec.notifications)
-                                                .map_err(Error::SerializeNotifications)?,
-                                        ),
-                                        value_from: None,
-                                    },
-                                ]),
-                                image: Some(controller_config.checker_image.clone()),
-                                name: "checkpoint-checker".to_string(),
-                                ..Default::default()
-                            }],
-                            restart_policy: Some(spec.restart_policy.to_string()),
-                            ..Default::default()
-                        }),
-                    },
-                    ..Default::default()
-                }),
+                spec: Some(make_job_spec(
+                    cp_name.clone(),
+                    cp_name,
+                    spec.into(),
+                    resources,
+                    controller_config,
+                )?),
             },
-            ..Default::default()
         }),
         status: Default::default(),
     })
 }
 
-fn make_serviceaccount(name: String, namespace: String, oref: OwnerReference) -> ServiceAccount {
+fn make_job(
+    name: String,
+    policy_name: String,
+    namespace: String,
+    oref: OwnerReference,
+    spec: &PolicySpec,
+    resources: &[CronPolicyResource],
+    controller_config: &ControllerConfig,
+) -> Result<Job, Error> {
+    Ok(Job {
+        metadata: ObjectMeta {
+            name: Some(name.clone()),
+            namespace: Some(namespace),
+            owner_references: Some(vec![oref]),
+            labels: Some(make_labels(POLICY_OWNED_LABEL_KEY, policy_name.clone())),
+            ..Default::default()
+        },
+        spec: Some(make_job_spec(
+            policy_name,
+            name,
+            spec.into(),
+            resources,
+            controller_config,
+        )?),
+        status: None,
+    })
+}
+
+/// Set timeZone of the CronJob.
+///
+/// k8s-openapi does not support timeZone yet, so the field is added to the serialized object.
+fn add_time_zone(
+    cj: &CronJob,
+    time_zone: Option<&str>,
+) -> Result<serde_json::Value, serde_json::Error> {
+    let mut value = serde_json::to_value(cj)?;
+    if let Some(time_zone) = time_zone {
+        value["spec"]["timeZone"] = serde_json::Value::String(time_zone.to_string());
+    }
+    Ok(value)
+}
+
+fn make_serviceaccount(
+    owned_label_key: &str,
+    name: String,
+    namespace: String,
+    oref: OwnerReference,
+) -> ServiceAccount {
     ServiceAccount {
         metadata: ObjectMeta {
             name: Some(name.clone()),
             namespace: Some(namespace),
             owner_references: Some(vec![oref]),
-            labels: Some(make_labels(name)),
+            labels: Some(make_labels(owned_label_key, name)),
             ..Default::default()
         },
         ..Default::default()
@@ -184,11 +547,93 @@ fn to_plural(word: &str) -> String {
     format!("{}s", word)
 }
 
+/// Plural names of the kinds found by API discovery
+#[derive(Default)]
+pub struct DiscoveredPlurals {
+    /// Kind -> (group, plural) pairs of the groups serving the kind
+    plurals: HashMap<String, Vec<(String, String)>>,
+}
+
+impl DiscoveredPlurals {
+    fn new(entries: impl IntoIterator<Item = (String, String, String)>) -> Self {
+        let mut plurals = HashMap::<_, Vec<_>>::new();
+        for (kind, group, plural) in entries {
+            plurals.entry(kind).or_default().push((group, plural));
+        }
+        Self { plurals }
+    }
+
+    fn from_discovery(discovery: &Discovery) -> Self {
+        Self::new(discovery.groups().flat_map(|group| {
+            group
+                .recommended_resources()
+                .into_iter()
+                .map(|(ar, _)| (ar.kind, ar.group, ar.plural))
+        }))
+    }
+
+    /// Plural name of the kind in the group.
+    /// If the group is not given, the kind is looked up in every group, and found only if all of them agree.
+    fn get(&self, group: Option<&str>, kind: &str) -> Option<String> {
+        let candidates = self.plurals.get(kind)?;
+        match group {
+            Some(group) => candidates
+                .iter()
+                .find(|(candidate, _)| candidate == group)
+                .map(|(_, plural)| plural.clone()),
+            None => {
+                let (_, plural) = candidates.first()?;
+                candidates
+                    .iter()
+                    .all(|(_, candidate)| candidate == plural)
+                    .then(|| plural.clone())
+            }
+        }
+    }
+}
+
+/// Whether the resource needs API discovery to resolve its plural name
+fn needs_discovery(
+    resource: &CronPolicyResource,
+    plural_overrides: &HashMap<String, String>,
+) -> bool {
+    resource.plural.is_none()
+        && !resource.kind.is_empty()
+        && !plural_overrides.contains_key(&resource.kind)
+}
+
+/// Fill in plural names of the resources that do not specify one.
+///
+/// The override map takes precedence over API discovery.
+/// Resources neither of them knows are left as is, to be pluralized by `to_plural`.
+fn resolve_plurals(
+    resources: &[CronPolicyResource],
+    plural_overrides: &HashMap<String, String>,
+    discovered: Option<&DiscoveredPlurals>,
+) -> Vec<CronPolicyResource> {
+    resources
+        .iter()
+        .cloned()
+        .map(|mut resource| {
+            if resource.plural.is_none() {
+                resource.plural = plural_overrides
+                    .get(&resource.kind)
+                    .cloned()
+                    .or_else(|| discovered?.get(resource.group.as_deref(), &resource.kind));
+            }
+            resource
+        })
+        .collect()
+}
+
 async fn make_role_rules(
     resources: &[CronPolicyResource],
+    access: &[RuleAccess],
+    patch_objects: bool,
     kube_client: kube::Client,
 ) -> Result<Vec<PolicyRule>, Error> {
-    resources
+    // Resolve API groups and plural names of the resources
+    let targets: Vec<(String, String, Option<String>)> = resources
         .iter()
         .map(|resource| {
             let kube_client = kube_client.clone();
@@ -209,46 +654,94 @@ async fn make_role_rules(
                         gv.0
                     }
                 };
-                Ok(PolicyRule {
-                    api_groups: Some(vec![group]),
-                    resources: Some(vec![resource
-                        .plural
-                        .clone()
-                        .unwrap_or_else(|| to_plural(&resource.kind.to_ascii_lowercase()))]),
-                    verbs: vec![if resource.name.is_some() {
-                        "get".to_string()
-                    } else {
-                        "list".to_string()
-                    }],
-                    resource_names: resource.name.clone().map(|name| vec![name]),
-                    ..Default::default()
-                })
+                let plural = resource
+                    .plural
+                    .clone()
+                    .unwrap_or_else(|| to_plural(&resource.kind.to_ascii_lowercase()));
+                Ok((group, plural, resource.name.clone()))
             }
         })
-        .collect::<FuturesUnordered<_>>()
+        .collect::<FuturesOrdered<_>>()
         .try_collect()
-        .await
+        .await?;
+
+    // Aggregate the resources of the same kind, as named resources are fetched with `get` and the others with `list`.
+    // (group, plural) -> (whether listed, names) map
+    let mut aggregated = BTreeMap::<(String, String), (bool, BTreeSet<String>)>::new();
+    for (group, plural, name) in targets {
+        let entry = aggregated.entry((group, plural)).or_default();
+        match name {
+            Some(name) => {
+                entry.1.insert(name);
+            }
+            None => entry.0 = true,
+        }
+    }
+
+    let make_verbs = |verb: &str| {
+        let mut verbs = vec![verb.to_string()];
+        // Checker patches finding annotations of the objects, or JS code remediates them
+        if patch_objects {
+            verbs.push("patch".to_string());
+        }
+        verbs
+    };
+    let mut rules = Vec::new();
+    for ((group, plural), (listed, names)) in aggregated {
+        if listed {
+            rules.push(PolicyRule {
+                api_groups: Some(vec![group.clone()]),
+                resources: Some(vec![plural.clone()]),
+                verbs: make_verbs("list"),
+                ..Default::default()
+            });
+        }
+        if !names.is_empty() {
+            rules.push(PolicyRule {
+                api_groups: Some(vec![group]),
+                resources: Some(vec![plural]),
+                verbs: make_verbs("get"),
+                resource_names: Some(names.into_iter().collect()),
+                ..Default::default()
+            });
+        }
+    }
+
+    // Allow `kubeGet` and `kubeList` in JS code
+    rules.extend(access.iter().map(|access| PolicyRule {
+        api_groups: Some(vec![access.group.clone()]),
+        resources: Some(vec![access.resource.clone()]),
+        verbs: vec![access.verb.to_string()],
+        resource_names: access.name.clone().map(|name| vec![name]),
+        ..Default::default()
+    }));
+
+    Ok(rules)
 }
 
 async fn make_clusterrole(
+    owned_label_key: &str,
     name: String,
     oref: OwnerReference,
     resources: &[CronPolicyResource],
+    access: &[RuleAccess],
+    patch_objects: bool,
     kube_client: kube::Client,
 ) -> Result<ClusterRole, Error> {
     Ok(ClusterRole {
         metadata: ObjectMeta {
             name: Some(name.clone()),
             owner_references: Some(vec![oref]),
-            labels: Some(make_labels(name)),
+            labels: Some(make_labels(owned_label_key, name)),
             ..Default::default()
         },
-        rules: Some(make_role_rules(resources, kube_client).await?),
+        rules: Some(make_role_rules(resources, access, patch_objects, kube_client).await?),
         aggregation_rule: None,
     })
 }
 
 fn make_clusterrolebinding(
+    owned_label_key: &str,
     name: String,
     oref: OwnerReference,
     serviceaccount_namespace: String,
@@ -257,7 +750,7 @@ fn make_clusterrolebinding(
         metadata: ObjectMeta {
             name: Some(name.clone()),
             owner_references: Some(vec![oref]),
-            labels: Some(make_labels(name.clone())),
+            labels: Some(make_labels(owned_label_key, name.clone())),
             ..Default::default()
         },
         role_ref: RoleRef {
@@ -274,11 +767,15 @@ fn make_clusterrolebinding(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn make_role(
+    owned_label_key: &str,
     name: String,
     oref: OwnerReference,
     target_namespace: String,
     resources: &[CronPolicyResource],
+    access: &[RuleAccess],
+    patch_objects: bool,
     kube_client: kube::Client,
 ) -> Result<Role, Error> {
     Ok(Role {
@@ -286,14 +783,15 @@ async fn make_role(
             name: Some(name.clone()),
             namespace: Some(target_namespace),
             owner_references: Some(vec![oref]),
-            labels: Some(make_labels(name)),
+            labels: Some(make_labels(owned_label_key, name)),
             ..Default::default()
         },
-        rules: Some(make_role_rules(resources, kube_client).await?),
+        rules: Some(make_role_rules(resources, access, patch_objects, kube_client).await?),
     })
 }
 
 fn make_rolebinding(
+    owned_label_key: &str,
     name: String,
     oref: OwnerReference,
     target_namespace: String,
@@ -304,7 +802,7 @@ fn make_rolebinding(
             name: Some(name.clone()),
             namespace: Some(target_namespace),
             owner_references: Some(vec![oref]),
-            labels: Some(make_labels(name.clone())),
+            labels: Some(make_labels(owned_label_key, name.clone())),
             ..Default::default()
         },
         role_ref: RoleRef {
@@ -326,59 +824,90 @@ type RolesAndClusterRoles = (
     Option<(ClusterRole, ClusterRoleBinding)>,
 );
 
+#[allow(clippy::too_many_arguments)]
 async fn make_roles_and_clusterroles(
+    owned_label_key: &str,
     cp_name: String,
     cronjob_namespace: String,
     oref: OwnerReference,
     resources: &[CronPolicyResource],
+    access: &[RuleAccess],
+    patch_objects: bool,
     kube_client: kube::Client,
 ) -> Result<RolesAndClusterRoles, Error> {
-    let mut namespaced_resources = BTreeMap::<String, Vec<CronPolicyResource>>::new(); // namespace -> [resource] map
+    // namespace -> ([resource], [access]) map
+    let mut namespaced_resources =
+        BTreeMap::<String, (Vec<CronPolicyResource>, Vec<RuleAccess>)>::new();
     let mut global_resources = Vec::<CronPolicyResource>::new();
-    for resource in resources {
+    let mut global_access = Vec::<RuleAccess>::new();
+    // Prometheus queries and HTTP endpoints do not need permissions on Kubernetes,
+    // and permissions on other clusters are up to their kubeconfigs
+    for resource in resources
+        .iter()
+        .filter(|resource| resource.is_kubernetes() && resource.cluster_ref.is_none())
+    {
         if let Some(namespace) = &resource.namespace {
             // Aggregate namespaced resources with same namespace
             namespaced_resources
                 .entry(namespace.clone())
                 .or_default()
+                .0
                 .push(resource.clone());
         } else {
             global_resources.push(resource.clone());
         }
     }
+    for access in access {
+        if let Some(namespace) = &access.namespace {
+            namespaced_resources
+                .entry(namespace.clone())
+                .or_default()
+                .1
+                .push(access.clone());
+        } else {
+            global_access.push(access.clone());
+        }
+    }
 
     let roles = namespaced_resources
         .into_iter()
-        .map(|(namespace, resources)| {
+        .map(|(namespace, (resources, access))| {
             let cp_name = cp_name.clone();
             let oref = oref.clone();
             let cronjob_namespace = cronjob_namespace.clone();
             let kube_client = kube_client.clone();
             async move {
                 let r = make_role(
+                    owned_label_key,
                     cp_name.clone(),
                     oref.clone(),
                     namespace.clone(),
                     &resources,
+                    &access,
+                    patch_objects,
                     kube_client,
                 )
                 .await?;
-                let rb = make_rolebinding(cp_name, oref, namespace, cronjob_namespace);
+                let rb =
+                    make_rolebinding(owned_label_key, cp_name, oref, namespace, cronjob_namespace);
                 Ok((r, rb))
             }
         })
         .collect::<FuturesUnordered<_>>()
         .try_collect()
         .await?;
-    let clusterrole = if !global_resources.is_empty() {
+    let clusterrole = if !global_resources.is_empty() || !global_access.is_empty() {
         let cr = make_clusterrole(
+            owned_label_key,
             cp_name.clone(),
             oref.clone(),
             &global_resources,
+            &global_access,
+            patch_objects,
             kube_client,
         )
         .await?;
-        let crb = make_clusterrolebinding(cp_name, oref, cronjob_namespace);
+        let crb = make_clusterrolebinding(owned_label_key, cp_name, oref, cronjob_namespace);
         Some((cr, crb))
     } else {
         None
@@ -387,39 +916,83 @@ async fn make_roles_and_clusterroles(
     Ok((roles, clusterrole))
 }
 
-pub async fn reconcile_cronpolicy(
-    cp: Arc<CronPolicy>,
-    ctx: Arc<ReconcilerContext>,
-) -> Result<Action, Error> {
-    let client = &ctx.client;
-    let config = &ctx.config;
+/// Resolve plural names of the resources, running API discovery if needed
+///
+/// Discovery results are cached for [`DISCOVERY_CACHE_TTL`], and discovery is skipped entirely
+/// when plural names or overrides cover every resource.
+async fn discover_resources(
+    ctx: &ReconcilerContext,
+    resources: &[CronPolicyResource],
+) -> Vec<CronPolicyResource> {
+    let plural_overrides = &ctx.config.plural_overrides;
+    if !resources
+        .iter()
+        .any(|resource| needs_discovery(resource, plural_overrides))
+    {
+        return resolve_plurals(resources, plural_overrides, None);
+    }
 
-    // Prepare Kubernetes object ownership reference
-    let oref = cp.controller_owner_ref(&()).unwrap();
+    let mut cache = ctx.discovered_plurals.lock().await;
+    let discovered = match &*cache {
+        Some((discovered_at, discovered)) if discovered_at.elapsed() < DISCOVERY_CACHE_TTL => {
+            Some(discovered.clone())
+        }
+        _ => match Discovery::new(ctx.client.clone()).run().await {
+            Ok(discovery) => {
+                let discovered = Arc::new(DiscoveredPlurals::from_discovery(&discovery));
+                *cache = Some((Instant::now(), discovered.clone()));
+                Some(discovered)
+            }
+            Err(error) => {
+                tracing::warn!(%error, "failed to run API discovery, inferring plural names from kinds");
+                None
+            }
+        },
+    };
+    drop(cache);
 
-    let cp_name = cp.name_any();
-    let cronjob_namespace = cp.spec.namespace.clone();
+    resolve_plurals(resources, plural_overrides, discovered.as_deref())
+}
 
+/// Create the checker ServiceAccount, and Roles or ClusterRole that allow the checker to list the target resources
+#[allow(clippy::too_many_arguments)]
+async fn apply_checker_rbac(
+    client: &kube::Client,
+    patch_params: &PatchParams,
+    owned_label_key: &str,
+    name: String,
+    namespace: String,
+    oref: OwnerReference,
+    resources: &[CronPolicyResource],
+    access: &[RuleAccess],
+    patch_objects: bool,
+) -> Result<(), Error> {
     // Prepare Kubernetes APIs
-    let sa_api = Api::<ServiceAccount>::namespaced(client.clone(), &cronjob_namespace);
+    let sa_api = Api::<ServiceAccount>::namespaced(client.clone(), &namespace);
     let cr_api = Api::<ClusterRole>::all(client.clone());
     let crb_api = Api::<ClusterRoleBinding>::all(client.clone());
-    let cj_api = Api::<CronJob>::namespaced(client.clone(), &cronjob_namespace);
-    let patch_params = PatchParams::apply("cronpolicy.checkpoint.devsisters.com");
 
     // Create ServiceAccount for checker
-    let sa = make_serviceaccount(cp_name.clone(), cronjob_namespace.clone(), oref.clone());
+    let sa = make_serviceaccount(
+        owned_label_key,
+        name.clone(),
+        namespace.clone(),
+        oref.clone(),
+    );
     sa_api
-        .patch(&sa.name_any(), &patch_params, &Patch::Apply(&sa))
+        .patch(&sa.name_any(), patch_params, &Patch::Apply(&sa))
         .await
         .map_err(Error::PatchServiceAccount)?;
 
     // Create Role or ClusterRole for the checker ServiceAccount that allows chechker to list the target resources
     let (roles, clusterrole) = make_roles_and_clusterroles(
-        cp_name.clone(),
-        cronjob_namespace.clone(),
-        oref.clone(),
-        &cp.spec.resources,
+        owned_label_key,
+        name,
+        namespace,
+        oref,
+        resources,
+        access,
+        patch_objects,
         client.clone(),
     )
     .await?;
@@ -428,38 +1001,312 @@ pub async fn reconcile_cronpolicy(
         let rb_api = Api::<RoleBinding>::namespaced(client.clone(), &rb.namespace().unwrap());
 
         r_api
-            .patch(&r.name_any(), &patch_params, &Patch::Apply(&r))
+            .patch(&r.name_any(), patch_params, &Patch::Apply(&r))
             .await
             .map_err(Error::PatchRole)?;
         rb_api
-            .patch(&rb.name_any(), &patch_params, &Patch::Apply(&rb))
+            .patch(&rb.name_any(), patch_params, &Patch::Apply(&rb))
             .await
             .map_err(Error::PatchRoleBinding)?;
     }
     if let Some((cr, crb)) = clusterrole {
         cr_api
-            .patch(&cr.name_any(), &patch_params, &Patch::Apply(&cr))
+            .patch(&cr.name_any(), patch_params, &Patch::Apply(&cr))
             .await
             .map_err(Error::PatchClusterRole)?;
         crb_api
-            .patch(&crb.name_any(), &patch_params, &Patch::Apply(&crb))
+            .patch(&crb.name_any(), patch_params, &Patch::Apply(&crb))
             .await
             .map_err(Error::PatchClusterRoleBinding)?;
     }
 
+    Ok(())
+}
+
+pub async fn reconcile_cronpolicy(
+    cp: Arc<CronPolicy>,
+    ctx: Arc<ReconcilerContext>,
+) -> Result<Action, Error> {
+    let client = &ctx.client;
+    let config = &ctx.config;
+
+    // Prepare Kubernetes object ownership reference
+    let oref = cp.controller_owner_ref(&()).unwrap();
+
+    let cp_name = cp.name_any();
+
+    // Resolve plural names of the resources
+    let resources = discover_resources(&ctx, &cp.spec.resources).await;
+    let cronjob_namespace = cp
+        .spec
+        .namespace
+        .clone()
+        .unwrap_or_else(|| config.default_checker_namespace.clone());
+
+    // Prepare Kubernetes APIs
+    let cj_api = Api::<CronJob>::namespaced(client.clone(), &cronjob_namespace);
+    let patch_params = PatchParams::apply("cronpolicy.checkpoint.devsisters.com");
+
+    // Create ServiceAccount, and Roles or ClusterRole for checker
+    apply_checker_rbac(
+        client,
+        &patch_params,
+        CRONPOLICY_OWNED_LABEL_KEY,
+        cp_name.clone(),
+        cronjob_namespace.clone(),
+        oref.clone(),
+        &resources,
+        cp.spec.access.as_deref().unwrap_or_default(),
+        cp.spec.finding_annotation || cp.spec.allow_remediation,
+    )
+    .await?;
+
+    // Create ConfigMap to persist the checker state
+    if cp.spec.persists_state() {
+        let (cm, r, rb) =
+            make_state_objects(cp_name.clone(), cronjob_namespace.clone(), oref.clone());
+        let cm_api = Api::<ConfigMap>::namespaced(client.clone(), &cronjob_namespace);
+        let r_api = Api::<Role>::namespaced(client.clone(), &cronjob_namespace);
+        let rb_api = Api::<RoleBinding>::namespaced(client.clone(), &cronjob_namespace);
+        cm_api
+            .patch(&cm.name_any(), &patch_params, &Patch::Apply(&cm))
+            .await
+            .map_err(Error::PatchConfigMap)?;
+        r_api
+            .patch(&r.name_any(), &patch_params, &Patch::Apply(&r))
+            .await
+            .map_err(Error::PatchRole)?;
+        rb_api
+            .patch(&rb.name_any(), &patch_params, &Patch::Apply(&rb))
+            .await
+            .map_err(Error::PatchRoleBinding)?;
+    }
+
     // Create CronJob of checker
-    let cj = make_cronjob(cp_name.clone(), cronjob_namespace, oref, &cp.spec, config)?;
+    let cj = make_cronjob(
+        cp_name.clone(),
+        cronjob_namespace.clone(),
+        oref,
+        &cp.spec,
+        &resources,
+        config,
+    )?;
+    let cj_name = cj.name_any();
+    let cj = add_time_zone(&cj, cp.spec.time_zone.as_deref()).map_err(Error::SerializeCronJob)?;
     cj_api
-        .patch(&cj.name_any(), &patch_params, &Patch::Apply(&cj))
+        .patch(&cj_name, &patch_params, &Patch::Apply(&cj))
         .await
         .map_err(Error::PatchCronJob)?;
 
+    // Run the checker immediately if the trigger annotation is newly set
+    let trigger = cp.annotations().get(TRIGGER_ANNOTATION_KEY);
+    let last_trigger = cp
+        .status
+        .as_ref()
+        .and_then(|status| status.last_manual_trigger.as_ref())
+        .map(|last_manual_trigger| &last_manual_trigger.trigger);
+    if let Some(trigger) = trigger.filter(|trigger| Some(*trigger) != last_trigger) {
+        trigger_cronpolicy(client, &cp, trigger, &cronjob_namespace, &resources, config).await?;
+    }
+
+    Ok(Action::await_change())
+}
+
+/// Create a Job from the job template of the CronPolicy, like `kubectl create job --from=cronjob/<name>`,
+/// and record it in the status
+async fn trigger_cronpolicy(
+    client: &kube::Client,
+    cp: &CronPolicy,
+    trigger: &str,
+    cronjob_namespace: &str,
+    resources: &[CronPolicyResource],
+    config: &ControllerConfig,
+) -> Result<(), Error> {
+    let cp_name = cp.name_any();
+    let now = Utc::now();
+    let job_name = manual_job_name(&cp_name, trigger);
+
+    let mut job = Job {
+        metadata: ObjectMeta {
+            name: Some(job_name.clone()),
+            namespace: Some(cronjob_namespace.to_string()),
+            owner_references: Some(vec![cp.controller_owner_ref(&()).unwrap()]),
+            labels: Some(make_labels(CRONPOLICY_OWNED_LABEL_KEY, cp_name.clone())),
+            ..Default::default()
+        },
+        spec: Some(make_job_spec(
+            cp_name.clone(),
+            cp_name.clone(),
+            (&cp.spec).into(),
+            resources,
+            config,
+        )?),
+        status: None,
+    };
+    job.annotations_mut().insert(
+        "cronjob.kubernetes.io/instantiate".to_string(),
+        "manual".to_string(),
+    );
+
+    // The Job exists if the status patch of the previous attempt failed
+    let job_api = Api::<Job>::namespaced(client.clone(), cronjob_namespace);
+    match job_api.create(&PostParams::default(), &job).await {
+        Ok(_) => tracing::info!(%cp_name, %job_name, "triggered checker job manually"),
+        Err(kube::Error::Api(response)) if response.code == 409 => {
+            tracing::debug!(%cp_name, %job_name, "checker job already triggered");
+        }
+        Err(error) => return Err(Error::CreateJob(error)),
+    }
+
+    let status = CronPolicyStatus {
+        last_manual_trigger: Some(CronPolicyManualTrigger {
+            trigger: trigger.to_string(),
+            job_name,
+            time: Time(now),
+        }),
+    };
+    let patch = serde_json::json!({ "status": status });
+    Api::<CronPolicy>::all(client.clone())
+        .patch_status(&cp_name, &PatchParams::default(), &Patch::Merge(patch))
+        .await
+        .map_err(Error::PatchStatus)?;
+
+    Ok(())
+}
+
+/// Name of the Job manually triggered with the trigger value
+///
+/// Derived from the trigger value, so that retries of the same trigger do not create another Job.
+fn manual_job_name(cp_name: &str, trigger: &str) -> String {
+    let hash = trigger.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{}-manual-{:08x}", cp_name, hash as u32)
+}
+
+/// Name of the checker Job and its ServiceAccount and roles of a Policy
+///
+/// Prefixed not to collide with the ones of a CronPolicy with the same name.
+fn policy_job_name(name: &str) -> String {
+    format!("policy-{}", name)
+}
+
+/// Summarize the checker Job into Policy status
+fn make_policy_status(job: &Job) -> PolicyStatus {
+    let job_status = job.status.clone().unwrap_or_default();
+    let phase = if job_status.succeeded.unwrap_or_default() > 0 {
+        PolicyPhase::Succeeded
+    } else if job_status
+        .conditions
+        .unwrap_or_default()
+        .iter()
+        .any(|condition| condition.type_ == "Failed" && condition.status == "True")
+    {
+        PolicyPhase::Failed
+    } else {
+        PolicyPhase::Running
+    };
+    PolicyStatus {
+        job_name: Some(job.name_any()),
+        phase: Some(phase),
+        start_time: job_status.start_time,
+        completion_time: job_status.completion_time,
+    }
+}
+
+/// Policy reconciler
+///
+/// The checker Job is created only once, and its progress is recorded in the status.
+pub async fn reconcile_policy(
+    policy: Arc<Policy>,
+    ctx: Arc<ReconcilerContext>,
+) -> Result<Action, Error> {
+    let client = &ctx.client;
+    let config = &ctx.config;
+
+    // Prepare Kubernetes object ownership reference
+    let oref = policy.controller_owner_ref(&()).unwrap();
+
+    let policy_name = policy.name_any();
+    let job_name = policy_job_name(&policy_name);
+    let job_namespace = policy
+        .spec
+        .namespace
+        .clone()
+        .unwrap_or_else(|| config.default_checker_namespace.clone());
+
+    // Prepare Kubernetes APIs
+    let policy_api = Api::<Policy>::all(client.clone());
+    let job_api = Api::<Job>::namespaced(client.clone(), &job_namespace);
+    let patch_params = PatchParams::apply("policy.checkpoint.devsisters.com");
+
+    let job = match job_api
+        .get_opt(&job_name)
+        .await
+        .map_err(Error::Kubernetes)?
+    {
+        Some(job) => job,
+        None if policy
+            .status
+            .as_ref()
+            .and_then(|status| status.job_name.as_ref())
+            .is_some() =>
+        {
+            // The Job was deleted after it ran, e.g. by TTL. Do not run the check again
+            return Ok(Action::await_change());
+        }
+        None => {
+            // Resolve plural names of the resources
+            let resources = discover_resources(&ctx, &policy.spec.resources).await;
+
+            // Create ServiceAccount, and Roles or ClusterRole for checker
+            apply_checker_rbac(
+                client,
+                &patch_params,
+                POLICY_OWNED_LABEL_KEY,
+                job_name.clone(),
+                job_namespace.clone(),
+                oref.clone(),
+                &resources,
+                policy.spec.access.as_deref().unwrap_or_default(),
+                policy.spec.finding_annotation || policy.spec.allow_remediation,
+            )
+            .await?;
+
+            // Create Job of checker
+            let job = make_job(
+                job_name.clone(),
+                policy_name.clone(),
+                job_namespace,
+                oref,
+                &policy.spec,
+                &resources,
+                config,
+            )?;
+            job_api
+                .patch(&job_name, &patch_params, &Patch::Apply(&job))
+                .await
+                .map_err(Error::PatchJob)?
+        }
+    };
+
+    // Record the progress of the Job
+    let status = make_policy_status(&job);
+    if policy.status.as_ref() != Some(&status) {
+        let patch = serde_json::json!({ "status": status });
+        policy_api
+            .patch_status(&policy_name, &PatchParams::default(), &Patch::Merge(patch))
+            .await
+            .map_err(Error::PatchStatus)?;
+    }
+
     Ok(Action::await_change())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::policy::CronPolicyPrometheusQuery;
 
     #[tokio::test]
     async fn test_make_roles_and_clusterroles() {
@@ -485,10 +1332,13 @@ mod tests {
         let resources = Vec::new();
 
         let (roles, clusterrole) = make_roles_and_clusterroles(
+            CRONPOLICY_OWNED_LABEL_KEY,
             cp_name.clone(),
             cronjob_namespace.clone(),
             oref.clone(),
             &resources,
+            &[],
+            false,
             kube_client.clone(),
         )
         .await
@@ -507,6 +1357,9 @@ mod tests {
                 namespace: None,
                 name: None,
                 list_params: None,
+                prometheus_query: None,
+                http: None,
+                cluster_ref: None,
             },
             CronPolicyResource {
                 group: Some("".to_string()),
@@ -516,6 +1369,9 @@ mod tests {
                 namespace: Some(some_namespace.clone()),
                 name: None,
                 list_params: None,
+                prometheus_query: None,
+                http: None,
+                cluster_ref: None,
             },
             CronPolicyResource {
                 group: Some("apps".to_string()),
@@ -525,6 +1381,9 @@ mod tests {
                 namespace: None,
                 name: None,
                 list_params: None,
+                prometheus_query: None,
+                http: None,
+                cluster_ref: None,
             },
             CronPolicyResource {
                 group: Some("apps".to_string()),
@@ -534,6 +1393,9 @@ mod tests {
                 namespace: Some(some_namespace.clone()),
                 name: None,
                 list_params: None,
+                prometheus_query: None,
+                http: None,
+                cluster_ref: None,
             },
             CronPolicyResource {
                 group: Some("apps".to_string()),
@@ -543,14 +1405,36 @@ mod tests {
                 namespace: Some(other_namespace.clone()),
                 name: None,
                 list_params: None,
+                prometheus_query: None,
+                http: None,
+                cluster_ref: None,
+            },
+            CronPolicyResource {
+                group: None,
+                version: None,
+                kind: "".to_string(),
+                plural: None,
+                namespace: Some(some_namespace.clone()),
+                name: None,
+                list_params: None,
+                prometheus_query: Some(CronPolicyPrometheusQuery {
+                    endpoint: "http://prometheus:9090".parse().unwrap(),
+                    query: "up".to_string(),
+                    auth: None,
+                }),
+                http: None,
+                cluster_ref: None,
             },
         ];
 
         let (roles, clusterrole) = make_roles_and_clusterroles(
+            CRONPOLICY_OWNED_LABEL_KEY,
             cp_name.clone(),
             cronjob_namespace,
             oref,
             &resources,
+            &[],
+            false,
             kube_client,
         )
         .await
@@ -587,4 +1471,182 @@ mod tests {
         assert_eq!(rule.api_groups, Some(vec!["apps".to_string()]));
         assert_eq!(rule.resources, Some(vec!["deployments".to_string()]));
     }
+
+    #[tokio::test]
+    async fn test_make_role_rules() {
+        // Mock Kubernetes client
+        // This client will not work
+        let kube_config = kube::Config {
+            cluster_url: "https://localhost:443".parse().unwrap(),
+            default_namespace: "default".to_string(),
+            root_cert: None,
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            accept_invalid_certs: true,
+            auth_info: Default::default(),
+            proxy_url: None,
+            tls_server_name: None,
+        };
+        let kube_client: kube::Client = kube_config.try_into().unwrap();
+
+        let make_resource = |kind: &str, name: Option<&str>| CronPolicyResource {
+            group: Some("".to_string()),
+            version: Some("v1".to_string()),
+            kind: kind.to_string(),
+            plural: None,
+            namespace: Some("some-namespace".to_string()),
+            name: name.map(str::to_string),
+            list_params: None,
+            prometheus_query: None,
+            http: None,
+            cluster_ref: None,
+        };
+        let resources = vec![
+            make_resource("Pod", Some("pod-b")),
+            make_resource("ConfigMap", Some("configmap")),
+            make_resource("Pod", None),
+            make_resource("Pod", Some("pod-a")),
+        ];
+
+        let rules = make_role_rules(&resources, &[], false, kube_client)
+            .await
+            .unwrap();
+        assert_eq!(rules.len(), 3);
+        let rule = &rules[0];
+        assert_eq!(rule.resources, Some(vec!["configmaps".to_string()]));
+        assert_eq!(rule.verbs, vec!["get".to_string()]);
+        assert_eq!(rule.resource_names, Some(vec!["configmap".to_string()]));
+        let rule = &rules[1];
+        assert_eq!(rule.resources, Some(vec!["pods".to_string()]));
+        assert_eq!(rule.verbs, vec!["list".to_string()]);
+        assert_eq!(rule.resource_names, None);
+        let rule = &rules[2];
+        assert_eq!(rule.resources, Some(vec!["pods".to_string()]));
+        assert_eq!(rule.verbs, vec!["get".to_string()]);
+        assert_eq!(
+            rule.resource_names,
+            Some(vec!["pod-a".to_string(), "pod-b".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_manual_job_name() {
+        assert_eq!(manual_job_name("cp", "a"), "cp-manual-8601ec8c");
+        assert_eq!(
+            manual_job_name("cp", "2026-10-17T00:00:00Z"),
+            manual_job_name("cp", "2026-10-17T00:00:00Z")
+        );
+        assert_ne!(manual_job_name("cp", "1"), manual_job_name("cp", "2"));
+    }
+
+    #[test]
+    fn test_resolve_plurals() {
+        let resources = vec![
+            CronPolicyResource {
+                group: Some("".to_string()),
+                version: Some("v1".to_string()),
+                kind: "Endpoints".to_string(),
+                plural: None,
+                namespace: None,
+                name: None,
+                list_params: None,
+                prometheus_query: None,
+                http: None,
+                cluster_ref: None,
+            },
+            CronPolicyResource {
+                group: Some("example.com".to_string()),
+                version: Some("v1".to_string()),
+                kind: "Octopus".to_string(),
+                plural: Some("octopi".to_string()),
+                namespace: None,
+                name: None,
+                list_params: None,
+                prometheus_query: None,
+                http: None,
+                cluster_ref: None,
+            },
+            CronPolicyResource {
+                group: Some("apps".to_string()),
+                version: Some("v1".to_string()),
+                kind: "Deployment".to_string(),
+                plural: None,
+                namespace: None,
+                name: None,
+                list_params: None,
+                prometheus_query: None,
+                http: None,
+                cluster_ref: None,
+            },
+        ];
+        let mut plural_overrides = HashMap::new();
+        plural_overrides.insert("Endpoints".to_string(), "endpoints".to_string());
+        plural_overrides.insert("Octopus".to_string(), "octopuses".to_string());
+
+        let resolved = resolve_plurals(&resources, &plural_overrides, None);
+        assert_eq!(resolved[0].plural, Some("endpoints".to_string()));
+        assert_eq!(resolved[1].plural, Some("octopi".to_string()));
+        assert_eq!(resolved[2].plural, None);
+        assert!(!needs_discovery(&resources[0], &plural_overrides));
+        assert!(!needs_discovery(&resources[1], &plural_overrides));
+        assert!(needs_discovery(&resources[2], &plural_overrides));
+    }
+
+    #[test]
+    fn test_resolve_plurals_with_discovery() {
+        let resource = |group: Option<&str>, kind: &str| CronPolicyResource {
+            group: group.map(str::to_string),
+            version: None,
+            kind: kind.to_string(),
+            plural: None,
+            namespace: None,
+            name: None,
+            list_params: None,
+            prometheus_query: None,
+            http: None,
+            cluster_ref: None,
+        };
+        let discovered = DiscoveredPlurals::new(
+            [
+                ("Deployment", "apps", "deployments"),
+                ("Ingress", "networking.k8s.io", "ingresses"),
+                ("Endpoints", "", "endpoints"),
+                ("Gateway", "gateway.networking.k8s.io", "gateways"),
+                ("Gateway", "networking.istio.io", "gateway-istios"),
+            ]
+            .map(|(kind, group, plural)| (kind.to_string(), group.to_string(), plural.to_string())),
+        );
+
+        let resources = vec![
+            resource(Some("apps"), "Deployment"),
+            // Group is omitted, so the kind is looked up in every group
+            resource(None, "Ingress"),
+            resource(None, "Endpoints"),
+            // Group does not serve the kind
+            resource(Some("extensions"), "Ingress"),
+            // Groups disagree on the plural name
+            resource(None, "Gateway"),
+            resource(Some("networking.istio.io"), "Gateway"),
+            // Unknown kind
+            resource(None, "Octopus"),
+        ];
+        let resolved = resolve_plurals(&resources, &HashMap::new(), Some(&discovered));
+        let plurals: Vec<_> = resolved
+            .iter()
+            .map(|resource| resource.plural.as_deref())
+            .collect();
+        assert_eq!(
+            plurals,
+            vec![
+                Some("deployments"),
+                Some("ingresses"),
+                Some("endpoints"),
+                None,
+                None,
+                Some("gateway-istios"),
+                None,
+            ]
+        );
+    }
 }
@@ -1,28 +1,69 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Debug,
+    sync::Arc,
+};
 
+use chrono::Utc;
+use itertools::join;
 use k8s_openapi::{
-    api::admissionregistration::v1::{
-        MutatingWebhook, MutatingWebhookConfiguration, ServiceReference, ValidatingWebhook,
-        ValidatingWebhookConfiguration, WebhookClientConfig,
+    api::{
+        admissionregistration::v1::{
+            MutatingWebhook, MutatingWebhookConfiguration, RuleWithOperations, ServiceReference,
+            ValidatingWebhook, ValidatingWebhookConfiguration, WebhookClientConfig,
+        },
+        authorization::v1::{
+            ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec,
+        },
     },
+    apimachinery::pkg::apis::meta::v1::{Condition, LabelSelector, Time},
     ByteString,
 };
 use kube::{
-    api::{ObjectMeta, Patch, PatchParams},
-    runtime::controller::Action,
-    Api, Resource,
+    api::{
+        ApiResource, DeleteParams, DynamicObject, ListParams, ObjectMeta, Patch, PatchParams,
+        PostParams,
+    },
+    runtime::{
+        controller::Action,
+        events::{Event, EventType},
+        finalizer::{self, finalizer},
+    },
+    Api, Resource, ResourceExt,
 };
+use serde::{de::DeserializeOwned, Serialize};
 use thiserror::Error;
+use url::Url;
 
-use super::ReconcilerContext;
+use super::{json_contains, set_condition, ReconcilerContext};
 use crate::{
     config::ControllerConfig,
-    types::rule::{MutatingRule, ValidatingRule},
+    handler::{
+        js::helper::prepare_kube_client,
+        preflight::{check_rule, check_service_account, COMPILE_FAILED_REASON},
+    },
+    types::{
+        rule::{
+            KubeClientMode, MatchCondition, MutatingRule, NamespacedMutatingRule,
+            NamespacedValidatingRule, RuleAccess, RuleSpec, RuleStatus, SideEffectClass,
+            ValidatingRule,
+        },
+        ruleset::RuleSet,
+    },
 };
 
 pub const VALIDATINGRULE_OWNED_LABEL_KEY: &str = "checkpoint.devsisters.com/validatingrule";
 pub const MUTATINGRULE_OWNED_LABEL_KEY: &str = "checkpoint.devsisters.com/mutatingrule";
 pub const SHOULD_UPDATE_ANNOTATION_KEY: &str = "checkpoint.devsisters.com/should-update";
+pub const NAMESPACEDVALIDATINGRULE_OWNED_LABEL_KEY: &str =
+    "checkpoint.devsisters.com/namespacedvalidatingrule";
+pub const NAMESPACEDMUTATINGRULE_OWNED_LABEL_KEY: &str =
+    "checkpoint.devsisters.com/namespacedmutatingrule";
+pub const RULESET_OWNED_LABEL_KEY: &str = "checkpoint.devsisters.com/ruleset";
+pub const RULE_NAMESPACE_LABEL_KEY: &str = "checkpoint.devsisters.com/namespace";
+pub const RULE_FINALIZER: &str = "checkpoint.devsisters.com/cleanup";
+pub const SERVICEACCOUNT_PERMITTED_CONDITION_TYPE: &str = "ServiceAccountPermitted";
+pub const PREFLIGHT_PASSED_CONDITION_TYPE: &str = "PreflightPassed";
 
 /// Errors can be raised within reconciler
 #[derive(Debug, Error)]
@@ -33,73 +74,247 @@ pub enum Error {
     ValidatingWebhookConfigurationCreationFailed(#[source] kube::Error),
     #[error("Failed to create MutatingWebhookConfiguration: {0}")]
     MutatingWebhookConfigurationCreationFailed(#[source] kube::Error),
+    #[error("Failed to patch status: {0}")]
+    PatchStatus(#[source] kube::Error),
+    #[error("Failed to serialize webhook configuration (This is a bug): {0}")]
+    SerializeWebhookConfiguration(#[source] serde_json::Error),
+    #[error("Failed to delete webhook configuration: {0}")]
+    DeleteWebhookConfiguration(#[source] kube::Error),
+    #[error("Failed to list webhook configurations: {0}")]
+    ListWebhookConfiguration(#[source] kube::Error),
+    #[error("Failed to list rules: {0}")]
+    ListRule(#[source] kube::Error),
+    #[error("Failed to get rule: {0}")]
+    GetRule(#[source] kube::Error),
+    #[error("Failed to list RuleSets: {0}")]
+    ListRuleSet(#[source] kube::Error),
+    #[error("Finalizer error: {0}")]
+    Finalizer(#[source] Box<finalizer::Error<Error>>),
 }
 
+/// Client config pointing at the path of the rule, at the external URL if specified
 fn webhook_client_config(
     config: &ControllerConfig,
+    spec: &RuleSpec,
     ca_bundle: ByteString,
-    path: &str,
-    rule_name: &str,
+    path: String,
 ) -> WebhookClientConfig {
+    let client_config = spec.client_config.clone().unwrap_or_default();
+    let path = match &client_config.path_prefix {
+        Some(path_prefix) if !path_prefix.trim_matches('/').is_empty() => {
+            format!("/{}{}", path_prefix.trim_matches('/'), path)
+        }
+        _ => path,
+    };
+    let external_url = client_config
+        .url
+        .or_else(|| config.webhook_external_url.as_ref().map(Url::to_string));
+    if let Some(external_url) = external_url {
+        return WebhookClientConfig {
+            ca_bundle: Some(ca_bundle),
+            service: None,
+            url: Some(format!("{}{}", external_url.trim_end_matches('/'), path)),
+        };
+    }
     WebhookClientConfig {
         ca_bundle: Some(ca_bundle),
         service: Some(ServiceReference {
             namespace: config.service_namespace.clone(),
             name: config.service_name.clone(),
-            path: Some(format!("/{}/{}", path, rule_name)),
-            port: Some(config.service_port),
+            path: Some(path),
+            port: Some(client_config.port.unwrap_or(config.service_port)),
         }),
         url: None,
     }
 }
 
+/// Set labels that indicate the webhook configuration is owned by a rule
+fn make_labels(
+    owned_label_key: &str,
+    name: &str,
+    namespace: Option<&str>,
+) -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::new();
+    labels.insert(owned_label_key.to_string(), name.to_string());
+    if let Some(namespace) = namespace {
+        labels.insert(RULE_NAMESPACE_LABEL_KEY.to_string(), namespace.to_string());
+    }
+    labels
+}
+
+/// Name of the webhook configuration generated from a namespaced rule
+pub fn namespaced_webhook_configuration_name(namespace: &str, name: &str) -> String {
+    format!("namespaced.{}.{}", namespace, name)
+}
+
+/// Name of the webhook configuration generated from a RuleSet
+pub fn ruleset_webhook_configuration_name(name: &str) -> String {
+    format!("ruleset.{}", name)
+}
+
+/// Prefix the priority of the mutating rule to the name of its webhook configuration
+///
+/// The API server calls mutating webhooks in the order of the names of their configurations, and
+/// digits sort before letters, so rules with priority mutate before the ones without.
+pub fn prioritized_webhook_configuration_name(name: String, priority: Option<u16>) -> String {
+    match priority {
+        Some(priority) => format!("{:03}.{}", priority, name),
+        None => name,
+    }
+}
+
+/// Match criteria of a webhook generated from a rule
+pub struct WebhookEntry {
+    pub name: Option<String>,
+    pub namespace_selector: Option<LabelSelector>,
+    pub object_selector: Option<LabelSelector>,
+    pub object_rules: Option<Vec<RuleWithOperations>>,
+    pub timeout_seconds: Option<i32>,
+    pub match_conditions: Option<Vec<MatchCondition>>,
+}
+
+/// List webhooks to generate from the rule
+///
+/// Criteria not specified in a webhook entry default to the ones of the rule.
+pub fn webhook_entries(spec: &RuleSpec) -> Vec<WebhookEntry> {
+    if let Some(webhooks) = &spec.webhooks {
+        webhooks
+            .iter()
+            .map(|webhook| WebhookEntry {
+                name: Some(webhook.name.clone()),
+                namespace_selector: webhook
+                    .namespace_selector
+                    .clone()
+                    .or_else(|| spec.namespace_selector.clone()),
+                object_selector: webhook
+                    .object_selector
+                    .clone()
+                    .or_else(|| spec.object_selector.clone()),
+                object_rules: webhook
+                    .object_rules
+                    .clone()
+                    .or_else(|| spec.object_rules.clone()),
+                timeout_seconds: webhook.timeout_seconds.or(spec.timeout_seconds),
+                match_conditions: webhook
+                    .match_conditions
+                    .clone()
+                    .or_else(|| spec.match_conditions.clone()),
+            })
+            .collect()
+    } else {
+        vec![WebhookEntry {
+            name: None,
+            namespace_selector: spec.namespace_selector.clone(),
+            object_selector: spec.object_selector.clone(),
+            object_rules: spec.object_rules.clone(),
+            timeout_seconds: spec.timeout_seconds,
+            match_conditions: spec.match_conditions.clone(),
+        }]
+    }
+}
+
+/// Exclude the namespace of checkpoint from the selectors of the rule, unless the rule opts out
+fn exclude_checkpoint_namespace(mut spec: RuleSpec, namespace: &str) -> RuleSpec {
+    if !spec.include_checkpoint_namespace {
+        spec.exclude_namespaces(vec![namespace.to_string()]);
+    }
+    spec
+}
+
+/// Spec of the rule to generate webhook configurations from, with the CheckpointConfig applied
+fn effective_rule_spec(ctx: &ReconcilerContext, spec: &RuleSpec) -> RuleSpec {
+    let spec = ctx.dynamic_config.current().apply_to_rule(spec);
+    if ctx.config.exclude_own_namespace {
+        exclude_checkpoint_namespace(spec, &ctx.config.service_namespace)
+    } else {
+        spec
+    }
+}
+
+/// Add matchConditions to webhooks of the webhook configuration, in the order of webhook entries.
+///
+/// k8s-openapi does not support matchConditions yet, so the field is added to the serialized object.
+fn add_match_conditions<T>(
+    webhook_configuration: &T,
+    spec: &RuleSpec,
+) -> Result<serde_json::Value, serde_json::Error>
+where
+    T: Serialize,
+{
+    let mut value = serde_json::to_value(webhook_configuration)?;
+    if let Some(webhooks) = value["webhooks"].as_array_mut() {
+        for (webhook, entry) in webhooks.iter_mut().zip(webhook_entries(spec)) {
+            if let Some(match_conditions) = entry.match_conditions {
+                webhook["matchConditions"] = serde_json::to_value(match_conditions)?;
+            }
+        }
+    }
+    Ok(value)
+}
+
 macro_rules! webhook_configuration {
     (
         @internal
         $webhook_configuration_ty:ident,
         $webhook_ty:ident,
         $ty:expr,
-        $path:expr,
-        $owned_label_key:ident,
         $name:expr,
-        $oref:expr,
+        $labels:expr,
+        $path:expr,
+        $owner_references:expr,
         $spec:expr,
         $config:expr,
-        $ca_bundle_lock:expr
+        $ca_bundle_lock:expr,
+        { $($extra_field:ident: $extra_value:expr),* $(,)? }
     ) => {
         {
             // Read CA bundle from RwLock
             let ca_bundle = $ca_bundle_lock.read().await.clone();
-
-            let mut labels = ::std::collections::BTreeMap::default();
-            labels.insert($owned_label_key.to_string(), $name.clone());
+            let spec: &RuleSpec = &$spec;
 
             $webhook_configuration_ty {
                 metadata: ObjectMeta {
                     name: Some($name.clone()),
-                    owner_references: Some(vec![$oref]),
-                    labels: Some(labels),
+                    owner_references: $owner_references,
+                    labels: Some($labels),
                     ..Default::default()
                 },
-                webhooks: Some(vec![$webhook_ty {
-                    name: format!("{}.{}.checkpoint.devsisters.com", $name, $ty),
-                    failure_policy: $spec.failure_policy.map(|fp| fp.to_string()),
-                    namespace_selector: $spec.namespace_selector,
-                    object_selector: $spec.object_selector,
-                    rules: $spec.object_rules,
-                    timeout_seconds: $spec.timeout_seconds,
-                    client_config: webhook_client_config(&$config, ca_bundle, $path, &$name),
-                    admission_review_versions: vec!["v1".to_string()],
-                    side_effects: "None".to_string(),
-                    ..Default::default()
-                }]),
+                webhooks: Some(
+                    webhook_entries(spec)
+                        .into_iter()
+                        .map(|entry| $webhook_ty {
+                            name: if let Some(entry_name) = &entry.name {
+                                format!("{}.{}.{}.checkpoint.devsisters.com", entry_name, $name, $ty)
+                            } else {
+                                format!("{}.{}.checkpoint.devsisters.com", $name, $ty)
+                            },
+                            failure_policy: spec.failure_policy.as_ref().map(|fp| fp.to_string()),
+                            namespace_selector: entry.namespace_selector,
+                            object_selector: entry.object_selector,
+                            rules: entry.object_rules,
+                            timeout_seconds: entry.timeout_seconds,
+                            match_policy: spec.match_policy.as_ref().map(|mp| mp.to_string()),
+                            client_config: webhook_client_config(
+                                &$config,
+                                spec,
+                                ca_bundle.clone(),
+                                $path.clone(),
+                            ),
+                            admission_review_versions: vec!["v1".to_string()],
+                            side_effects: spec.side_effect_class().to_string(),
+                            $($extra_field: $extra_value,)*
+                        })
+                        .collect(),
+                ),
             }
         }
     };
     (
         validate,
         $name:expr,
-        $oref:expr,
+        $labels:expr,
+        $path:expr,
+        $owner_references:expr,
         $spec:expr,
         $config:expr,
         $ca_bundle_lock:expr
@@ -109,19 +324,22 @@ macro_rules! webhook_configuration {
             ValidatingWebhookConfiguration,
             ValidatingWebhook,
             "validatingwebhook",
-            "validate",
-            VALIDATINGRULE_OWNED_LABEL_KEY,
             $name,
-            $oref,
+            $labels,
+            $path,
+            $owner_references,
             $spec,
             $config,
-            $ca_bundle_lock
+            $ca_bundle_lock,
+            {}
         )
     };
     (
         mutate,
         $name:expr,
-        $oref:expr,
+        $labels:expr,
+        $path:expr,
+        $owner_references:expr,
         $spec:expr,
         $config:expr,
         $ca_bundle_lock:expr
@@ -131,18 +349,175 @@ macro_rules! webhook_configuration {
             MutatingWebhookConfiguration,
             MutatingWebhook,
             "mutatingwebhook",
-            "mutate",
-            MUTATINGRULE_OWNED_LABEL_KEY,
             $name,
-            $oref,
+            $labels,
+            $path,
+            $owner_references,
             $spec,
             $config,
-            $ca_bundle_lock
+            $ca_bundle_lock,
+            {
+                reinvocation_policy: $spec.reinvocation_policy.as_ref().map(|rp| rp.to_string()),
+            }
         )
     };
 }
 
+/// Review whether the ServiceAccount of the rule is permitted to access the resources
+///
+/// Returns status, reason, and message of the condition.
+async fn review_access(
+    spec: &RuleSpec,
+    access: &[RuleAccess],
+) -> (&'static str, &'static str, String) {
+    let client = match prepare_kube_client(spec.service_account.clone(), spec.timeout_seconds).await
+    {
+        Ok(client) => client,
+        Err(error) => return ("False", "ServiceAccountUnavailable", format!("{:#}", error)),
+    };
+    let ssar_api = Api::<SelfSubjectAccessReview>::all(client);
+
+    let mut forbidden = Vec::new();
+    for access in access {
+        let ssar = SelfSubjectAccessReview {
+            metadata: Default::default(),
+            spec: SelfSubjectAccessReviewSpec {
+                resource_attributes: Some(ResourceAttributes {
+                    group: Some(access.group.clone()),
+                    resource: Some(access.resource.clone()),
+                    verb: Some(access.verb.to_string()),
+                    namespace: access.namespace.clone(),
+                    name: access.name.clone(),
+                    ..Default::default()
+                }),
+                non_resource_attributes: None,
+            },
+            status: None,
+        };
+        match ssar_api.create(&PostParams::default(), &ssar).await {
+            Ok(SelfSubjectAccessReview {
+                status: Some(status),
+                ..
+            }) if status.allowed => {}
+            Ok(_) => forbidden.push(access),
+            Err(error) => return ("Unknown", "AccessReviewFailed", error.to_string()),
+        }
+    }
+
+    if forbidden.is_empty() {
+        (
+            "True",
+            "Permitted",
+            "ServiceAccount is permitted to access all resources".to_string(),
+        )
+    } else {
+        let forbidden = forbidden.into_iter().map(|access| {
+            format!(
+                "`{}` `{}` in group `{}`{}{}",
+                access.verb,
+                access.resource,
+                access.group,
+                access
+                    .namespace
+                    .as_ref()
+                    .map(|namespace| format!(" in namespace `{}`", namespace))
+                    .unwrap_or_default(),
+                access
+                    .name
+                    .as_ref()
+                    .map(|name| format!(" named `{}`", name))
+                    .unwrap_or_default(),
+            )
+        });
+        (
+            "False",
+            "Forbidden",
+            format!(
+                "ServiceAccount is not permitted to {}",
+                join(forbidden, ", ")
+            ),
+        )
+    }
+}
+
+/// Update `PreflightPassed` condition of the rule, and `ServiceAccountPermitted` condition if the rule declares
+/// its access
+///
+/// Access of rules impersonating the requester depends on the requester, so it is not reviewed. The code is only
+/// compiled again when the generation of the rule changes.
+async fn update_conditions<K>(
+    client: &kube::Client,
+    api: &Api<K>,
+    name: &str,
+    generation: Option<i64>,
+    spec: &RuleSpec,
+    status: Option<&RuleStatus>,
+) -> Result<(), Error>
+where
+    K: Resource + Clone + DeserializeOwned + Debug,
+{
+    let mut conditions = status
+        .map(|status| status.conditions.clone())
+        .unwrap_or_default();
+    // The code only changes with the generation, so the compile result of the generation is reused
+    let last_preflight = conditions
+        .iter()
+        .find(|condition| condition.type_ == PREFLIGHT_PASSED_CONDITION_TYPE)
+        .filter(|condition| generation.is_some() && condition.observed_generation == generation);
+    let preflight = match last_preflight {
+        Some(condition) if condition.reason != COMPILE_FAILED_REASON => {
+            check_service_account(client, spec).await
+        }
+        Some(condition) if condition.status == "False" => {
+            ("False", COMPILE_FAILED_REASON, condition.message.clone())
+        }
+        _ => check_rule(client, spec).await,
+    };
+
+    let mut changed = false;
+    let mut set = |type_: &str, (condition_status, reason, message): (&str, &str, String)| {
+        changed |= set_condition(
+            &mut conditions,
+            Condition {
+                last_transition_time: Time(Utc::now()),
+                message,
+                observed_generation: generation,
+                reason: reason.to_string(),
+                status: condition_status.to_string(),
+                type_: type_.to_string(),
+            },
+        );
+    };
+
+    set(PREFLIGHT_PASSED_CONDITION_TYPE, preflight);
+    if let Some(access) = &spec.access {
+        if spec.kube_client_mode.unwrap_or_default() != KubeClientMode::ImpersonateRequester {
+            set(
+                SERVICEACCOUNT_PERMITTED_CONDITION_TYPE,
+                review_access(spec, access).await,
+            );
+        }
+    }
+    if !changed {
+        return Ok(());
+    }
+
+    let patch = serde_json::json!({
+        "status": {
+            "conditions": conditions,
+        }
+    });
+    api.patch_status(name, &PatchParams::default(), &Patch::Merge(patch))
+        .await
+        .map_err(Error::PatchStatus)?;
+
+    Ok(())
+}
+
 /// ValidatingRule reconciler
+///
+/// The webhook configuration is owned by the rule, but it is also deleted with a finalizer
+/// so that it does not outlive the rule when garbage collection is delayed.
 pub async fn reconcile_validatingrule(
     validating_rule: Arc<ValidatingRule>,
     ctx: Arc<ReconcilerContext>,
@@ -150,43 +525,188 @@ pub async fn reconcile_validatingrule(
     // Get Kubernetes client from context data
     let client = &ctx.client;
 
-    let validating_rule = (*validating_rule).clone();
+    // Prepare Kubernetes API
+    let vr_api = Api::<ValidatingRule>::all(client.clone());
+    let vwc_api = Api::<ValidatingWebhookConfiguration>::all(client.clone());
+
+    finalizer(&vr_api, RULE_FINALIZER, validating_rule, |event| async {
+        match event {
+            finalizer::Event::Apply(validating_rule) => {
+                let validating_rule = (*validating_rule).clone();
 
-    // Prepare ownership reference
-    let oref = validating_rule.controller_owner_ref(&()).unwrap();
+                // Prepare ownership reference
+                let oref = validating_rule.controller_owner_ref(&()).unwrap();
+
+                let name = validating_rule
+                    .metadata
+                    .name
+                    .clone()
+                    .ok_or(Error::MissingObjectKey(".metadata.name"))?;
+                let spec = effective_rule_spec(&ctx, &validating_rule.spec.0);
+
+                // Report the preflight result and the access of the ServiceAccount
+                update_conditions(
+                    &ctx.client,
+                    &vr_api,
+                    &name,
+                    validating_rule.metadata.generation,
+                    &spec,
+                    validating_rule.status.as_ref().map(|status| &status.0),
+                )
+                .await?;
+
+                // Rules in a RuleSet are evaluated by the webhook of the RuleSet
+                if let Some(rule_set) = rule_set_of(client, &name).await? {
+                    tracing::debug!(%name, %rule_set, "rule is evaluated by RuleSet");
+                    delete_webhook_configuration(&vwc_api, &name).await?;
+                    return Ok(ctx.resync_action());
+                }
+
+                // Popluate ValidatingWebhookConfiguration
+                let vwc: ValidatingWebhookConfiguration = webhook_configuration!(
+                    validate,
+                    name,
+                    make_labels(VALIDATINGRULE_OWNED_LABEL_KEY, &name, None),
+                    format!("/validate/{}", name),
+                    Some(vec![oref]),
+                    spec,
+                    ctx.config,
+                    ctx.ca_bundle
+                );
+
+                let vwc = add_match_conditions(&vwc, &spec)
+                    .map_err(Error::SerializeWebhookConfiguration)?;
+
+                // Create or update ValidatingWebhookConfiguration if it does not exist or drifted
+                let drifted = apply_webhook_configuration::<ValidatingWebhookConfiguration>(
+                    client,
+                    &name,
+                    "validatingrule.checkpoint.devsisters.com",
+                    &vwc,
+                )
+                .await
+                .map_err(Error::ValidatingWebhookConfigurationCreationFailed)?;
+                if drifted {
+                    publish_drift_event(&ctx, &validating_rule, &name).await;
+                }
+
+                Ok(ctx.resync_action())
+            }
+            finalizer::Event::Cleanup(validating_rule) => {
+                delete_webhook_configuration(&vwc_api, &validating_rule.name_any()).await?;
+                ctx.forget_reported_error(validating_rule.uid());
+                Ok(Action::await_change())
+            }
+        }
+    })
+    .await
+    .map_err(|error| Error::Finalizer(Box::new(error)))
+}
+
+/// Name of the RuleSet the ValidatingRule is in, if any
+async fn rule_set_of(client: &kube::Client, name: &str) -> Result<Option<String>, Error> {
+    let rule_sets = Api::<RuleSet>::all(client.clone())
+        .list(&ListParams::default())
+        .await
+        .map_err(Error::ListRuleSet)?;
+    Ok(rule_sets
+        .items
+        .into_iter()
+        .find(|rule_set| rule_set.spec.rules.iter().any(|rule| rule == name))
+        .map(|rule_set| rule_set.name_any()))
+}
 
-    let name = validating_rule
-        .metadata
-        .name
-        .ok_or(Error::MissingObjectKey(".metadata.name"))?;
+/// RuleSet reconciler
+///
+/// The webhook configuration is owned by the RuleSet, and also deleted with a finalizer like the
+/// ones of rules.
+pub async fn reconcile_ruleset(
+    rule_set: Arc<RuleSet>,
+    ctx: Arc<ReconcilerContext>,
+) -> Result<Action, Error> {
+    // Get Kubernetes client from context data
+    let client = &ctx.client;
 
     // Prepare Kubernetes API
+    let rs_api = Api::<RuleSet>::all(client.clone());
+    let vr_api = Api::<ValidatingRule>::all(client.clone());
     let vwc_api = Api::<ValidatingWebhookConfiguration>::all(client.clone());
 
-    // Popluate ValidatingWebhookConfiguration
-    let vwc: ValidatingWebhookConfiguration = webhook_configuration!(
-        validate,
-        name,
-        oref,
-        validating_rule.spec.0,
-        ctx.config,
-        ctx.ca_bundle
-    );
-
-    // Create or update ValidatingWebhookConfiguration
-    vwc_api
-        .patch(
-            &name,
-            &PatchParams::apply("validatingrule.checkpoint.devsisters.com"),
-            &Patch::Apply(&vwc),
-        )
-        .await
-        .map_err(Error::ValidatingWebhookConfigurationCreationFailed)?;
+    finalizer(&rs_api, RULE_FINALIZER, rule_set, |event| async {
+        match event {
+            finalizer::Event::Apply(rule_set) => {
+                let rule_set = (*rule_set).clone();
+
+                // Prepare ownership reference
+                let oref = rule_set.controller_owner_ref(&()).unwrap();
+
+                let name = rule_set
+                    .metadata
+                    .name
+                    .clone()
+                    .ok_or(Error::MissingObjectKey(".metadata.name"))?;
+                let vwc_name = ruleset_webhook_configuration_name(&name);
+
+                // The RuleSet has side effects on dry run if any of the rules has
+                let mut side_effects = SideEffectClass::None;
+                for rule_name in &rule_set.spec.rules {
+                    match vr_api.get_opt(rule_name).await.map_err(Error::GetRule)? {
+                        Some(rule) => {
+                            if rule.spec.0.side_effect_class() == SideEffectClass::NoneOnDryRun {
+                                side_effects = SideEffectClass::NoneOnDryRun;
+                            }
+                        }
+                        None => tracing::warn!(%name, %rule_name, "rule of RuleSet is not found"),
+                    }
+                }
+                let spec = effective_rule_spec(&ctx, &rule_set.spec.to_rule_spec(side_effects));
+
+                // Popluate ValidatingWebhookConfiguration
+                let vwc: ValidatingWebhookConfiguration = webhook_configuration!(
+                    validate,
+                    vwc_name,
+                    make_labels(RULESET_OWNED_LABEL_KEY, &name, None),
+                    format!("/validate-set/{}", name),
+                    Some(vec![oref]),
+                    spec,
+                    ctx.config,
+                    ctx.ca_bundle
+                );
+
+                let vwc = add_match_conditions(&vwc, &spec)
+                    .map_err(Error::SerializeWebhookConfiguration)?;
+
+                // Create or update ValidatingWebhookConfiguration if it does not exist or drifted
+                let drifted = apply_webhook_configuration::<ValidatingWebhookConfiguration>(
+                    client,
+                    &vwc_name,
+                    "validatingrule.checkpoint.devsisters.com",
+                    &vwc,
+                )
+                .await
+                .map_err(Error::ValidatingWebhookConfigurationCreationFailed)?;
+                if drifted {
+                    publish_drift_event(&ctx, &rule_set, &vwc_name).await;
+                }
 
-    Ok(Action::await_change())
+                Ok(ctx.resync_action())
+            }
+            finalizer::Event::Cleanup(rule_set) => {
+                let vwc_name = ruleset_webhook_configuration_name(&rule_set.name_any());
+                delete_webhook_configuration(&vwc_api, &vwc_name).await?;
+                ctx.forget_reported_error(rule_set.uid());
+                Ok(Action::await_change())
+            }
+        }
+    })
+    .await
+    .map_err(|error| Error::Finalizer(Box::new(error)))
 }
 
 /// MutatingRule reconciler
+///
+/// The webhook configuration is owned by the rule, but it is also deleted with a finalizer
+/// so that it does not outlive the rule when garbage collection is delayed.
 pub async fn reconcile_mutatingrule(
     mutating_rule: Arc<MutatingRule>,
     ctx: Arc<ReconcilerContext>,
@@ -194,38 +714,755 @@ pub async fn reconcile_mutatingrule(
     // Get Kubernetes client from context data
     let client = &ctx.client;
 
-    let mutating_rule = (*mutating_rule).clone();
+    // Prepare Kubernetes API
+    let mr_api = Api::<MutatingRule>::all(client.clone());
+    let mwc_api = Api::<MutatingWebhookConfiguration>::all(client.clone());
+
+    finalizer(&mr_api, RULE_FINALIZER, mutating_rule, |event| async {
+        match event {
+            finalizer::Event::Apply(mutating_rule) => {
+                let mutating_rule = (*mutating_rule).clone();
+
+                // Prepare ownership reference
+                let oref = mutating_rule.controller_owner_ref(&()).unwrap();
+
+                let name = mutating_rule
+                    .metadata
+                    .name
+                    .clone()
+                    .ok_or(Error::MissingObjectKey(".metadata.name"))?;
+                let spec = effective_rule_spec(&ctx, &mutating_rule.spec.0);
+
+                // Report the preflight result and the access of the ServiceAccount
+                update_conditions(
+                    &ctx.client,
+                    &mr_api,
+                    &name,
+                    mutating_rule.metadata.generation,
+                    &spec,
+                    mutating_rule.status.as_ref().map(|status| &status.0),
+                )
+                .await?;
+
+                let mwc_name = prioritized_webhook_configuration_name(name.clone(), spec.priority);
+
+                // Popluate MutatingWebhookConfiguration
+                let mwc: MutatingWebhookConfiguration = webhook_configuration!(
+                    mutate,
+                    mwc_name,
+                    make_labels(MUTATINGRULE_OWNED_LABEL_KEY, &name, None),
+                    format!("/mutate/{}", name),
+                    Some(vec![oref]),
+                    spec,
+                    ctx.config,
+                    ctx.ca_bundle
+                );
+
+                let mwc = add_match_conditions(&mwc, &spec)
+                    .map_err(Error::SerializeWebhookConfiguration)?;
+
+                // Create or update MutatingWebhookConfiguration if it does not exist or drifted
+                let drifted = apply_webhook_configuration::<MutatingWebhookConfiguration>(
+                    client,
+                    &mwc_name,
+                    "mutatingrule.checkpoint.devsisters.com",
+                    &mwc,
+                )
+                .await
+                .map_err(Error::MutatingWebhookConfigurationCreationFailed)?;
+                if drifted {
+                    publish_drift_event(&ctx, &mutating_rule, &mwc_name).await;
+                }
+
+                // Delete the webhook configuration named with the previous priority
+                let owned_selector = format!("{}={}", MUTATINGRULE_OWNED_LABEL_KEY, name);
+                delete_owned_webhook_configurations(&mwc_api, &owned_selector, Some(&mwc_name))
+                    .await?;
+                update_webhook_configuration_name(
+                    &mr_api,
+                    &name,
+                    &mwc_name,
+                    mutating_rule.status.as_ref().map(|status| &status.0),
+                )
+                .await?;
+
+                Ok(ctx.resync_action())
+            }
+            finalizer::Event::Cleanup(mutating_rule) => {
+                let owned_selector = format!(
+                    "{}={}",
+                    MUTATINGRULE_OWNED_LABEL_KEY,
+                    mutating_rule.name_any()
+                );
+                delete_owned_webhook_configurations(&mwc_api, &owned_selector, None).await?;
+                ctx.forget_reported_error(mutating_rule.uid());
+                Ok(Action::await_change())
+            }
+        }
+    })
+    .await
+    .map_err(|error| Error::Finalizer(Box::new(error)))
+}
+
+/// Whether the live webhook configuration differs from the desired one
+///
+/// The live object is compared as raw JSON, since typed objects drop fields k8s-openapi does not know yet
+/// (e.g. `matchConditions`), which would look like drift on every resync.
+fn has_drifted(
+    existing: &DynamicObject,
+    desired: &serde_json::Value,
+) -> Result<bool, serde_json::Error> {
+    let existing = serde_json::to_value(existing)?;
+    Ok(!json_contains(&existing, desired))
+}
+
+/// Server-side apply of webhook configurations, taking over the fields edited by others
+///
+/// Fields edited with `kubectl edit` belong to another field manager, so applying without force conflicts forever.
+fn webhook_configuration_patch_params(field_manager: &str) -> PatchParams {
+    PatchParams::apply(field_manager).force()
+}
+
+/// Apply the webhook configuration if it does not exist or drifted from the desired one
+///
+/// Returns whether existing webhook configuration had drifted.
+async fn apply_webhook_configuration<K>(
+    client: &kube::Client,
+    name: &str,
+    field_manager: &str,
+    desired: &serde_json::Value,
+) -> Result<bool, kube::Error>
+where
+    K: Resource<DynamicType = ()>,
+{
+    let api = Api::<DynamicObject>::all_with(client.clone(), &ApiResource::erase::<K>(&()));
+    let drifted = match api.get_opt(name).await? {
+        Some(existing) => {
+            if !has_drifted(&existing, desired).map_err(kube::Error::SerdeError)? {
+                return Ok(false);
+            }
+            true
+        }
+        None => false,
+    };
+
+    api.patch(
+        name,
+        &webhook_configuration_patch_params(field_manager),
+        &Patch::Apply(desired),
+    )
+    .await?;
+
+    Ok(drifted)
+}
+
+/// Publish Event on the rule reporting the webhook configuration drift was corrected
+async fn publish_drift_event<K>(ctx: &ReconcilerContext, rule: &K, webhook_configuration_name: &str)
+where
+    K: Resource<DynamicType = ()>,
+{
+    tracing::info!(name = %webhook_configuration_name, "corrected drifted webhook configuration");
+    crate::events::publish(
+        ctx.client.clone(),
+        ctx.reporter.clone(),
+        rule,
+        Event {
+            type_: EventType::Normal,
+            reason: "DriftCorrected".to_string(),
+            note: Some(format!(
+                "Webhook configuration {} was modified and has been re-applied",
+                webhook_configuration_name
+            )),
+            action: "Reconcile".to_string(),
+            secondary: None,
+        },
+    )
+    .await;
+}
+
+/// Delete the webhook configuration if exists
+async fn delete_webhook_configuration<K>(api: &Api<K>, name: &str) -> Result<(), Error>
+where
+    K: Resource + Clone + DeserializeOwned + Debug,
+{
+    match api.delete(name, &DeleteParams::default()).await {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(error)) if error.code == 404 => Ok(()),
+        Err(error) => Err(Error::DeleteWebhookConfiguration(error)),
+    }
+}
+
+/// Delete the webhook configurations owned by the rule, except the one to keep
+async fn delete_owned_webhook_configurations<K>(
+    api: &Api<K>,
+    owned_selector: &str,
+    keep: Option<&str>,
+) -> Result<(), Error>
+where
+    K: Resource + Clone + DeserializeOwned + Debug,
+{
+    let wcs = api
+        .list(&ListParams::default().labels(owned_selector))
+        .await
+        .map_err(Error::ListWebhookConfiguration)?
+        .items;
+    for wc in wcs {
+        let name = wc.name_any();
+        if Some(name.as_str()) == keep {
+            continue;
+        }
+        tracing::info!(%name, "deleting webhook configuration of the rule");
+        delete_webhook_configuration(api, &name).await?;
+    }
+    Ok(())
+}
+
+/// Label selector of the webhook configurations owned by the namespaced rule
+fn namespaced_owned_selector(owned_label_key: &str, namespace: &str, name: &str) -> String {
+    format!(
+        "{}={},{}={}",
+        owned_label_key, name, RULE_NAMESPACE_LABEL_KEY, namespace
+    )
+}
+
+/// Report the name of the generated webhook configuration in the status of the rule
+async fn update_webhook_configuration_name<K>(
+    api: &Api<K>,
+    name: &str,
+    webhook_configuration_name: &str,
+    status: Option<&RuleStatus>,
+) -> Result<(), Error>
+where
+    K: Resource + Clone + DeserializeOwned + Debug,
+{
+    let current = status.and_then(|status| status.webhook_configuration_name.as_deref());
+    if current == Some(webhook_configuration_name) {
+        return Ok(());
+    }
+
+    let patch = serde_json::json!({
+        "status": {
+            "webhookConfigurationName": webhook_configuration_name,
+        }
+    });
+    api.patch_status(name, &PatchParams::default(), &Patch::Merge(patch))
+        .await
+        .map_err(Error::PatchStatus)?;
+
+    Ok(())
+}
+
+/// NamespacedValidatingRule reconciler
+///
+/// Cluster-scoped webhook configuration cannot be owned by namespaced rule,
+/// so the webhook configuration is deleted with a finalizer.
+pub async fn reconcile_namespacedvalidatingrule(
+    namespaced_validating_rule: Arc<NamespacedValidatingRule>,
+    ctx: Arc<ReconcilerContext>,
+) -> Result<Action, Error> {
+    // Get Kubernetes client from context data
+    let client = &ctx.client;
+
+    let namespace = namespaced_validating_rule
+        .namespace()
+        .ok_or(Error::MissingObjectKey(".metadata.namespace"))?;
+
+    // Prepare Kubernetes API
+    let nvr_api = Api::<NamespacedValidatingRule>::namespaced(client.clone(), &namespace);
+    let vwc_api = Api::<ValidatingWebhookConfiguration>::all(client.clone());
+
+    finalizer(
+        &nvr_api,
+        RULE_FINALIZER,
+        namespaced_validating_rule,
+        |event| async {
+            match event {
+                finalizer::Event::Apply(namespaced_validating_rule) => {
+                    let name = namespaced_validating_rule.name_any();
+                    let vwc_name = namespaced_webhook_configuration_name(&namespace, &name);
+                    let spec = effective_rule_spec(
+                        &ctx,
+                        &namespaced_validating_rule.spec.0.pin_namespace(&namespace),
+                    );
+
+                    // Report the preflight result and the access of the ServiceAccount
+                    update_conditions(
+                        &ctx.client,
+                        &nvr_api,
+                        &name,
+                        namespaced_validating_rule.metadata.generation,
+                        &spec,
+                        namespaced_validating_rule
+                            .status
+                            .as_ref()
+                            .map(|status| &status.0),
+                    )
+                    .await?;
+
+                    // Popluate ValidatingWebhookConfiguration
+                    let vwc: ValidatingWebhookConfiguration = webhook_configuration!(
+                        validate,
+                        vwc_name,
+                        make_labels(
+                            NAMESPACEDVALIDATINGRULE_OWNED_LABEL_KEY,
+                            &name,
+                            Some(&namespace)
+                        ),
+                        format!("/namespaced/validate/{}/{}", namespace, name),
+                        None,
+                        spec,
+                        ctx.config,
+                        ctx.ca_bundle
+                    );
+
+                    let vwc = add_match_conditions(&vwc, &spec)
+                        .map_err(Error::SerializeWebhookConfiguration)?;
+
+                    // Create or update ValidatingWebhookConfiguration if it does not exist or drifted
+                    let drifted = apply_webhook_configuration::<ValidatingWebhookConfiguration>(
+                        client,
+                        &vwc_name,
+                        "validatingrule.checkpoint.devsisters.com",
+                        &vwc,
+                    )
+                    .await
+                    .map_err(Error::ValidatingWebhookConfigurationCreationFailed)?;
+                    if drifted {
+                        publish_drift_event(&ctx, &*namespaced_validating_rule, &vwc_name).await;
+                    }
 
-    // Prepare ownership reference
-    let oref = mutating_rule.controller_owner_ref(&()).unwrap();
+                    Ok(ctx.resync_action())
+                }
+                finalizer::Event::Cleanup(namespaced_validating_rule) => {
+                    let vwc_name = namespaced_webhook_configuration_name(
+                        &namespace,
+                        &namespaced_validating_rule.name_any(),
+                    );
+                    delete_webhook_configuration(&vwc_api, &vwc_name).await?;
+                    ctx.forget_reported_error(namespaced_validating_rule.uid());
+                    Ok(Action::await_change())
+                }
+            }
+        },
+    )
+    .await
+    .map_err(|error| Error::Finalizer(Box::new(error)))
+}
+
+/// NamespacedMutatingRule reconciler
+///
+/// Cluster-scoped webhook configuration cannot be owned by namespaced rule,
+/// so the webhook configuration is deleted with a finalizer.
+pub async fn reconcile_namespacedmutatingrule(
+    namespaced_mutating_rule: Arc<NamespacedMutatingRule>,
+    ctx: Arc<ReconcilerContext>,
+) -> Result<Action, Error> {
+    // Get Kubernetes client from context data
+    let client = &ctx.client;
 
-    let name = mutating_rule
-        .metadata
-        .name
-        .ok_or(Error::MissingObjectKey(".metadata.name"))?;
+    let namespace = namespaced_mutating_rule
+        .namespace()
+        .ok_or(Error::MissingObjectKey(".metadata.namespace"))?;
 
     // Prepare Kubernetes API
+    let nmr_api = Api::<NamespacedMutatingRule>::namespaced(client.clone(), &namespace);
     let mwc_api = Api::<MutatingWebhookConfiguration>::all(client.clone());
 
-    // Popluate MutatingWebhookConfiguration
-    let mwc: MutatingWebhookConfiguration = webhook_configuration!(
-        mutate,
-        name,
-        oref,
-        mutating_rule.spec.0,
-        ctx.config,
-        ctx.ca_bundle
-    );
-
-    // Create or update MutatingWebhookConfiguration
-    mwc_api
-        .patch(
-            &name,
-            &PatchParams::apply("mutatingrule.checkpoint.devsisters.com"),
-            &Patch::Apply(&mwc),
-        )
+    finalizer(
+        &nmr_api,
+        RULE_FINALIZER,
+        namespaced_mutating_rule,
+        |event| async {
+            match event {
+                finalizer::Event::Apply(namespaced_mutating_rule) => {
+                    let name = namespaced_mutating_rule.name_any();
+                    let spec = effective_rule_spec(
+                        &ctx,
+                        &namespaced_mutating_rule.spec.0.pin_namespace(&namespace),
+                    );
+                    let mwc_name = prioritized_webhook_configuration_name(
+                        namespaced_webhook_configuration_name(&namespace, &name),
+                        spec.priority,
+                    );
+
+                    // Report the preflight result and the access of the ServiceAccount
+                    update_conditions(
+                        &ctx.client,
+                        &nmr_api,
+                        &name,
+                        namespaced_mutating_rule.metadata.generation,
+                        &spec,
+                        namespaced_mutating_rule
+                            .status
+                            .as_ref()
+                            .map(|status| &status.0),
+                    )
+                    .await?;
+
+                    // Popluate MutatingWebhookConfiguration
+                    let mwc: MutatingWebhookConfiguration = webhook_configuration!(
+                        mutate,
+                        mwc_name,
+                        make_labels(
+                            NAMESPACEDMUTATINGRULE_OWNED_LABEL_KEY,
+                            &name,
+                            Some(&namespace)
+                        ),
+                        format!("/namespaced/mutate/{}/{}", namespace, name),
+                        None,
+                        spec,
+                        ctx.config,
+                        ctx.ca_bundle
+                    );
+
+                    let mwc = add_match_conditions(&mwc, &spec)
+                        .map_err(Error::SerializeWebhookConfiguration)?;
+
+                    // Create or update MutatingWebhookConfiguration if it does not exist or drifted
+                    let drifted = apply_webhook_configuration::<MutatingWebhookConfiguration>(
+                        client,
+                        &mwc_name,
+                        "mutatingrule.checkpoint.devsisters.com",
+                        &mwc,
+                    )
+                    .await
+                    .map_err(Error::MutatingWebhookConfigurationCreationFailed)?;
+                    if drifted {
+                        publish_drift_event(&ctx, &*namespaced_mutating_rule, &mwc_name).await;
+                    }
+
+                    // Delete the webhook configuration named with the previous priority
+                    let owned_selector = namespaced_owned_selector(
+                        NAMESPACEDMUTATINGRULE_OWNED_LABEL_KEY,
+                        &namespace,
+                        &name,
+                    );
+                    delete_owned_webhook_configurations(&mwc_api, &owned_selector, Some(&mwc_name))
+                        .await?;
+                    update_webhook_configuration_name(
+                        &nmr_api,
+                        &name,
+                        &mwc_name,
+                        namespaced_mutating_rule
+                            .status
+                            .as_ref()
+                            .map(|status| &status.0),
+                    )
+                    .await?;
+
+                    Ok(ctx.resync_action())
+                }
+                finalizer::Event::Cleanup(namespaced_mutating_rule) => {
+                    let owned_selector = namespaced_owned_selector(
+                        NAMESPACEDMUTATINGRULE_OWNED_LABEL_KEY,
+                        &namespace,
+                        &namespaced_mutating_rule.name_any(),
+                    );
+                    delete_owned_webhook_configurations(&mwc_api, &owned_selector, None).await?;
+                    ctx.forget_reported_error(namespaced_mutating_rule.uid());
+                    Ok(Action::await_change())
+                }
+            }
+        },
+    )
+    .await
+    .map_err(|error| Error::Finalizer(Box::new(error)))
+}
+
+/// Delete webhook configurations with the owned label whose rule no longer exists
+async fn sweep_orphaned<W, R>(client: &kube::Client, owned_label_key: &str) -> Result<(), Error>
+where
+    W: Resource<DynamicType = ()> + Clone + DeserializeOwned + Debug,
+    R: Resource<DynamicType = ()> + Clone + DeserializeOwned + Debug,
+{
+    let wc_api = Api::<W>::all(client.clone());
+    let rule_api = Api::<R>::all(client.clone());
+
+    let rules: HashSet<_> = rule_api
+        .list(&ListParams::default())
+        .await
+        .map_err(Error::ListRule)?
+        .items
+        .into_iter()
+        .map(|rule| (rule.namespace(), rule.name_any()))
+        .collect();
+
+    let wcs = wc_api
+        .list(&ListParams::default().labels(owned_label_key))
         .await
-        .map_err(Error::MutatingWebhookConfigurationCreationFailed)?;
+        .map_err(Error::ListWebhookConfiguration)?
+        .items;
+    for wc in wcs {
+        let labels = wc.labels();
+        let Some(rule_name) = labels.get(owned_label_key) else {
+            continue;
+        };
+        let rule_namespace = labels.get(RULE_NAMESPACE_LABEL_KEY);
+        if rules.contains(&(rule_namespace.cloned(), rule_name.clone())) {
+            continue;
+        }
+
+        tracing::info!(name = %wc.name_any(), %rule_name, ?rule_namespace, "deleting orphaned webhook configuration");
+        delete_webhook_configuration(&wc_api, &wc.name_any()).await?;
+    }
+
+    Ok(())
+}
+
+/// Delete webhook configurations left behind by rules that no longer exist
+///
+/// This cleans up configurations of rules deleted while the controller was not running.
+pub async fn sweep_orphaned_webhook_configurations(client: &kube::Client) -> Result<(), Error> {
+    sweep_orphaned::<ValidatingWebhookConfiguration, ValidatingRule>(
+        client,
+        VALIDATINGRULE_OWNED_LABEL_KEY,
+    )
+    .await?;
+    sweep_orphaned::<MutatingWebhookConfiguration, MutatingRule>(
+        client,
+        MUTATINGRULE_OWNED_LABEL_KEY,
+    )
+    .await?;
+    sweep_orphaned::<ValidatingWebhookConfiguration, NamespacedValidatingRule>(
+        client,
+        NAMESPACEDVALIDATINGRULE_OWNED_LABEL_KEY,
+    )
+    .await?;
+    sweep_orphaned::<MutatingWebhookConfiguration, NamespacedMutatingRule>(
+        client,
+        NAMESPACEDMUTATINGRULE_OWNED_LABEL_KEY,
+    )
+    .await?;
+    sweep_orphaned::<ValidatingWebhookConfiguration, RuleSet>(client, RULESET_OWNED_LABEL_KEY)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::types::{
+        config::CheckpointConfigSpec,
+        rule::{FailurePolicy, RuleRollout, SideEffectClass},
+    };
+
+    #[test]
+    fn test_webhook_configuration_patch_params_force() {
+        let params = webhook_configuration_patch_params("checkpoint.devsisters.com");
+        assert!(params.force);
+        assert_eq!(
+            params.field_manager.as_deref(),
+            Some("checkpoint.devsisters.com")
+        );
+    }
+
+    fn match_conditions_spec() -> RuleSpec {
+        serde_json::from_value(json!({
+            "matchConditions": [
+                {"name": "exclude-leases", "expression": "request.resource.resource != 'leases'"},
+            ],
+            "code": "",
+        }))
+        .unwrap()
+    }
+
+    fn desired_webhook_configuration(spec: &RuleSpec) -> serde_json::Value {
+        let vwc = ValidatingWebhookConfiguration {
+            metadata: ObjectMeta {
+                name: Some("rule".to_string()),
+                ..Default::default()
+            },
+            webhooks: Some(vec![ValidatingWebhook {
+                name: "rule.validatingwebhook.checkpoint.devsisters.com".to_string(),
+                admission_review_versions: vec!["v1".to_string()],
+                side_effects: "None".to_string(),
+                ..Default::default()
+            }]),
+        };
+        add_match_conditions(&vwc, spec).unwrap()
+    }
+
+    #[test]
+    fn test_has_drifted_with_match_conditions() {
+        let spec = match_conditions_spec();
+        let desired = desired_webhook_configuration(&spec);
+        assert!(desired["webhooks"][0]["matchConditions"].is_array());
+
+        // Live object is identical except fields set by the API server
+        let mut live = desired.clone();
+        live["metadata"]["uid"] = json!("1234");
+        live["metadata"]["resourceVersion"] = json!("1");
+        live["webhooks"][0]["timeoutSeconds"] = json!(10);
+        let existing: DynamicObject = serde_json::from_value(live.clone()).unwrap();
+        assert!(!has_drifted(&existing, &desired).unwrap());
+
+        // Typed objects drop matchConditions, which used to be reported as drift
+        let typed: ValidatingWebhookConfiguration = serde_json::from_value(live.clone()).unwrap();
+        assert!(!json_contains(
+            &serde_json::to_value(typed).unwrap(),
+            &desired
+        ));
+
+        // Modified matchConditions are drift
+        live["webhooks"][0]["matchConditions"][0]["expression"] = json!("true");
+        let existing: DynamicObject = serde_json::from_value(live.clone()).unwrap();
+        assert!(has_drifted(&existing, &desired).unwrap());
+
+        // Removed matchConditions are drift
+        live["webhooks"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("matchConditions");
+        let existing: DynamicObject = serde_json::from_value(live).unwrap();
+        assert!(has_drifted(&existing, &desired).unwrap());
+    }
+
+    #[test]
+    fn test_webhook_entries_with_checkpoint_config() {
+        let spec: RuleSpec = serde_json::from_value(json!({
+            "failurePolicy": "Fail",
+            "webhooks": [
+                {"name": "inherit"},
+                {"name": "own", "namespaceSelector": {"matchLabels": {"team": "a"}}, "timeoutSeconds": 3},
+            ],
+            "code": "",
+        }))
+        .unwrap();
+        let checkpoint_config: CheckpointConfigSpec = serde_json::from_value(json!({
+            "excludedNamespaces": ["kube-system"],
+            "defaultFailurePolicy": "Ignore",
+            "defaultTimeoutSeconds": 5,
+        }))
+        .unwrap();
+
+        let spec = checkpoint_config.apply_to_rule(&spec);
+        // Failure policy of the rule takes precedence
+        assert_eq!(spec.failure_policy, Some(FailurePolicy::Fail));
+
+        let excluded = json!({
+            "key": "kubernetes.io/metadata.name",
+            "operator": "NotIn",
+            "values": ["kube-system"],
+        });
+        let entries = webhook_entries(&spec);
+        assert_eq!(entries[0].timeout_seconds, Some(5));
+        assert_eq!(
+            serde_json::to_value(&entries[0].namespace_selector).unwrap(),
+            json!({"matchExpressions": [excluded]}),
+        );
+        assert_eq!(entries[1].timeout_seconds, Some(3));
+        assert_eq!(
+            serde_json::to_value(&entries[1].namespace_selector).unwrap(),
+            json!({"matchLabels": {"team": "a"}, "matchExpressions": [excluded]}),
+        );
+
+        // Selectors are left as is without excluded namespaces
+        let spec: RuleSpec = serde_json::from_value(json!({"code": ""})).unwrap();
+        let spec = CheckpointConfigSpec::default().apply_to_rule(&spec);
+        assert!(spec.namespace_selector.is_none());
+    }
 
-    Ok(Action::await_change())
+    #[test]
+    fn test_exclude_checkpoint_namespace() {
+        let spec: RuleSpec = serde_json::from_value(json!({
+            "namespaceSelector": {"matchLabels": {"team": "a"}},
+            "code": "",
+        }))
+        .unwrap();
+        let spec = exclude_checkpoint_namespace(spec, "checkpoint");
+        assert_eq!(
+            serde_json::to_value(&spec.namespace_selector).unwrap(),
+            json!({
+                "matchLabels": {"team": "a"},
+                "matchExpressions": [{
+                    "key": "kubernetes.io/metadata.name",
+                    "operator": "NotIn",
+                    "values": ["checkpoint"],
+                }],
+            }),
+        );
+
+        // Rules opting out are left as is
+        let spec: RuleSpec = serde_json::from_value(json!({
+            "includeCheckpointNamespace": true,
+            "code": "",
+        }))
+        .unwrap();
+        let spec = exclude_checkpoint_namespace(spec, "checkpoint");
+        assert!(spec.namespace_selector.is_none());
+    }
+
+    #[test]
+    fn test_side_effect_class() {
+        let spec: RuleSpec = serde_json::from_value(json!({"code": "allow();"})).unwrap();
+        assert_eq!(spec.side_effect_class(), SideEffectClass::None);
+
+        let spec: RuleSpec =
+            serde_json::from_value(json!({"code": "kubeGet({kind: 'Namespace'});"})).unwrap();
+        assert_eq!(spec.side_effect_class(), SideEffectClass::NoneOnDryRun);
+
+        // Specified side effects take precedence
+        let spec: RuleSpec = serde_json::from_value(json!({
+            "sideEffects": "None",
+            "code": "kubeList({kind: 'Pod'});",
+        }))
+        .unwrap();
+        assert_eq!(spec.side_effect_class(), SideEffectClass::None);
+    }
+
+    #[test]
+    fn test_apply_default_client_config() {
+        let spec: RuleSpec = serde_json::from_value(json!({
+            "clientConfig": {"pathPrefix": "/canary"},
+            "code": "",
+        }))
+        .unwrap();
+        let checkpoint_config: CheckpointConfigSpec = serde_json::from_value(json!({
+            "defaultClientConfig": {"port": 8443, "pathPrefix": "/stable"},
+        }))
+        .unwrap();
+
+        // Fields of the rule take precedence
+        let spec = checkpoint_config.apply_to_rule(&spec);
+        let client_config = spec.client_config.unwrap();
+        assert_eq!(client_config.port, Some(8443));
+        assert_eq!(client_config.path_prefix.as_deref(), Some("/canary"));
+        assert!(client_config.url.is_none());
+    }
+
+    #[test]
+    fn test_rollout_enforces() {
+        let rollout = |percentage| -> RuleRollout {
+            serde_json::from_value(json!({"mode": "canary", "percentage": percentage})).unwrap()
+        };
+        let keys: Vec<_> = (0..1000)
+            .map(|i| format!("rule/default/pod-{}", i))
+            .collect();
+
+        assert!(keys.iter().all(|key| !rollout(0).enforces(key)));
+        assert!(keys.iter().all(|key| rollout(100).enforces(key)));
+        let enforced = keys.iter().filter(|key| rollout(30).enforces(key)).count();
+        assert!((200..400).contains(&enforced));
+        // The same key is decided the same way
+        assert!(keys
+            .iter()
+            .all(|key| rollout(30).enforces(key) == rollout(30).enforces(key)));
+    }
+
+    #[test]
+    fn test_prioritized_webhook_configuration_name() {
+        let mut names = vec![
+            prioritized_webhook_configuration_name("a".to_string(), None),
+            prioritized_webhook_configuration_name("b".to_string(), Some(100)),
+            prioritized_webhook_configuration_name("c".to_string(), Some(20)),
+            prioritized_webhook_configuration_name(
+                namespaced_webhook_configuration_name("default", "d"),
+                Some(20),
+            ),
+        ];
+        names.sort();
+        assert_eq!(names, ["020.c", "020.namespaced.default.d", "100.b", "a"]);
+    }
 }
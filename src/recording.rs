@@ -0,0 +1,129 @@
+//! Recording of sampled admission requests, to replay against modified rules
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context, Result};
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{
+    api::{ObjectMeta, PostParams},
+    core::{
+        admission::{AdmissionRequest, AdmissionResponse},
+        DynamicObject,
+    },
+    Api, Resource, ResourceExt,
+};
+use serde::{Deserialize, Serialize};
+
+/// Label key of recordings to select the recordings of a rule
+pub const RECORDING_RULE_LABEL_KEY: &str = "checkpoint.devsisters.com/recording-rule";
+/// Label key of recordings for the kind of the rule
+pub const RECORDING_RULE_KIND_LABEL_KEY: &str = "checkpoint.devsisters.com/recording-rule-kind";
+/// Label key of recordings for the namespace of namespaced rules
+pub const RECORDING_RULE_NAMESPACE_LABEL_KEY: &str =
+    "checkpoint.devsisters.com/recording-rule-namespace";
+
+const REQUEST_KEY: &str = "request.json";
+const RESPONSE_KEY: &str = "response.json";
+
+/// Decision of a rule on a request
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordedResponse {
+    pub allowed: bool,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patch: Option<serde_json::Value>,
+}
+
+impl RecordedResponse {
+    pub fn from_response(resp: &AdmissionResponse) -> Result<Self> {
+        Ok(Self {
+            allowed: resp.allowed,
+            message: resp.result.message.clone(),
+            patch: resp
+                .patch
+                .as_deref()
+                .map(serde_json::from_slice)
+                .transpose()
+                .context("failed to deserialize patch")?,
+        })
+    }
+}
+
+/// Whether the request is recorded for the rule
+///
+/// Requests on Secrets are never recorded, not to copy their data into ConfigMaps.
+pub fn samples(
+    recording: &crate::types::rule::RuleRecording,
+    req: &AdmissionRequest<DynamicObject>,
+) -> bool {
+    let is_secret = req.kind.group.is_empty() && req.kind.kind == "Secret";
+    !is_secret && recording.samples(&req.uid)
+}
+
+/// Make the recording of the request and the decision of the rule on it
+pub fn make_recording<K>(
+    rule: &K,
+    req: &AdmissionRequest<DynamicObject>,
+    resp: &AdmissionResponse,
+) -> Result<ConfigMap>
+where
+    K: Resource<DynamicType = ()>,
+{
+    let mut labels = BTreeMap::from([
+        (RECORDING_RULE_LABEL_KEY.to_string(), rule.name_any()),
+        (
+            RECORDING_RULE_KIND_LABEL_KEY.to_string(),
+            K::kind(&()).into_owned(),
+        ),
+    ]);
+    if let Some(namespace) = rule.namespace() {
+        labels.insert(RECORDING_RULE_NAMESPACE_LABEL_KEY.to_string(), namespace);
+    }
+
+    let request = serde_json::to_string(req).context("failed to serialize request")?;
+    let response = serde_json::to_string(&RecordedResponse::from_response(resp)?)
+        .context("failed to serialize response")?;
+    Ok(ConfigMap {
+        metadata: ObjectMeta {
+            generate_name: Some(format!("{}-", rule.name_any())),
+            labels: Some(labels),
+            ..Default::default()
+        },
+        data: Some(BTreeMap::from([
+            (REQUEST_KEY.to_string(), request),
+            (RESPONSE_KEY.to_string(), response),
+        ])),
+        ..Default::default()
+    })
+}
+
+/// Create the recording in the namespace
+pub async fn record(
+    kube_client: kube::Client,
+    namespace: &str,
+    recording: ConfigMap,
+) -> Result<()> {
+    Api::<ConfigMap>::namespaced(kube_client, namespace)
+        .create(&PostParams::default(), &recording)
+        .await
+        .context("failed to create ConfigMap")?;
+    Ok(())
+}
+
+/// Read the request and the recorded decision from the recording
+pub fn parse_recording(
+    recording: &ConfigMap,
+) -> Result<(AdmissionRequest<DynamicObject>, RecordedResponse)> {
+    let get = |key: &str| {
+        recording
+            .data
+            .as_ref()
+            .and_then(|data| data.get(key))
+            .ok_or_else(|| anyhow!("`{}` is not found", key))
+    };
+    let request = serde_json::from_str(get(REQUEST_KEY)?).context("failed to parse request")?;
+    let response = serde_json::from_str(get(RESPONSE_KEY)?).context("failed to parse response")?;
+    Ok((request, response))
+}
@@ -0,0 +1,171 @@
+//! TLS configuration of the webhook server, and of the clients of notification sinks
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use k8s_openapi::api::core::v1::Secret;
+use kube::Api;
+use rustls::{
+    server::AllowAnyAuthenticatedClient, Certificate, ClientConfig, PrivateKey, RootCertStore,
+    ServerConfig,
+};
+use rustls_pemfile::Item;
+
+use crate::config::WebhookConfig;
+
+/// Where the serving certificate and private key are read from
+#[derive(Clone)]
+pub enum CertSource {
+    Files {
+        cert_path: PathBuf,
+        key_path: PathBuf,
+    },
+    /// Secret of `kubernetes.io/tls` type
+    Secret { api: Api<Secret>, name: String },
+}
+
+impl CertSource {
+    pub fn from_config(kube_client: kube::Client, config: &WebhookConfig) -> Result<Self> {
+        match (&config.tls_secret_name, &config.cert_path, &config.key_path) {
+            (Some(name), None, None) => {
+                let api = match &config.tls_secret_namespace {
+                    Some(namespace) => Api::namespaced(kube_client, namespace),
+                    None => Api::default_namespaced(kube_client),
+                };
+                Ok(Self::Secret {
+                    api,
+                    name: name.clone(),
+                })
+            }
+            (None, Some(cert_path), Some(key_path)) => Ok(Self::Files {
+                cert_path: cert_path.clone(),
+                key_path: key_path.clone(),
+            }),
+            _ => {
+                bail!("either TLS Secret name or both certificate and key paths must be specified")
+            }
+        }
+    }
+
+    /// Read PEM certificate chain and private key
+    pub async fn read_pem(&self) -> Result<(Vec<u8>, Vec<u8>)> {
+        match self {
+            Self::Files {
+                cert_path,
+                key_path,
+            } => {
+                let cert = tokio::fs::read(cert_path)
+                    .await
+                    .context("failed to read certificate file")?;
+                let key = tokio::fs::read(key_path)
+                    .await
+                    .context("failed to read private key file")?;
+                Ok((cert, key))
+            }
+            Self::Secret { api, name } => {
+                let secret = api.get(name).await.context("failed to get TLS Secret")?;
+                let mut data = secret.data.unwrap_or_default();
+                let cert = data
+                    .remove("tls.crt")
+                    .context("tls.crt does not exist in TLS Secret")?;
+                let key = data
+                    .remove("tls.key")
+                    .context("tls.key does not exist in TLS Secret")?;
+                Ok((cert.0, key.0))
+            }
+        }
+    }
+}
+
+/// Parse PEM certificate chain and private key
+fn parse_cert_and_key(cert: &[u8], key: &[u8]) -> Result<(Vec<Certificate>, PrivateKey)> {
+    let cert = rustls_pemfile::certs(&mut &*cert)
+        .context("failed to parse certificate")?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = match rustls_pemfile::read_one(&mut &*key).context("failed to parse private key")? {
+        Some(Item::RSAKey(key)) | Some(Item::PKCS8Key(key)) | Some(Item::ECKey(key)) => {
+            PrivateKey(key)
+        }
+        _ => bail!("private key format not supported"),
+    };
+    Ok((cert, key))
+}
+
+/// Make TLS server configuration from PEM certificate chain and private key
+///
+/// If `client_ca` PEM is given, clients must present a certificate signed by one of the CAs.
+pub fn make_server_config(
+    cert: &[u8],
+    key: &[u8],
+    client_ca: Option<&[u8]>,
+) -> Result<ServerConfig> {
+    let (cert, key) = parse_cert_and_key(cert, key)?;
+
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            let client_ca =
+                rustls_pemfile::certs(&mut &*client_ca).context("failed to parse client CA")?;
+            let (added, _) = roots.add_parsable_certificates(&client_ca);
+            if added == 0 {
+                bail!("no valid certificate in client CA");
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots))
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder
+        .with_single_cert(cert, key)
+        .context("invalid certificate or private key")?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+/// Make TLS client configuration verifying the server with PEM CA certificates
+///
+/// If `cert_and_key` PEMs are given, the client presents the certificate for mutual TLS.
+pub fn make_client_config(ca: &[u8], cert_and_key: Option<(&[u8], &[u8])>) -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    let ca = rustls_pemfile::certs(&mut &*ca).context("failed to parse CA")?;
+    let (added, _) = roots.add_parsable_certificates(&ca);
+    if added == 0 {
+        bail!("no valid certificate in CA");
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    match cert_and_key {
+        Some((cert, key)) => {
+            let (cert, key) = parse_cert_and_key(cert, key)?;
+            builder
+                .with_single_cert(cert, key)
+                .context("invalid certificate or private key")
+        }
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+/// Read TLS server configuration from the certificate source and client CA file
+pub async fn read_server_config(
+    cert_source: &CertSource,
+    client_ca_path: Option<&Path>,
+) -> Result<Arc<ServerConfig>> {
+    let (cert, key) = cert_source.read_pem().await?;
+    let client_ca = match client_ca_path {
+        Some(path) => Some(
+            tokio::fs::read(path)
+                .await
+                .context("failed to read client CA file")?,
+        ),
+        None => None,
+    };
+    make_server_config(&cert, &key, client_ca.as_deref()).map(Arc::new)
+}
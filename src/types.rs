@@ -1,3 +1,6 @@
+pub mod config;
+pub mod denial;
 pub mod policy;
 pub mod rule;
+pub mod ruleset;
 pub mod testcase;
@@ -0,0 +1,85 @@
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::rule::{FailurePolicy, RuleClientConfig, RuleLimits, RuleSpec};
+
+/// Name of the singleton CheckpointConfig. CheckpointConfigs with other names are ignored.
+pub const CHECKPOINT_CONFIG_NAME: &str = "checkpoint";
+
+/// Runtime configuration of the controller and the webhook.
+///
+/// Only the CheckpointConfig named `checkpoint` is used, and changes are applied without restarting pods.
+#[derive(Serialize, Deserialize, JsonSchema, CustomResource, Clone, Debug, Default, PartialEq)]
+#[kube(
+    group = "checkpoint.devsisters.com",
+    version = "v1",
+    kind = "CheckpointConfig",
+    shortname = "cpc",
+    category = "checkpoint"
+)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckpointConfigSpec {
+    /// Namespaces excluded from every Rule.
+    ///
+    /// Objects in the namespaces are not sent to the webhook, and the webhook allows them without evaluating JS code.
+    #[serde(default)]
+    pub excluded_namespaces: Option<Vec<String>>,
+    /// FailurePolicy of Rules that do not specify one. Defaults to Fail.
+    #[serde(default)]
+    pub default_failure_policy: Option<FailurePolicy>,
+    /// TimeoutSeconds of Rules that do not specify one. Defaults to 10 seconds.
+    #[serde(default)]
+    pub default_timeout_seconds: Option<i32>,
+    /// Limits of JS code evaluation of Rules that do not specify them.
+    #[serde(default)]
+    pub default_limits: Option<RuleLimits>,
+    /// ClientConfig of Rules, for the fields they do not specify.
+    /// Namespaced Rules always use this ClientConfig, as they cannot specify one.
+    #[serde(default)]
+    pub default_client_config: Option<RuleClientConfig>,
+    /// Log level directives in `RUST_LOG` format (e.g. `info,checkpoint=debug`).
+    /// Defaults to `RUST_LOG` of each process.
+    #[serde(default)]
+    pub log_level: Option<String>,
+}
+
+impl CheckpointConfigSpec {
+    /// Whether objects in the namespace are excluded from every Rule
+    pub fn is_excluded_namespace(&self, namespace: &str) -> bool {
+        self.excluded_namespaces
+            .iter()
+            .flatten()
+            .any(|excluded| excluded == namespace)
+    }
+
+    /// Fill in the defaults the Rule does not specify, and exclude the excluded namespaces from its selectors.
+    pub fn apply_to_rule(&self, spec: &RuleSpec) -> RuleSpec {
+        let mut spec = spec.clone();
+        if spec.failure_policy.is_none() {
+            spec.failure_policy = self.default_failure_policy.clone();
+        }
+        if spec.timeout_seconds.is_none() {
+            spec.timeout_seconds = self.default_timeout_seconds;
+        }
+        if spec.limits.is_none() {
+            spec.limits = self.default_limits.clone();
+        }
+        if let Some(default_client_config) = &self.default_client_config {
+            spec.client_config = Some(
+                spec.client_config
+                    .unwrap_or_default()
+                    .or(default_client_config),
+            );
+        }
+
+        if let Some(excluded_namespaces) = self
+            .excluded_namespaces
+            .clone()
+            .filter(|namespaces| !namespaces.is_empty())
+        {
+            spec.exclude_namespaces(excluded_namespaces);
+        }
+        spec
+    }
+}
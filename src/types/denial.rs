@@ -0,0 +1,116 @@
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Rule which denied the request.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DenialRecordRule {
+    /// Kind of the rule (e.g. `ValidatingRule`).
+    pub kind: String,
+    /// Name of the rule.
+    pub name: String,
+    /// Namespace of the rule. Empty for cluster-scoped rules.
+    #[serde(default)]
+    pub namespace: Option<String>,
+}
+
+/// Object of the denied request.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DenialRecordObject {
+    /// API group of the object.
+    pub group: String,
+    /// API version of the object.
+    pub version: String,
+    /// Kind of the object.
+    pub kind: String,
+    /// Namespace of the object. Empty for cluster-scoped objects.
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// Name of the object. May be empty if the name is generated by the API server.
+    pub name: String,
+}
+
+/// User who made the denied request.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DenialRecordUser {
+    /// Name of the user.
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Groups the user belongs to.
+    #[serde(default)]
+    pub groups: Option<Vec<String>>,
+}
+
+/// Request recently denied by a rule, kept in the status of the rule.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RecentDenial {
+    /// Operation of the request (e.g. `CREATE`).
+    pub operation: String,
+    /// Object of the request.
+    pub object: DenialRecordObject,
+    /// Name of the user who made the request.
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Reason of the denial.
+    pub reason: String,
+    /// Whether the request was allowed by the canary rollout of the Rule.
+    #[serde(default)]
+    pub would_deny: bool,
+    /// Time of the denial.
+    pub time: Time,
+}
+
+impl From<DenialRecordSpec> for RecentDenial {
+    fn from(record: DenialRecordSpec) -> Self {
+        Self {
+            operation: record.operation,
+            object: record.object,
+            username: record.user.username,
+            reason: record.reason,
+            would_deny: record.would_deny,
+            time: record.time,
+        }
+    }
+}
+
+/// Record of a request denied by a rule.
+#[derive(Serialize, Deserialize, JsonSchema, CustomResource, Clone, Debug)]
+#[kube(
+    group = "checkpoint.devsisters.com",
+    version = "v1",
+    kind = "DenialRecord",
+    namespaced,
+    shortname = "dr",
+    category = "checkpoint",
+    printcolumn = r#"{"name":"Rule Kind", "type":"string", "jsonPath":".spec.rule.kind"}"#,
+    printcolumn = r#"{"name":"Rule", "type":"string", "jsonPath":".spec.rule.name"}"#,
+    printcolumn = r#"{"name":"Operation", "type":"string", "jsonPath":".spec.operation"}"#,
+    printcolumn = r#"{"name":"Object Kind", "type":"string", "priority":1, "jsonPath":".spec.object.kind"}"#,
+    printcolumn = r#"{"name":"Object", "type":"string", "priority":1, "jsonPath":".spec.object.name"}"#,
+    printcolumn = r#"{"name":"Time", "type":"date", "jsonPath":".spec.time"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct DenialRecordSpec {
+    /// Rule which denied the request.
+    pub rule: DenialRecordRule,
+    /// UID of the admission request, which can be correlated with API server audit logs.
+    pub request_uid: String,
+    /// Operation of the request (e.g. `CREATE`).
+    pub operation: String,
+    /// Object of the request.
+    pub object: DenialRecordObject,
+    /// User who made the request.
+    pub user: DenialRecordUser,
+    /// Reason of the denial.
+    pub reason: String,
+    /// Whether the request was allowed by the canary rollout of the Rule, and would be denied once the Rule is fully rolled out.
+    #[serde(default)]
+    pub would_deny: bool,
+    /// Time of the denial.
+    pub time: Time,
+}
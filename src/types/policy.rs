@@ -1,10 +1,22 @@
-use std::{collections::HashMap, fmt};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
 
+use k8s_openapi::{
+    api::core::v1::{
+        Affinity, ConfigMapKeySelector, LocalObjectReference, PodSecurityContext,
+        ResourceRequirements, SecretKeySelector, Toleration,
+    },
+    apimachinery::pkg::apis::meta::v1::Time,
+};
 use kube::CustomResource;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
+use crate::types::rule::RuleAccess;
+
 /// List param to select the resources.
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
 #[serde(rename_all = "camelCase")]
@@ -17,6 +29,64 @@ pub struct CronPolicyResourceListParams {
     pub field_selector: Option<String>,
 }
 
+/// Authentication to a Prometheus server.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CronPolicyPrometheusAuth {
+    /// Reference to the bearer token in a ConfigMap or a Secret in the namespace of the checker.
+    #[serde(default)]
+    pub bearer_token_from: Option<CronPolicyValueFrom>,
+    /// Username of the basic authentication.
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Reference to the password of the basic authentication in a ConfigMap or a Secret in the namespace of the checker.
+    #[serde(default)]
+    pub password_from: Option<CronPolicyValueFrom>,
+}
+
+/// PromQL query to evaluate on a Prometheus server.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CronPolicyPrometheusQuery {
+    /// URL of the Prometheus server (e.g. `http://prometheus.monitoring:9090`).
+    pub endpoint: Url,
+    /// PromQL query to evaluate at the time of the check.
+    pub query: String,
+    /// Optional authentication to the Prometheus server.
+    #[serde(default)]
+    pub auth: Option<CronPolicyPrometheusAuth>,
+}
+
+/// Expected type of the HTTP response body.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub enum CronPolicyHttpResponseType {
+    /// Parse the body as JSON
+    #[default]
+    Json,
+    /// Provide the body as a string
+    Text,
+}
+
+/// HTTP request to fetch data from an external endpoint.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CronPolicyHttpResource {
+    /// URL to request.
+    pub url: Url,
+    /// HTTP method to use (default: GET)
+    #[serde(default)]
+    pub method: Option<CronPolicyNotificationWebhookMethod>,
+    /// Additional HTTP headers to append to the request
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Optional body of the request
+    #[serde(default)]
+    pub body: Option<String>,
+    /// Expected type of the response body (default: Json)
+    #[serde(default)]
+    pub response_type: CronPolicyHttpResponseType,
+}
+
 /// Specifier for the resources to check.
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -27,7 +97,8 @@ pub struct CronPolicyResource {
     /// Optional API version the resources belong to.
     /// If omitted, checkpoint will try to find latest version with provided kind.
     pub version: Option<String>,
-    /// Kind of the resources.
+    /// Kind of the resources. Required unless `prometheusQuery` or `http` is specified.
+    #[serde(default)]
     pub kind: String,
     /// Optional plural name. Use inferred from kind if not specified.
     #[serde(default)]
@@ -41,10 +112,26 @@ pub struct CronPolicyResource {
     /// Optional list params to list the resources.
     #[serde(default)]
     pub list_params: Option<CronPolicyResourceListParams>,
+    /// Optional PromQL query to fetch metrics from Prometheus instead of Kubernetes objects.
+    /// The `data` field of the Prometheus query API response is provided to the JS code.
+    #[serde(default)]
+    pub prometheus_query: Option<CronPolicyPrometheusQuery>,
+    /// Optional HTTP request to fetch data from an external endpoint instead of Kubernetes objects.
+    /// The response body is provided to the JS code. Requests failed or responded with non-success status fail the check.
+    #[serde(default)]
+    pub http: Option<CronPolicyHttpResource>,
+    /// Optional reference to a key of a Secret holding the kubeconfig of the cluster to fetch the resources from,
+    /// in the namespace of the checker. Resources are fetched from the cluster checkpoint runs in if not specified.
+    /// `group` and `version` must be specified with this, as they cannot be discovered from the other clusters.
+    #[serde(default)]
+    pub cluster_ref: Option<SecretKeySelector>,
 }
 
-fn default_cronpolicyspec_namespace() -> String {
-    "default".to_string()
+impl CronPolicyResource {
+    /// Whether the resource is Kubernetes objects, not Prometheus query nor HTTP endpoint
+    pub fn is_kubernetes(&self) -> bool {
+        self.prometheus_query.is_none() && self.http.is_none()
+    }
 }
 
 /// Restart policy for all containers within the pod. One of OnFailure, Never. More info: https://kubernetes.io/docs/concepts/workloads/pods/pod-lifecycle/#restart-policy
@@ -64,6 +151,58 @@ impl fmt::Display for RestartPolicy {
     }
 }
 
+/// Overrides of the checker pod.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CronPolicyPodTemplate {
+    /// Labels to add to the checker pod.
+    #[serde(default)]
+    pub labels: Option<BTreeMap<String, String>>,
+    /// Annotations to add to the checker pod.
+    #[serde(default)]
+    pub annotations: Option<BTreeMap<String, String>>,
+    /// Compute resources of the checker container.
+    #[serde(default)]
+    pub resources: Option<ResourceRequirements>,
+    /// Selector which must match a node's labels for the checker pod to be scheduled on that node.
+    #[serde(default)]
+    pub node_selector: Option<BTreeMap<String, String>>,
+    /// Tolerations of the checker pod.
+    #[serde(default)]
+    pub tolerations: Option<Vec<Toleration>>,
+    /// Scheduling constraints of the checker pod.
+    #[serde(default)]
+    pub affinity: Option<Affinity>,
+    /// Priority class name of the checker pod.
+    #[serde(default)]
+    pub priority_class_name: Option<String>,
+    /// Pod-level security attributes of the checker pod.
+    #[serde(default)]
+    pub security_context: Option<PodSecurityContext>,
+    /// Secrets to pull the checker image.
+    #[serde(default)]
+    pub image_pull_secrets: Option<Vec<LocalObjectReference>>,
+}
+
+/// How to treat concurrent executions of a Job. One of Allow, Forbid, Replace. More info: https://kubernetes.io/docs/concepts/workloads/controllers/cron-jobs/#concurrency-policy
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub enum ConcurrencyPolicy {
+    Allow,
+    Forbid,
+    Replace,
+}
+
+impl fmt::Display for ConcurrencyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Allow => write!(f, "Allow"),
+            Self::Forbid => write!(f, "Forbid"),
+            Self::Replace => write!(f, "Replace"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
 #[serde(rename_all = "UPPERCASE")]
 pub enum CronPolicyNotificationWebhookMethod {
@@ -93,21 +232,139 @@ pub struct CronPolicyNotificationWebhook {
     /// Body template of the webhook.
     /// Curly braces must be repeated (`{{` or `}}`) to distinguished from template variables
     pub body: String,
+    /// Reference to the bearer token to authenticate to the webhook.
+    #[serde(default)]
+    pub bearer_token_from: Option<CronPolicyValueFrom>,
+    /// Reference to a Secret of `kubernetes.io/basic-auth` type with the credentials to authenticate to the webhook.
+    #[serde(default)]
+    pub basic_auth_from: Option<LocalObjectReference>,
+    /// Reference to the PEM-encoded CA bundle to verify the webhook server, in addition to the public roots.
+    #[serde(default)]
+    pub ca_from: Option<CronPolicyValueFrom>,
+    /// Skip verifying the certificate of the webhook server. Defaults to false.
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
 }
 
 /// Configuration of a Slack webhook to notify when policy check failed.
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct CronPolicyNotificationSlack {
-    /// Slack incoming webhook URL to notify
-    pub webhook_url: Url,
+    /// Slack incoming webhook URL to notify. Either this or `botTokenFrom` must be specified.
+    #[serde(default)]
+    pub webhook_url: Option<Url>,
+    /// Reference to the Slack bot token to notify with the Web API instead of the incoming webhook.
+    /// Not supported with `notificationsFrom`, as the token is provided to the checker by the controller.
+    #[serde(default)]
+    pub bot_token_from: Option<CronPolicyValueFrom>,
+    /// Channel to post to with the bot token.
+    #[serde(default)]
+    pub channel: Option<String>,
+    /// Channels to post to with the bot token instead of `channel`, by the severity of the output.
+    #[serde(default)]
+    pub severity_channels: BTreeMap<Severity, String>,
+    /// IDs of Slack users to mention in the message.
+    #[serde(default)]
+    pub mention_users: Vec<String>,
+    /// IDs of Slack user groups to mention in the message.
+    #[serde(default)]
+    pub mention_groups: Vec<String>,
+    /// Upload the full output in JSON as a snippet. Requires the bot token.
+    #[serde(default)]
+    pub upload_output: bool,
+    /// Reply in the thread of the previous message of the policy. Requires the bot token and the state to be persisted.
+    #[serde(default)]
+    pub thread: bool,
     /// Slack message template.
     /// Curly braces must be repeated (`{{` or `}}`) to distinguished from template variables
     pub message: String,
 }
 
-/// Configurations of notifications to notify when policy chech failed
+/// TLS configuration of a message bus, with the PEM-encoded certificates read from ConfigMaps or Secrets.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CronPolicyNotificationTls {
+    /// Reference to the CA certificates to verify the server.
+    pub ca_from: CronPolicyValueFrom,
+    /// Reference to the client certificate chain, for mutual TLS. Requires `keyFrom`.
+    #[serde(default)]
+    pub cert_from: Option<CronPolicyValueFrom>,
+    /// Reference to the client private key, for mutual TLS. Requires `certFrom`.
+    #[serde(default)]
+    pub key_from: Option<CronPolicyValueFrom>,
+}
+
+/// SASL PLAIN credentials of a Kafka cluster.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CronPolicyNotificationKafkaSasl {
+    pub username: String,
+    /// Reference to the password.
+    pub password_from: CronPolicyValueFrom,
+}
+
+/// Configuration of a Kafka topic to publish to when policy check failed.
+///
+/// Credentials are provided to the checker by the controller, so they are not supported with `notificationsFrom`.
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CronPolicyNotificationKafka {
+    /// Addresses of the bootstrap brokers in `host:port` format.
+    pub brokers: Vec<String>,
+    pub topic: String,
+    /// Partition of the topic to publish to (default: 0)
+    #[serde(default)]
+    pub partition: i32,
+    /// Message template. Defaults to the output in JSON with the name of the policy and the severity.
+    /// Curly braces must be repeated (`{{` or `}}`) to distinguished from template variables
+    #[serde(default)]
+    pub message: Option<String>,
+    #[serde(default)]
+    pub tls: Option<CronPolicyNotificationTls>,
+    #[serde(default)]
+    pub sasl: Option<CronPolicyNotificationKafkaSasl>,
+}
+
+/// Configuration of a NATS subject to publish to when policy check failed.
+///
+/// Credentials are provided to the checker by the controller, so they are not supported with `notificationsFrom`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CronPolicyNotificationNats {
+    /// Address of the NATS server, e.g. `nats://nats.nats:4222`
+    pub url: String,
+    pub subject: String,
+    /// Message template. Defaults to the output in JSON with the name of the policy and the severity.
+    /// Curly braces must be repeated (`{{` or `}}`) to distinguished from template variables
+    #[serde(default)]
+    pub message: Option<String>,
+    #[serde(default)]
+    pub tls: Option<CronPolicyNotificationTls>,
+    /// Username to authenticate with `passwordFrom`.
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Reference to the password of `username`.
+    #[serde(default)]
+    pub password_from: Option<CronPolicyValueFrom>,
+    /// Reference to the token to authenticate with.
+    #[serde(default)]
+    pub token_from: Option<CronPolicyValueFrom>,
+}
+
+/// Reference to a key of a ConfigMap or a Secret. Exactly one of them must be specified.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CronPolicyValueFrom {
+    /// Selects a key of a ConfigMap.
+    #[serde(default)]
+    pub config_map_key_ref: Option<ConfigMapKeySelector>,
+    /// Selects a key of a Secret.
+    #[serde(default)]
+    pub secret_key_ref: Option<SecretKeySelector>,
+}
+
+/// Configurations of notifications to notify when policy chech failed
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
 pub struct CronPolicyNotification {
     /// Configuration of a Slack webhook
     #[serde(default)]
@@ -115,6 +372,70 @@ pub struct CronPolicyNotification {
     /// Configuration of a custom webhook
     #[serde(default)]
     pub webhook: Option<CronPolicyNotificationWebhook>,
+    /// Configuration of a Kafka topic
+    #[serde(default)]
+    pub kafka: Option<CronPolicyNotificationKafka>,
+    /// Configuration of a NATS subject
+    #[serde(default)]
+    pub nats: Option<CronPolicyNotificationNats>,
+    /// Additional notification channels routed by the severity of the output.
+    #[serde(default)]
+    pub routes: Vec<CronPolicyNotificationRoute>,
+    /// Limits of how often to notify. Requires the state to be persisted, which is implied unless `notificationsFrom` is used.
+    #[serde(default)]
+    pub throttle: Option<CronPolicyNotificationThrottle>,
+}
+
+/// Severity of the output, set by JS code with `setOutput(output, severity)`.
+#[derive(
+    Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Info => write!(f, "info"),
+            Self::Warning => write!(f, "warning"),
+            Self::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+/// Notification channels for the outputs with specific severities.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct CronPolicyNotificationRoute {
+    /// Severities of the output to notify to this route.
+    pub severities: Vec<Severity>,
+    /// Configuration of a Slack webhook
+    #[serde(default)]
+    pub slack: Option<CronPolicyNotificationSlack>,
+    /// Configuration of a custom webhook
+    #[serde(default)]
+    pub webhook: Option<CronPolicyNotificationWebhook>,
+    /// Configuration of a Kafka topic
+    #[serde(default)]
+    pub kafka: Option<CronPolicyNotificationKafka>,
+    /// Configuration of a NATS subject
+    #[serde(default)]
+    pub nats: Option<CronPolicyNotificationNats>,
+}
+
+/// Limits of how often to notify
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CronPolicyNotificationThrottle {
+    /// Minimum interval in seconds between notifications of the same output.
+    #[serde(default)]
+    pub min_interval_seconds: Option<u64>,
+    /// Maximum number of notifications in an hour.
+    #[serde(default)]
+    pub max_per_hour: Option<u32>,
 }
 
 /// CronPolicies check the specified resources with the provided JS code periodically.
@@ -124,7 +445,12 @@ pub struct CronPolicyNotification {
     version = "v1",
     kind = "CronPolicy",
     shortname = "cp",
-    status = "CronPolicyStatus"
+    status = "CronPolicyStatus",
+    category = "checkpoint",
+    printcolumn = r#"{"name":"Schedule", "type":"string", "jsonPath":".spec.schedule"}"#,
+    printcolumn = r#"{"name":"Suspend", "type":"boolean", "jsonPath":".spec.suspend"}"#,
+    printcolumn = r#"{"name":"Last Trigger", "type":"date", "description":"Time of the last manual trigger", "jsonPath":".status.lastManualTrigger.time"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
 )]
 #[serde(rename_all = "camelCase")]
 pub struct CronPolicySpec {
@@ -133,20 +459,182 @@ pub struct CronPolicySpec {
     pub suspend: bool,
     /// The schedule in Cron format, see https://en.wikipedia.org/wiki/Cron.
     pub schedule: String,
+    /// The time zone name for the given schedule, see https://en.wikipedia.org/wiki/List_of_tz_database_time_zones.  Defaults to the time zone of the kube-controller-manager, usually UTC.  Requires Kubernetes 1.25 or later.
+    #[serde(default)]
+    pub time_zone: Option<String>,
+
+    /// Specifier for the resources to check.
+    pub resources: Vec<CronPolicyResource>,
+    /// JS code to evaluate on the resources.  Either this or `codeFrom` must be specified.
+    #[serde(default)]
+    pub code: String,
+    /// Reference to the JS code in a ConfigMap or a Secret in the namespace of the checker.
+    #[serde(default)]
+    pub code_from: Option<CronPolicyValueFrom>,
+    /// Configurations of notifications to notify when policy check failed.
+    #[serde(default)]
+    pub notifications: CronPolicyNotification,
+    /// Reference to the configurations of notifications in JSON, in a ConfigMap or a Secret in the namespace of the checker.  Takes precedence over `notifications`.
+    #[serde(default)]
+    pub notifications_from: Option<CronPolicyValueFrom>,
+    /// Annotate objects reported with `addFinding` in JS code with `checkpoint.devsisters.com/finding` annotation, and clear it when resolved.  Defaults to false.
+    #[serde(default)]
+    pub finding_annotation: bool,
+    /// Allow JS code to modify the objects of `resources` with `kubePatch` and `kubeAnnotate`, e.g. to label violating objects.
+    ///
+    /// The checker ServiceAccount is granted to patch them.  Defaults to false.
+    #[serde(default)]
+    pub allow_remediation: bool,
+    /// Kubernetes resources to access with `kubeGet` or `kubeList` function in JS code, in addition to `resources`.
+    ///
+    /// The checker ServiceAccount is granted to access them.
+    #[serde(default)]
+    pub access: Option<Vec<RuleAccess>>,
+    /// Persist the output of each run in a ConfigMap named `<name>-state` in the namespace of the CronJob, and expose the previous one to JS code with `getPreviousOutput()`.  Defaults to false.
+    #[serde(default)]
+    pub persist_state: bool,
+    /// Notify only when the output differs from the one of the previous run.  Implies `persistState`.  Defaults to false.
+    #[serde(default)]
+    pub notify_only_on_change: bool,
+
+    /// Namespace name for the CronJob.  Defaults to the default checker namespace of the controller.
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// Restart policy for all containers within the pod. One of OnFailure, Never. More info: https://kubernetes.io/docs/concepts/workloads/pods/pod-lifecycle/#restart-policy
+    pub restart_policy: RestartPolicy,
+    /// Overrides of the checker pod such as resources, scheduling constraints, and security context.
+    #[serde(default)]
+    pub pod_template: Option<CronPolicyPodTemplate>,
+    /// Specifies how to treat concurrent executions of a Job. One of Allow, Forbid, Replace.  Defaults to Allow.
+    #[serde(default)]
+    pub concurrency_policy: Option<ConcurrencyPolicy>,
+    /// Optional deadline in seconds for starting the job if it misses scheduled time for any reason.  Missed jobs executions will be counted as failed ones.
+    #[serde(default)]
+    pub starting_deadline_seconds: Option<i64>,
+    /// The number of successful finished jobs to retain.  Defaults to 3.
+    #[serde(default)]
+    pub successful_jobs_history_limit: Option<i32>,
+    /// The number of failed finished jobs to retain.  Defaults to 1.
+    #[serde(default)]
+    pub failed_jobs_history_limit: Option<i32>,
+    /// Specifies the number of retries before marking the checker job failed.  Defaults to 6.
+    #[serde(default)]
+    pub backoff_limit: Option<i32>,
+    /// Specifies the duration in seconds relative to the startTime that the checker job may be continuously active before the system tries to terminate it.
+    #[serde(default)]
+    pub active_deadline_seconds: Option<i64>,
+}
 
+impl CronPolicySpec {
+    /// Whether the checker state needs to be persisted between runs
+    pub fn persists_state(&self) -> bool {
+        self.persist_state || self.notify_only_on_change || self.notifications.throttle.is_some()
+    }
+}
+
+/// Record of the last manual trigger of a CronPolicy.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CronPolicyManualTrigger {
+    /// Value of the `checkpoint.devsisters.com/trigger` annotation that triggered the Job.
+    pub trigger: String,
+    /// Name of the triggered Job.
+    pub job_name: String,
+    /// Time when the Job was triggered.
+    pub time: Time,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CronPolicyStatus {
+    /// The last manual trigger by `checkpoint.devsisters.com/trigger` annotation.
+    #[serde(default)]
+    pub last_manual_trigger: Option<CronPolicyManualTrigger>,
+}
+
+/// Policies check the specified resources with the provided JS code once, as soon as they are created.
+///
+/// Changes to the spec after the checker Job is created are not applied. Recreate the Policy to run the check again.
+#[derive(Serialize, Deserialize, JsonSchema, CustomResource, Clone, Debug)]
+#[kube(
+    group = "checkpoint.devsisters.com",
+    version = "v1",
+    kind = "Policy",
+    shortname = "pol",
+    status = "PolicyStatus",
+    category = "checkpoint",
+    printcolumn = r#"{"name":"Phase", "type":"string", "jsonPath":".status.phase"}"#,
+    printcolumn = r#"{"name":"Started", "type":"date", "jsonPath":".status.startTime"}"#,
+    printcolumn = r#"{"name":"Completed", "type":"date", "jsonPath":".status.completionTime"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicySpec {
     /// Specifier for the resources to check.
     pub resources: Vec<CronPolicyResource>,
-    /// JS code to evaluate on the resources.
+    /// JS code to evaluate on the resources.  Either this or `codeFrom` must be specified.
+    #[serde(default)]
     pub code: String,
+    /// Reference to the JS code in a ConfigMap or a Secret in the namespace of the checker.
+    #[serde(default)]
+    pub code_from: Option<CronPolicyValueFrom>,
     /// Configurations of notifications to notify when policy check failed.
+    #[serde(default)]
     pub notifications: CronPolicyNotification,
+    /// Reference to the configurations of notifications in JSON, in a ConfigMap or a Secret in the namespace of the checker.  Takes precedence over `notifications`.
+    #[serde(default)]
+    pub notifications_from: Option<CronPolicyValueFrom>,
+    /// Annotate objects reported with `addFinding` in JS code with `checkpoint.devsisters.com/finding` annotation, and clear it when resolved.  Defaults to false.
+    #[serde(default)]
+    pub finding_annotation: bool,
+    /// Allow JS code to modify the objects of `resources` with `kubePatch` and `kubeAnnotate`, e.g. to label violating objects.
+    ///
+    /// The checker ServiceAccount is granted to patch them.  Defaults to false.
+    #[serde(default)]
+    pub allow_remediation: bool,
+    /// Kubernetes resources to access with `kubeGet` or `kubeList` function in JS code, in addition to `resources`.
+    ///
+    /// The checker ServiceAccount is granted to access them.
+    #[serde(default)]
+    pub access: Option<Vec<RuleAccess>>,
 
-    /// Namespace name for the CronJob.  Defaults to "default".
-    #[serde(default = "default_cronpolicyspec_namespace")]
-    pub namespace: String,
+    /// Namespace name for the Job.  Defaults to the default checker namespace of the controller.
+    #[serde(default)]
+    pub namespace: Option<String>,
     /// Restart policy for all containers within the pod. One of OnFailure, Never. More info: https://kubernetes.io/docs/concepts/workloads/pods/pod-lifecycle/#restart-policy
     pub restart_policy: RestartPolicy,
+    /// Overrides of the checker pod such as resources, scheduling constraints, and security context.
+    #[serde(default)]
+    pub pod_template: Option<CronPolicyPodTemplate>,
+    /// Specifies the number of retries before marking the checker job failed.  Defaults to 6.
+    #[serde(default)]
+    pub backoff_limit: Option<i32>,
+    /// Specifies the duration in seconds relative to the startTime that the checker job may be continuously active before the system tries to terminate it.
+    #[serde(default)]
+    pub active_deadline_seconds: Option<i64>,
 }
 
-#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
-pub struct CronPolicyStatus {}
+/// Phase of the checker Job of a Policy.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub enum PolicyPhase {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyStatus {
+    /// Name of the checker Job.
+    #[serde(default)]
+    pub job_name: Option<String>,
+    /// Phase of the checker Job.
+    #[serde(default)]
+    pub phase: Option<PolicyPhase>,
+    /// Time when the checker Job started.
+    #[serde(default)]
+    pub start_time: Option<Time>,
+    /// Time when the checker Job finished.
+    #[serde(default)]
+    pub completion_time: Option<Time>,
+}
@@ -1,14 +1,18 @@
+pub mod v2;
+
 use std::fmt;
 
 use k8s_openapi::{
     api::admissionregistration::v1::RuleWithOperations,
-    apimachinery::pkg::apis::meta::v1::LabelSelector,
+    apimachinery::pkg::apis::meta::v1::{Condition, LabelSelector, LabelSelectorRequirement},
 };
 use kube::CustomResource;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+use crate::types::{denial::RecentDenial, policy::CronPolicyNotification};
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq, Eq)]
 #[serde(rename_all = "PascalCase")]
 pub enum FailurePolicy {
     #[default]
@@ -25,6 +29,54 @@ impl fmt::Display for FailurePolicy {
     }
 }
 
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub enum ReinvocationPolicy {
+    Never,
+    IfNeeded,
+}
+
+impl fmt::Display for ReinvocationPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Never => write!(f, "Never"),
+            Self::IfNeeded => write!(f, "IfNeeded"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub enum MatchPolicy {
+    Exact,
+    Equivalent,
+}
+
+impl fmt::Display for MatchPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Exact => write!(f, "Exact"),
+            Self::Equivalent => write!(f, "Equivalent"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "PascalCase")]
+pub enum SideEffectClass {
+    None,
+    NoneOnDryRun,
+}
+
+impl fmt::Display for SideEffectClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "None"),
+            Self::NoneOnDryRun => write!(f, "NoneOnDryRun"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ServiceAccountInfo {
@@ -32,6 +84,184 @@ pub struct ServiceAccountInfo {
     pub name: String,
 }
 
+/// Identity of the Kubernetes client of `kubeGet` and `kubeList`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum KubeClientMode {
+    #[default]
+    ServiceAccount,
+    ImpersonateRequester,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAccessVerb {
+    Get,
+    List,
+}
+
+impl fmt::Display for RuleAccessVerb {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Get => write!(f, "get"),
+            Self::List => write!(f, "list"),
+        }
+    }
+}
+
+/// CEL expression to pre-filter requests before sending them to the webhook
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchCondition {
+    /// Identifier of the condition. Must be unique within the Rule.
+    pub name: String,
+    /// CEL expression that must evaluate to bool.
+    /// The request is sent to the webhook only if all conditions evaluate to true.
+    pub expression: String,
+}
+
+/// Webhook entry of a Rule with its own match criteria
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleWebhook {
+    /// Name of the webhook entry. Must be unique within the Rule.
+    pub name: String,
+    /// NamespaceSelector for the webhook entry. Defaults to namespaceSelector of the Rule.
+    pub namespace_selector: Option<LabelSelector>,
+    /// ObjectSelector for the webhook entry. Defaults to objectSelector of the Rule.
+    pub object_selector: Option<LabelSelector>,
+    /// ObjectRules for the webhook entry. Defaults to objectRules of the Rule.
+    pub object_rules: Option<Vec<RuleWithOperations>>,
+    /// TimeoutSeconds for the webhook entry. Defaults to timeoutSeconds of the Rule.
+    pub timeout_seconds: Option<i32>,
+    /// MatchConditions for the webhook entry. Defaults to matchConditions of the Rule.
+    pub match_conditions: Option<Vec<MatchCondition>>,
+}
+
+/// Where the API server sends admission requests of a Rule
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleClientConfig {
+    /// Base URL of the checkpoint webhook (e.g. `https://checkpoint.example.com:8443`), to which the path of the Rule is appended.
+    /// Must be reachable from the API server and served with a certificate signed by the CA bundle of the controller.
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Port of the Service of the checkpoint webhook, to pin the Rule to one of the webhook deployments.
+    /// Defaults to the Service port of the controller. Ignored if url is specified.
+    #[serde(default)]
+    pub port: Option<i32>,
+    /// Prefix of the path of the Rule (e.g. `/canary`), for a checkpoint webhook serving Rules under the prefix.
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+}
+
+impl RuleClientConfig {
+    /// Fill in the fields not specified from the other client config
+    pub fn or(&self, other: &RuleClientConfig) -> RuleClientConfig {
+        RuleClientConfig {
+            url: self.url.clone().or_else(|| other.url.clone()),
+            port: self.port.or(other.port),
+            path_prefix: self
+                .path_prefix
+                .clone()
+                .or_else(|| other.path_prefix.clone()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum RolloutMode {
+    Canary,
+}
+
+/// Gradual rollout of the denials of a Rule
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleRollout {
+    /// Mode of the rollout. Only `canary` is supported.
+    pub mode: RolloutMode,
+    /// Percentage of requests to enforce denials for. Denials of the other requests are only audit-logged.
+    #[schemars(range(max = 100))]
+    pub percentage: u8,
+}
+
+impl RuleRollout {
+    /// Whether denials are enforced for the request with the key
+    ///
+    /// Requests are bucketed by the hash of the key, so that the same key is decided the same way by every replica.
+    pub fn enforces(&self, key: &str) -> bool {
+        match self.mode {
+            RolloutMode::Canary => bucket(key) < u64::from(self.percentage),
+        }
+    }
+}
+
+/// Sampling of the requests to a Rule to record
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleRecording {
+    /// Percentage of requests to record
+    #[schemars(range(max = 100))]
+    pub percentage: u8,
+}
+
+impl RuleRecording {
+    /// Whether the request with the UID is sampled
+    pub fn samples(&self, uid: &str) -> bool {
+        bucket(uid) < u64::from(self.percentage)
+    }
+}
+
+/// Bucket of the key out of 100, by the 64-bit FNV-1a hash of the key
+///
+/// The hash must not change between releases, or replicas of different versions decide differently during upgrades.
+fn bucket(key: &str) -> u64 {
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    hash % 100
+}
+
+/// Kubernetes resource access of JS code
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleAccess {
+    /// API group of the resources.
+    pub group: String,
+    /// Plural name of the resources (e.g. `pods`).
+    pub resource: String,
+    /// Verb to access the resources with. `get` for `kubeGet`, `list` for `kubeList`.
+    pub verb: RuleAccessVerb,
+    /// Optional Namespace name of the resources. All Namespaces if not specified.
+    #[serde(default)]
+    pub namespace: Option<String>,
+    /// Optional name of the resource.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// Limits of JS code evaluation of a Rule, enforced in each webhook replica
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleLimits {
+    /// Maximum number of simultaneous evaluations.
+    #[serde(default)]
+    pub max_concurrency: Option<u32>,
+    /// Maximum number of evaluations per second.
+    #[serde(default)]
+    pub max_requests_per_second: Option<u32>,
+    /// Number of consecutive evaluation errors to open the circuit breaker of the Rule at.
+    ///
+    /// While the circuit breaker is open, requests are allowed with a warning without evaluating JS code,
+    /// so that a broken Rule with failurePolicy Fail does not block requests until it is fixed.
+    #[serde(default)]
+    pub max_consecutive_errors: Option<u32>,
+    /// Duration to keep the circuit breaker open before evaluating JS code again. Defaults to 60 seconds.
+    #[serde(default)]
+    pub error_cooldown_seconds: Option<u64>,
+}
+
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct RuleSpec {
@@ -59,19 +289,211 @@ pub struct RuleSpec {
     /// TimeoutSeconds specifies the timeout for this Rule.
     /// Default to 10 seconds.
     pub timeout_seconds: Option<i32>,
+    /// ReinvocationPolicy for webhook configuration.
+    ///
+    /// ReinvocationPolicy indicates whether this Rule should be called again when other admission plugins modified the object after the initial call - allowed values are Never or IfNeeded.
+    /// Only used by MutatingRule. Defaults to Never.
+    pub reinvocation_policy: Option<ReinvocationPolicy>,
+    /// Priority of the mutation among the ones of other Rules, from 0 to 999.
+    ///
+    /// The API server calls mutating webhooks in the order of the names of their configurations, so the priority is
+    /// prefixed to the name of the webhook configuration. Rules with lower priority mutate first, and Rules with priority
+    /// mutate before the ones without. Only used by MutatingRule.
+    #[serde(default)]
+    #[schemars(range(max = 999))]
+    pub priority: Option<u16>,
+    /// MatchPolicy for webhook configuration.
+    ///
+    /// MatchPolicy defines how the ObjectRules are used to match incoming requests - allowed values are Exact or Equivalent.
+    /// Defaults to Equivalent.
+    pub match_policy: Option<MatchPolicy>,
+    /// MatchConditions for webhook configuration.
+    ///
+    /// MatchConditions filter requests with CEL expressions at the API server, before JS code is evaluated.
+    /// Requires Kubernetes 1.27 or later.
+    pub match_conditions: Option<Vec<MatchCondition>>,
+    /// Webhook entries for webhook configuration.
+    ///
+    /// Each entry has its own match criteria and evaluates the same JS code.
+    /// Criteria not specified in an entry default to the ones of the Rule.
+    /// If omitted, a single webhook is generated with the criteria of the Rule.
+    pub webhooks: Option<Vec<RuleWebhook>>,
+    /// SideEffects for webhook configuration.
+    ///
+    /// SideEffects states whether evaluating the Rule has side effects - allowed values are None or NoneOnDryRun.
+    /// Defaults to NoneOnDryRun if JS code calls `kubeGet` or `kubeList`, otherwise None.
+    #[serde(default)]
+    pub side_effects: Option<SideEffectClass>,
+    /// ClientConfig for webhook configuration.
+    ///
+    /// ClientConfig overrides where the API server sends admission requests, for checkpoint deployed out of the cluster.
+    /// Defaults to the external URL of the controller if configured, otherwise the Service of the checkpoint webhook.
+    #[serde(default)]
+    pub client_config: Option<RuleClientConfig>,
+    /// Whether to send requests in the namespace of checkpoint to the Rule.
+    ///
+    /// The controller excludes the namespace of checkpoint from the webhook configurations of Rules if configured to,
+    /// so that checkpoint can restart itself when the Rules fail. Set to true to opt out, e.g. for Rules on checkpoint itself.
+    #[serde(default)]
+    pub include_checkpoint_namespace: bool,
 
     /// The name of ServiceAccount to use to run JS code.
     ///
     /// If you want to use `kubeGet` or `kubeList` function in JS code, you must provide ServiceAccount info with this field.
     pub service_account: Option<ServiceAccountInfo>,
 
+    /// Identity `kubeGet` and `kubeList` access Kubernetes resources with. Defaults to `serviceAccount`.
+    ///
+    /// With `impersonateRequester`, the webhook impersonates the user and groups of the request instead of using
+    /// the ServiceAccount, so that the code sees what the user is permitted to access.
+    #[serde(default)]
+    pub kube_client_mode: Option<KubeClientMode>,
+
+    /// Kubernetes resources to access with `kubeGet` or `kubeList` function in JS code.
+    ///
+    /// If provided, the controller checks whether the ServiceAccount is permitted to access them
+    /// and reports the result with `ServiceAccountPermitted` condition.
+    pub access: Option<Vec<RuleAccess>>,
+
+    /// Limits of JS code evaluation in each webhook replica.
+    ///
+    /// Requests over the limits fail without evaluating JS code, and are handled by failurePolicy,
+    /// so that a hot Rule does not starve other Rules.
+    pub limits: Option<RuleLimits>,
+
+    /// Rollout of the denials of the Rule.
+    ///
+    /// With `canary` mode, denials are enforced only for the percentage of requests, and the other requests are allowed
+    /// with a warning and audit-logged as would-deny. Defaults to enforcing all denials.
+    #[serde(default)]
+    pub rollout: Option<RuleRollout>,
+
+    /// Recording of the requests to the Rule.
+    ///
+    /// Sampled requests are recorded with the decisions of the Rule as ConfigMaps if the webhook is configured to,
+    /// and can be replayed against modified Rules with `checkpoint-cli replay`.
+    #[serde(default)]
+    pub recording: Option<RuleRecording>,
+
+    /// Notifications of the requests denied by the Rule.
+    ///
+    /// The webhook notifies the channels in background when the Rule denies a request. Would-deny requests of the rollout
+    /// are not notified. Routes are not used since denials have no severity, and channels with credentials read from
+    /// ConfigMaps or Secrets are not supported.
+    ///
+    /// Ignored for namespaced Rules, not to let users of a namespace make the webhook send requests anywhere.
+    #[serde(default)]
+    pub notifications: Option<CronPolicyNotification>,
+
+    /// Fields of the admission request JS code requires, as dot-separated paths (e.g. `object.metadata.labels`, `oldObject`).
+    ///
+    /// Requests without any of the fields are denied without evaluating JS code, telling which field is missing.
+    #[serde(default)]
+    pub requires: Option<Vec<String>>,
+
     /// JS code to evaluate when validating request.
     pub code: String,
 }
 
-#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+impl RuleSpec {
+    /// SideEffects of the webhook configuration, inferred from JS code if not specified
+    pub fn side_effect_class(&self) -> SideEffectClass {
+        match &self.side_effects {
+            Some(side_effects) => side_effects.clone(),
+            None if crate::js::uses_kube_api(&self.code) => SideEffectClass::NoneOnDryRun,
+            None => SideEffectClass::None,
+        }
+    }
+
+    /// Exclude the namespaces from the namespace selectors of the Rule and its webhook entries having their own ones
+    pub fn exclude_namespaces(&mut self, namespaces: Vec<String>) {
+        let exclude = |selector: Option<LabelSelector>| {
+            let mut selector = selector.unwrap_or_default();
+            selector
+                .match_expressions
+                .get_or_insert_with(Vec::new)
+                .push(LabelSelectorRequirement {
+                    key: "kubernetes.io/metadata.name".to_string(),
+                    operator: "NotIn".to_string(),
+                    values: Some(namespaces.clone()),
+                });
+            Some(selector)
+        };
+        self.namespace_selector = exclude(self.namespace_selector.take());
+        if let Some(webhooks) = &mut self.webhooks {
+            for webhook in webhooks {
+                if webhook.namespace_selector.is_some() {
+                    webhook.namespace_selector = exclude(webhook.namespace_selector.take());
+                }
+            }
+        }
+    }
+
+    /// Restrict the Rule to the namespace.
+    ///
+    /// Namespace selectors are narrowed to the namespace, object rules are narrowed to namespaced resources
+    /// since namespace selectors do not apply to cluster-scoped ones, and the ServiceAccount is looked up in the namespace.
+    /// The client config and notifications are ignored.
+    pub fn pin_namespace(&self, namespace: &str) -> RuleSpec {
+        let pin_selector = |selector: Option<LabelSelector>| {
+            let mut selector = selector.unwrap_or_default();
+            selector
+                .match_expressions
+                .get_or_insert_with(Vec::new)
+                .push(LabelSelectorRequirement {
+                    key: "kubernetes.io/metadata.name".to_string(),
+                    operator: "In".to_string(),
+                    values: Some(vec![namespace.to_string()]),
+                });
+            Some(selector)
+        };
+        let pin_rules = |rules: Option<Vec<RuleWithOperations>>| {
+            rules.map(|rules| {
+                rules
+                    .into_iter()
+                    .map(|mut rule| {
+                        rule.scope = Some("Namespaced".to_string());
+                        rule
+                    })
+                    .collect()
+            })
+        };
+
+        let mut spec = self.clone();
+        spec.namespace_selector = pin_selector(spec.namespace_selector.take());
+        spec.object_rules = pin_rules(spec.object_rules.take());
+        if let Some(webhooks) = &mut spec.webhooks {
+            for webhook in webhooks {
+                if webhook.namespace_selector.is_some() {
+                    webhook.namespace_selector = pin_selector(webhook.namespace_selector.take());
+                }
+                webhook.object_rules = pin_rules(webhook.object_rules.take());
+            }
+        }
+        if let Some(service_account) = &mut spec.service_account {
+            service_account.namespace = namespace.to_string();
+        }
+        // Namespaced Rules must not send admission requests anywhere but checkpoint, nor denials anywhere
+        spec.client_config = None;
+        spec.notifications = None;
+        spec
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
 #[serde(rename_all = "camelCase")]
-pub struct RuleStatus {}
+pub struct RuleStatus {
+    /// Latest available observations of the Rule's state.
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
+    /// Name of the webhook configuration generated from the Rule. Mutations of Rules are applied in the order of the
+    /// names.
+    #[serde(default)]
+    pub webhook_configuration_name: Option<String>,
+    /// Requests recently denied by the Rule, newest first. Written by the webhook if configured to.
+    #[serde(default)]
+    pub recent_denials: Vec<RecentDenial>,
+}
 
 #[derive(Serialize, Deserialize, JsonSchema, CustomResource, Clone, Debug)]
 #[kube(
@@ -79,7 +501,10 @@ pub struct RuleStatus {}
     version = "v1",
     kind = "ValidatingRule",
     shortname = "vr",
-    status = "ValidatingRuleStatus"
+    status = "ValidatingRuleStatus",
+    category = "checkpoint",
+    printcolumn = r#"{"name":"Permitted", "type":"string", "jsonPath":".status.conditions[?(@.type==\"ServiceAccountPermitted\")].status"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
 )]
 #[serde(transparent)]
 pub struct ValidatingRuleSpec(pub RuleSpec);
@@ -94,10 +519,74 @@ pub struct ValidatingRuleStatus(pub RuleStatus);
     version = "v1",
     kind = "MutatingRule",
     shortname = "mr",
-    status = "MutatingRuleStatus"
+    status = "MutatingRuleStatus",
+    category = "checkpoint",
+    printcolumn = r#"{"name":"Permitted", "type":"string", "jsonPath":".status.conditions[?(@.type==\"ServiceAccountPermitted\")].status"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
 )]
 pub struct MutatingRuleSpec(pub RuleSpec);
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 #[serde(transparent)]
 pub struct MutatingRuleStatus(pub RuleStatus);
+
+#[derive(Serialize, Deserialize, JsonSchema, CustomResource, Clone, Debug)]
+#[kube(
+    group = "checkpoint.devsisters.com",
+    version = "v1",
+    kind = "NamespacedValidatingRule",
+    namespaced,
+    shortname = "nvr",
+    status = "ValidatingRuleStatus",
+    category = "checkpoint",
+    printcolumn = r#"{"name":"Permitted", "type":"string", "jsonPath":".status.conditions[?(@.type==\"ServiceAccountPermitted\")].status"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(transparent)]
+pub struct NamespacedValidatingRuleSpec(pub RuleSpec);
+
+#[derive(Serialize, Deserialize, JsonSchema, CustomResource, Clone, Debug)]
+#[kube(
+    group = "checkpoint.devsisters.com",
+    version = "v1",
+    kind = "NamespacedMutatingRule",
+    namespaced,
+    shortname = "nmr",
+    status = "MutatingRuleStatus",
+    category = "checkpoint",
+    printcolumn = r#"{"name":"Permitted", "type":"string", "jsonPath":".status.conditions[?(@.type==\"ServiceAccountPermitted\")].status"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(transparent)]
+pub struct NamespacedMutatingRuleSpec(pub RuleSpec);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_is_stable() {
+        // 0xaf63dc4c8601ec8c and 0x85944171f73967e8 are the FNV-1a hashes of "a" and "foobar"
+        assert_eq!(bucket("a"), 96);
+        assert_eq!(bucket("foobar"), 68);
+    }
+
+    #[test]
+    fn test_rollout_enforces() {
+        let rollout = |percentage| RuleRollout {
+            mode: RolloutMode::Canary,
+            percentage,
+        };
+        assert!(!rollout(0).enforces("a"));
+        assert!(!rollout(96).enforces("a"));
+        assert!(rollout(97).enforces("a"));
+        assert!(rollout(100).enforces("a"));
+    }
+
+    #[test]
+    fn test_recording_samples() {
+        let recording = |percentage| RuleRecording { percentage };
+        assert!(!recording(68).samples("foobar"));
+        assert!(recording(69).samples("foobar"));
+    }
+}
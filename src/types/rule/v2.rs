@@ -0,0 +1,433 @@
+//! `v2` API version of ValidatingRule and MutatingRule
+//!
+//! `v1` remains the storage version, and the API server converts objects between the versions with
+//! the conversion webhook at `/internal/convert/rules`. Match criteria are given only per webhook entry in
+//! `v2`, and the language of the code is explicit.
+
+use k8s_openapi::{
+    api::admissionregistration::v1::RuleWithOperations,
+    apimachinery::pkg::apis::meta::v1::LabelSelector,
+};
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    FailurePolicy, KubeClientMode, MatchCondition, MatchPolicy, MutatingRuleStatus,
+    ReinvocationPolicy, RuleAccess, RuleClientConfig, RuleLimits, RuleRecording, RuleRollout,
+    ServiceAccountInfo, SideEffectClass, ValidatingRuleStatus,
+};
+use crate::types::policy::CronPolicyNotification;
+
+pub const API_VERSION_V1: &str = "checkpoint.devsisters.com/v1";
+pub const API_VERSION_V2: &str = "checkpoint.devsisters.com/v2";
+
+/// Name of the webhook entry converted from the match criteria of a `v1` Rule without webhook entries
+pub const DEFAULT_WEBHOOK_NAME: &str = "default";
+
+/// Annotation marking the `default` webhook entry as converted from the match criteria of a `v1` Rule, so that
+/// only such an entry is converted back to the criteria instead of a webhook entry named `default`
+pub const DEFAULT_WEBHOOK_ANNOTATION_KEY: &str = "checkpoint.devsisters.com/default-webhook";
+
+/// Errors can be raised when converting Rules between API versions
+#[derive(thiserror::Error, Debug)]
+pub enum ConversionError {
+    #[error("object does not have apiVersion")]
+    MissingApiVersion,
+    #[error("conversion from {0} to {1} is not supported")]
+    UnsupportedApiVersion(String, String),
+    #[error("kind {0} is not supported")]
+    UnsupportedKind(String),
+    #[error("invalid spec: {0}")]
+    InvalidSpec(#[source] serde_json::Error),
+}
+
+/// Language of the code of a Rule
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq, Eq)]
+pub enum RuleEngine {
+    #[default]
+    JavaScript,
+}
+
+/// Webhook entry of a Rule with its match criteria
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleWebhook {
+    /// Name of the webhook entry. Must be unique within the Rule.
+    pub name: String,
+    /// NamespaceSelector decides whether to run the Rule on an object based on whether the namespace for that object matches the selector.
+    pub namespace_selector: Option<LabelSelector>,
+    /// ObjectSelector decides whether to run the Rule based on if the object has matching labels.
+    pub object_selector: Option<LabelSelector>,
+    /// ObjectRules describes what operations on what resources/subresources the webhook entry cares about.
+    pub object_rules: Option<Vec<RuleWithOperations>>,
+    /// TimeoutSeconds specifies the timeout for the webhook entry. Default to 10 seconds.
+    pub timeout_seconds: Option<i32>,
+    /// MatchConditions filter requests with CEL expressions at the API server, before the code is evaluated. Requires Kubernetes 1.27 or later.
+    pub match_conditions: Option<Vec<MatchCondition>>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleSpec {
+    /// FailurePolicy defines how unrecognized errors from the admission endpoint are handled - allowed values are Ignore or Fail. Defaults to Fail.
+    pub failure_policy: Option<FailurePolicy>,
+    /// ReinvocationPolicy indicates whether this Rule should be called again when other admission plugins modified the object after the initial call - allowed values are Never or IfNeeded. Only used by MutatingRule. Defaults to Never.
+    pub reinvocation_policy: Option<ReinvocationPolicy>,
+    /// Priority of the mutation among the ones of other Rules, from 0 to 999. The priority is prefixed to the name of the webhook configuration, as the API server calls mutating webhooks in the order of the names. Rules with lower priority mutate first, and Rules with priority mutate before the ones without. Only used by MutatingRule.
+    #[serde(default)]
+    #[schemars(range(max = 999))]
+    pub priority: Option<u16>,
+    /// MatchPolicy defines how the ObjectRules are used to match incoming requests - allowed values are Exact or Equivalent. Defaults to Equivalent.
+    pub match_policy: Option<MatchPolicy>,
+    /// Webhook entries for webhook configuration. Each entry has its own match criteria and evaluates the same code.
+    pub webhooks: Vec<RuleWebhook>,
+    /// SideEffects states whether evaluating the Rule has side effects - allowed values are None or NoneOnDryRun. Defaults to NoneOnDryRun if the code accesses Kubernetes resources, otherwise None.
+    #[serde(default)]
+    pub side_effects: Option<SideEffectClass>,
+    /// ClientConfig overrides where the API server sends admission requests, for checkpoint deployed out of the cluster. Defaults to the external URL of the controller if configured, otherwise the Service of the checkpoint webhook.
+    #[serde(default)]
+    pub client_config: Option<RuleClientConfig>,
+    /// Whether to send requests in the namespace of checkpoint to the Rule. The controller excludes the namespace of checkpoint from the webhook configurations of Rules if configured to, so that checkpoint can restart itself when the Rules fail.
+    #[serde(default)]
+    pub include_checkpoint_namespace: bool,
+
+    /// The name of ServiceAccount to use to run the code.
+    pub service_account: Option<ServiceAccountInfo>,
+    /// Identity `kubeGet` and `kubeList` access Kubernetes resources with. With `impersonateRequester`, the webhook impersonates the user and groups of the request instead of using the ServiceAccount. Defaults to `serviceAccount`.
+    #[serde(default)]
+    pub kube_client_mode: Option<KubeClientMode>,
+    /// Kubernetes resources to access with `kubeGet` or `kubeList` function in the code.
+    pub access: Option<Vec<RuleAccess>>,
+    /// Limits of code evaluation in each webhook replica.
+    pub limits: Option<RuleLimits>,
+    /// Rollout of the denials of the Rule. With `canary` mode, denials are enforced only for the percentage of requests, and the other requests are allowed with a warning and audit-logged as would-deny. Defaults to enforcing all denials.
+    #[serde(default)]
+    pub rollout: Option<RuleRollout>,
+    /// Recording of the requests to the Rule. Sampled requests are recorded with the decisions of the Rule as ConfigMaps if the webhook is configured to, and can be replayed against modified Rules with `checkpoint-cli replay`.
+    #[serde(default)]
+    pub recording: Option<RuleRecording>,
+    /// Notifications of the requests denied by the Rule. The webhook notifies the channels in background when the Rule denies a request. Would-deny requests of the rollout are not notified. Routes are not used since denials have no severity, and channels with credentials read from ConfigMaps or Secrets are not supported.
+    #[serde(default)]
+    pub notifications: Option<CronPolicyNotification>,
+    /// Fields of the admission request the code requires, as dot-separated paths (e.g. `object.metadata.labels`, `oldObject`). Requests without any of the fields are denied without evaluating the code.
+    #[serde(default)]
+    pub requires: Option<Vec<String>>,
+
+    /// Language of the code. Defaults to JavaScript.
+    #[serde(default)]
+    pub engine: RuleEngine,
+    /// Code to evaluate when validating or mutating request.
+    pub code: String,
+}
+
+impl From<super::RuleSpec> for RuleSpec {
+    fn from(spec: super::RuleSpec) -> Self {
+        let webhooks = match spec.webhooks {
+            Some(webhooks) => webhooks
+                .into_iter()
+                .map(|webhook| RuleWebhook {
+                    name: webhook.name,
+                    namespace_selector: webhook
+                        .namespace_selector
+                        .or_else(|| spec.namespace_selector.clone()),
+                    object_selector: webhook
+                        .object_selector
+                        .or_else(|| spec.object_selector.clone()),
+                    object_rules: webhook.object_rules.or_else(|| spec.object_rules.clone()),
+                    timeout_seconds: webhook.timeout_seconds.or(spec.timeout_seconds),
+                    match_conditions: webhook
+                        .match_conditions
+                        .or_else(|| spec.match_conditions.clone()),
+                })
+                .collect(),
+            None => vec![RuleWebhook {
+                name: DEFAULT_WEBHOOK_NAME.to_string(),
+                namespace_selector: spec.namespace_selector,
+                object_selector: spec.object_selector,
+                object_rules: spec.object_rules,
+                timeout_seconds: spec.timeout_seconds,
+                match_conditions: spec.match_conditions,
+            }],
+        };
+        Self {
+            failure_policy: spec.failure_policy,
+            reinvocation_policy: spec.reinvocation_policy,
+            priority: spec.priority,
+            match_policy: spec.match_policy,
+            webhooks,
+            side_effects: spec.side_effects,
+            client_config: spec.client_config,
+            include_checkpoint_namespace: spec.include_checkpoint_namespace,
+            service_account: spec.service_account,
+            kube_client_mode: spec.kube_client_mode,
+            access: spec.access,
+            limits: spec.limits,
+            rollout: spec.rollout,
+            recording: spec.recording,
+            notifications: spec.notifications,
+            requires: spec.requires,
+            engine: RuleEngine::JavaScript,
+            code: spec.code,
+        }
+    }
+}
+
+/// Webhook entries are kept as they are. `convert_rule` moves the marked `default` entry back to the criteria.
+impl From<RuleSpec> for super::RuleSpec {
+    fn from(spec: RuleSpec) -> Self {
+        Self {
+            failure_policy: spec.failure_policy,
+            namespace_selector: None,
+            object_selector: None,
+            object_rules: None,
+            timeout_seconds: None,
+            reinvocation_policy: spec.reinvocation_policy,
+            priority: spec.priority,
+            match_policy: spec.match_policy,
+            match_conditions: None,
+            webhooks: Some(spec.webhooks.into_iter().map(Into::into).collect()),
+            side_effects: spec.side_effects,
+            client_config: spec.client_config,
+            include_checkpoint_namespace: spec.include_checkpoint_namespace,
+            service_account: spec.service_account,
+            kube_client_mode: spec.kube_client_mode,
+            access: spec.access,
+            limits: spec.limits,
+            rollout: spec.rollout,
+            recording: spec.recording,
+            notifications: spec.notifications,
+            requires: spec.requires,
+            code: spec.code,
+        }
+    }
+}
+
+/// Move the single `default` webhook entry converted from the match criteria of a `v1` Rule back to the criteria
+fn flatten_default_webhook(spec: &mut super::RuleSpec) {
+    let Some(webhooks) = &mut spec.webhooks else {
+        return;
+    };
+    if webhooks.len() != 1 || webhooks[0].name != DEFAULT_WEBHOOK_NAME {
+        return;
+    }
+    let webhook = webhooks.remove(0);
+    spec.webhooks = None;
+    spec.namespace_selector = webhook.namespace_selector;
+    spec.object_selector = webhook.object_selector;
+    spec.object_rules = webhook.object_rules;
+    spec.timeout_seconds = webhook.timeout_seconds;
+    spec.match_conditions = webhook.match_conditions;
+}
+
+/// Set or remove the annotation marking the `default` webhook entry converted from the match criteria
+fn mark_default_webhook(object: &mut serde_json::Value, converted: bool) {
+    if !converted {
+        take_default_webhook_mark(object);
+        return;
+    }
+    let Some(metadata) = object.get_mut("metadata").and_then(serde_json::Value::as_object_mut) else {
+        return;
+    };
+    let annotations = metadata
+        .entry("annotations")
+        .or_insert_with(|| serde_json::json!({}));
+    if annotations.is_null() {
+        *annotations = serde_json::json!({});
+    }
+    if let Some(annotations) = annotations.as_object_mut() {
+        annotations.insert(DEFAULT_WEBHOOK_ANNOTATION_KEY.to_string(), "true".into());
+    }
+}
+
+/// Remove the annotation marking the `default` webhook entry converted from the match criteria, returning whether
+/// it was set
+fn take_default_webhook_mark(object: &mut serde_json::Value) -> bool {
+    object
+        .pointer_mut("/metadata/annotations")
+        .and_then(serde_json::Value::as_object_mut)
+        .and_then(|annotations| annotations.remove(DEFAULT_WEBHOOK_ANNOTATION_KEY))
+        .is_some()
+}
+
+impl From<RuleWebhook> for super::RuleWebhook {
+    fn from(webhook: RuleWebhook) -> Self {
+        Self {
+            name: webhook.name,
+            namespace_selector: webhook.namespace_selector,
+            object_selector: webhook.object_selector,
+            object_rules: webhook.object_rules,
+            timeout_seconds: webhook.timeout_seconds,
+            match_conditions: webhook.match_conditions,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, CustomResource, Clone, Debug)]
+#[kube(
+    group = "checkpoint.devsisters.com",
+    version = "v2",
+    kind = "ValidatingRule",
+    shortname = "vr",
+    status = "ValidatingRuleStatus",
+    category = "checkpoint",
+    printcolumn = r#"{"name":"Permitted", "type":"string", "jsonPath":".status.conditions[?(@.type==\"ServiceAccountPermitted\")].status"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(transparent)]
+pub struct ValidatingRuleSpec(pub RuleSpec);
+
+#[derive(Serialize, Deserialize, JsonSchema, CustomResource, Clone, Debug)]
+#[kube(
+    group = "checkpoint.devsisters.com",
+    version = "v2",
+    kind = "MutatingRule",
+    shortname = "mr",
+    status = "MutatingRuleStatus",
+    category = "checkpoint",
+    printcolumn = r#"{"name":"Permitted", "type":"string", "jsonPath":".status.conditions[?(@.type==\"ServiceAccountPermitted\")].status"}"#,
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(transparent)]
+pub struct MutatingRuleSpec(pub RuleSpec);
+
+/// Convert a ValidatingRule or MutatingRule object to the API version
+///
+/// Only the spec differs between the versions. Metadata and status are kept as they are, except the annotation
+/// marking the `default` webhook entry converted from the match criteria of a `v1` Rule.
+pub fn convert_rule(
+    mut object: serde_json::Value,
+    desired_api_version: &str,
+) -> Result<serde_json::Value, ConversionError> {
+    let api_version = object["apiVersion"]
+        .as_str()
+        .ok_or(ConversionError::MissingApiVersion)?
+        .to_string();
+    if api_version == desired_api_version {
+        return Ok(object);
+    }
+    let kind = object["kind"].as_str().unwrap_or_default();
+    if kind != "ValidatingRule" && kind != "MutatingRule" {
+        return Err(ConversionError::UnsupportedKind(kind.to_string()));
+    }
+
+    let spec = object["spec"].take();
+    let spec = match (api_version.as_str(), desired_api_version) {
+        (API_VERSION_V1, API_VERSION_V2) => {
+            let spec: super::RuleSpec =
+                serde_json::from_value(spec).map_err(ConversionError::InvalidSpec)?;
+            mark_default_webhook(&mut object, spec.webhooks.is_none());
+            serde_json::to_value(RuleSpec::from(spec))
+        }
+        (API_VERSION_V2, API_VERSION_V1) => {
+            let spec: RuleSpec =
+                serde_json::from_value(spec).map_err(ConversionError::InvalidSpec)?;
+            let mut spec = super::RuleSpec::from(spec);
+            if take_default_webhook_mark(&mut object) {
+                flatten_default_webhook(&mut spec);
+            }
+            serde_json::to_value(spec)
+        }
+        _ => {
+            return Err(ConversionError::UnsupportedApiVersion(
+                api_version,
+                desired_api_version.to_string(),
+            ))
+        }
+    }
+    .map_err(ConversionError::InvalidSpec)?;
+
+    object["spec"] = spec;
+    object["apiVersion"] = desired_api_version.into();
+    Ok(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn v1_rule(spec: serde_json::Value) -> serde_json::Value {
+        json!({
+            "apiVersion": API_VERSION_V1,
+            "kind": "ValidatingRule",
+            "metadata": {"name": "rule"},
+            "spec": spec,
+        })
+    }
+
+    /// Spec with the fields omitted by the object filled, to compare specs
+    fn normalized_v1_spec(object: &serde_json::Value) -> serde_json::Value {
+        let spec: super::super::RuleSpec = serde_json::from_value(object["spec"].clone()).unwrap();
+        serde_json::to_value(spec).unwrap()
+    }
+
+    fn round_trip(object: &serde_json::Value) -> (serde_json::Value, serde_json::Value) {
+        let v2 = convert_rule(object.clone(), API_VERSION_V2).unwrap();
+        let v1 = convert_rule(v2.clone(), API_VERSION_V1).unwrap();
+        (v2, v1)
+    }
+
+    #[test]
+    fn test_round_trip_criteria() {
+        let object = v1_rule(json!({
+            "objectRules": [{
+                "apiGroups": [""],
+                "apiVersions": ["v1"],
+                "operations": ["CREATE"],
+                "resources": ["pods"],
+            }],
+            "timeoutSeconds": 5,
+            "code": "",
+        }));
+        let (v2, v1) = round_trip(&object);
+
+        assert_eq!(v2["spec"]["webhooks"][0]["name"], DEFAULT_WEBHOOK_NAME);
+        assert_eq!(
+            v2["metadata"]["annotations"][DEFAULT_WEBHOOK_ANNOTATION_KEY],
+            "true"
+        );
+        assert_eq!(normalized_v1_spec(&v1), normalized_v1_spec(&object));
+        assert!(v1["metadata"]["annotations"]
+            .get(DEFAULT_WEBHOOK_ANNOTATION_KEY)
+            .is_none());
+    }
+
+    #[test]
+    fn test_round_trip_webhook_named_default() {
+        let object = v1_rule(json!({
+            "webhooks": [{
+                "name": DEFAULT_WEBHOOK_NAME,
+                "objectRules": [{
+                    "apiGroups": [""],
+                    "apiVersions": ["v1"],
+                    "operations": ["CREATE"],
+                    "resources": ["pods"],
+                }],
+            }],
+            "code": "",
+        }));
+        let (v2, v1) = round_trip(&object);
+
+        assert!(v2["metadata"].get("annotations").is_none());
+        assert_eq!(normalized_v1_spec(&v1), normalized_v1_spec(&object));
+    }
+
+    #[test]
+    fn test_v2_webhook_named_default_is_kept() {
+        let object = json!({
+            "apiVersion": API_VERSION_V2,
+            "kind": "MutatingRule",
+            "metadata": {"name": "rule"},
+            "spec": {
+                "webhooks": [{"name": DEFAULT_WEBHOOK_NAME, "timeoutSeconds": 5}],
+                "code": "",
+            },
+        });
+        let v1 = convert_rule(object, API_VERSION_V1).unwrap();
+
+        assert_eq!(v1["spec"]["webhooks"][0]["name"], DEFAULT_WEBHOOK_NAME);
+        assert!(v1["spec"]["timeoutSeconds"].is_null());
+    }
+}
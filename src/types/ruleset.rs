@@ -0,0 +1,72 @@
+use k8s_openapi::{
+    api::admissionregistration::v1::RuleWithOperations,
+    apimachinery::pkg::apis::meta::v1::LabelSelector,
+};
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::rule::{FailurePolicy, MatchPolicy, RuleClientConfig, RuleSpec, SideEffectClass};
+
+/// Group of ValidatingRules evaluated in a single webhook call.
+///
+/// The webhook generated for the RuleSet evaluates the Rules in order, and stops at the first Rule denying the request.
+/// Match criteria of the Rules are not applied, and the Rules do not get webhooks of their own while they are in a RuleSet.
+#[derive(Serialize, Deserialize, JsonSchema, CustomResource, Clone, Debug)]
+#[kube(
+    group = "checkpoint.devsisters.com",
+    version = "v1",
+    kind = "RuleSet",
+    category = "checkpoint",
+    printcolumn = r#"{"name":"Age", "type":"date", "jsonPath":".metadata.creationTimestamp"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleSetSpec {
+    /// Names of the ValidatingRules to evaluate, in order.
+    pub rules: Vec<String>,
+    /// FailurePolicy for webhook configuration. Defaults to Fail.
+    pub failure_policy: Option<FailurePolicy>,
+    /// NamespaceSelector for webhook configuration.
+    pub namespace_selector: Option<LabelSelector>,
+    /// ObjectSelector for webhook configuration.
+    pub object_selector: Option<LabelSelector>,
+    /// ObjectRules for Rules field in webhook configuration.
+    pub object_rules: Option<Vec<RuleWithOperations>>,
+    /// TimeoutSeconds for webhook configuration, for the evaluation of all the Rules. Defaults to 10 seconds.
+    pub timeout_seconds: Option<i32>,
+    /// MatchPolicy for webhook configuration. Defaults to Equivalent.
+    pub match_policy: Option<MatchPolicy>,
+    /// Where the API server sends requests to. Defaults to the webhook Service of checkpoint.
+    #[serde(default)]
+    pub client_config: Option<RuleClientConfig>,
+}
+
+impl RuleSetSpec {
+    /// Spec of a Rule to generate the webhook of the RuleSet from
+    pub fn to_rule_spec(&self, side_effects: SideEffectClass) -> RuleSpec {
+        RuleSpec {
+            failure_policy: self.failure_policy.clone(),
+            namespace_selector: self.namespace_selector.clone(),
+            object_selector: self.object_selector.clone(),
+            object_rules: self.object_rules.clone(),
+            timeout_seconds: self.timeout_seconds,
+            reinvocation_policy: None,
+            priority: None,
+            match_policy: self.match_policy.clone(),
+            match_conditions: None,
+            webhooks: None,
+            side_effects: Some(side_effects),
+            client_config: self.client_config.clone(),
+            include_checkpoint_namespace: false,
+            service_account: None,
+            kube_client_mode: None,
+            access: None,
+            limits: None,
+            rollout: None,
+            recording: None,
+            notifications: None,
+            requires: None,
+            code: String::new(),
+        }
+    }
+}
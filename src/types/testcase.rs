@@ -5,12 +5,17 @@ use std::{
 };
 
 use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
 use kube::core::{admission::AdmissionRequest, DynamicObject, ObjectList};
 use serde::{de::DeserializeOwned, Deserialize};
 
 use crate::{
-    handler::js::helper::{KubeGetArgument, KubeListArgument},
-    types::rule::{MutatingRule, ValidatingRule},
+    checker::CheckerOutput,
+    handler::js::helper::{DiscoveredResource, KubeGetArgument, KubeListArgument},
+    types::{
+        policy::CronPolicy,
+        rule::{MutatingRule, ValidatingRule},
+    },
 };
 
 /// Path of a YAML file that contains object definition or object itself
@@ -78,6 +83,14 @@ where
             }
         }
     }
+
+    /// Path of the file relative to the base path, if it is not an inline object
+    pub fn path(&self, base_path: &Path) -> Option<PathBuf> {
+        match self {
+            Self::FilePath(path) => Some(join_or_absolute(base_path, path).into_owned()),
+            Self::Object(_) => None,
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -87,7 +100,91 @@ pub struct TestCase {
     pub validating_rules: Vec<FilePathOrObject<ValidatingRule>>,
     #[serde(default)]
     pub mutating_rules: Vec<FilePathOrObject<MutatingRule>>,
+    #[serde(default)]
     pub cases: Vec<Case>,
+    /// CronPolicy to evaluate in `checkCases`
+    #[serde(default)]
+    pub cron_policy: Option<FilePathOrObject<CronPolicy>>,
+    #[serde(default)]
+    pub check_cases: Vec<CheckCase>,
+}
+
+impl TestCase {
+    /// Paths of the files the test case refers to, relative to the base path
+    pub fn referenced_paths(&self, base_path: &Path) -> Vec<PathBuf> {
+        let rules = self
+            .validating_rules
+            .iter()
+            .map(|rule| rule.path(base_path))
+            .chain(self.mutating_rules.iter().map(|rule| rule.path(base_path)));
+        let cases = self.cases.iter().flat_map(|case| {
+            let stubs = case
+                .stubs
+                .kube_get
+                .iter()
+                .map(|stub| stub.output.path(base_path))
+                .chain(
+                    case.stubs
+                        .kube_list
+                        .iter()
+                        .map(|stub| stub.output.path(base_path)),
+                )
+                .chain(
+                    case.stubs
+                        .kube_discover
+                        .iter()
+                        .map(|stub| stub.path(base_path)),
+                );
+            let requests = [
+                case.request
+                    .as_ref()
+                    .and_then(|request| request.path(base_path)),
+                case.expected
+                    .as_ref()
+                    .and_then(|expected| expected.final_object.as_ref())
+                    .and_then(|final_object| final_object.path(base_path)),
+            ];
+            let steps = case.steps.iter().flat_map(|step| {
+                [
+                    step.request.path(base_path),
+                    step.expected
+                        .final_object
+                        .as_ref()
+                        .and_then(|final_object| final_object.path(base_path)),
+                ]
+            });
+            requests.into_iter().chain(steps).chain(stubs)
+        });
+        let cron_policy = self
+            .cron_policy
+            .as_ref()
+            .map(|cron_policy| cron_policy.path(base_path));
+        let check_cases = self.check_cases.iter().flat_map(|check_case| {
+            let stubs = check_case
+                .stubs
+                .kube_get
+                .iter()
+                .map(|stub| stub.output.path(base_path))
+                .chain(
+                    check_case
+                        .stubs
+                        .kube_list
+                        .iter()
+                        .map(|stub| stub.output.path(base_path)),
+                );
+            check_case
+                .resources
+                .iter()
+                .map(|resource| resource.path(base_path))
+                .chain(stubs)
+        });
+        rules
+            .chain(cases)
+            .chain(cron_policy)
+            .chain(check_cases)
+            .flatten()
+            .collect()
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -96,6 +193,23 @@ pub struct Case {
     pub name: Option<String>,
     #[serde(default)]
     pub stubs: Stub,
+    /// Request of the case.  Either `request` and `expected`, or `steps` must be specified.
+    ///
+    /// `object` of DELETE requests is moved to `oldObject` as the API server sends it.
+    #[serde(default)]
+    pub request: Option<FilePathOrObject<AdmissionRequest<DynamicObject>>>,
+    #[serde(default)]
+    pub expected: Option<Expected>,
+    /// Requests evaluated in order, sharing the stubs.
+    ///
+    /// The object stored by a step becomes `oldObject` of the following UPDATE or DELETE request,
+    /// and also `object` of the UPDATE request if omitted, e.g. to test CREATE then UPDATE.
+    #[serde(default)]
+    pub steps: Vec<Step>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Step {
     pub request: FilePathOrObject<AdmissionRequest<DynamicObject>>,
     pub expected: Expected,
 }
@@ -107,6 +221,12 @@ pub struct Stub {
     pub kube_get: Vec<StubSpec<KubeGetArgument, Option<DynamicObject>>>,
     #[serde(default)]
     pub kube_list: Vec<StubSpec<KubeListArgument, ObjectList<DynamicObject>>>,
+    /// Resources returned by `kubeDiscover`
+    #[serde(default)]
+    pub kube_discover: Option<FilePathOrObject<Vec<DiscoveredResource>>>,
+    /// Current time seen by `Date`, e.g. `2023-01-01T00:00:00Z`
+    #[serde(default)]
+    pub now: Option<DateTime<Utc>>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -123,4 +243,44 @@ pub struct Expected {
     pub message: String,
     #[serde(default)]
     pub final_object: Option<FilePathOrObject<DynamicObject>>,
+    /// Number of `kubeGet` calls by all rules
+    #[serde(default)]
+    pub kube_get_calls: Option<usize>,
+    /// Number of `kubeList` calls by all rules
+    #[serde(default)]
+    pub kube_list_calls: Option<usize>,
+}
+
+/// Case to evaluate the code of a CronPolicy with fixtures instead of resources in the cluster
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckCase {
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Fetched resources in the order of `resources` of the CronPolicy.
+    ///
+    /// Each one is an object or `null` for a resource with a name, a list of objects for a resource without a name,
+    /// or the result of a Prometheus query or an HTTP request.
+    #[serde(default)]
+    pub resources: Vec<FilePathOrObject<serde_json::Value>>,
+    /// Output of the previous run, returned by `getPreviousOutput`
+    #[serde(default)]
+    pub previous_output: Option<CheckerOutput>,
+    #[serde(default)]
+    pub stubs: Stub,
+    pub expected: CheckExpected,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckExpected {
+    /// Output set with `setOutput` or `addViolation`, or `null` if the check passes
+    #[serde(default)]
+    pub output: Option<CheckerOutput>,
+    /// Number of `kubeGet` calls by the code
+    #[serde(default)]
+    pub kube_get_calls: Option<usize>,
+    /// Number of `kubeList` calls by the code
+    #[serde(default)]
+    pub kube_list_calls: Option<usize>,
 }
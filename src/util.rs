@@ -64,3 +64,58 @@ pub async fn find_group_version_pairs_by_kind(
 
     Ok(api_groups)
 }
+
+/// Check whether the resource of the group and version exists in the cluster
+///
+/// `resource` may contain a subresource, e.g. `pods/log`.
+pub async fn resource_exists(
+    group: &str,
+    version: &str,
+    resource: &str,
+    kube_client: kube::Client,
+) -> Result<bool, kube::Error> {
+    let resources = if group.is_empty() {
+        kube_client.list_core_api_resources(version).await
+    } else {
+        kube_client
+            .list_api_group_resources(&format!("{}/{}", group, version))
+            .await
+    };
+    match resources {
+        Ok(resources) => Ok(resources.resources.iter().any(|r| r.name == resource)),
+        // The group or version does not exist
+        Err(kube::Error::Api(error)) if error.code == 404 => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+/// Match the text with the glob pattern, where `*` matches any string and `?` matches any character
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<_> = pattern.chars().collect();
+    let text: Vec<_> = text.chars().collect();
+
+    // Position to resume from when a mismatch is found after `*`
+    let mut backtrack = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, star_t)) => {
+                    backtrack = Some((star, star_t + 1));
+                    p = star + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}